@@ -46,7 +46,19 @@ impl App {
         let download_len = metainfo.download_len();
         let is_seed = matches!(args.mode, Mode::Seed);
 
-        let storage = StorageInfo::new(&metainfo, self.download_dir.clone());
+        let conf = TorrentConf {
+            alerts: TorrentAlertConf {
+                completed_pieces: true,
+                peers: true,
+                peer_disconnects: false,
+            },
+            ..Default::default()
+        };
+        let storage = StorageInfo::new(
+            &metainfo,
+            self.download_dir.clone(),
+            &conf.path_rules,
+        );
         let files = storage
             .files
             .iter()
@@ -74,14 +86,9 @@ impl App {
         let torrent_id = self.engine.create_torrent(TorrentParams {
             metainfo: metainfo.clone(),
             listen_addr: args.listen,
+            queued: false,
             mode: args.mode,
-            conf: Some(TorrentConf {
-                alerts: TorrentAlertConf {
-                    completed_pieces: true,
-                    peers: true,
-                },
-                ..Default::default()
-            }),
+            conf: Some(conf),
         })?;
 
         let torrent = Torrent {
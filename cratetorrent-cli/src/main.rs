@@ -1,6 +1,7 @@
-use std::{io, net::SocketAddr, path::PathBuf};
+use std::{fs, io, net::SocketAddr, path::PathBuf};
 
 use cratetorrent::prelude::*;
+use cratetorrent_tracker::TrackerHandle;
 use futures::{select, stream::StreamExt};
 use structopt::StructOpt;
 use termion::{
@@ -46,6 +47,21 @@ pub struct Args {
 
     #[structopt(short, long)]
     quit_after_complete: bool,
+
+    /// The socket address on which to run a tracker over HTTP, for swarms
+    /// without a tracker of their own.
+    #[structopt(long)]
+    tracker_http: Option<SocketAddr>,
+
+    /// The socket address on which to run a tracker over UDP, for swarms
+    /// without a tracker of their own.
+    #[structopt(long)]
+    tracker_udp: Option<SocketAddr>,
+
+    /// Whether the tracker only tracks the torrent of the metainfo, rather
+    /// than any torrent announced to it.
+    #[structopt(long)]
+    tracker_private: bool,
 }
 
 fn parse_mode(s: &str) -> Mode {
@@ -70,6 +86,9 @@ async fn main() -> Result<()> {
 
     let quit_after_complete = args.quit_after_complete;
 
+    // the tracker is started before the torrent, which may be using it
+    let tracker = start_tracker(&args)?;
+
     // set up TUI backend
     let stdout = io::stdout().into_raw_mode()?;
     let stdout = MouseTerminal::from(stdout);
@@ -128,6 +147,30 @@ async fn main() -> Result<()> {
     }
 
     app.engine.shutdown().await?;
+    if let Some(tracker) = tracker {
+        tracker.shutdown().await?;
+    }
 
     Ok(())
 }
+
+/// Starts the tracker if it was asked for.
+fn start_tracker(args: &Args) -> Result<Option<TrackerHandle>> {
+    if args.tracker_http.is_none() && args.tracker_udp.is_none() {
+        return Ok(None);
+    }
+
+    let allowed_info_hashes = if args.tracker_private {
+        let metainfo = Metainfo::from_bytes(&fs::read(&args.metainfo)?)?;
+        Some(metainfo.info_hashes().into_iter().collect())
+    } else {
+        None
+    };
+    let tracker = cratetorrent_tracker::spawn(cratetorrent_tracker::Conf {
+        http_addr: args.tracker_http,
+        udp_addr: args.tracker_udp,
+        allowed_info_hashes,
+        ..Default::default()
+    })?;
+    Ok(Some(tracker))
+}
@@ -1,6 +1,9 @@
 use std::borrow::Cow;
 
-use cratetorrent::{peer::ConnectionState, torrent::stats::Peers};
+use cratetorrent::{
+    peer::{ConnectionState, Transport},
+    torrent::stats::Peers,
+};
 use tui::{
     backend::Backend,
     layout::{Alignment, Constraint, Corner, Direction, Layout, Rect},
@@ -253,6 +256,13 @@ pub fn draw_peers(f: &mut Frame<impl Backend>, torrent: &Torrent, area: Rect) {
                     // always show the peer's address
                     let mut buf = peer.addr.to_string();
 
+                    // the transport is only known once the peer is connected
+                    match peer.transport {
+                        Some(Transport::Tcp) => buf += " :: tcp",
+                        Some(Transport::Utp) => buf += " :: utp",
+                        None => {}
+                    }
+
                     // peer id: we may not be able to show it if we don't have
                     // it yet because peer is still connecting or if it's not
                     // valid utf8
@@ -0,0 +1,86 @@
+//! This module includes the errors that could occur when running the tracker,
+//! as well as those that are reported to peers whose requests couldn't be
+//! served.
+
+use std::fmt;
+
+pub use hyper::Error as HttpError;
+pub use tokio::io::Error as IoError;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The tracker was configured to serve requests over neither HTTP nor
+    /// UDP.
+    NoListenAddr,
+    /// An error of the HTTP server, such as when its address can't be bound.
+    Http(HttpError),
+    /// Holds IO related errors, such as those of the UDP socket.
+    Io(IoError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            NoListenAddr => write!(fmt, "no HTTP or UDP listen address"),
+            Http(e) => write!(fmt, "http error: {}", e),
+            Io(e) => write!(fmt, "io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Error::*;
+        match self {
+            Http(e) => Some(e),
+            Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<HttpError> for Error {
+    fn from(e: HttpError) -> Self {
+        Self::Http(e)
+    }
+}
+
+impl From<IoError> for Error {
+    fn from(e: IoError) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// The reasons for which a peer's request may be refused. These are sent to
+/// the peer as the failure reason of the response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RequestError {
+    /// A required parameter was not in the request.
+    MissingParam(&'static str),
+    /// A parameter of the request had an invalid value.
+    InvalidParam(&'static str),
+    /// The request was malformed or of an unknown type.
+    InvalidRequest,
+    /// The UDP connection id of the request was not one we handed out, or it
+    /// expired.
+    InvalidConnectionId,
+    /// The torrent is not on the tracker's allow-list.
+    TorrentNotAllowed,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use RequestError::*;
+        match self {
+            MissingParam(param) => write!(fmt, "missing parameter {}", param),
+            InvalidParam(param) => write!(fmt, "invalid parameter {}", param),
+            InvalidRequest => write!(fmt, "invalid request"),
+            InvalidConnectionId => write!(fmt, "invalid connection id"),
+            TorrentNotAllowed => write!(fmt, "torrent not allowed"),
+        }
+    }
+}
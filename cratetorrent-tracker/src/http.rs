@@ -0,0 +1,408 @@
+//! The HTTP tracker protocol, as described in
+//! [BEP 3](http://bittorrent.org/beps/bep_0003.html), with scrapes as
+//! described in [BEP 48](http://bittorrent.org/beps/bep_0048.html).
+//!
+//! Requests are GET requests whose parameters are in the query string, and
+//! responses are bencoded dictionaries. A request that can't be served is
+//! still responded to with status 200, but with only a failure reason in the
+//! dictionary.
+
+use std::{
+    collections::HashMap, convert::Infallible, future::Future, net::SocketAddr,
+    str::FromStr, sync::Arc, time::Instant,
+};
+
+use hyper::{
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use percent_encoding::percent_decode_str;
+use serde_bytes::ByteBuf;
+use tokio::sync::broadcast;
+
+use crate::{
+    error::{RequestError, Result},
+    swarm::{self, Announce, Event, Scrape, SwarmTable},
+    InfoHash,
+};
+
+#[derive(Debug, Serialize)]
+struct AnnounceResponse {
+    interval: u64,
+    #[serde(rename = "min interval")]
+    min_interval: u64,
+    /// The number of seeders in the swarm.
+    complete: usize,
+    /// The number of leechers in the swarm.
+    incomplete: usize,
+    #[serde(with = "serde_bytes")]
+    peers: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    peers6: Vec<u8>,
+}
+
+#[derive(Debug, Serialize)]
+struct ScrapeResponse {
+    files: HashMap<ByteBuf, ScrapeFile>,
+}
+
+#[derive(Debug, Serialize)]
+struct ScrapeFile {
+    complete: usize,
+    incomplete: usize,
+    downloaded: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct FailureResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: String,
+}
+
+/// Binds the HTTP server to the address, and returns its actual address and
+/// the future that runs the server until a shutdown signal arrives.
+pub(crate) fn bind(
+    addr: SocketAddr,
+    table: Arc<SwarmTable>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) -> Result<(SocketAddr, impl Future<Output = Result<()>>)> {
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let remote_addr = conn.remote_addr();
+        let table = Arc::clone(&table);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let resp = handle_request(&table, remote_addr, req);
+                async move { Ok::<_, Infallible>(resp) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    let addr = server.local_addr();
+    let server = server.with_graceful_shutdown(async move {
+        shutdown_rx.recv().await.ok();
+    });
+    Ok((addr, async move { Ok(server.await?) }))
+}
+
+fn handle_request(
+    table: &SwarmTable,
+    remote_addr: SocketAddr,
+    req: Request<Body>,
+) -> Response<Body> {
+    let query = req.uri().query().unwrap_or_default();
+    let result = match req.uri().path() {
+        "/announce" => announce(table, remote_addr, query),
+        "/scrape" => scrape(table, query),
+        path => {
+            log::debug!("Unknown path {} requested by {}", path, remote_addr);
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .expect("invalid response");
+        }
+    };
+    let body = result.unwrap_or_else(|e| {
+        log::debug!("Refusing request of {}: {}", remote_addr, e);
+        serde_bencode::to_bytes(&FailureResponse {
+            failure_reason: e.to_string(),
+        })
+        .expect("failed to encode response")
+    });
+    Response::new(Body::from(body))
+}
+
+fn announce(
+    table: &SwarmTable,
+    remote_addr: SocketAddr,
+    query: &str,
+) -> Result<Vec<u8>, RequestError> {
+    let mut info_hash = None;
+    let mut port = None;
+    let mut left = None;
+    let mut event = None;
+    let mut peer_count = None;
+    for (key, value) in parse_query(query) {
+        match key {
+            "info_hash" => {
+                info_hash = Some(parse_info_hash(&value)?);
+            }
+            "port" => {
+                port = Some(parse_value::<u16>(&value, "port")?);
+            }
+            "left" => {
+                left = Some(parse_value::<u64>(&value, "left")?);
+            }
+            "numwant" => {
+                peer_count = Some(parse_value::<usize>(&value, "numwant")?);
+            }
+            "event" => {
+                event = match value.as_slice() {
+                    b"started" => Some(Event::Started),
+                    b"completed" => Some(Event::Completed),
+                    b"stopped" => Some(Event::Stopped),
+                    b"" | b"empty" => None,
+                    _ => return Err(RequestError::InvalidParam("event")),
+                };
+            }
+            _ => {}
+        }
+    }
+
+    // the IP address the peer may send is ignored, as otherwise anyone could
+    // announce other hosts
+    let ip = swarm::to_canonical(remote_addr.ip());
+    let params = Announce {
+        info_hash: info_hash.ok_or(RequestError::MissingParam("info_hash"))?,
+        addr: SocketAddr::new(
+            ip,
+            port.ok_or(RequestError::MissingParam("port"))?,
+        ),
+        event,
+        left: left.ok_or(RequestError::MissingParam("left"))?,
+        peer_count,
+    };
+    log::debug!("HTTP announce: {:?}", params);
+
+    let result = table.announce(params, Instant::now())?;
+    let (peers, peers6) = result.compact_peers();
+    let resp = AnnounceResponse {
+        interval: table.announce_interval.as_secs(),
+        min_interval: table.min_announce_interval.as_secs(),
+        complete: result.seeder_count,
+        incomplete: result.leecher_count,
+        peers,
+        peers6,
+    };
+    Ok(serde_bencode::to_bytes(&resp).expect("failed to encode response"))
+}
+
+fn scrape(table: &SwarmTable, query: &str) -> Result<Vec<u8>, RequestError> {
+    let info_hashes = parse_query(query)
+        .into_iter()
+        .filter(|(key, _)| *key == "info_hash")
+        .map(|(_, value)| parse_info_hash(&value))
+        .collect::<Result<Vec<_>, _>>()?;
+    // scraping all torrents is not supported
+    if info_hashes.is_empty() {
+        return Err(RequestError::MissingParam("info_hash"));
+    }
+
+    let scrapes = table.scrape(&info_hashes);
+    let files = info_hashes
+        .iter()
+        .zip(scrapes)
+        .map(|(info_hash, scrape)| {
+            let Scrape {
+                seeder_count,
+                leecher_count,
+                download_count,
+            } = scrape;
+            (
+                ByteBuf::from(info_hash.to_vec()),
+                ScrapeFile {
+                    complete: seeder_count,
+                    incomplete: leecher_count,
+                    downloaded: download_count,
+                },
+            )
+        })
+        .collect();
+    Ok(serde_bencode::to_bytes(&ScrapeResponse { files })
+        .expect("failed to encode response"))
+}
+
+/// Splits the query string into its keys and values, with the values percent
+/// decoded into raw bytes, since the info hash is not valid UTF-8.
+fn parse_query(query: &str) -> Vec<(&str, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut pair = pair.splitn(2, '=');
+            let key = pair.next().unwrap_or_default();
+            let value = pair.next().unwrap_or_default();
+            (key, percent_decode_str(value).collect())
+        })
+        .collect()
+}
+
+fn parse_info_hash(value: &[u8]) -> Result<InfoHash, RequestError> {
+    let mut info_hash = [0; 20];
+    if value.len() != info_hash.len() {
+        return Err(RequestError::InvalidParam("info_hash"));
+    }
+    info_hash.copy_from_slice(value);
+    Ok(info_hash)
+}
+
+fn parse_value<T: FromStr>(
+    value: &[u8],
+    param: &'static str,
+) -> Result<T, RequestError> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or(RequestError::InvalidParam(param))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use serde_bencode::value::Value;
+
+    use super::*;
+    use crate::Conf;
+
+    fn request(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    /// Sends the request to the handler and returns the decoded response
+    /// dictionary.
+    async fn send(
+        table: &SwarmTable,
+        remote_addr: SocketAddr,
+        uri: &str,
+    ) -> HashMap<Vec<u8>, Value> {
+        let resp = handle_request(table, remote_addr, request(uri));
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        match serde_bencode::from_bytes(&body).unwrap() {
+            Value::Dict(dict) => dict,
+            value => panic!("unexpected response {:?}", value),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_announce() {
+        let table = SwarmTable::new(&Conf::default());
+        let seed = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 1234);
+        let leech = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 1234);
+        let info_hash = "%01".repeat(20);
+
+        let resp = send(
+            &table,
+            seed,
+            &format!(
+                "/announce?info_hash={}&peer_id={}&port=6881&uploaded=0\
+                &downloaded=0&left=0&compact=1&event=started",
+                info_hash,
+                "%02".repeat(20),
+            ),
+        )
+        .await;
+        assert_eq!(resp[&b"complete"[..]], Value::Int(1));
+        assert_eq!(resp[&b"incomplete"[..]], Value::Int(0));
+        assert_eq!(resp[&b"interval"[..]], Value::Int(1800));
+        assert_eq!(resp[&b"min interval"[..]], Value::Int(60));
+        assert_eq!(resp[&b"peers"[..]], Value::Bytes(Vec::new()));
+
+        let resp = send(
+            &table,
+            leech,
+            &format!(
+                "/announce?info_hash={}&port=6882&left=100&numwant=10",
+                info_hash,
+            ),
+        )
+        .await;
+        assert_eq!(resp[&b"complete"[..]], Value::Int(1));
+        assert_eq!(resp[&b"incomplete"[..]], Value::Int(1));
+        // the seed is returned with the port it announced
+        assert_eq!(
+            resp[&b"peers"[..]],
+            Value::Bytes(vec![10, 0, 0, 1, 0x1a, 0xe1])
+        );
+        assert_eq!(resp[&b"peers6"[..]], Value::Bytes(Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn test_scrape() {
+        let table = SwarmTable::new(&Conf::default());
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1234);
+        send(
+            &table,
+            addr,
+            &format!("/announce?info_hash={}&port=1&left=0", "%01".repeat(20)),
+        )
+        .await;
+
+        let resp = send(
+            &table,
+            addr,
+            &format!(
+                "/scrape?info_hash={}&info_hash={}",
+                "%01".repeat(20),
+                "%02".repeat(20)
+            ),
+        )
+        .await;
+        let files = match &resp[&b"files"[..]] {
+            Value::Dict(files) => files,
+            value => panic!("unexpected files {:?}", value),
+        };
+        let file = |n: u8, complete: i64| {
+            let mut file = HashMap::new();
+            file.insert(b"complete".to_vec(), Value::Int(complete));
+            file.insert(b"incomplete".to_vec(), Value::Int(0));
+            file.insert(b"downloaded".to_vec(), Value::Int(0));
+            (vec![n; 20], Value::Dict(file))
+        };
+        assert_eq!(files, &vec![file(1, 1), file(2, 0)].into_iter().collect());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_requests() {
+        let table = SwarmTable::new(&Conf {
+            allowed_info_hashes: Some(vec![[2; 20]].into_iter().collect()),
+            ..Conf::default()
+        });
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1234);
+        let failure_reason = |reason: &str| {
+            let mut resp = HashMap::new();
+            resp.insert(
+                b"failure reason".to_vec(),
+                Value::Bytes(reason.as_bytes().to_vec()),
+            );
+            resp
+        };
+
+        assert_eq!(
+            send(&table, addr, "/announce?port=1&left=0").await,
+            failure_reason("missing parameter info_hash")
+        );
+        assert_eq!(
+            send(&table, addr, "/announce?info_hash=%01&port=1&left=0").await,
+            failure_reason("invalid parameter info_hash")
+        );
+        assert_eq!(
+            send(
+                &table,
+                addr,
+                &format!(
+                    "/announce?info_hash={}&port=x&left=0",
+                    "%02".repeat(20)
+                )
+            )
+            .await,
+            failure_reason("invalid parameter port")
+        );
+        assert_eq!(
+            send(
+                &table,
+                addr,
+                &format!(
+                    "/announce?info_hash={}&port=1&left=0",
+                    "%01".repeat(20)
+                )
+            )
+            .await,
+            failure_reason("torrent not allowed")
+        );
+
+        let resp = handle_request(&table, addr, request("/stats"));
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,234 @@
+//! `cratetorrent-tracker` is a BitTorrent tracker that keeps its swarms in
+//! memory.
+//!
+//! It serves announces and scrapes over HTTP, as described in
+//! [BEP 3](http://bittorrent.org/beps/bep_0003.html) and
+//! [BEP 48](http://bittorrent.org/beps/bep_0048.html), and over UDP, as
+//! described in [BEP 15](http://bittorrent.org/beps/bep_0015.html). Peers are
+//! always returned in compact form, with IPv6 peers in a separate list, as
+//! described in [BEP 7](http://bittorrent.org/beps/bep_0007.html).
+//!
+//! It is meant for running private swarms, such as on air-gapped networks or
+//! in tests, rather than for serving the public at large: nothing is
+//! persisted, so all swarms are forgotten when the tracker stops.
+//!
+//! # Example
+//!
+//! The tracker is spawned on the tokio executor, and it runs until it is shut
+//! down via its handle:
+//!
+//! ```no_run
+//! use cratetorrent_tracker::Conf;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let mut conf = Conf::default();
+//!     conf.http_addr = Some("0.0.0.0:8000".parse()?);
+//!     conf.udp_addr = Some("0.0.0.0:8000".parse()?);
+//!     let tracker = cratetorrent_tracker::spawn(conf)?;
+//!
+//!     // run the swarms...
+//!
+//!     tracker.shutdown().await?;
+//!     Ok(())
+//! }
+//! ```
+
+#[macro_use]
+extern crate serde_derive;
+
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::{future::FutureExt, select, stream::StreamExt};
+use tokio::{
+    sync::broadcast,
+    task::{self, JoinHandle},
+    time,
+};
+
+use swarm::SwarmTable;
+
+pub use error::{Error, Result};
+
+pub mod error;
+mod http;
+mod swarm;
+mod udp;
+
+/// The SHA-1 hash that identifies a torrent.
+pub type InfoHash = [u8; 20];
+
+/// The configuration of the tracker.
+#[derive(Clone, Debug)]
+pub struct Conf {
+    /// The address on which announces and scrapes are served over HTTP, if
+    /// at all.
+    pub http_addr: Option<SocketAddr>,
+
+    /// The address on which announces and scrapes are served over UDP, if at
+    /// all.
+    pub udp_addr: Option<SocketAddr>,
+
+    /// How often peers are asked to announce.
+    pub announce_interval: Duration,
+
+    /// How often peers may announce at most. This is only sent to peers over
+    /// HTTP, as the UDP protocol has no such field.
+    pub min_announce_interval: Duration,
+
+    /// Peers that haven't announced for this long are removed from their
+    /// swarm. This should be longer than the announce interval.
+    pub peer_timeout: Duration,
+
+    /// The number of peers returned in an announce response if the peer
+    /// didn't say how many it wants.
+    pub default_peer_count: usize,
+
+    /// The maximum number of peers returned in an announce response,
+    /// regardless of how many the peer asked for.
+    pub max_peer_count: usize,
+
+    /// If set, only the torrents with these info hashes are tracked, and
+    /// announces for other torrents are refused.
+    pub allowed_info_hashes: Option<HashSet<InfoHash>>,
+}
+
+impl Default for Conf {
+    fn default() -> Self {
+        Self {
+            http_addr: None,
+            udp_addr: None,
+            announce_interval: Duration::from_secs(30 * 60),
+            min_announce_interval: Duration::from_secs(60),
+            // peers that missed two announces in a row are assumed to be gone
+            peer_timeout: Duration::from_secs(2 * 30 * 60 + 60),
+            default_peer_count: 50,
+            // this many IPv6 peers still fit in a UDP packet comfortably
+            max_peer_count: 200,
+            allowed_info_hashes: None,
+        }
+    }
+}
+
+/// Spawns the tracker on the tokio executor, and returns a handle with which
+/// it can be shut down.
+///
+/// The tracker's sockets are bound before this function returns, so binding
+/// errors are returned here. The actual addresses of the tracker, which may
+/// differ from the configured ones if port 0 was given, are available via the
+/// handle.
+///
+/// # Important
+///
+/// This must be called from within a tokio context.
+pub fn spawn(conf: Conf) -> Result<TrackerHandle> {
+    if conf.http_addr.is_none() && conf.udp_addr.is_none() {
+        return Err(Error::NoListenAddr);
+    }
+
+    let table = Arc::new(SwarmTable::new(&conf));
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let mut join_handles = Vec::new();
+
+    let http_addr = match conf.http_addr {
+        Some(addr) => {
+            let (addr, server) =
+                http::bind(addr, Arc::clone(&table), shutdown_tx.subscribe())?;
+            log::info!("Serving HTTP tracker on {}", addr);
+            join_handles.push(task::spawn(server));
+            Some(addr)
+        }
+        None => None,
+    };
+
+    let udp_addr = match conf.udp_addr {
+        Some(addr) => {
+            let server = udp::Server::bind(addr, Arc::clone(&table))?;
+            let addr = server.local_addr()?;
+            log::info!("Serving UDP tracker on {}", addr);
+            join_handles.push(task::spawn(server.run(shutdown_tx.subscribe())));
+            Some(addr)
+        }
+        None => None,
+    };
+
+    // peers that leave without telling the tracker are periodically removed
+    let mut shutdown_rx = shutdown_tx.subscribe();
+    let check_interval = conf.peer_timeout.min(Duration::from_secs(60));
+    join_handles.push(task::spawn(async move {
+        let mut timer = time::interval(check_interval).fuse();
+        loop {
+            select! {
+                _ = timer.select_next_some() => {
+                    table.remove_expired(Instant::now());
+                }
+                _ = shutdown_rx.recv().fuse() => break,
+            }
+        }
+        Ok(())
+    }));
+
+    Ok(TrackerHandle {
+        http_addr,
+        udp_addr,
+        shutdown_tx,
+        join_handles,
+    })
+}
+
+/// A handle to the running tracker.
+pub struct TrackerHandle {
+    http_addr: Option<SocketAddr>,
+    udp_addr: Option<SocketAddr>,
+    /// Every task of the tracker stops when this is sent on, or dropped.
+    shutdown_tx: broadcast::Sender<()>,
+    join_handles: Vec<JoinHandle<Result<()>>>,
+}
+
+impl TrackerHandle {
+    /// The address on which the tracker serves HTTP requests, if it does.
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.http_addr
+    }
+
+    /// The address on which the tracker serves UDP requests, if it does.
+    pub fn udp_addr(&self) -> Option<SocketAddr> {
+        self.udp_addr
+    }
+
+    /// The HTTP announce URL of the tracker, if it serves HTTP requests.
+    ///
+    /// The URL's host is the address the tracker is bound to, so if that is
+    /// the unspecified address, the URL only works on the local host.
+    pub fn http_announce_url(&self) -> Option<String> {
+        self.http_addr
+            .map(|addr| format!("http://{}/announce", addr))
+    }
+
+    /// The UDP announce URL of the tracker, if it serves UDP requests.
+    ///
+    /// The same caveat applies as for [`Self::http_announce_url`].
+    pub fn udp_announce_url(&self) -> Option<String> {
+        self.udp_addr.map(|addr| format!("udp://{}/announce", addr))
+    }
+
+    /// Gracefully shuts down the tracker and waits for all its tasks to stop.
+    pub async fn shutdown(self) -> Result<()> {
+        log::info!("Shutting down tracker");
+        // the tasks may have stopped due to an error already
+        self.shutdown_tx.send(()).ok();
+        let mut result = Ok(());
+        for join_handle in self.join_handles {
+            if let Err(e) = join_handle.await.expect("task error") {
+                log::error!("Tracker error: {}", e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,422 @@
+//! The in-memory table of the swarms the tracker knows of, shared by the HTTP
+//! and UDP servers.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rand::seq::IteratorRandom;
+
+use crate::{error::RequestError, Conf, InfoHash};
+
+/// The optional announce event.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Event {
+    /// The peer joined the swarm.
+    Started,
+    /// The peer finished downloading the torrent.
+    Completed,
+    /// The peer is leaving the swarm.
+    Stopped,
+}
+
+/// The parameters of an announce that are relevant to the tracker, regardless
+/// of the protocol over which it arrived.
+#[derive(Debug)]
+pub(crate) struct Announce {
+    pub info_hash: InfoHash,
+    /// The address on which the peer accepts connections, which is the
+    /// source IP of the request with the port the peer announced.
+    pub addr: SocketAddr,
+    pub event: Option<Event>,
+    /// The number of bytes the peer still has to download.
+    pub left: u64,
+    /// The number of peers the peer wants, if it said so.
+    pub peer_count: Option<usize>,
+}
+
+/// The tracker's response to an announce.
+#[derive(Debug, Default)]
+pub(crate) struct AnnounceResult {
+    /// A random selection of the other peers in the swarm.
+    pub peers: Vec<SocketAddr>,
+    pub seeder_count: usize,
+    pub leecher_count: usize,
+}
+
+impl AnnounceResult {
+    /// Returns the IPv4 and IPv6 peers in compact form, which is 6 and 18
+    /// bytes per peer, respectively: the IP address followed by the port,
+    /// both in network byte order.
+    pub fn compact_peers(&self) -> (Vec<u8>, Vec<u8>) {
+        let mut peers = Vec::new();
+        let mut peers6 = Vec::new();
+        for addr in self.peers.iter() {
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    peers.extend_from_slice(&ip.octets());
+                    peers.extend_from_slice(&addr.port().to_be_bytes());
+                }
+                IpAddr::V6(ip) => {
+                    peers6.extend_from_slice(&ip.octets());
+                    peers6.extend_from_slice(&addr.port().to_be_bytes());
+                }
+            }
+        }
+        (peers, peers6)
+    }
+}
+
+/// The statistics of a swarm.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Scrape {
+    pub seeder_count: usize,
+    pub leecher_count: usize,
+    /// The number of times the torrent was downloaded in full.
+    pub download_count: usize,
+}
+
+/// The swarms of the torrents the tracker knows of, keyed by info hash.
+///
+/// A torrent becomes known to the tracker with the first announce of one of
+/// its peers, unless an allow-list is configured, in which case only the
+/// torrents on the list are tracked.
+pub(crate) struct SwarmTable {
+    /// How often peers are asked to announce.
+    pub announce_interval: Duration,
+    /// How often peers may announce at most.
+    pub min_announce_interval: Duration,
+    swarms: Mutex<HashMap<InfoHash, Swarm>>,
+    allowed_info_hashes: Option<HashSet<InfoHash>>,
+    peer_timeout: Duration,
+    default_peer_count: usize,
+    max_peer_count: usize,
+}
+
+#[derive(Default)]
+struct Swarm {
+    /// The peers in the swarm, keyed by the address on which they accept
+    /// connections.
+    peers: HashMap<SocketAddr, Peer>,
+    download_count: usize,
+}
+
+struct Peer {
+    is_seed: bool,
+    last_announce_time: Instant,
+}
+
+impl SwarmTable {
+    pub fn new(conf: &Conf) -> Self {
+        Self {
+            announce_interval: conf.announce_interval,
+            min_announce_interval: conf.min_announce_interval,
+            swarms: Mutex::new(HashMap::new()),
+            allowed_info_hashes: conf.allowed_info_hashes.clone(),
+            peer_timeout: conf.peer_timeout,
+            default_peer_count: conf.default_peer_count,
+            max_peer_count: conf.max_peer_count,
+        }
+    }
+
+    /// Records the announce of the peer in the torrent's swarm and returns
+    /// some other peers in the swarm.
+    ///
+    /// Seeds are only given leeches, as they have no use for other seeds.
+    pub fn announce(
+        &self,
+        params: Announce,
+        now: Instant,
+    ) -> Result<AnnounceResult, RequestError> {
+        if !self.is_allowed(&params.info_hash) {
+            return Err(RequestError::TorrentNotAllowed);
+        }
+
+        let mut swarms = self.swarms.lock().expect("swarm table poisoned");
+        let swarm = swarms.entry(params.info_hash).or_default();
+
+        if params.event == Some(Event::Stopped) {
+            swarm.peers.remove(&params.addr);
+            let (seeder_count, leecher_count) = swarm.counts();
+            return Ok(AnnounceResult {
+                peers: Vec::new(),
+                seeder_count,
+                leecher_count,
+            });
+        }
+
+        let is_seed = params.left == 0;
+        // a peer may repeat the event if it didn't get our response, but it
+        // only completed the download once
+        let was_seed = swarm
+            .peers
+            .get(&params.addr)
+            .map(|peer| peer.is_seed)
+            .unwrap_or(false);
+        if params.event == Some(Event::Completed) && !was_seed {
+            swarm.download_count += 1;
+        }
+        swarm.peers.insert(
+            params.addr,
+            Peer {
+                is_seed,
+                last_announce_time: now,
+            },
+        );
+
+        let peer_count = params
+            .peer_count
+            .unwrap_or(self.default_peer_count)
+            .min(self.max_peer_count);
+        let peers = swarm
+            .peers
+            .iter()
+            .filter(|(addr, peer)| {
+                **addr != params.addr && !(is_seed && peer.is_seed)
+            })
+            .map(|(addr, _)| *addr)
+            .choose_multiple(&mut rand::thread_rng(), peer_count);
+        let (seeder_count, leecher_count) = swarm.counts();
+
+        Ok(AnnounceResult {
+            peers,
+            seeder_count,
+            leecher_count,
+        })
+    }
+
+    /// Returns the statistics of the swarms of the info hashes, in the same
+    /// order. The statistics of unknown torrents are all zeros.
+    pub fn scrape(&self, info_hashes: &[InfoHash]) -> Vec<Scrape> {
+        let swarms = self.swarms.lock().expect("swarm table poisoned");
+        info_hashes
+            .iter()
+            .map(|info_hash| match swarms.get(info_hash) {
+                Some(swarm) => {
+                    let (seeder_count, leecher_count) = swarm.counts();
+                    Scrape {
+                        seeder_count,
+                        leecher_count,
+                        download_count: swarm.download_count,
+                    }
+                }
+                None => Scrape::default(),
+            })
+            .collect()
+    }
+
+    /// Removes the peers that haven't announced within the peer timeout, and
+    /// forgets the swarms that are left with nothing to remember.
+    pub fn remove_expired(&self, now: Instant) {
+        let mut swarms = self.swarms.lock().expect("swarm table poisoned");
+        let peer_timeout = self.peer_timeout;
+        for swarm in swarms.values_mut() {
+            swarm.peers.retain(|_, peer| {
+                now.saturating_duration_since(peer.last_announce_time)
+                    < peer_timeout
+            });
+        }
+        swarms.retain(|_, swarm| {
+            !swarm.peers.is_empty() || swarm.download_count > 0
+        });
+    }
+
+    fn is_allowed(&self, info_hash: &InfoHash) -> bool {
+        self.allowed_info_hashes
+            .as_ref()
+            .map(|allowed| allowed.contains(info_hash))
+            .unwrap_or(true)
+    }
+}
+
+impl Swarm {
+    /// Returns the number of seeders and leechers in the swarm.
+    fn counts(&self) -> (usize, usize) {
+        let seeder_count = self.peers.values().filter(|p| p.is_seed).count();
+        (seeder_count, self.peers.len() - seeder_count)
+    }
+}
+
+/// Returns the IP address of a request's source, with IPv4-mapped IPv6
+/// addresses, which are seen on dual-stack sockets, turned into IPv4 ones.
+pub(crate) fn to_canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => match ip.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                Ipv4Addr::new(a, b, c, d).into()
+            }
+            _ => ip.into(),
+        },
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    fn table(conf: Conf) -> SwarmTable {
+        SwarmTable::new(&conf)
+    }
+
+    fn addr(n: u8) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, n).into(), 6881)
+    }
+
+    fn announce(n: u8, left: u64, event: Option<Event>) -> Announce {
+        Announce {
+            info_hash: [1; 20],
+            addr: addr(n),
+            event,
+            left,
+            peer_count: None,
+        }
+    }
+
+    #[test]
+    fn should_return_other_peers() {
+        let table = table(Conf::default());
+        let now = Instant::now();
+
+        let resp = table.announce(announce(1, 10, None), now).unwrap();
+        assert!(resp.peers.is_empty());
+        assert_eq!(resp.leecher_count, 1);
+
+        table.announce(announce(2, 0, None), now).unwrap();
+        let resp = table.announce(announce(3, 10, None), now).unwrap();
+        let mut peers = resp.peers;
+        peers.sort();
+        assert_eq!(peers, vec![addr(1), addr(2)]);
+        assert_eq!(resp.seeder_count, 1);
+        assert_eq!(resp.leecher_count, 2);
+
+        // seeds aren't given other seeds
+        table.announce(announce(4, 0, None), now).unwrap();
+        let resp = table.announce(announce(2, 0, None), now).unwrap();
+        let mut peers = resp.peers;
+        peers.sort();
+        assert_eq!(peers, vec![addr(1), addr(3)]);
+    }
+
+    #[test]
+    fn should_limit_peer_count() {
+        let table = table(Conf {
+            max_peer_count: 3,
+            ..Conf::default()
+        });
+        let now = Instant::now();
+        for n in 0..10 {
+            table.announce(announce(n, 10, None), now).unwrap();
+        }
+
+        let mut params = announce(10, 10, None);
+        params.peer_count = Some(2);
+        assert_eq!(table.announce(params, now).unwrap().peers.len(), 2);
+        let mut params = announce(10, 10, None);
+        params.peer_count = Some(100);
+        assert_eq!(table.announce(params, now).unwrap().peers.len(), 3);
+    }
+
+    #[test]
+    fn should_track_events() {
+        let table = table(Conf::default());
+        let now = Instant::now();
+
+        table
+            .announce(announce(1, 10, Some(Event::Started)), now)
+            .unwrap();
+        table.announce(announce(2, 10, None), now).unwrap();
+        table
+            .announce(announce(1, 0, Some(Event::Completed)), now)
+            .unwrap();
+        // repeated events aren't counted twice
+        table
+            .announce(announce(1, 0, Some(Event::Completed)), now)
+            .unwrap();
+        assert_eq!(
+            table.scrape(&[[1; 20], [2; 20]]),
+            vec![
+                Scrape {
+                    seeder_count: 1,
+                    leecher_count: 1,
+                    download_count: 1,
+                },
+                Scrape::default(),
+            ]
+        );
+
+        let resp = table
+            .announce(announce(1, 0, Some(Event::Stopped)), now)
+            .unwrap();
+        assert!(resp.peers.is_empty());
+        assert_eq!(resp.seeder_count, 0);
+        assert_eq!(resp.leecher_count, 1);
+    }
+
+    #[test]
+    fn should_remove_expired_peers() {
+        let table = table(Conf {
+            peer_timeout: Duration::from_secs(60),
+            ..Conf::default()
+        });
+        let now = Instant::now();
+        table.announce(announce(1, 10, None), now).unwrap();
+        table
+            .announce(announce(2, 10, None), now + Duration::from_secs(30))
+            .unwrap();
+
+        table.remove_expired(now + Duration::from_secs(60));
+        assert_eq!(table.scrape(&[[1; 20]])[0].leecher_count, 1);
+
+        // the swarm is forgotten once it's empty, as nobody downloaded it
+        table.remove_expired(now + Duration::from_secs(90));
+        assert!(table.swarms.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_only_track_allowed_torrents() {
+        let table = table(Conf {
+            allowed_info_hashes: Some(vec![[2; 20]].into_iter().collect()),
+            ..Conf::default()
+        });
+        let now = Instant::now();
+        assert_eq!(
+            table.announce(announce(1, 10, None), now).unwrap_err(),
+            RequestError::TorrentNotAllowed
+        );
+        let mut params = announce(1, 10, None);
+        params.info_hash = [2; 20];
+        assert!(table.announce(params, now).is_ok());
+    }
+
+    #[test]
+    fn should_encode_compact_peers() {
+        let resp = AnnounceResult {
+            peers: vec![
+                SocketAddr::new(Ipv4Addr::new(1, 2, 3, 4).into(), 0x1a2b),
+                SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0x3c4d),
+            ],
+            ..Default::default()
+        };
+        let (peers, peers6) = resp.compact_peers();
+        assert_eq!(peers, vec![1, 2, 3, 4, 0x1a, 0x2b]);
+        let mut expected = vec![0; 15];
+        expected.extend_from_slice(&[1, 0x3c, 0x4d]);
+        assert_eq!(peers6, expected);
+    }
+
+    #[test]
+    fn should_canonicalize_mapped_addrs() {
+        let ip = Ipv4Addr::new(1, 2, 3, 4);
+        assert_eq!(to_canonical(ip.to_ipv6_mapped().into()), IpAddr::V4(ip));
+        assert_eq!(
+            to_canonical(Ipv6Addr::LOCALHOST.into()),
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+    }
+}
@@ -0,0 +1,441 @@
+//! The UDP tracker protocol, as described in
+//! [BEP 15](http://bittorrent.org/beps/bep_0015.html).
+//!
+//! Before announcing or scraping, a peer has to obtain a connection id with a
+//! connect request, which proves that the peer is not spoofing its address.
+//! Rather than remembering the connection ids we hand out, they are derived
+//! from the peer's IP address and the current time with a hash keyed by
+//! a secret, so that they can be verified without keeping state.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{future::FutureExt, select};
+use tokio::{net::UdpSocket, sync::broadcast};
+
+use crate::{
+    error::{RequestError, Result},
+    swarm::{self, Announce, Event, SwarmTable},
+};
+
+/// The magic constant that identifies the protocol in connect requests.
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// How long a connection id is valid for at least. Peers may use it for
+/// a minute, and we accept it for up to twice as long.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+/// The maximum number of info hashes that may be scraped in a single request.
+const MAX_SCRAPE_COUNT: usize = 74;
+
+/// The largest request we expect, which is a scrape request with the maximum
+/// number of info hashes.
+const MAX_REQUEST_LEN: usize = 16 + MAX_SCRAPE_COUNT * 20;
+
+/// The length of an announce request after its header.
+const ANNOUNCE_LEN: usize = 82;
+
+pub(crate) struct Server {
+    socket: UdpSocket,
+    table: Arc<SwarmTable>,
+    /// The keyed hasher with which connection ids are derived.
+    hash_state: RandomState,
+    /// The time from which the epochs of connection ids are counted.
+    start_time: Instant,
+}
+
+impl Server {
+    /// Binds the UDP socket of the server to the address.
+    pub fn bind(addr: SocketAddr, table: Arc<SwarmTable>) -> Result<Self> {
+        let socket = std::net::UdpSocket::bind(addr)?;
+        Ok(Self {
+            socket: UdpSocket::from_std(socket)?,
+            table,
+            hash_state: RandomState::new(),
+            start_time: Instant::now(),
+        })
+    }
+
+    /// Returns the actual address of the server.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Serves requests until a shutdown signal arrives.
+    pub async fn run(
+        mut self,
+        mut shutdown_rx: broadcast::Receiver<()>,
+    ) -> Result<()> {
+        let mut buf = vec![0; MAX_REQUEST_LEN];
+        loop {
+            let result = select! {
+                result = self.socket.recv_from(&mut buf).fuse() => result,
+                _ = shutdown_rx.recv().fuse() => return Ok(()),
+            };
+            let (len, addr) = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    // errors of earlier sends to unreachable peers may be
+                    // reported here, which is no reason to stop serving
+                    log::debug!("Error receiving UDP request: {}", e);
+                    continue;
+                }
+            };
+            if let Some(resp) =
+                self.handle_request(&buf[..len], addr, Instant::now())
+            {
+                if let Err(e) = self.socket.send_to(&resp, &addr).await {
+                    log::debug!(
+                        "Error sending UDP response to {}: {}",
+                        addr,
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns the response to the request, if it was long enough to be
+    /// responded to.
+    fn handle_request(
+        &self,
+        mut req: &[u8],
+        addr: SocketAddr,
+        now: Instant,
+    ) -> Option<Vec<u8>> {
+        if req.len() < 16 {
+            return None;
+        }
+        let ip = swarm::to_canonical(addr.ip());
+        let connection_id = req.get_u64();
+        let action = req.get_u32();
+        let transaction_id = req.get_u32();
+
+        let mut resp = BytesMut::new();
+        let result = match action {
+            ACTION_CONNECT if connection_id == PROTOCOL_ID => {
+                resp.put_u64(self.connection_id(ip, self.epoch(now)));
+                Ok(())
+            }
+            _ if !self.is_valid_connection_id(connection_id, ip, now) => {
+                Err(RequestError::InvalidConnectionId)
+            }
+            ACTION_ANNOUNCE => self.announce(req, ip, now, &mut resp),
+            ACTION_SCRAPE => self.scrape(req, &mut resp),
+            _ => Err(RequestError::InvalidRequest),
+        };
+
+        let mut header = BytesMut::with_capacity(8 + resp.len());
+        match result {
+            Ok(()) => {
+                header.put_u32(action);
+                header.put_u32(transaction_id);
+                header.put_slice(&resp);
+            }
+            Err(e) => {
+                log::debug!("Refusing UDP request of {}: {}", addr, e);
+                header.put_u32(ACTION_ERROR);
+                header.put_u32(transaction_id);
+                header.put_slice(e.to_string().as_bytes());
+            }
+        }
+        Some(header.to_vec())
+    }
+
+    fn announce(
+        &self,
+        mut req: &[u8],
+        ip: IpAddr,
+        now: Instant,
+        resp: &mut BytesMut,
+    ) -> Result<(), RequestError> {
+        if req.len() < ANNOUNCE_LEN {
+            return Err(RequestError::InvalidRequest);
+        }
+        let mut info_hash = [0; 20];
+        req.copy_to_slice(&mut info_hash);
+        // peer id
+        req.advance(20);
+        // downloaded
+        req.advance(8);
+        let left = req.get_u64();
+        // uploaded
+        req.advance(8);
+        let event = match req.get_u32() {
+            1 => Some(Event::Completed),
+            2 => Some(Event::Started),
+            3 => Some(Event::Stopped),
+            _ => None,
+        };
+        // the IP address the peer may send is ignored, as otherwise anyone
+        // could announce other hosts
+        req.advance(4);
+        // key
+        req.advance(4);
+        // -1 means the peer leaves it to us
+        let peer_count = req.get_i32();
+        let peer_count = if peer_count < 0 {
+            None
+        } else {
+            Some(peer_count as usize)
+        };
+        let port = req.get_u16();
+
+        let params = Announce {
+            info_hash,
+            addr: SocketAddr::new(ip, port),
+            event,
+            left,
+            peer_count,
+        };
+        log::debug!("UDP announce: {:?}", params);
+        let result = self.table.announce(params, now)?;
+
+        resp.put_u32(self.table.announce_interval.as_secs() as u32);
+        resp.put_u32(result.leecher_count as u32);
+        resp.put_u32(result.seeder_count as u32);
+        // only peers of the same IP version as the request can be returned,
+        // as the response has no way of telling them apart
+        let (peers, peers6) = result.compact_peers();
+        if ip.is_ipv4() {
+            resp.put_slice(&peers);
+        } else {
+            resp.put_slice(&peers6);
+        }
+        Ok(())
+    }
+
+    fn scrape(
+        &self,
+        req: &[u8],
+        resp: &mut BytesMut,
+    ) -> Result<(), RequestError> {
+        let chunks = req.chunks_exact(20);
+        if !chunks.remainder().is_empty()
+            || chunks.len() == 0
+            || chunks.len() > MAX_SCRAPE_COUNT
+        {
+            return Err(RequestError::InvalidRequest);
+        }
+        let info_hashes: Vec<_> = chunks
+            .map(|chunk| {
+                let mut info_hash = [0; 20];
+                info_hash.copy_from_slice(chunk);
+                info_hash
+            })
+            .collect();
+        for scrape in self.table.scrape(&info_hashes) {
+            resp.put_u32(scrape.seeder_count as u32);
+            resp.put_u32(scrape.download_count as u32);
+            resp.put_u32(scrape.leecher_count as u32);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of connection id lifetimes that passed since the
+    /// server started.
+    fn epoch(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.start_time).as_secs()
+            / CONNECTION_ID_LIFETIME.as_secs()
+    }
+
+    fn connection_id(&self, ip: IpAddr, epoch: u64) -> u64 {
+        let mut hasher = self.hash_state.build_hasher();
+        ip.hash(&mut hasher);
+        epoch.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Checks whether the connection id was handed out to the IP address in
+    /// the current or the previous epoch.
+    fn is_valid_connection_id(
+        &self,
+        connection_id: u64,
+        ip: IpAddr,
+        now: Instant,
+    ) -> bool {
+        let epoch = self.epoch(now);
+        connection_id == self.connection_id(ip, epoch)
+            || connection_id == self.connection_id(ip, epoch.saturating_sub(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+    use crate::Conf;
+
+    fn server() -> Server {
+        let table = Arc::new(SwarmTable::new(&Conf::default()));
+        Server::bind((Ipv4Addr::LOCALHOST, 0).into(), table).unwrap()
+    }
+
+    /// Sends the request to the server and returns the action, the
+    /// transaction id and the body of the response.
+    fn send(
+        server: &Server,
+        req: &[u8],
+        addr: SocketAddr,
+        now: Instant,
+    ) -> (u32, u32, Vec<u8>) {
+        let resp = server.handle_request(req, addr, now).unwrap();
+        let mut resp = &resp[..];
+        let action = resp.get_u32();
+        let transaction_id = resp.get_u32();
+        (action, transaction_id, resp.to_vec())
+    }
+
+    fn connect(server: &Server, addr: SocketAddr, now: Instant) -> u64 {
+        let mut req = BytesMut::new();
+        req.put_u64(PROTOCOL_ID);
+        req.put_u32(ACTION_CONNECT);
+        req.put_u32(7);
+        let (action, transaction_id, resp) = send(server, &req, addr, now);
+        assert_eq!(action, ACTION_CONNECT);
+        assert_eq!(transaction_id, 7);
+        (&resp[..]).get_u64()
+    }
+
+    fn announce_req(connection_id: u64, port: u16, left: u64) -> BytesMut {
+        let mut req = BytesMut::new();
+        req.put_u64(connection_id);
+        req.put_u32(ACTION_ANNOUNCE);
+        req.put_u32(8);
+        req.put_slice(&[1; 20]);
+        req.put_slice(&[2; 20]);
+        req.put_u64(0);
+        req.put_u64(left);
+        req.put_u64(0);
+        // started
+        req.put_u32(2);
+        req.put_u32(0);
+        req.put_u32(0);
+        req.put_i32(-1);
+        req.put_u16(port);
+        req
+    }
+
+    #[tokio::test]
+    async fn test_connection_id() {
+        let server = server();
+        let addr: SocketAddr = (Ipv4Addr::new(10, 0, 0, 1), 1234).into();
+        let now = Instant::now();
+        let connection_id = connect(&server, addr, now);
+
+        assert!(server.is_valid_connection_id(connection_id, addr.ip(), now));
+        assert!(server.is_valid_connection_id(
+            connection_id,
+            addr.ip(),
+            now + CONNECTION_ID_LIFETIME
+        ));
+        assert!(!server.is_valid_connection_id(
+            connection_id,
+            addr.ip(),
+            now + 2 * CONNECTION_ID_LIFETIME
+        ));
+        assert!(!server.is_valid_connection_id(
+            connection_id,
+            Ipv4Addr::new(10, 0, 0, 2).into(),
+            now
+        ));
+
+        let req = announce_req(connection_id + 1, 6881, 0);
+        let (action, transaction_id, resp) = send(&server, &req, addr, now);
+        assert_eq!(action, ACTION_ERROR);
+        assert_eq!(transaction_id, 8);
+        assert_eq!(resp, b"invalid connection id");
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_scrape() {
+        let server = server();
+        let now = Instant::now();
+        let seed: SocketAddr = (Ipv4Addr::new(10, 0, 0, 1), 1234).into();
+        let leech: SocketAddr =
+            (Ipv4Addr::new(10, 0, 0, 2).to_ipv6_mapped(), 1234).into();
+        let leech6: SocketAddr = (Ipv6Addr::LOCALHOST, 1234).into();
+
+        let connection_id = connect(&server, seed, now);
+        let req = announce_req(connection_id, 6881, 0);
+        let (action, _, resp) = send(&server, &req, seed, now);
+        assert_eq!(action, ACTION_ANNOUNCE);
+        let mut resp = &resp[..];
+        assert_eq!(resp.get_u32(), 1800);
+        assert_eq!(resp.get_u32(), 0);
+        assert_eq!(resp.get_u32(), 1);
+        assert!(resp.is_empty());
+
+        // mapped IPv4 addresses of dual-stack sockets are IPv4 peers
+        let connection_id = connect(&server, leech, now);
+        let req = announce_req(connection_id, 6882, 100);
+        let (_, _, resp) = send(&server, &req, leech, now);
+        let mut resp = &resp[..];
+        resp.advance(4);
+        assert_eq!(resp.get_u32(), 1);
+        assert_eq!(resp.get_u32(), 1);
+        assert_eq!(resp, &[10, 0, 0, 1, 0x1a, 0xe1]);
+
+        // IPv6 peers are only given IPv6 peers
+        let connection_id = connect(&server, leech6, now);
+        let req = announce_req(connection_id, 6883, 100);
+        let (_, _, resp) = send(&server, &req, leech6, now);
+        let mut resp = &resp[..];
+        resp.advance(4);
+        assert_eq!(resp.get_u32(), 2);
+        assert_eq!(resp.get_u32(), 1);
+        assert!(resp.is_empty());
+
+        let mut req = BytesMut::new();
+        req.put_u64(connection_id);
+        req.put_u32(ACTION_SCRAPE);
+        req.put_u32(9);
+        req.put_slice(&[1; 20]);
+        req.put_slice(&[3; 20]);
+        let (action, transaction_id, resp) = send(&server, &req, leech6, now);
+        assert_eq!(action, ACTION_SCRAPE);
+        assert_eq!(transaction_id, 9);
+        let mut resp = &resp[..];
+        assert_eq!(resp.get_u32(), 1);
+        assert_eq!(resp.get_u32(), 0);
+        assert_eq!(resp.get_u32(), 2);
+        assert_eq!(resp, &[0; 12]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_requests() {
+        let server = server();
+        let addr: SocketAddr = (Ipv4Addr::LOCALHOST, 1234).into();
+        let now = Instant::now();
+
+        // too short to respond to
+        assert!(server.handle_request(&[0; 15], addr, now).is_none());
+
+        let connection_id = connect(&server, addr, now);
+        let mut req = announce_req(connection_id, 6881, 0);
+        req.truncate(40);
+        let (action, _, resp) = send(&server, &req, addr, now);
+        assert_eq!(action, ACTION_ERROR);
+        assert_eq!(resp, b"invalid request");
+
+        let mut req = BytesMut::new();
+        req.put_u64(connection_id);
+        req.put_u32(ACTION_SCRAPE);
+        req.put_u32(9);
+        req.put_slice(&[1; 19]);
+        let (action, _, _) = send(&server, &req, addr, now);
+        assert_eq!(action, ACTION_ERROR);
+    }
+}
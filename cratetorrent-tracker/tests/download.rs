@@ -0,0 +1,112 @@
+//! Tests that cratetorrent peers find each other through the tracker, with
+//! the tracker as their only source of peers.
+
+// the fixtures are shared with the engine's own integration tests
+#[path = "../../cratetorrent/tests/common/mod.rs"]
+mod common;
+
+use std::{
+    fs,
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    time::Duration,
+};
+
+use common::{create_metainfo, free_addr, test_data, test_dir};
+use cratetorrent::{alert::Alert, prelude::*, PeerId};
+use cratetorrent_tracker::{Conf, TrackerHandle};
+
+#[tokio::test]
+async fn test_download_with_http_tracker() {
+    let tracker = spawn_tracker();
+    let url = tracker.http_announce_url().unwrap();
+    download("http", &url).await;
+    tracker.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_download_with_udp_tracker() {
+    let tracker = spawn_tracker();
+    let url = tracker.udp_announce_url().unwrap();
+    download("udp", &url).await;
+    tracker.shutdown().await.unwrap();
+}
+
+fn spawn_tracker() -> TrackerHandle {
+    let localhost = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+    cratetorrent_tracker::spawn(Conf {
+        http_addr: Some(localhost),
+        udp_addr: Some(localhost),
+        // the downloader may announce before the seed, in which case it has to
+        // ask again soon
+        min_announce_interval: Duration::from_secs(1),
+        ..Conf::default()
+    })
+    .unwrap()
+}
+
+/// Seeds a torrent with the tracker at the URL, and downloads it with another
+/// engine that is given no peers.
+async fn download(name: &str, tracker_url: &str) {
+    let dir = test_dir(&format!("tracker-{}", name));
+    let seed_dir = dir.join("seed");
+    let download_dir = dir.join("download");
+    let data = test_data(200_000);
+    let metainfo =
+        create_metainfo(&seed_dir.join(name), &data, &[&[tracker_url]]);
+
+    let (seed, _seed_alerts) =
+        spawn_engine(&seed_dir, *b"cbt-seed000000000000");
+    seed.create_torrent(TorrentParams {
+        metainfo: metainfo.clone(),
+        conf: None,
+        mode: Mode::Seed,
+        listen_addr: Some(free_addr()),
+        queued: false,
+    })
+    .unwrap();
+
+    let (downloader, mut alerts) =
+        spawn_engine(&download_dir, *b"cbt-downloader000000");
+    downloader
+        .create_torrent(TorrentParams {
+            metainfo,
+            conf: None,
+            mode: Mode::Download { seeds: Vec::new() },
+            listen_addr: Some(free_addr()),
+            queued: false,
+        })
+        .unwrap();
+
+    let complete = async {
+        while let Some(alert) = alerts.next().await {
+            if let Alert::TorrentComplete(_) = alert {
+                return;
+            }
+        }
+        panic!("engine stopped before completing the download");
+    };
+    tokio::time::timeout(Duration::from_secs(60), complete)
+        .await
+        .expect("download timed out");
+
+    downloader.shutdown().await.unwrap();
+    seed.shutdown().await.unwrap();
+
+    assert_eq!(fs::read(download_dir.join(name)).unwrap(), data);
+    fs::remove_dir_all(&dir).ok();
+}
+
+/// Spawns an engine with its own client id, as the engines connect to each
+/// other and tell apart their duplicate connections by their ids.
+fn spawn_engine(
+    dir: &Path,
+    client_id: PeerId,
+) -> (EngineHandle, AlertReceiver) {
+    common::spawn_engine(dir, |conf| {
+        conf.engine.client_id = client_id;
+        // the UDP protocol has no minimum announce interval, so the torrent
+        // has to be told to ask for peers again soon
+        conf.torrent.announce_interval = Duration::from_secs(1);
+    })
+}
@@ -19,9 +19,15 @@
 //! statistics about a torrent's [peers](crate::conf::TorrentAlertConf::peers).
 //! More will be added later.
 
+use std::net::SocketAddr;
+
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
-use crate::{error::Error, torrent::stats::TorrentStats, TorrentId};
+use crate::{
+    error::Error,
+    torrent::stats::{DisconnectReason, TorrentStats},
+    TorrentId,
+};
 
 pub(crate) type AlertSender = UnboundedSender<Alert>;
 /// The channel on which alerts from the engine can be received. See [`Alert`]
@@ -40,6 +46,25 @@ pub enum Alert {
         id: TorrentId,
         stats: Box<TorrentStats>,
     },
+    /// Posted when a peer is banned for sending us corrupt data. Once banned,
+    /// no connections are made to or accepted from the peer's IP address for
+    /// the rest of the torrent's lifetime.
+    PeerBanned { id: TorrentId, addr: SocketAddr },
+    /// Posted when a session with a peer ends, including when the peer
+    /// couldn't be connected to, if enabled in the torrent's
+    /// [configuration](crate::conf::TorrentAlertConf::peer_disconnects).
+    PeerDisconnected {
+        id: TorrentId,
+        addr: SocketAddr,
+        reason: DisconnectReason,
+        /// The number of payload bytes downloaded from the peer in the
+        /// session.
+        downloaded: u64,
+        /// The number of payload bytes uploaded to the peer in the session.
+        uploaded: u64,
+        /// The number of bytes the peer sent us that we already had.
+        wasted: u64,
+    },
     /// An error from somewhere inside the engine.
     Error(Error),
 }
@@ -1,8 +1,12 @@
 //! This module defines types used to configure the engine and its parts.
 
-use std::{path::PathBuf, time::Duration};
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    path::PathBuf,
+    time::Duration,
+};
 
-use crate::PeerId;
+use crate::{ip_filter::IpFilter, PeerId};
 
 /// The default cratetorrent client id.
 pub const CRATETORRENT_CLIENT_ID: &PeerId = b"cbt-0000000000000000";
@@ -24,6 +28,12 @@ impl Conf {
             engine: EngineConf {
                 client_id: *CRATETORRENT_CLIENT_ID,
                 download_dir: download_dir.into(),
+                lsd: Some(LsdConf::default()),
+                ip_filter: IpFilter::new(),
+                // a total that a typical host can handle, and few enough
+                // concurrent connects not to overwhelm home routers
+                max_connected_peer_count: 200,
+                max_half_open_count: 20,
             },
             torrent: TorrentConf::default(),
         }
@@ -38,6 +48,54 @@ pub struct EngineConf {
     /// The directory in which a torrent's files are placed upon download and
     /// from which they are seeded.
     pub download_dir: PathBuf,
+    /// The configuration of local service discovery. If not set, torrents are
+    /// not announced on, and peers are not looked for on, the local network.
+    pub lsd: Option<LsdConf>,
+    /// The address ranges of peers with which no torrent connects. It may
+    /// be replaced at runtime via
+    /// [`EngineHandle::set_ip_filter`](crate::engine::EngineHandle::set_ip_filter).
+    pub ip_filter: IpFilter,
+    /// The max number of connected peers of all torrents combined.
+    ///
+    /// The connections are divided fairly among the torrents, with the
+    /// torrents that are downloading served before seeds. A torrent's own
+    /// limit, [`TorrentConf::max_connected_peer_count`], still applies.
+    pub max_connected_peer_count: usize,
+    /// The max number of outbound connections of all torrents combined that
+    /// are being established, that is, whose handshake hasn't finished.
+    ///
+    /// The peers that can't be connected to yet are queued by their torrents.
+    pub max_half_open_count: usize,
+}
+
+/// Configuration of local service discovery, as described in
+/// [BEP 14](http://bittorrent.org/beps/bep_0014.html).
+#[derive(Clone, Debug)]
+pub struct LsdConf {
+    /// The multicast group and port on which torrents are announced and on
+    /// which other hosts' announcements are received.
+    pub multicast_addr: SocketAddrV4,
+    /// The address of the local interface on which to join the multicast
+    /// group. If unspecified, the operating system picks the interface.
+    pub interface: Ipv4Addr,
+    /// How often each torrent is announced on the local network.
+    pub announce_interval: Duration,
+}
+
+impl Default for LsdConf {
+    fn default() -> Self {
+        Self {
+            // the group and port defined by BEP 14
+            multicast_addr: SocketAddrV4::new(
+                Ipv4Addr::new(239, 192, 152, 143),
+                6771,
+            ),
+            interface: Ipv4Addr::UNSPECIFIED,
+            // BEP 14 asks not to announce a torrent more than once a minute,
+            // and this is what other clients use
+            announce_interval: Duration::from_secs(5 * 60),
+        }
+    }
 }
 
 /// Configuration for a torrent.
@@ -51,8 +109,44 @@ pub struct TorrentConf {
     pub min_requested_peer_count: usize,
 
     /// The max number of connected peers the torrent should have.
+    ///
+    /// The torrent may have fewer if the engine-wide limit,
+    /// [`EngineConf::max_connected_peer_count`], is reached.
     pub max_connected_peer_count: usize,
 
+    /// The maximum number of peers the torrent keeps track of, including
+    /// connected ones. When reached, the peers that failed the most are
+    /// forgotten to make room for new ones.
+    pub max_peer_list_len: usize,
+
+    /// How long to wait before retrying a peer we couldn't connect to. The
+    /// wait is doubled with each consecutive failure.
+    pub peer_retry_interval: Duration,
+
+    /// After this many failed connection attempts in a row, the peer is
+    /// forgotten.
+    pub max_peer_failure_count: u32,
+
+    /// The number of interested peers that are unchoked because they upload
+    /// to us the fastest. One more peer is unchoked optimistically.
+    pub upload_slots: usize,
+
+    /// How often the peers to unchoke are picked anew, based on the rates at
+    /// which they upload to us.
+    pub unchoke_interval: Duration,
+
+    /// How often the optimistically unchoked peer is rotated, giving peers
+    /// that aren't uploading to us a chance to show that they would.
+    ///
+    /// This is only checked when the unchoked peers are picked, so it should
+    /// be a multiple of the unchoke interval.
+    pub optimistic_unchoke_interval: Duration,
+
+    /// How the peers we upload to are picked once we have the whole torrent,
+    /// at which point peers can no longer be ranked by how fast they upload
+    /// to us.
+    pub seed_choke_policy: SeedChokePolicy,
+
     /// If the tracker doesn't provide a minimum announce interval, we default
     /// to announcing every 30 seconds.
     pub announce_interval: Duration,
@@ -60,9 +154,160 @@ pub struct TorrentConf {
     /// After this many attempts, the torrent stops announcing to a tracker.
     pub tracker_error_threshold: usize,
 
+    /// Whether to announce to a tracker in every tracker tier, or only to the
+    /// first tier that has a working tracker, which is the behavior
+    /// recommended by [BEP 12](http://bittorrent.org/beps/bep_0012.html).
+    pub announce_to_all_tiers: bool,
+
+    /// How often to scrape each tracker for the number of seeders, leechers
+    /// and completed downloads of the torrent. If not set, trackers are only
+    /// scraped on demand.
+    pub scrape_interval: Option<Duration>,
+
+    /// How long to wait for a tracker to respond to an announce or a scrape,
+    /// after which the request is considered failed.
+    pub tracker_timeout: Duration,
+
+    /// How long to wait before announcing to a tracker again after it failed,
+    /// unless its announce interval is shorter. The wait is doubled with each
+    /// error.
+    pub tracker_backoff: Duration,
+
+    /// How long to wait for trackers to respond to the announce of the
+    /// torrent's stop, so that unresponsive trackers don't hold up shutting
+    /// down the torrent.
+    pub stop_announce_timeout: Duration,
+
+    /// Whether to announce the torrent on, and look for its peers on, the
+    /// local network, provided that the engine has local service discovery
+    /// enabled. This is always disabled for private torrents.
+    pub enable_lsd: bool,
+
+    /// Whether connections with peers are encrypted using message stream
+    /// encryption.
+    pub encryption: EncryptionPolicy,
+
+    /// Which transport protocols are used to connect to peers.
+    ///
+    /// Regardless of this setting, peers may connect to us over both TCP and
+    /// uTP.
+    pub transport: TransportPolicy,
+
+    /// Web seeds are only downloaded from while the torrent has fewer
+    /// connected peers than this, so as not to burden the servers needlessly.
+    pub web_seed_peer_threshold: usize,
+
+    /// How long to wait before retrying a web seed after it failed. The wait
+    /// is doubled with each consecutive failure.
+    pub web_seed_backoff: Duration,
+
     /// Specifies which optional alerts to send, besides the default periodic
     /// stats update.
     pub alerts: TorrentAlertConf,
+
+    /// How the names of the torrent's files and directories are made valid
+    /// file names before they are created.
+    pub path_rules: PathRules,
+}
+
+/// The rules with which the names of a torrent's files and directories are
+/// made valid file names.
+///
+/// Paths that could point outside of the download directory are already
+/// rejected when the metainfo is parsed, so these rules only concern names
+/// that some file systems don't allow. The names are changed the same way on
+/// every platform, so that a torrent is always downloaded to the same paths.
+#[derive(Clone, Debug)]
+pub struct PathRules {
+    /// The character with which invalid characters in names are replaced.
+    ///
+    /// This must itself be valid in names.
+    pub replacement: char,
+    /// The characters that are replaced in names, besides control characters,
+    /// which are always replaced.
+    pub invalid_chars: Vec<char>,
+    /// The maximum length of a name, in bytes. Longer names are shortened,
+    /// keeping their extension if it's short.
+    ///
+    /// This must be at least 4, so that any character fits in a name.
+    pub max_name_len: usize,
+    /// Whether the names that are reserved on Windows, such as `CON` or
+    /// `lpt1.txt`, are prefixed with the replacement character.
+    pub rename_reserved_names: bool,
+}
+
+impl Default for PathRules {
+    fn default() -> Self {
+        Self {
+            replacement: '_',
+            // these are not allowed on Windows
+            invalid_chars: vec!['\\', ':', '*', '?', '"', '<', '>', '|'],
+            // the limit of most file systems
+            max_name_len: 255,
+            rename_reserved_names: true,
+        }
+    }
+}
+
+impl PathRules {
+    /// Returns whether the rules can be followed, i.e. the replacement
+    /// character is itself valid and any character fits in a name.
+    pub(crate) fn is_valid(&self) -> bool {
+        let replacement = self.replacement;
+        self.max_name_len >= 4
+            && replacement != '/'
+            && !replacement.is_control()
+            && !self.invalid_chars.contains(&replacement)
+    }
+}
+
+/// Whether and how message stream encryption (also known as protocol
+/// encryption) is used with peers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EncryptionPolicy {
+    /// Connections are never encrypted and peers that connect to us with
+    /// encryption are rejected.
+    Disabled,
+    /// We try to encrypt outbound connections but fall back to plaintext if
+    /// the peer doesn't support encryption, and accept both encrypted and
+    /// plaintext inbound connections.
+    Enabled,
+    /// Only encrypted connections are made and accepted.
+    Forced,
+}
+
+/// The transport protocols over which outbound peer connections are made.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransportPolicy {
+    /// Connect over TCP and fall back to uTP if that fails.
+    PreferTcp,
+    /// Connect over uTP and fall back to TCP if that fails.
+    ///
+    /// uTP's congestion control yields to other traffic on the network, so
+    /// this is the friendlier option for users sharing their connection.
+    PreferUtp,
+    /// Try both protocols at the same time and use whichever connects first.
+    Both,
+}
+
+/// How a seed picks the interested peers it uploads to. Regardless of the
+/// policy, one more peer is unchoked optimistically.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeedChokePolicy {
+    /// Peers take turns: an unchoked peer keeps its upload slot until it has
+    /// downloaded `quota` bytes from us, after which the slot is given to the
+    /// peer that has waited the longest for one.
+    ///
+    /// This spreads our upload evenly among the swarm.
+    RoundRobin { quota: u64 },
+    /// The peers we upload to the fastest are unchoked, so that our upload
+    /// capacity isn't wasted on slow peers.
+    FastestUpload,
+    /// Peers that have just started or are about to finish the download are
+    /// favored over the ones in the middle of it. Peers that download
+    /// everything but never finish, so as not to have to upload, spend most
+    /// of their time in the middle.
+    AntiLeech,
 }
 
 /// Configuration of a torrent's optional alerts.
@@ -84,6 +329,12 @@ pub struct TorrentAlertConf {
     /// when it is specifically needed, e.g. when the UI is showing the peers of
     /// a torrent.
     pub peers: bool,
+    /// Receive an alert each time a peer session ends, with the reason it
+    /// ended and the bytes transferred in it.
+    ///
+    /// Sessions end often, as every failed connection attempt is one, so this
+    /// is only worth enabling when the alerts are needed.
+    pub peer_disconnects: bool,
 }
 
 impl Default for TorrentConf {
@@ -96,11 +347,39 @@ impl Default for TorrentConf {
             // This value is mostly picked for performance while keeping in mind
             // not to overwhelm the host.
             max_connected_peer_count: 50,
+            // enough to pick the best peers from even in large swarms
+            max_peer_list_len: 2000,
+            peer_retry_interval: Duration::from_secs(60),
+            max_peer_failure_count: 3,
+            // the intervals are those recommended by the protocol
+            // specification
+            upload_slots: 4,
+            unchoke_interval: Duration::from_secs(10),
+            optimistic_unchoke_interval: Duration::from_secs(30),
+            // a few pieces' worth of typical size per turn
+            seed_choke_policy: SeedChokePolicy::RoundRobin {
+                quota: 4 * 1024 * 1024,
+            },
             // needs teting
             announce_interval: Duration::from_secs(60 * 60),
             // needs testing
             tracker_error_threshold: 15,
+            announce_to_all_tiers: false,
+            // scrapes are cheap for trackers, but their results don't change
+            // quickly
+            scrape_interval: Some(Duration::from_secs(30 * 60)),
+            tracker_timeout: Duration::from_secs(30),
+            tracker_backoff: Duration::from_secs(15),
+            // the stop announce is a courtesy to the tracker, which forgets
+            // about us anyway after a while
+            stop_announce_timeout: Duration::from_secs(5),
+            enable_lsd: true,
+            encryption: EncryptionPolicy::Enabled,
+            transport: TransportPolicy::PreferTcp,
+            web_seed_peer_threshold: 10,
+            web_seed_backoff: Duration::from_secs(30),
             alerts: Default::default(),
+            path_rules: PathRules::default(),
         }
     }
 }
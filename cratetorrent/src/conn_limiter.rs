@@ -0,0 +1,260 @@
+//! Engine-wide limits on the number of peer connections, shared by all
+//! torrents.
+//!
+//! Each torrent has its own limit on the number of peers it connects to, but
+//! with many torrents these add up to more connections than the host can
+//! handle. On top of that, many connection attempts at once may overwhelm home
+//! routers. Thus the engine limits both the total number of connections and the
+//! number of outbound connections that are still being established, that is,
+//! whose handshake hasn't finished (half-open connections).
+//!
+//! The connections are divided fairly among the torrents: each torrent is
+//! given an equal share, but the share of torrents that want fewer connections
+//! than that is given to the others. Torrents that are downloading are served
+//! first and seeds are given the connections that remain.
+//!
+//! Torrents report their connections with each tick, and reserve connections
+//! before making or accepting them. The peers a torrent can't connect to yet
+//! wait in its peer list.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::{conf::EngineConf, TorrentId};
+
+/// A torrent's use of the engine's connections.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Usage {
+    /// The number of the torrent's connections, including half-open ones.
+    pub connected_count: usize,
+    /// The number of outbound connections whose handshake hasn't finished.
+    pub half_open_count: usize,
+    /// The number of connections the torrent would have if there were no
+    /// engine-wide limit: its connections and the peers waiting to be
+    /// connected, up to the torrent's own limit.
+    pub wanted_count: usize,
+    /// Seeds are given connections only after the downloading torrents.
+    pub is_seed: bool,
+}
+
+pub(crate) struct ConnectionLimiter {
+    max_connected_peer_count: usize,
+    max_half_open_count: usize,
+    /// The last reported usage of each torrent, adjusted by the connections
+    /// reserved since.
+    torrents: Mutex<HashMap<TorrentId, Usage>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(conf: &EngineConf) -> Self {
+        Self {
+            max_connected_peer_count: conf.max_connected_peer_count,
+            max_half_open_count: conf.max_half_open_count,
+            torrents: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records the torrent's current use of connections, which is expected
+    /// to be done with each tick of the torrent.
+    pub fn update(&self, id: TorrentId, usage: Usage) {
+        self.torrents.lock().unwrap().insert(id, usage);
+    }
+
+    /// Releases all connections of the torrent, once it's stopped.
+    pub fn remove(&self, id: TorrentId) {
+        self.torrents.lock().unwrap().remove(&id);
+    }
+
+    /// Returns how many of the `count` outbound connections the torrent may
+    /// start now, and reserves them.
+    ///
+    /// This is limited by the torrent's fair share of all connections and by
+    /// the number of half-open connections that may still be made.
+    pub fn reserve_outbound(&self, id: TorrentId, count: usize) -> usize {
+        let mut torrents = self.torrents.lock().unwrap();
+        let share = self.fair_share(&torrents, id);
+        let total_connected_count = total(&torrents, |u| u.connected_count);
+        let total_half_open_count = total(&torrents, |u| u.half_open_count);
+        let usage = torrents.entry(id).or_default();
+
+        let count = count
+            .min(share.saturating_sub(usage.connected_count))
+            .min(
+                self.max_connected_peer_count
+                    .saturating_sub(total_connected_count),
+            )
+            .min(
+                self.max_half_open_count
+                    .saturating_sub(total_half_open_count),
+            );
+        usage.connected_count += count;
+        usage.half_open_count += count;
+        count
+    }
+
+    /// Returns whether the torrent may accept a connection from a peer, and
+    /// reserves it if so.
+    ///
+    /// Like outbound connections, this is limited by the torrent's fair share,
+    /// so that a seed that many peers connect to doesn't take the connections
+    /// of the downloading torrents.
+    pub fn reserve_inbound(&self, id: TorrentId) -> bool {
+        let mut torrents = self.torrents.lock().unwrap();
+        let total_connected_count = total(&torrents, |u| u.connected_count);
+        if total_connected_count >= self.max_connected_peer_count {
+            return false;
+        }
+        // the peer that connects to us may not be among the peers the torrent
+        // wants to connect to, so the torrent wants at least one more
+        // connection than it has
+        let usage = torrents.entry(id).or_default();
+        usage.wanted_count = usage.wanted_count.max(usage.connected_count + 1);
+        let connected_count = usage.connected_count;
+        if connected_count >= self.fair_share(&torrents, id) {
+            return false;
+        }
+        torrents.entry(id).or_default().connected_count += 1;
+        true
+    }
+
+    /// Returns the number of connections the torrent is entitled to.
+    ///
+    /// The connections are handed out to the downloading torrents, then to
+    /// the seeds. Within each group, the torrents that want the fewest
+    /// connections are served first, and each is given an equal part of the
+    /// connections left, or fewer if it doesn't want as many. This way the
+    /// connections that a torrent doesn't need are divided among the others.
+    fn fair_share(
+        &self,
+        torrents: &HashMap<TorrentId, Usage>,
+        id: TorrentId,
+    ) -> usize {
+        let mut left = self.max_connected_peer_count;
+        for is_seed in [false, true].iter() {
+            let mut group: Vec<_> = torrents
+                .iter()
+                .filter(|(_, usage)| usage.is_seed == *is_seed)
+                .map(|(id, usage)| (*id, usage.wanted_count))
+                .collect();
+            group.sort_by_key(|(_, wanted_count)| *wanted_count);
+            let mut group_len = group.len();
+            for (torrent_id, wanted_count) in group {
+                // the parts are rounded down, but the torrents served later
+                // get what is left over, so no connection is left unused
+                let share = wanted_count.min(left / group_len);
+                if torrent_id == id {
+                    return share;
+                }
+                left -= share;
+                group_len -= 1;
+            }
+        }
+        // the torrent hasn't reported its usage yet
+        left
+    }
+}
+
+fn total(
+    torrents: &HashMap<TorrentId, Usage>,
+    count: impl Fn(&Usage) -> usize,
+) -> usize {
+    torrents.values().map(count).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(
+        max_connected_peer_count: usize,
+        max_half_open_count: usize,
+    ) -> ConnectionLimiter {
+        let mut conf = crate::conf::Conf::new("/tmp").engine;
+        conf.max_connected_peer_count = max_connected_peer_count;
+        conf.max_half_open_count = max_half_open_count;
+        ConnectionLimiter::new(&conf)
+    }
+
+    fn usage(wanted_count: usize, is_seed: bool) -> Usage {
+        Usage {
+            wanted_count,
+            is_seed,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_divide_connections_fairly() {
+        let limiter = limiter(100, 100);
+        let (a, b, c) = (TorrentId::new(), TorrentId::new(), TorrentId::new());
+        limiter.update(a, usage(10, false));
+        limiter.update(b, usage(100, false));
+        limiter.update(c, usage(100, false));
+
+        // the connections the first torrent doesn't want are split between
+        // the other two
+        assert_eq!(limiter.reserve_outbound(a, 100), 10);
+        assert_eq!(limiter.reserve_outbound(b, 100), 45);
+        assert_eq!(limiter.reserve_outbound(c, 100), 45);
+        // and no more are given out
+        assert_eq!(limiter.reserve_outbound(b, 100), 0);
+        assert!(!limiter.reserve_inbound(a));
+
+        // until a torrent stops
+        limiter.remove(a);
+        assert!(limiter.reserve_inbound(b));
+    }
+
+    #[test]
+    fn test_prioritize_downloads_over_seeds() {
+        let limiter = limiter(50, 100);
+        let (seed, download) = (TorrentId::new(), TorrentId::new());
+        limiter.update(seed, usage(50, true));
+        limiter.update(download, usage(40, false));
+
+        assert_eq!(limiter.reserve_outbound(seed, 50), 10);
+        assert_eq!(limiter.reserve_outbound(download, 50), 40);
+    }
+
+    #[test]
+    fn test_limit_inbound_connections_of_seeds() {
+        let limiter = limiter(50, 100);
+        let (seed, download) = (TorrentId::new(), TorrentId::new());
+        // the seed has no peers to connect to, but many connect to it
+        limiter.update(seed, usage(0, true));
+        limiter.update(download, usage(40, false));
+
+        let accepted_count =
+            (0..50).filter(|_| limiter.reserve_inbound(seed)).count();
+        assert_eq!(accepted_count, 10);
+        // so the download can still connect to its peers
+        assert_eq!(limiter.reserve_outbound(download, 50), 40);
+        assert!(!limiter.reserve_inbound(download));
+
+        // without downloads the seed may accept as many as the total limit
+        limiter.remove(download);
+        let accepted_count =
+            (0..50).filter(|_| limiter.reserve_inbound(seed)).count();
+        assert_eq!(accepted_count, 40);
+    }
+
+    #[test]
+    fn test_limit_half_open_connections() {
+        let limiter = limiter(100, 8);
+        let id = TorrentId::new();
+        limiter.update(id, usage(50, false));
+        assert_eq!(limiter.reserve_outbound(id, 50), 8);
+        assert_eq!(limiter.reserve_outbound(id, 50), 0);
+
+        // once the connections are established, more may be made
+        limiter.update(
+            id,
+            Usage {
+                connected_count: 8,
+                half_open_count: 0,
+                wanted_count: 50,
+                is_seed: false,
+            },
+        );
+        assert_eq!(limiter.reserve_outbound(id, 50), 8);
+    }
+}
@@ -1,7 +1,7 @@
 //! This module defines the entity responsible for disk IO and various utility
 //! types and functions.
 
-use std::collections::HashMap;
+use std::{collections::HashMap, net::SocketAddr};
 
 use tokio::{
     sync::{
@@ -13,7 +13,7 @@ use tokio::{
 
 use crate::{
     engine, error::Error, peer, storage_info::StorageInfo, torrent, BlockInfo,
-    TorrentId,
+    Sha256Hash, TorrentId,
 };
 use error::*;
 use io::torrent::Torrent;
@@ -47,7 +47,7 @@ pub(crate) enum Command {
     NewTorrent {
         id: TorrentId,
         storage_info: StorageInfo,
-        piece_hashes: Vec<u8>,
+        piece_hashes: PieceHashes,
         torrent_tx: torrent::Sender,
     },
     /// Request to eventually write a block to disk.
@@ -55,6 +55,9 @@ pub(crate) enum Command {
         id: TorrentId,
         block_info: BlockInfo,
         data: Vec<u8>,
+        /// The peer that sent the block, or `None` if it was downloaded from
+        /// a web seed.
+        sender: Option<SocketAddr>,
     },
     /// Request to eventually read a block from disk and return it via the
     /// sender.
@@ -67,6 +70,21 @@ pub(crate) enum Command {
     Shutdown,
 }
 
+/// The expected hashes of all pieces in a torrent, against which downloaded
+/// pieces are verified.
+#[derive(Clone, Debug)]
+pub(crate) enum PieceHashes {
+    /// The concatenation of the 20 byte SHA-1 hash of each piece, in v1
+    /// torrents.
+    V1(Vec<u8>),
+    /// The merkle root of the blocks of each piece, in v2 torrents.
+    V2(Vec<Sha256Hash>),
+    /// Both of the above, in hybrid torrents. The v1 hashes of pieces that
+    /// end a file cover the padding after the file too, see
+    /// [`StorageInfo::padded_len`].
+    Hybrid { v1: Vec<u8>, v2: Vec<Sha256Hash> },
+}
+
 /// The entity responsible for saving downloaded file blocks to disk and
 /// verifying whether downloaded pieces are valid.
 struct Disk {
@@ -160,8 +178,9 @@ impl Disk {
                     id,
                     block_info,
                     data,
+                    sender,
                 } => {
-                    self.write_block(id, block_info, data).await?;
+                    self.write_block(id, block_info, data, sender).await?;
                 }
                 Command::ReadBlock {
                     id,
@@ -190,6 +209,7 @@ impl Disk {
         id: TorrentId,
         block_info: BlockInfo,
         data: Vec<u8>,
+        sender: Option<SocketAddr>,
     ) -> Result<()> {
         log::trace!("Saving torrent {} block {} to disk", id, block_info);
 
@@ -202,7 +222,7 @@ impl Disk {
             log::error!("Torrent {} not found", id);
             Error::InvalidTorrentId
         })?;
-        torrent.write().await.write_block(block_info, data)
+        torrent.write().await.write_block(block_info, data, sender)
     }
 
     /// Attempts to read a block from disk and return the result via the given
@@ -244,7 +264,7 @@ mod tests {
     use tokio::sync::mpsc;
 
     use super::*;
-    use crate::{block_count, FileInfo, BLOCK_LEN};
+    use crate::{block_count, metainfo::FileAttrs, FileInfo, BLOCK_LEN};
 
     /// Tests the allocation of a torrent, and then the allocation of the same
     /// torrent returning an error.
@@ -346,6 +366,7 @@ mod tests {
                         id,
                         block_info: block,
                         data: data.to_vec(),
+                        sender: None,
                     })
                     .unwrap();
             });
@@ -398,6 +419,7 @@ mod tests {
 
         // write an invalid piece to disk
         let index = 0;
+        let sender = SocketAddr::from(([127, 0, 0, 1], 6881));
         let invalid_piece: Vec<_> =
             pieces[index].iter().map(|b| b.saturating_add(5)).collect();
         for_each_block(index, invalid_piece.len() as u32, |block| {
@@ -411,18 +433,57 @@ mod tests {
                     id,
                     block_info: block,
                     data: data.to_vec(),
+                    sender: Some(sender),
                 })
                 .unwrap();
         });
 
         // wait for disk write result
+        let invalid_blocks = if let Some(torrent::Command::PieceCompletion(Ok(
+            piece,
+        ))) = torrent_rx.recv().await
+        {
+            assert_eq!(piece.index, index);
+            assert_eq!(piece.is_valid, false);
+            // the blocks of the failed piece are reported along with who sent
+            // them
+            let block_count = block_count(pieces[index].len() as u32);
+            assert_eq!(piece.blocks.len(), block_count);
+            assert!(piece.blocks.iter().all(|b| b.sender == Some(sender)));
+            piece.blocks
+        } else {
+            panic!("piece could not be written to disk");
+        };
+
+        // then write the valid piece, whose blocks are also reported as it had
+        // failed before
+        for_each_block(index, pieces[index].len() as u32, |block| {
+            let block_end = block.offset + block.len;
+            let data =
+                &pieces[index][block.offset as usize..block_end as usize];
+            disk_tx
+                .send(Command::WriteBlock {
+                    id,
+                    block_info: block,
+                    data: data.to_vec(),
+                    sender: None,
+                })
+                .unwrap();
+        });
         if let Some(torrent::Command::PieceCompletion(Ok(piece))) =
             torrent_rx.recv().await
         {
             assert_eq!(piece.index, index);
-            assert_eq!(piece.is_valid, false);
+            assert_eq!(piece.is_valid, true);
+            assert_eq!(piece.blocks.len(), invalid_blocks.len());
+            let blocks = piece.blocks.iter().zip(invalid_blocks.iter());
+            for (valid, invalid) in blocks {
+                assert_eq!(valid.offset, invalid.offset);
+                assert_eq!(valid.sender, None);
+                assert_ne!(valid.hash, invalid.hash);
+            }
         } else {
-            assert!(false, "piece could not be written to disk");
+            panic!("piece could not be written to disk");
         }
     }
 
@@ -467,6 +528,7 @@ mod tests {
                     id,
                     block_info: block,
                     data: data.to_vec(),
+                    sender: None,
                 })
                 .unwrap();
         });
@@ -549,7 +611,7 @@ mod tests {
     struct Env {
         id: TorrentId,
         pieces: Vec<Vec<u8>>,
-        piece_hashes: Vec<u8>,
+        piece_hashes: PieceHashes,
         info: StorageInfo,
         torrent_tx: torrent::Sender,
         torrent_rx: torrent::Receiver,
@@ -592,6 +654,7 @@ mod tests {
                 piece_hashes.extend(hash.as_slice());
             }
             assert_eq!(piece_hashes.len(), pieces.len() * 20);
+            let piece_hashes = PieceHashes::V1(piece_hashes);
 
             // clean up any potential previous test env
             {
@@ -622,6 +685,10 @@ mod tests {
                     torrent_offset: 0,
                     len: download_len,
                 }],
+                file_attrs: vec![FileAttrs::default()],
+                symlinks: Vec::new(),
+                is_file_aligned: false,
+                padded_len: None,
             };
 
             let (torrent_tx, torrent_rx) = mpsc::unbounded_channel();
@@ -9,22 +9,28 @@ mod tests {
         fs,
         io::Read,
         ops::Range,
+        os::unix::fs::PermissionsExt,
         path::{Path, PathBuf},
         sync,
     };
 
     use sha1::{Digest, Sha1};
+    use tokio::sync::mpsc;
 
     use crate::{
         disk::{
             error::*,
             io::{
                 file::TorrentFile,
-                piece::{self, Piece},
+                piece::{self, Piece, PieceHash},
+                torrent::Torrent,
             },
+            PieceHashes,
         },
         iovecs::IoVec,
-        storage_info::FileInfo,
+        merkle,
+        metainfo::{FileAttrs, Symlink},
+        storage_info::{FileInfo, StorageInfo},
         FileIndex, BLOCK_LEN,
     };
 
@@ -44,6 +50,7 @@ mod tests {
                 torrent_offset: 0,
                 len: 2 * piece.len as u64,
             },
+            &FileAttrs::default(),
         )
         .expect("cannot create test file");
 
@@ -62,6 +69,8 @@ mod tests {
         // read and compare
         let mut file_content = Vec::new();
         file.handle
+            .as_mut()
+            .unwrap()
             .read_to_end(&mut file_content)
             .expect("cannot read test file");
         assert_eq!(
@@ -88,6 +97,7 @@ mod tests {
                 torrent_offset: 0,
                 len: 2 * piece.len as u64,
             },
+            &FileAttrs::default(),
         )
         .expect("cannot create test file");
         let files = &[sync::RwLock::new(file)];
@@ -102,6 +112,8 @@ mod tests {
         let mut file = files[0].write().unwrap();
         let mut file_content = Vec::new();
         file.handle
+            .as_mut()
+            .unwrap()
             .read_to_end(&mut file_content)
             .expect("cannot read test file");
         assert_eq!(
@@ -128,6 +140,7 @@ mod tests {
                 torrent_offset: 0,
                 len: 2 * piece.len as u64,
             },
+            &FileAttrs::default(),
         )
         .expect("cannot create test file");
         let files = &[sync::RwLock::new(file)];
@@ -155,6 +168,7 @@ mod tests {
                 torrent_offset: 0,
                 len: 2 * piece.len as u64,
             },
+            &FileAttrs::default(),
         )
         .expect("cannot create test file");
         let files = &[sync::RwLock::new(file)];
@@ -200,6 +214,7 @@ mod tests {
                 torrent_offset: 0,
                 len: BLOCK_LEN as u64 + 3,
             },
+            &FileAttrs::default(),
         )
         .expect("cannot create test file 1");
         let file2 = TorrentFile::new(
@@ -209,6 +224,7 @@ mod tests {
                 torrent_offset: file1.info.len,
                 len: BLOCK_LEN as u64 - 1500,
             },
+            &FileAttrs::default(),
         )
         .expect("cannot create test file 2");
         let file3 = TorrentFile::new(
@@ -218,6 +234,7 @@ mod tests {
                 torrent_offset: file2.info.torrent_offset + file2.info.len,
                 len: piece.len as u64 - (file1.info.len + file2.info.len),
             },
+            &FileAttrs::default(),
         )
         .expect("cannot create test file 3");
         let files = &[
@@ -237,6 +254,8 @@ mod tests {
             let mut file = file.write().unwrap();
             let mut file_content = Vec::new();
             file.handle
+                .as_mut()
+                .unwrap()
                 .read_to_end(&mut file_content)
                 .expect("cannot read test file");
             // compare the content of file to the portion that corresponds to
@@ -275,6 +294,7 @@ mod tests {
                 torrent_offset: 0,
                 len: BLOCK_LEN as u64 + 3,
             },
+            &FileAttrs::default(),
         )
         .expect("cannot create test file 1");
         let file2 = TorrentFile::new(
@@ -284,6 +304,7 @@ mod tests {
                 torrent_offset: file1.info.len,
                 len: BLOCK_LEN as u64 - 1500,
             },
+            &FileAttrs::default(),
         )
         .expect("cannot create test file 2");
         let file3 = TorrentFile::new(
@@ -293,6 +314,7 @@ mod tests {
                 torrent_offset: file2.info.torrent_offset + file2.info.len,
                 len: piece.len as u64 - (file1.info.len + file2.info.len),
             },
+            &FileAttrs::default(),
         )
         .expect("cannot create test file 3");
         let files = &[
@@ -325,6 +347,321 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    /// Tests that padding files are not created, and that their part of a
+    /// piece is zeroed, skipped when writing, and read back as zeros.
+    #[test]
+    fn should_skip_padding_files() {
+        let file_range = 0..3;
+        let download_dir = Path::new(DOWNLOAD_DIR);
+        let pad_attrs = FileAttrs {
+            attr: Some("p".into()),
+            md5sum: None,
+        };
+        let file1 = TorrentFile::new(
+            download_dir,
+            FileInfo {
+                path: PathBuf::from("Piece_padding_files1.test"),
+                torrent_offset: 0,
+                len: BLOCK_LEN as u64 + 3,
+            },
+            &FileAttrs::default(),
+        )
+        .expect("cannot create test file 1");
+        let pad = TorrentFile::new(
+            download_dir,
+            FileInfo {
+                path: PathBuf::from("Piece_padding_files_pad.test"),
+                torrent_offset: file1.info.len,
+                len: BLOCK_LEN as u64 - 3,
+            },
+            &pad_attrs,
+        )
+        .expect("cannot create padding file");
+        assert!(pad.handle.is_none());
+        assert!(!download_dir.join(&pad.info.path).exists());
+        let file2 = TorrentFile::new(
+            download_dir,
+            FileInfo {
+                path: PathBuf::from("Piece_padding_files2.test"),
+                torrent_offset: 2 * BLOCK_LEN as u64,
+                len: 2 * BLOCK_LEN as u64,
+            },
+            &FileAttrs::default(),
+        )
+        .expect("cannot create test file 2");
+        let files = &[
+            sync::RwLock::new(file1),
+            sync::RwLock::new(pad),
+            sync::RwLock::new(file2),
+        ];
+
+        // whatever peers send for the padding is replaced with zeros
+        let data = make_piece(file_range.clone());
+        let pad_range = BLOCK_LEN + 3..2 * BLOCK_LEN;
+        let mut piece = Piece {
+            blocks: BTreeMap::new(),
+            senders: BTreeMap::new(),
+            pad_ranges: vec![pad_range],
+            ..data
+        };
+        for (offset, block) in data.blocks.iter() {
+            piece.enqueue_block(*offset, block.clone(), None);
+        }
+        let mut expected: Vec<_> =
+            data.blocks.values().flatten().copied().collect();
+        for b in &mut expected[BLOCK_LEN as usize + 3..2 * BLOCK_LEN as usize] {
+            *b = 0;
+        }
+        assert_eq!(
+            piece.blocks.values().flatten().copied().collect::<Vec<_>>(),
+            expected
+        );
+
+        piece.write(0, files).expect("cannot write piece to files");
+        assert!(!download_dir
+            .join(&files[1].read().unwrap().info.path)
+            .exists());
+
+        let blocks = piece::read(0, file_range, files, piece.len)
+            .expect("cannot read piece from files");
+        let actual: Vec<_> = blocks
+            .iter()
+            .map(AsRef::as_ref)
+            .cloned()
+            .flatten()
+            .collect();
+        assert_eq!(actual, expected);
+
+        // clean up env
+        for file in [&files[0], &files[2]].iter() {
+            let path = download_dir.join(&file.read().unwrap().info.path);
+            fs::remove_file(path).expect("cannot remove test file");
+        }
+    }
+
+    /// Tests that an executable file is made executable once all its bytes
+    /// have been written, but not before.
+    #[test]
+    fn should_make_complete_file_executable() {
+        let download_dir = Path::new(DOWNLOAD_DIR);
+        let path = download_dir.join("TorrentFile_executable.test");
+        // the file may be left over from a previous run with its permissions
+        fs::remove_file(&path).ok();
+        let mut file = TorrentFile::new(
+            download_dir,
+            FileInfo {
+                path: PathBuf::from("TorrentFile_executable.test"),
+                torrent_offset: 0,
+                len: 2 * BLOCK_LEN as u64,
+            },
+            &FileAttrs {
+                attr: Some("x".into()),
+                md5sum: None,
+            },
+        )
+        .expect("cannot create test file");
+        let is_executable = |path: &Path| {
+            fs::metadata(path).unwrap().permissions().mode() & 0o111 != 0
+        };
+
+        let block = vec![1; BLOCK_LEN as usize];
+        for i in 0..2 {
+            assert!(!is_executable(&path));
+            let file_slice =
+                file.info.get_slice(i * BLOCK_LEN as u64, BLOCK_LEN as u64);
+            let mut iovecs = vec![IoVec::from_slice(&block)];
+            file.write(file_slice, &mut iovecs)
+                .expect("cannot write block to file");
+        }
+        assert!(is_executable(&path));
+
+        // clean up env
+        fs::remove_file(&path).expect("cannot remove test file");
+    }
+
+    /// Tests that the torrent's symlinks are created relative to their own
+    /// directory, and that its padding files are not created.
+    #[test]
+    fn should_create_symlinks() {
+        let download_dir = Path::new(DOWNLOAD_DIR).join("Torrent_symlinks");
+        if download_dir.exists() {
+            fs::remove_dir_all(&download_dir)
+                .expect("cannot clean up previous test dir");
+        }
+        let info = StorageInfo {
+            piece_count: 1,
+            piece_len: 2 * BLOCK_LEN,
+            last_piece_len: 2 * BLOCK_LEN,
+            download_len: 2 * BLOCK_LEN as u64,
+            download_dir: download_dir.clone(),
+            files: vec![
+                FileInfo {
+                    path: PathBuf::from("bin/run"),
+                    torrent_offset: 0,
+                    len: BLOCK_LEN as u64,
+                },
+                FileInfo {
+                    path: PathBuf::from(".pad/16384"),
+                    torrent_offset: BLOCK_LEN as u64,
+                    len: BLOCK_LEN as u64,
+                },
+            ],
+            file_attrs: vec![
+                FileAttrs::default(),
+                FileAttrs {
+                    attr: Some("p".into()),
+                    md5sum: None,
+                },
+            ],
+            symlinks: vec![
+                Symlink {
+                    path: PathBuf::from("run"),
+                    target: PathBuf::from("bin/run"),
+                },
+                Symlink {
+                    path: PathBuf::from("a/b/run"),
+                    target: PathBuf::from("bin/run"),
+                },
+            ],
+            is_file_aligned: false,
+            padded_len: None,
+        };
+        let (torrent_tx, _torrent_rx) = mpsc::unbounded_channel();
+        Torrent::new(info, PieceHashes::V1(vec![0; 20]), torrent_tx)
+            .expect("cannot create torrent");
+
+        assert!(download_dir.join("bin/run").is_file());
+        assert!(!download_dir.join(".pad").exists());
+        assert_eq!(
+            fs::read_link(download_dir.join("run")).unwrap(),
+            Path::new("bin/run")
+        );
+        assert_eq!(
+            fs::read_link(download_dir.join("a/b/run")).unwrap(),
+            Path::new("../../bin/run")
+        );
+        // the links resolve to the file
+        assert!(download_dir.join("a/b/run").is_file());
+
+        // clean up env
+        fs::remove_dir_all(&download_dir).expect("cannot remove test dir");
+    }
+
+    /// Tests that the empty files of a v2 torrent are created on disk, even
+    /// though they have no pieces.
+    #[test]
+    fn should_create_empty_files() {
+        let download_dir = Path::new(DOWNLOAD_DIR).join("Torrent_empty_files");
+        if download_dir.exists() {
+            fs::remove_dir_all(&download_dir)
+                .expect("cannot clean up previous test dir");
+        }
+        let info = StorageInfo {
+            piece_count: 2,
+            piece_len: BLOCK_LEN,
+            last_piece_len: 100,
+            download_len: BLOCK_LEN as u64 + 100,
+            download_dir: download_dir.clone(),
+            files: vec![
+                FileInfo {
+                    path: PathBuf::from("a"),
+                    torrent_offset: 0,
+                    len: BLOCK_LEN as u64,
+                },
+                FileInfo {
+                    path: PathBuf::from("empty"),
+                    torrent_offset: BLOCK_LEN as u64,
+                    len: 0,
+                },
+                FileInfo {
+                    path: PathBuf::from("b"),
+                    torrent_offset: BLOCK_LEN as u64,
+                    len: 100,
+                },
+            ],
+            file_attrs: vec![FileAttrs::default(); 3],
+            symlinks: Vec::new(),
+            is_file_aligned: true,
+            padded_len: None,
+        };
+        // the empty file is in neither piece
+        assert_eq!(info.files_intersecting_piece(0), 0..1);
+        assert_eq!(info.files_intersecting_piece(1), 2..3);
+
+        let (torrent_tx, _torrent_rx) = mpsc::unbounded_channel();
+        Torrent::new(info, PieceHashes::V2(vec![[0; 32]; 2]), torrent_tx)
+            .expect("cannot create torrent");
+        let empty = fs::metadata(download_dir.join("empty")).unwrap();
+        assert!(empty.is_file());
+        assert_eq!(empty.len(), 0);
+
+        // clean up env
+        fs::remove_dir_all(&download_dir).expect("cannot remove test dir");
+    }
+
+    /// Tests that a v2 piece is verified against the root of its blocks'
+    /// merkle tree, including the last piece of a file, whose tree is padded.
+    #[test]
+    fn should_verify_merkle_piece_hash() {
+        let blocks = vec![vec![1; BLOCK_LEN as usize], vec![2; 100]];
+        let leaves: Vec<_> =
+            blocks.iter().map(|b| merkle::hash_block(b)).collect();
+        let mut piece = Piece {
+            expected_hash: PieceHash::Merkle {
+                root: merkle::root(&leaves, 4, 0),
+                leaf_count: 4,
+            },
+            len: BLOCK_LEN + 100,
+            blocks: blocks
+                .into_iter()
+                .enumerate()
+                .map(|(i, b)| (i as u32 * BLOCK_LEN, b))
+                .collect(),
+            senders: BTreeMap::new(),
+            file_range: 0..1,
+            pad_ranges: Vec::new(),
+        };
+        assert!(piece.matches_hash());
+
+        // a corrupt block must fail the check
+        piece.blocks.get_mut(&BLOCK_LEN).unwrap()[0] = 3;
+        assert!(!piece.matches_hash());
+    }
+
+    /// Tests that a hybrid piece must match both its merkle root and its
+    /// SHA-1 hash, which covers the padding after the piece's file.
+    #[test]
+    fn should_verify_hybrid_piece_hash() {
+        let data = vec![1; 100];
+        let mut padded = data.clone();
+        padded.resize(BLOCK_LEN as usize, 0);
+        let sha1 = Sha1::digest(&padded).into();
+        let root = merkle::hash_block(&data);
+        let mut piece = Piece {
+            expected_hash: PieceHash::Hybrid {
+                sha1,
+                root,
+                leaf_count: 1,
+                pad_len: BLOCK_LEN - 100,
+            },
+            len: 100,
+            blocks: vec![(0, data)].into_iter().collect(),
+            senders: BTreeMap::new(),
+            file_range: 0..1,
+            pad_ranges: Vec::new(),
+        };
+        assert!(piece.matches_hash());
+
+        // the data matches the v2 hash but without the padding not the v1 hash
+        piece.expected_hash = PieceHash::Hybrid {
+            sha1,
+            root,
+            leaf_count: 1,
+            pad_len: 0,
+        };
+        assert!(!piece.matches_hash());
+    }
+
     /// Creates a piece for testing that has 4 blocks of length `BLOCK_LEN`.
     fn make_piece(files: Range<FileIndex>) -> Piece {
         let blocks = vec![
@@ -350,7 +687,7 @@ mod tests {
             for block in blocks.iter() {
                 hasher.update(&block);
             }
-            hasher.finalize().into()
+            PieceHash::Sha1(hasher.finalize().into())
         };
         let len = blocks.len() as u32 * BLOCK_LEN;
         // convert blocks to a b-tree map
@@ -367,7 +704,9 @@ mod tests {
             expected_hash,
             len,
             blocks,
+            senders: BTreeMap::new(),
             file_range: files,
+            pad_ranges: Vec::new(),
         }
     }
 }
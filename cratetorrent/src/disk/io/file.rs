@@ -1,6 +1,6 @@
 use std::{
     fs::{File, OpenOptions},
-    os::unix::io::AsRawFd,
+    os::unix::{fs::PermissionsExt, io::AsRawFd},
     path::Path,
 };
 
@@ -10,22 +10,43 @@ use crate::{
     disk::error::*,
     iovecs,
     iovecs::{IoVec, IoVecs},
+    metainfo::FileAttrs,
     storage_info::FileSlice,
     FileInfo,
 };
 
 pub(crate) struct TorrentFile {
     pub info: FileInfo,
-    pub handle: File,
+    /// The handle of the file on disk, or `None` if this is a padding file,
+    /// which is not stored, and whose contents are all zeros.
+    pub handle: Option<File>,
+    /// Whether the file should be made executable once all its bytes have been
+    /// written.
+    is_executable: bool,
+    /// The number of bytes not yet written to the file.
+    missing_len: u64,
 }
 
 impl TorrentFile {
     /// Opens the file in create, read, and write modes at the path of combining the
     /// download directory and the path defined in the file info.
+    ///
+    /// Padding files are not created on disk.
     pub fn new(
         download_dir: &Path,
         info: FileInfo,
+        attrs: &FileAttrs,
     ) -> Result<Self, NewTorrentError> {
+        if attrs.is_padding() {
+            log::trace!("Not creating padding file {:?}", info);
+            return Ok(Self {
+                missing_len: info.len,
+                info,
+                handle: None,
+                is_executable: false,
+            });
+        }
+
         log::trace!(
             "Opening and creating file {:?} in dir {:?}",
             info,
@@ -42,7 +63,12 @@ impl TorrentFile {
                 NewTorrentError::Io(e)
             })?;
         debug_assert!(path.exists());
-        Ok(Self { info, handle })
+        Ok(Self {
+            missing_len: info.len,
+            info,
+            handle: Some(handle),
+            is_executable: attrs.is_executable(),
+        })
     }
 
     /// Writes to file at most the slice length number of bytes of blocks at the
@@ -58,12 +84,22 @@ impl TorrentFile {
     ///
     /// Since the syscall may be invoked repeatedly to perform disk IO, this
     /// means that this operation is not guaranteed to be atomic.
+    ///
+    /// Nothing is written to padding files, but the slice of blocks is still
+    /// skipped.
+    ///
+    /// Once all bytes of an executable file have been written, its execute
+    /// permissions are set.
     pub fn write<'a>(
-        &self,
+        &mut self,
         file_slice: FileSlice,
         blocks: &'a mut [IoVec<&'a [u8]>],
     ) -> Result<&'a mut [IoVec<&'a [u8]>], WriteError> {
         let mut iovecs = IoVecs::bounded(blocks, file_slice.len as usize);
+        let handle = match &self.handle {
+            Some(handle) => handle,
+            None => return Ok(iovecs.into_tail()),
+        };
         // the write buffer cannot be larger than the file slice we want to
         // write to
         debug_assert!(
@@ -81,7 +117,7 @@ impl TorrentFile {
         let mut total_write_count = 0;
         while !iovecs.as_slice().is_empty() {
             let write_count = pwritev(
-                self.handle.as_raw_fd(),
+                handle.as_raw_fd(),
                 iovecs.as_slice(),
                 file_slice.offset as i64,
             )
@@ -106,6 +142,15 @@ impl TorrentFile {
             iovecs.advance(write_count);
         }
 
+        self.missing_len = self.missing_len.saturating_sub(file_slice.len);
+        if self.is_executable && self.missing_len == 0 {
+            log::debug!(
+                "File {:?} complete, making it executable",
+                self.info.path
+            );
+            set_executable(handle).map_err(WriteError::Io)?;
+        }
+
         Ok(iovecs.into_tail())
     }
 
@@ -122,11 +167,21 @@ impl TorrentFile {
     ///
     /// Since the syscall may be invoked repeatedly to perform disk IO, this
     /// means that this operation is not guaranteed to be atomic.
+    ///
+    /// Nothing is read from padding files: the buffers are expected to be
+    /// zeroed, so the slice of blocks is just skipped.
     pub fn read<'a>(
         &self,
         file_slice: FileSlice,
         mut iovecs: &'a mut [IoVec<&'a mut [u8]>],
     ) -> Result<&'a mut [IoVec<&'a mut [u8]>], ReadError> {
+        let handle = match &self.handle {
+            Some(handle) => handle,
+            None => {
+                return Ok(iovecs::advance(iovecs, file_slice.len as usize))
+            }
+        };
+
         // This is simpler than the write implementation as the preadv method
         // stops reading in from the file if reaching EOF. We do need to advance
         // the iovecs read buffer cursor after a read as we may want to read
@@ -138,16 +193,17 @@ impl TorrentFile {
         // transferred to disk (or an error occurs)
         let mut total_read_count = 0;
         while !iovecs.is_empty() && (total_read_count as u64) < file_slice.len {
-            let read_count = preadv(
-                self.handle.as_raw_fd(),
-                iovecs,
-                file_slice.offset as i64,
-            )
-            .map_err(|e| {
-                log::warn!("File {:?} read error: {}", self.info.path, e);
-                // FIXME: convert actual error here
-                ReadError::Io(std::io::Error::last_os_error())
-            })?;
+            let read_count =
+                preadv(handle.as_raw_fd(), iovecs, file_slice.offset as i64)
+                    .map_err(|e| {
+                        log::warn!(
+                            "File {:?} read error: {}",
+                            self.info.path,
+                            e
+                        );
+                        // FIXME: convert actual error here
+                        ReadError::Io(std::io::Error::last_os_error())
+                    })?;
 
             // if there was nothing to read from file it means we tried to
             // read a piece from a portion of a file not yet downloaded or
@@ -167,3 +223,11 @@ impl TorrentFile {
         Ok(iovecs)
     }
 }
+
+/// Grants execute permission to those who may read the file.
+fn set_executable(handle: &File) -> std::io::Result<()> {
+    let mut permissions = handle.metadata()?.permissions();
+    let mode = permissions.mode();
+    permissions.set_mode(mode | (mode & 0o444) >> 2);
+    handle.set_permissions(permissions)
+}
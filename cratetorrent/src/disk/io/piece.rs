@@ -1,5 +1,6 @@
 use std::{
     collections::BTreeMap,
+    net::SocketAddr,
     ops::Range,
     sync::{self, Arc},
 };
@@ -10,14 +11,41 @@ use crate::{
     block_count, block_len,
     disk::{error::*, io::file::TorrentFile},
     iovecs::IoVec,
-    CachedBlock, FileIndex, Sha1Hash,
+    merkle,
+    torrent::BlockHash,
+    CachedBlock, FileIndex, Sha1Hash, Sha256Hash,
 };
 
+/// The expected hash of a piece.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum PieceHash {
+    /// The SHA-1 hash of the piece's data, in v1 torrents.
+    Sha1(Sha1Hash),
+    /// The root of the merkle tree of the piece's blocks, in v2 torrents.
+    Merkle {
+        root: Sha256Hash,
+        /// The number of leaves in the piece's subtree, which may be more
+        /// than the number of blocks in the piece if it's the last piece of
+        /// a file.
+        leaf_count: usize,
+    },
+    /// Both of the above, in hybrid torrents, both of which have to match.
+    Hybrid {
+        sha1: Sha1Hash,
+        root: Sha256Hash,
+        leaf_count: usize,
+        /// The number of zero bytes after the piece's data that is included in
+        /// its SHA-1 hash: in the v1 view of the torrent the piece is
+        /// followed by a padding file if it's the last piece of a file.
+        pad_len: u32,
+    },
+}
+
 /// An in-progress piece download that keeps in memory the so far downloaded
 /// blocks and the expected hash of the piece.
 pub(crate) struct Piece {
     /// The expected hash of the whole piece.
-    pub expected_hash: Sha1Hash,
+    pub expected_hash: PieceHash,
     /// The length of the piece, in bytes.
     pub len: u32,
     /// The so far downloaded blocks. Once the size of this map reaches the
@@ -32,26 +60,71 @@ pub(crate) struct Piece {
     // performant due to cache locality (we would have to count the missing
     // blocks though, or keep a separate counter)
     pub blocks: BTreeMap<u32, Vec<u8>>,
+    /// The peer that sent each block, mapped to the block's offset. Blocks
+    /// downloaded from web seeds are not included.
+    pub senders: BTreeMap<u32, SocketAddr>,
     /// The files that this piece overlaps with.
     ///
     /// This is a left-inclusive range of all all file indices, that can be used
     /// to index the `Torrent::files` vector to get the file handles.
     pub file_range: Range<FileIndex>,
+    /// The byte ranges within the piece that belong to padding files. Their
+    /// bytes are always zero, regardless of what peers send.
+    pub pad_ranges: Vec<Range<u32>>,
 }
 
 impl Piece {
-    /// Places block into piece's write buffer if it doesn't exist. TODO: should
-    /// we return an error if it does?
-    pub fn enqueue_block(&mut self, offset: u32, data: Vec<u8>) {
+    /// Places block into piece's write buffer if it doesn't exist, recording
+    /// the peer that sent it, if any. TODO: should we return an error if it
+    /// does?
+    pub fn enqueue_block(
+        &mut self,
+        offset: u32,
+        mut data: Vec<u8>,
+        sender: Option<SocketAddr>,
+    ) {
         use std::collections::btree_map::Entry;
         let entry = self.blocks.entry(offset);
         if matches!(entry, Entry::Occupied(_)) {
             log::warn!("Duplicate piece block at offset {}", offset);
         } else {
+            let block_end = offset + data.len() as u32;
+            for pad in self.pad_ranges.iter() {
+                let start = pad.start.max(offset);
+                let end = pad.end.min(block_end);
+                if start < end {
+                    for b in &mut data
+                        [(start - offset) as usize..(end - offset) as usize]
+                    {
+                        *b = 0;
+                    }
+                }
+            }
             entry.or_insert(data);
+            if let Some(sender) = sender {
+                self.senders.insert(offset, sender);
+            }
         }
     }
 
+    /// Returns the SHA-1 hash of each block, along with the peer that sent
+    /// it.
+    ///
+    /// # Important
+    ///
+    /// Like hashing the whole piece, this should be executed on a thread pool
+    /// and not the executor.
+    pub fn block_hashes(&self) -> Vec<BlockHash> {
+        self.blocks
+            .iter()
+            .map(|(offset, block)| BlockHash {
+                offset: *offset,
+                sender: self.senders.get(offset).copied(),
+                hash: Sha1::digest(block).into(),
+            })
+            .collect()
+    }
+
     /// Returns true if the piece has all its blocks in its write buffer.
     pub fn is_complete(&self) -> bool {
         self.blocks.len() == block_count(self.len)
@@ -68,13 +141,51 @@ impl Piece {
         // sanity check that we only call this method if we have all blocks in
         // piece
         debug_assert_eq!(self.blocks.len(), block_count(self.len));
+        match self.expected_hash {
+            PieceHash::Sha1(expected_hash) => {
+                self.matches_sha1_hash(expected_hash, 0)
+            }
+            PieceHash::Merkle { root, leaf_count } => {
+                self.matches_merkle_root(root, leaf_count)
+            }
+            PieceHash::Hybrid {
+                sha1,
+                root,
+                leaf_count,
+                pad_len,
+            } => {
+                self.matches_sha1_hash(sha1, pad_len)
+                    && self.matches_merkle_root(root, leaf_count)
+            }
+        }
+    }
+
+    /// Returns whether the SHA-1 hash of the piece's blocks, followed by the
+    /// given number of zeros, matches the expected hash.
+    fn matches_sha1_hash(&self, expected_hash: Sha1Hash, pad_len: u32) -> bool {
         let mut hasher = Sha1::new();
         for block in self.blocks.values() {
             hasher.update(&block);
         }
+        if pad_len > 0 {
+            hasher.update(vec![0; pad_len as usize]);
+        }
         let hash = hasher.finalize();
         log::debug!("Piece hash: {:x}", hash);
-        hash.as_slice() == self.expected_hash
+        hash.as_slice() == expected_hash
+    }
+
+    /// Returns whether the merkle root of the piece's blocks, padded to the
+    /// given number of leaves, matches the expected root.
+    fn matches_merkle_root(&self, root: Sha256Hash, leaf_count: usize) -> bool {
+        let leaves: Vec<_> = self
+            .blocks
+            .values()
+            .map(|block| merkle::hash_block(block))
+            .collect();
+        let hash = merkle::root(&leaves, leaf_count, 0);
+        log::debug!("Piece hash: {}", hex::encode(hash));
+        hash == root
     }
 
     /// Writes the piece's blocks to the files the piece overlaps with.
@@ -108,7 +219,7 @@ impl Piece {
         let mut total_write_count = 0;
 
         for file in files.iter() {
-            let file = file.write().unwrap();
+            let mut file = file.write().unwrap();
 
             // determine which part of the file we need to write to
             debug_assert!(self.len as u64 > total_write_count);
@@ -1,6 +1,8 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, HashMap, HashSet},
     fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
     sync::{
         self,
         atomic::{AtomicU64, AtomicUsize, Ordering},
@@ -12,17 +14,21 @@ use lru::LruCache;
 use tokio::task;
 
 use crate::{
+    block_count,
     disk::{
         error::*,
         io::{
             file::TorrentFile,
-            piece::{self, Piece},
+            piece::{self, Piece, PieceHash},
         },
+        PieceHashes,
     },
+    merkle,
+    metainfo::Symlink,
     peer,
     storage_info::StorageInfo,
     torrent::{self, PieceCompletion},
-    Block, BlockInfo, CachedBlock, PieceIndex,
+    Block, BlockInfo, CachedBlock, PieceIndex, Sha1Hash, Sha256Hash,
 };
 
 /// Torrent information related to disk IO.
@@ -46,8 +52,8 @@ pub(crate) struct Torrent {
     /// them to an IO worker threads. See more in [`ThreadContext`].
     thread_ctx: Arc<ThreadContext>,
 
-    /// The concatenation of all expected piece hashes.
-    piece_hashes: Vec<u8>,
+    /// The expected hashes of all pieces.
+    piece_hashes: PieceHashes,
 }
 
 /// Contains fields that are commonly accessed by torrent's IO threads.
@@ -113,6 +119,13 @@ struct ThreadContext {
     // vector of pairs of `TorrentFile` and `FileInfo`).
     files: Vec<sync::RwLock<TorrentFile>>,
 
+    /// The pieces that failed their hash check and haven't passed it since.
+    ///
+    /// The hashes of the blocks of a failed piece are reported to the
+    /// torrent, as well as those of the piece once it passes, so that the
+    /// torrent can tell which peers sent the corrupt blocks.
+    failed_pieces: sync::Mutex<HashSet<PieceIndex>>,
+
     /// Various disk IO related statistics.
     ///
     /// Stas are atomically updated by the IO worker threads themselves.
@@ -140,12 +153,13 @@ impl Torrent {
     /// torrent archive, they are created and all files are opened.
     pub fn new(
         info: StorageInfo,
-        piece_hashes: Vec<u8>,
+        piece_hashes: PieceHashes,
         torrent_tx: torrent::Sender,
     ) -> Result<Self, NewTorrentError> {
         // TODO: since this is done as part of a tokio::task, should we use
         // tokio_fs here?
-        if !info.download_dir.is_dir() {
+        let is_new = !info.download_dir.is_dir();
+        if is_new {
             log::warn!(
                 "Creating missing download directory {:?}",
                 info.download_dir
@@ -166,6 +180,7 @@ impl Torrent {
             vec![sync::RwLock::new(TorrentFile::new(
                 &info.download_dir,
                 file.clone(),
+                &info.file_attrs[0],
             )?)]
         } else {
             debug_assert!(!info.files.is_empty());
@@ -173,11 +188,21 @@ impl Torrent {
             log::debug!("Setting up directory structure");
 
             let mut torrent_files = Vec::with_capacity(info.files.len());
-            for file in info.files.iter() {
+            for (file, attrs) in info.files.iter().zip(info.file_attrs.iter()) {
+                // padding files are not stored, so there is nothing to set up
+                if attrs.is_padding() {
+                    torrent_files.push(sync::RwLock::new(TorrentFile::new(
+                        &info.download_dir,
+                        file.clone(),
+                        attrs,
+                    )?));
+                    continue;
+                }
+
                 let path = info.download_dir.join(&file.path);
                 // file or subdirectory in download root must not exist if
                 // download root does not exists
-                debug_assert!(!path.exists());
+                debug_assert!(!is_new || !path.exists());
                 debug_assert!(path.is_absolute());
 
                 // get the parent of the file path: if there is one (i.e.
@@ -200,8 +225,14 @@ impl Torrent {
                 torrent_files.push(sync::RwLock::new(TorrentFile::new(
                     &info.download_dir,
                     file.clone(),
+                    attrs,
                 )?));
             }
+
+            for link in info.symlinks.iter() {
+                create_symlink(&info.download_dir, link)?;
+            }
+
             torrent_files
         };
 
@@ -214,6 +245,7 @@ impl Torrent {
                     READ_CACHE_UPPER_BOUND,
                 )),
                 files,
+                failed_pieces: sync::Mutex::new(HashSet::new()),
                 stats: Stats::default(),
             }),
             piece_hashes,
@@ -224,6 +256,7 @@ impl Torrent {
         &mut self,
         info: BlockInfo,
         data: Vec<u8>,
+        sender: Option<SocketAddr>,
     ) -> Result<()> {
         log::trace!("Saving block {} to disk", info);
 
@@ -236,7 +269,7 @@ impl Torrent {
             .get_mut(&piece_index)
             .expect("Newly inserted piece not present");
 
-        piece.enqueue_block(info.offset, data);
+        piece.enqueue_block(info.offset, data, sender);
 
         // if the piece has all its blocks, it means we can hash it and save it
         // to disk and clear its write buffer
@@ -259,6 +292,22 @@ impl Torrent {
             let ctx = Arc::clone(&self.thread_ctx);
             task::spawn_blocking(move || {
                 let is_piece_valid = piece.matches_hash();
+                // the blocks of a piece are only hashed if it failed now or
+                // before, for the torrent to find out who sent corrupt blocks
+                let should_hash_blocks = {
+                    let mut failed_pieces = ctx.failed_pieces.lock().unwrap();
+                    if is_piece_valid {
+                        failed_pieces.remove(&piece_index)
+                    } else {
+                        failed_pieces.insert(piece_index);
+                        true
+                    }
+                };
+                let blocks = if should_hash_blocks {
+                    piece.block_hashes()
+                } else {
+                    Vec::new()
+                };
 
                 // save piece to disk if it's valid
                 if is_piece_valid {
@@ -309,6 +358,7 @@ impl Torrent {
                         PieceCompletion {
                             index: piece_index,
                             is_valid: is_piece_valid,
+                            blocks,
                         },
                     )))
                     .map_err(|e| {
@@ -334,35 +384,87 @@ impl Torrent {
             "piece index is invalid"
         );
 
-        // get the position of the piece in the concatenated hash string
-        let hash_pos = piece_index * 20;
-        // the above assert should take care of this, but just in case
-        debug_assert!(hash_pos + 20 <= self.piece_hashes.len());
-
-        let hash_slice = &self.piece_hashes[hash_pos..hash_pos + 20];
-        let mut expected_hash = [0; 20];
-        expected_hash.copy_from_slice(hash_slice);
-        log::debug!(
-            "Piece {} expected hash {}",
-            piece_index,
-            hex::encode(&expected_hash)
-        );
-
         let len = self.info.piece_len(piece_index);
         log::debug!("Piece {} is {} bytes long", piece_index, len);
 
         let file_range = self.info.files_intersecting_piece(piece_index);
         log::debug!("Piece {} intersects files: {:?}", piece_index, file_range);
 
+        let expected_hash = match &self.piece_hashes {
+            PieceHashes::V1(hashes) => {
+                PieceHash::Sha1(sha1_piece_hash(hashes, piece_index))
+            }
+            PieceHashes::V2(hashes) => {
+                let (root, leaf_count) =
+                    self.merkle_piece_hash(hashes, piece_index);
+                PieceHash::Merkle { root, leaf_count }
+            }
+            PieceHashes::Hybrid { v1, v2 } => {
+                let (root, leaf_count) =
+                    self.merkle_piece_hash(v2, piece_index);
+                // anything in the piece's v1 view past the piece's file is
+                // padding
+                let v1_len = self.info.padded_piece_len(piece_index);
+                PieceHash::Hybrid {
+                    sha1: sha1_piece_hash(v1, piece_index),
+                    root,
+                    leaf_count,
+                    pad_len: v1_len - len,
+                }
+            }
+        };
+
+        // the parts of the piece that fall into padding files
+        let piece_offset = self.info.torrent_piece_offset(piece_index);
+        let piece_end = piece_offset + len as u64;
+        let pad_ranges = self.info.files[file_range.clone()]
+            .iter()
+            .zip(self.info.file_attrs[file_range.clone()].iter())
+            .filter(|(_, attrs)| attrs.is_padding())
+            .map(|(file, _)| {
+                let start = file.torrent_offset.max(piece_offset);
+                let end = file.torrent_end_offset().min(piece_end);
+                (start - piece_offset) as u32..(end - piece_offset) as u32
+            })
+            .collect();
+
         let piece = Piece {
             expected_hash,
             len,
             blocks: BTreeMap::new(),
+            senders: BTreeMap::new(),
             file_range,
+            pad_ranges,
         };
         self.write_buf.insert(piece_index, piece);
     }
 
+    /// Returns the expected merkle root of the piece in a v2 torrent, along
+    /// with the number of leaves in the piece's subtree.
+    fn merkle_piece_hash(
+        &self,
+        hashes: &[Sha256Hash],
+        piece_index: PieceIndex,
+    ) -> (Sha256Hash, usize) {
+        let root = hashes[piece_index];
+        log::debug!(
+            "Piece {} expected hash {}",
+            piece_index,
+            hex::encode(root)
+        );
+        // in v2 torrents a piece is always within a single file, and if it's
+        // that file's only piece, its hash is the root of the file's whole
+        // tree, which may be smaller than a piece
+        let file_index = self.info.files_intersecting_piece(piece_index).start;
+        let file_len = self.info.files[file_index].len;
+        let leaf_count = if file_len <= self.info.piece_len as u64 {
+            merkle::leaf_count(file_len)
+        } else {
+            block_count(self.info.piece_len)
+        };
+        (root, leaf_count)
+    }
+
     /// Returns the specified block via the sender, either from the read cache
     /// or from the disk.
     ///
@@ -396,7 +498,8 @@ impl Torrent {
         {
             log::debug!("Piece {} is in the read cache", piece_index);
             // the block's index in piece may be invalid
-            if block_index >= blocks.len() {
+            let padded_len = self.info.padded_piece_len(piece_index);
+            if block_index >= block_count(padded_len) {
                 log::debug!(
                     "Piece {} block offset {} is invalid",
                     piece_index,
@@ -411,7 +514,7 @@ impl Torrent {
             }
 
             // return block via sender
-            let block = Arc::clone(&blocks[block_index]);
+            let block = padded_block(blocks, &block_info);
             result_tx
                 .send(peer::Command::Block(Block::new(block_info, block)))?;
         } else {
@@ -442,7 +545,7 @@ impl Torrent {
                     Ok(blocks) => {
                         log::debug!("Read piece {}", piece_index);
                         // pick requested block
-                        let block = Arc::clone(&blocks[block_index]);
+                        let block = padded_block(&blocks, &block_info);
 
                         // Place piece in read cache. Another concurrent read
                         // could already have read the piece just before this
@@ -496,6 +599,74 @@ impl Torrent {
     }
 }
 
+/// Creates the symlink in the download directory, along with its parent
+/// directories.
+///
+/// The link's target is relative to the torrent's root, so it is made relative
+/// to the link's own directory, which keeps the download directory
+/// relocatable. Existing paths are left alone.
+fn create_symlink(
+    download_dir: &Path,
+    link: &Symlink,
+) -> Result<(), NewTorrentError> {
+    let path = download_dir.join(&link.path);
+    if fs::symlink_metadata(&path).is_ok() {
+        log::warn!("Not creating symlink {:?} as path exists", path);
+        return Ok(());
+    }
+    if let Some(subdir) = path.parent() {
+        if !subdir.exists() {
+            log::info!("Creating torrent subdir {:?}", subdir);
+            fs::create_dir_all(subdir)?;
+        }
+    }
+
+    let depth = link.path.components().count().saturating_sub(1);
+    let mut target: PathBuf = (0..depth).map(|_| "..").collect();
+    target.push(&link.target);
+    log::debug!("Creating symlink {:?} -> {:?}", path, target);
+    std::os::unix::fs::symlink(&target, &path).map_err(|e| {
+        log::error!("Failed to create symlink {:?}", path);
+        NewTorrentError::Io(e)
+    })
+}
+
+/// Returns the expected SHA-1 hash of the piece, given the concatenation of
+/// all piece hashes of a v1 torrent.
+fn sha1_piece_hash(hashes: &[u8], piece_index: PieceIndex) -> Sha1Hash {
+    // get the position of the piece in the concatenated hash string
+    let hash_pos = piece_index * 20;
+    // the piece index is checked by the caller, but just in case
+    debug_assert!(hash_pos + 20 <= hashes.len());
+
+    let mut expected_hash = [0; 20];
+    expected_hash.copy_from_slice(&hashes[hash_pos..hash_pos + 20]);
+    log::debug!(
+        "Piece {} expected hash {}",
+        piece_index,
+        hex::encode(expected_hash)
+    );
+    expected_hash
+}
+
+/// Returns the requested block from the blocks of its piece.
+///
+/// The v1 peers of a hybrid torrent see each file followed by padding up to
+/// the next piece boundary, which is not stored, so a block that extends past
+/// the piece's data is filled up with zeros.
+fn padded_block(blocks: &[CachedBlock], block_info: &BlockInfo) -> CachedBlock {
+    match blocks.get(block_info.index_in_piece()) {
+        Some(block) if block.len() >= block_info.len as usize => {
+            Arc::clone(block)
+        }
+        block => {
+            let mut data = block.map(|b| b.to_vec()).unwrap_or_default();
+            data.resize(block_info.len as usize, 0);
+            Arc::new(data)
+        }
+    }
+}
+
 // TODO(https://github.com/mandreyel/cratetorrent/issues/22):
 // make this configurable
 const READ_CACHE_UPPER_BOUND: usize = 1000;
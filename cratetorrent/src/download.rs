@@ -123,11 +123,10 @@ impl PieceDownload {
         debug_assert!(block.offset < self.len);
         debug_assert!(block.len <= self.len);
 
-        // we should only receive blocks that we have requested before
-        debug_assert!(matches!(
-            self.blocks[block.index_in_piece()],
-            BlockStatus::Requested
-        ));
+        // The block may no longer be requested: its request may have timed
+        // out and been freed, or in endgame the same block is requested from
+        // several peers and another one may have sent it first. The previous
+        // status tells the caller if the block is a duplicate.
 
         // TODO(https://github.com/mandreyel/cratetorrent/issues/9): record
         // rount trip time for this block
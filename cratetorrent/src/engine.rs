@@ -16,25 +16,30 @@
 
 use std::{
     collections::HashMap,
-    net::{Ipv4Addr, SocketAddr},
+    net::{Ipv6Addr, SocketAddr},
+    sync::Arc,
 };
 
 use futures::stream::StreamExt;
+use reqwest::Url;
 use tokio::{
     sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
     task,
 };
 
 use crate::{
-    alert::{AlertReceiver, AlertSender},
+    alert::{Alert, AlertReceiver, AlertSender},
     conf::{Conf, TorrentConf},
+    conn_limiter::ConnectionLimiter,
     disk::{self, error::NewTorrentError},
     error::*,
+    ip_filter::{IpFilter, IpFilterStats, SharedIpFilter},
+    lsd,
     metainfo::Metainfo,
     storage_info::StorageInfo,
     torrent::{self, Torrent},
-    tracker::Tracker,
-    Bitfield, TorrentId,
+    tracker::{self, Tracker},
+    web_seed, Bitfield, TorrentId,
 };
 
 /// Spawns the engine as a tokio task.
@@ -47,10 +52,14 @@ use crate::{
 /// various components in the engine will send alerts of events.
 pub fn spawn(conf: Conf) -> Result<(EngineHandle, AlertReceiver)> {
     log::info!("Spawning engine task");
+    if !conf.torrent.path_rules.is_valid() {
+        return Err(Error::InvalidPathRules);
+    }
 
     // create alert channels and return alert port to user
     let (alert_tx, alert_rx) = mpsc::unbounded_channel();
     let (mut engine, tx) = Engine::new(conf, alert_tx)?;
+    let ip_filter = Arc::clone(&engine.ip_filter);
 
     let join_handle = task::spawn(async move { engine.run().await });
     log::info!("Spawned engine task");
@@ -58,6 +67,7 @@ pub fn spawn(conf: Conf) -> Result<(EngineHandle, AlertReceiver)> {
     Ok((
         EngineHandle {
             tx,
+            ip_filter,
             join_handle: Some(join_handle),
         },
         alert_rx,
@@ -69,21 +79,130 @@ type JoinHandle = task::JoinHandle<Result<()>>;
 /// A handle to the currently running torrent engine.
 pub struct EngineHandle {
     tx: Sender,
+    /// The IP filter shared with the engine, which the handle may update
+    /// without going through the engine task.
+    ip_filter: Arc<SharedIpFilter>,
     join_handle: Option<JoinHandle>,
 }
 
 impl EngineHandle {
-    /// Creates and starts a torrent, if its metainfo is valid.
+    /// Creates and starts a torrent, if its metainfo is valid, or adds it to
+    /// the queue if [`TorrentParams::queued`] is set.
     ///
     /// If successful, it returns the id of the torrent. This id can be used to
-    /// identify the torrent when issuing further commands to engine.
+    /// identify the torrent when issuing further commands to engine. The
+    /// torrent's own path rules, if it has its own configuration, must be
+    /// valid.
     pub fn create_torrent(&self, params: TorrentParams) -> Result<TorrentId> {
         log::trace!("Creating torrent");
+        if let Some(conf) = &params.conf {
+            if !conf.path_rules.is_valid() {
+                return Err(Error::InvalidPathRules);
+            }
+        }
         let id = TorrentId::new();
         self.tx.send(Command::CreateTorrent { id, params })?;
         Ok(id)
     }
 
+    /// Starts a torrent that was created queued. Torrents that are already
+    /// running are left alone.
+    pub fn start_torrent(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Starting torrent {}", id);
+        self.tx.send(Command::StartTorrent { id })?;
+        Ok(())
+    }
+
+    /// Scrapes all trackers of the torrent for its swarm statistics: the
+    /// number of seeders, leechers and completed downloads.
+    ///
+    /// This is done regardless of the torrent's scrape interval. The results
+    /// are included in the torrent's next [stats
+    /// alert](crate::alert::Alert::TorrentStats), under the trackers.
+    pub fn scrape(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Scraping torrent {}", id);
+        self.tx.send(Command::Scrape { id })?;
+        Ok(())
+    }
+
+    /// Announces the torrent to its tracker with the given URL, or to all its
+    /// trackers if no URL is given, without waiting for the next scheduled
+    /// announce.
+    ///
+    /// Trackers that asked not to be contacted before their minimum announce
+    /// interval are skipped, unless the announce is forced. Trackers that
+    /// were disabled after failing too many times are given another chance.
+    pub fn announce(
+        &self,
+        id: TorrentId,
+        tracker_url: Option<Url>,
+        force: bool,
+    ) -> Result<()> {
+        log::trace!("Announcing torrent {}", id);
+        self.tx.send(Command::Announce {
+            id,
+            tracker_url,
+            force,
+        })?;
+        Ok(())
+    }
+
+    /// Adds a tracker to the torrent's tier at the given index, or as a new
+    /// lowest priority tier if there is no such tier.
+    pub fn add_tracker(
+        &self,
+        id: TorrentId,
+        url: Url,
+        tier: usize,
+    ) -> Result<()> {
+        log::trace!("Adding tracker {} to torrent {}", url, id);
+        if !tracker::is_supported_url(&url) {
+            return Err(Error::InvalidTrackerUrl);
+        }
+        self.tx.send(Command::AddTracker { id, url, tier })?;
+        Ok(())
+    }
+
+    /// Removes the tracker with the given URL from the torrent.
+    pub fn remove_tracker(&self, id: TorrentId, url: Url) -> Result<()> {
+        log::trace!("Removing tracker {} from torrent {}", url, id);
+        self.tx.send(Command::RemoveTracker { id, url })?;
+        Ok(())
+    }
+
+    /// Replaces all trackers of the torrent with the given ones, grouped into
+    /// tiers in order of priority.
+    ///
+    /// The trackers that the torrent already had keep their state, such as
+    /// their announce interval.
+    pub fn replace_trackers(
+        &self,
+        id: TorrentId,
+        trackers: Vec<Vec<Url>>,
+    ) -> Result<()> {
+        log::trace!("Replacing trackers of torrent {}", id);
+        if !trackers.iter().flatten().all(tracker::is_supported_url) {
+            return Err(Error::InvalidTrackerUrl);
+        }
+        self.tx.send(Command::ReplaceTrackers { id, trackers })?;
+        Ok(())
+    }
+
+    /// Replaces the engine's IP filter.
+    ///
+    /// The new filter applies to all torrents right away, and the connected
+    /// peers that it blocks are disconnected on the torrents' next tick.
+    pub fn set_ip_filter(&self, filter: IpFilter) {
+        log::trace!("Replacing IP filter");
+        self.ip_filter.replace(filter);
+    }
+
+    /// Returns how many addresses the IP filter blocked since the engine
+    /// started.
+    pub fn ip_filter_stats(&self) -> IpFilterStats {
+        self.ip_filter.stats()
+    }
+
     /// Gracefully shuts down the engine and waits for all its torrents to do
     /// the same.
     ///
@@ -124,6 +243,13 @@ pub struct TorrentParams {
     // TODO: probably use an engine wide address, but requires some
     // rearchitecting
     pub listen_addr: Option<SocketAddr>,
+    /// Whether to add the torrent to the queue instead of starting it.
+    ///
+    /// A queued torrent doesn't connect to peers or announce itself to its
+    /// trackers, but it does scrape them periodically, so that the health of
+    /// its swarm is known before it's started with
+    /// [`EngineHandle::start_torrent`].
+    pub queued: bool,
 }
 
 /// The download mode.
@@ -155,6 +281,29 @@ pub(crate) enum Command {
         id: TorrentId,
         result: Result<(), NewTorrentError>,
     },
+    /// Starts the queued torrent.
+    StartTorrent { id: TorrentId },
+    /// Scrapes the trackers of the torrent.
+    Scrape { id: TorrentId },
+    /// Announces the torrent to one or all of its trackers.
+    Announce {
+        id: TorrentId,
+        tracker_url: Option<Url>,
+        force: bool,
+    },
+    /// Adds a tracker to the torrent.
+    AddTracker {
+        id: TorrentId,
+        url: Url,
+        tier: usize,
+    },
+    /// Removes a tracker from the torrent.
+    RemoveTracker { id: TorrentId, url: Url },
+    /// Replaces the trackers of the torrent.
+    ReplaceTrackers {
+        id: TorrentId,
+        trackers: Vec<Vec<Url>>,
+    },
     /// Gracefully shuts down the engine and waits for all its torrents to do
     /// the same.
     Shutdown,
@@ -172,9 +321,19 @@ struct Engine {
     disk_tx: disk::Sender,
     disk_join_handle: Option<disk::JoinHandle>,
 
+    /// The local service discovery channel, if it is enabled and could be
+    /// started.
+    lsd_tx: Option<lsd::Sender>,
+    lsd_join_handle: Option<lsd::JoinHandle>,
+
     /// The channel on which tasks in the engine post alerts to user.
     alert_tx: AlertSender,
 
+    /// The IP filter shared by all torrents and the engine handle.
+    ip_filter: Arc<SharedIpFilter>,
+    /// Divides the engine-wide connection limits among the torrents.
+    conn_limiter: Arc<ConnectionLimiter>,
+
     /// The global engine configuration that includes defaults for torrents
     /// whose config is not overridden.
     conf: Conf,
@@ -190,17 +349,38 @@ struct TorrentEntry {
 
 impl Engine {
     /// Creates a new engine, spawning the disk task.
-    fn new(conf: Conf, alert_tx: AlertSender) -> Result<(Self, Sender)> {
+    fn new(mut conf: Conf, alert_tx: AlertSender) -> Result<(Self, Sender)> {
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
         let (disk_join_handle, disk_tx) = disk::spawn(cmd_tx.clone())?;
 
+        // Local service discovery is not essential for the engine to work, so
+        // if it can't be started (e.g. because there is no multicast capable
+        // network interface) we continue without it.
+        let (lsd_join_handle, lsd_tx) = match conf.engine.lsd.clone() {
+            Some(lsd_conf) => match lsd::spawn(lsd_conf) {
+                Ok((join_handle, tx)) => (Some(join_handle), Some(tx)),
+                Err(e) => {
+                    log::warn!("Error starting local service discovery: {}", e);
+                    (None, None)
+                }
+            },
+            None => (None, None),
+        };
+
         Ok((
             Self {
                 torrents: HashMap::new(),
                 cmd_rx,
                 disk_tx,
                 disk_join_handle: Some(disk_join_handle),
+                lsd_tx,
+                lsd_join_handle,
                 alert_tx,
+                // the filter may be large, so it's not kept in the config too
+                ip_filter: Arc::new(SharedIpFilter::new(std::mem::take(
+                    &mut conf.engine.ip_filter,
+                ))),
+                conn_limiter: Arc::new(ConnectionLimiter::new(&conf.engine)),
                 conf,
             },
             cmd_tx,
@@ -229,6 +409,40 @@ impl Engine {
                         );
                     }
                 },
+                Command::StartTorrent { id } => {
+                    self.send_torrent_cmd(id, torrent::Command::Start)?;
+                }
+                Command::Scrape { id } => {
+                    self.send_torrent_cmd(id, torrent::Command::Scrape)?;
+                }
+                Command::Announce {
+                    id,
+                    tracker_url,
+                    force,
+                } => {
+                    self.send_torrent_cmd(
+                        id,
+                        torrent::Command::Announce { tracker_url, force },
+                    )?;
+                }
+                Command::AddTracker { id, url, tier } => {
+                    self.send_torrent_cmd(
+                        id,
+                        torrent::Command::AddTracker { url, tier },
+                    )?;
+                }
+                Command::RemoveTracker { id, url } => {
+                    self.send_torrent_cmd(
+                        id,
+                        torrent::Command::RemoveTracker { url },
+                    )?;
+                }
+                Command::ReplaceTrackers { id, trackers } => {
+                    self.send_torrent_cmd(
+                        id,
+                        torrent::Command::ReplaceTrackers { trackers },
+                    )?;
+                }
                 Command::Shutdown => {
                     self.shutdown().await?;
                     break;
@@ -246,40 +460,68 @@ impl Engine {
         params: TorrentParams,
     ) -> Result<()> {
         let conf = params.conf.unwrap_or_else(|| self.conf.torrent.clone());
+        // private torrents may only get peers from their trackers
+        let lsd_tx = if conf.enable_lsd && !params.metainfo.private {
+            self.lsd_tx.clone()
+        } else {
+            None
+        };
         let storage_info = StorageInfo::new(
             &params.metainfo,
             self.conf.engine.download_dir.clone(),
+            &conf.path_rules,
         );
+        let piece_hashes = params.metainfo.piece_hashes();
+        let merkle_trees = params.metainfo.merkle_trees();
+        let info_hashes = params.metainfo.info_hashes();
+        let metainfo = &params.metainfo;
+        let web_seeds = metainfo
+            .url_list
+            .iter()
+            .map(|url| {
+                // the files are found on the web seed under their original
+                // names, rather than the sanitized ones
+                let file_urls = web_seed::file_urls(
+                    url,
+                    &metainfo.name,
+                    &metainfo.files,
+                    &metainfo.file_attrs,
+                    metainfo.is_archive(),
+                );
+                (url.clone(), file_urls)
+            })
+            .collect();
         // TODO: don't duplicate trackers if multiple torrents use the same
         // ones (common in practice)
         let trackers = params
             .metainfo
             .trackers
             .into_iter()
-            .map(Tracker::new)
+            .map(|tier| tier.into_iter().map(Tracker::new).collect())
             .collect();
         let own_pieces = params.mode.own_pieces(storage_info.piece_count);
 
-        // create and spawn torrent
-        // TODO: For now we spawn automatically, but later when we add torrent
-        // pause/restart APIs, this will be a separate step. There should be
-        // a `start` flag in `params` that says whether to immediately spawn
-        // a new torrent (or maybe in `TorrentConf`).
+        // create and spawn torrent, which waits to be started if it's queued
         let (mut torrent, torrent_tx) = Torrent::new(torrent::Params {
             id,
             disk_tx: self.disk_tx.clone(),
-            info_hash: params.metainfo.info_hash,
+            info_hashes,
             storage_info: storage_info.clone(),
+            merkle_trees,
             own_pieces,
             trackers,
+            web_seeds,
             client_id: self.conf.engine.client_id,
             listen_addr: params.listen_addr.unwrap_or_else(|| {
-                // the port 0 tells the kernel to assign a free port from the
-                // dynamic range
-                SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+                // listen on all IPv6 and IPv4 interfaces, and the port 0 tells
+                // the kernel to assign a free port from the dynamic range
+                SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
             }),
             conf,
             alert_tx: self.alert_tx.clone(),
+            lsd_tx,
+            ip_filter: Arc::clone(&self.ip_filter),
+            conn_limiter: Arc::clone(&self.conn_limiter),
         });
 
         // Allocate torrent on disk. This is an asynchronous process and we can
@@ -300,13 +542,14 @@ impl Engine {
         self.disk_tx.send(disk::Command::NewTorrent {
             id,
             storage_info,
-            piece_hashes: params.metainfo.pieces,
+            piece_hashes,
             torrent_tx: torrent_tx.clone(),
         })?;
 
         let seeds = params.mode.seeds();
+        let queued = params.queued;
         let join_handle =
-            task::spawn(async move { torrent.start(&seeds).await });
+            task::spawn(async move { torrent.start(&seeds, queued).await });
 
         self.torrents.insert(
             id,
@@ -319,6 +562,25 @@ impl Engine {
         Ok(())
     }
 
+    /// Sends the command to the torrent, or tells the user that there is no
+    /// such torrent.
+    fn send_torrent_cmd(
+        &self,
+        id: TorrentId,
+        cmd: torrent::Command,
+    ) -> Result<()> {
+        // the torrent may have stopped in the meantime
+        let is_running = self
+            .torrents
+            .get(&id)
+            .map(|torrent| torrent.tx.send(cmd).is_ok())
+            .unwrap_or(false);
+        if !is_running {
+            self.alert_tx.send(Alert::Error(Error::InvalidTorrentId))?;
+        }
+        Ok(())
+    }
+
     /// Gracefully shuts down the engine and all its components.
     async fn shutdown(&mut self) -> Result<()> {
         log::info!("Shutting down engine");
@@ -344,6 +606,14 @@ impl Engine {
             }
         }
 
+        // torrents have unregistered themselves, so LSD can be shut down
+        if let Some(lsd_tx) = &self.lsd_tx {
+            lsd_tx.send(lsd::Command::Shutdown).ok();
+        }
+        if let Some(join_handle) = self.lsd_join_handle.take() {
+            join_handle.await.expect("LSD task has panicked");
+        }
+
         // send a shutdown command to disk
         self.disk_tx.send(disk::Command::Shutdown)?;
         // and join on its handle
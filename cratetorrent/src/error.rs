@@ -23,9 +23,14 @@ pub enum Error {
     // TODO: consider adding more variations (path exists, doesn't exist,
     // permission issues)
     InvalidDownloadPath,
+    /// The rules with which file names are sanitized can't be followed, see
+    /// [`crate::conf::PathRules`].
+    InvalidPathRules,
     /// The torrent ID did not correspond to any entry. This is returned when
     /// the user specified a torrent that does not exist.
     InvalidTorrentId,
+    /// The tracker URL given by the user has a protocol that is not supported.
+    InvalidTrackerUrl,
     /// Holds global IO related errors.
     Io(IoError),
     /// An error specific to a torrent.
@@ -46,7 +51,9 @@ impl fmt::Display for Error {
         match self {
             Channel => write!(fmt, "channel error"),
             InvalidDownloadPath => write!(fmt, "invalid download path"),
+            InvalidPathRules => write!(fmt, "invalid path rules"),
             InvalidTorrentId => write!(fmt, "invalid torrent id"),
+            InvalidTrackerUrl => write!(fmt, "invalid tracker url"),
             Io(e) => e.fmt(fmt),
             Torrent { id, error } => {
                 write!(fmt, "torrent {} error: {}", id, error)
@@ -466,9 +466,13 @@ impl<'a> IoVecs<'a> {
 
                 // restore the second half of the split buffer
                 self.bufs[second_half.pos] = split_buf_second_half;
+                // return a slice to the buffers starting at the split position
+                &mut self.bufs[second_half.pos..]
+            } else {
+                // the buffer at the split position is the last one of the
+                // first half, so the second half starts after it
+                &mut self.bufs[second_half.pos + 1..]
             }
-            // return a slice to the buffers starting at the split position
-            &mut self.bufs[second_half.pos..]
         } else {
             // otherwise there is no second half, so we return an empty slice
             let write_buf_len = self.bufs.len();
@@ -701,6 +705,39 @@ mod tests {
         assert_eq!(second_half, expected_second_half);
     }
 
+    /// Tests splitting of the blocks at a buffer boundary, without consuming
+    /// the first half.
+    ///
+    /// ----------------------------------
+    /// | file slice: 32                 |
+    /// ---------------------------------------------------
+    /// | block: 16      | block: 16     ^ block: 16      |
+    /// ---------------------------------^-----------------
+    ///                                  ^
+    ///                      split here into 32 and 16 long halves
+    #[test]
+    fn should_split_at_buffer_boundary() {
+        let file_len = 32;
+        let blocks = vec![
+            (0..16).collect::<Vec<u8>>(),
+            (16..32).collect::<Vec<u8>>(),
+            (32..48).collect::<Vec<u8>>(),
+        ];
+
+        let mut bufs: Vec<_> =
+            blocks.iter().map(|buf| IoVec::from_slice(&buf)).collect();
+        let iovecs = IoVecs::bounded(&mut bufs, file_len);
+        assert_eq!(iovecs.as_slice().len(), 2);
+
+        let second_half = iovecs.into_tail();
+        assert_eq!(second_half.len(), 1);
+        let second_half: Vec<_> =
+            second_half.iter().map(IoVec::as_slice).flatten().collect();
+        let expected_second_half: Vec<_> =
+            blocks.iter().flatten().skip(file_len as usize).collect();
+        assert_eq!(second_half, expected_second_half);
+    }
+
     /// Tests splitting of the blocks that do not align with file boundary.
     ///
     /// ------------------------------
@@ -0,0 +1,468 @@
+//! An IP filter blocks connections with peers whose addresses fall into any of
+//! its ranges of IPv4 and IPv6 addresses.
+//!
+//! The filter applies to all torrents of the engine. It may be set in the
+//! [engine configuration](crate::conf::EngineConf::ip_filter) and replaced at
+//! runtime via [`EngineHandle::set_ip_filter`](crate::engine::EngineHandle::set_ip_filter).
+//!
+//! Rules may be added one by one, or loaded from blocklists in the eMule
+//! `ipfilter.dat` format:
+//!
+//! ```text
+//! 001.002.003.000 - 001.002.003.255 , 000 , Some organization
+//! ```
+//!
+//! where only ranges with an access level below 128 are blocked, or in the P2P
+//! plaintext format:
+//!
+//! ```text
+//! Some organization:1.2.3.0-1.2.3.255
+//! ```
+//!
+//! In both formats, empty lines and lines starting with `#` are ignored.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, BufRead},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+use crate::net::to_ipv4_mapped;
+
+pub type Result<T, E = IpFilterError> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum IpFilterError {
+    /// The first and last addresses of a range are of different IP versions,
+    /// or the first address is after the last.
+    InvalidRange,
+    /// A line of a blocklist is not a valid rule. The line number starts
+    /// from 1.
+    InvalidRule { line: usize },
+    /// An IO error occurred while reading a blocklist.
+    Io(io::Error),
+}
+
+impl From<io::Error> for IpFilterError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl fmt::Display for IpFilterError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use IpFilterError::*;
+        match self {
+            InvalidRange => write!(fmt, "invalid ip range"),
+            InvalidRule { line } => {
+                write!(fmt, "invalid ip filter rule on line {}", line)
+            }
+            Io(e) => e.fmt(fmt),
+        }
+    }
+}
+
+impl std::error::Error for IpFilterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A set of IPv4 and IPv6 address ranges with which no connections are made.
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+    v4: Ranges<u32>,
+    v6: Ranges<u128>,
+}
+
+impl IpFilter {
+    /// Returns an empty filter, which blocks no addresses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Blocks all addresses from `first` to `last`, inclusive.
+    ///
+    /// Both addresses must be of the same IP version and `first` must not be
+    /// after `last`.
+    pub fn add_rule(&mut self, first: IpAddr, last: IpAddr) -> Result<()> {
+        match (first, last) {
+            (IpAddr::V4(first), IpAddr::V4(last)) if first <= last => {
+                self.v4.insert(first.into(), last.into());
+            }
+            (IpAddr::V6(first), IpAddr::V6(last)) if first <= last => {
+                self.v6.insert(first.into(), last.into());
+            }
+            _ => return Err(IpFilterError::InvalidRange),
+        }
+        Ok(())
+    }
+
+    /// Returns true if the address falls into any of the blocked ranges.
+    ///
+    /// IPv4-mapped IPv6 addresses are checked against the IPv4 rules.
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => self.v4.contains(ip.into()),
+            IpAddr::V6(ip) => match to_ipv4_mapped(&ip) {
+                Some(ip) => self.v4.contains(ip.into()),
+                None => self.v6.contains(ip.into()),
+            },
+        }
+    }
+
+    /// Returns true if the filter doesn't block any addresses.
+    pub fn is_empty(&self) -> bool {
+        self.v4.0.is_empty() && self.v6.0.is_empty()
+    }
+
+    /// Adds the rules of a blocklist in the eMule `ipfilter.dat` format and
+    /// returns the number of rules that block addresses.
+    ///
+    /// Each line is of the form `first - last , level , description`, and
+    /// the range is blocked if its access level is below 128. The description
+    /// is optional.
+    pub fn load_emule_dat(&mut self, reader: impl BufRead) -> Result<usize> {
+        self.load(reader, |line| {
+            let mut fields = line.splitn(3, ',');
+            let range = fields.next()?;
+            let level: u32 = match fields.next() {
+                Some(level) => level.trim().parse().ok()?,
+                None => 0,
+            };
+            let mut range = range.splitn(2, '-');
+            let first = parse_ip(range.next()?)?;
+            let last = parse_ip(range.next()?)?;
+            Some((first, last, level < 128))
+        })
+    }
+
+    /// Adds the rules of a blocklist in the P2P plaintext format and returns
+    /// the number of rules that were added.
+    ///
+    /// Each line is of the form `description:first-last`, where the
+    /// description may itself contain colons, as may IPv6 addresses. The range
+    /// starts after the first colon that is followed by a valid range.
+    pub fn load_p2p(&mut self, reader: impl BufRead) -> Result<usize> {
+        self.load(reader, |line| {
+            line.match_indices(':').find_map(|(pos, _)| {
+                let mut range = line[pos + 1..].splitn(2, '-');
+                let first = parse_ip(range.next()?)?;
+                let last = parse_ip(range.next()?)?;
+                Some((first, last, true))
+            })
+        })
+    }
+
+    /// Adds the rules of a blocklist whose lines are parsed by `parse_line`
+    /// into a range and whether it's blocked, and returns the number of
+    /// blocked ranges.
+    fn load(
+        &mut self,
+        reader: impl BufRead,
+        parse_line: impl Fn(&str) -> Option<(IpAddr, IpAddr, bool)>,
+    ) -> Result<usize> {
+        let mut count = 0;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid_rule = || IpFilterError::InvalidRule { line: i + 1 };
+            let (first, last, is_blocked) =
+                parse_line(line).ok_or_else(invalid_rule)?;
+            if is_blocked {
+                self.add_rule(first, last).map_err(|_| invalid_rule())?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// Parses an IP address, allowing leading zeros in the octets of IPv4
+/// addresses, as is common in blocklists.
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    if s.contains(':') {
+        return s.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    let mut octets = [0; 4];
+    let mut parts = s.split('.');
+    for octet in octets.iter_mut() {
+        let part = parts.next()?;
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        *octet = part.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+/// Disjoint, inclusive ranges of addresses, each mapped from its first to
+/// its last address, so that the range that may contain an address is found
+/// by looking up the last range that starts at or before it.
+#[derive(Clone, Debug)]
+struct Ranges<T>(BTreeMap<T, T>);
+
+impl<T> Default for Ranges<T> {
+    fn default() -> Self {
+        Self(BTreeMap::new())
+    }
+}
+
+impl<T: Copy + Ord> Ranges<T> {
+    /// Inserts the range, merging it with the ranges it overlaps.
+    fn insert(&mut self, mut first: T, mut last: T) {
+        if let Some((&prev_first, &prev_last)) =
+            self.0.range(..=first).next_back()
+        {
+            if prev_last >= first {
+                first = prev_first;
+                last = last.max(prev_last);
+            }
+        }
+        let overlapping: Vec<_> = self
+            .0
+            .range(first..=last)
+            .map(|(first, last)| (*first, *last))
+            .collect();
+        for (next_first, next_last) in overlapping {
+            self.0.remove(&next_first);
+            last = last.max(next_last);
+        }
+        self.0.insert(first, last);
+    }
+
+    fn contains(&self, addr: T) -> bool {
+        match self.0.range(..=addr).next_back() {
+            Some((_, last)) => addr <= *last,
+            None => false,
+        }
+    }
+}
+
+/// The number of addresses the IP filter blocked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IpFilterStats {
+    /// The number of peers we didn't connect to, including the peers received
+    /// from trackers that were dropped right away.
+    pub outbound_blocked_count: u64,
+    /// The number of connections from peers that were rejected.
+    pub inbound_blocked_count: u64,
+}
+
+/// The engine's IP filter, which is shared by all torrents and which the user
+/// may replace at any time.
+#[derive(Debug, Default)]
+pub(crate) struct SharedIpFilter {
+    filter: RwLock<IpFilter>,
+    /// Incremented each time the filter is replaced, so that torrents know to
+    /// disconnect the connected peers that the new filter blocks.
+    version: AtomicU64,
+    outbound_blocked_count: AtomicU64,
+    inbound_blocked_count: AtomicU64,
+}
+
+impl SharedIpFilter {
+    pub fn new(filter: IpFilter) -> Self {
+        Self {
+            filter: RwLock::new(filter),
+            ..Default::default()
+        }
+    }
+
+    /// Replaces the filter. The counters are kept.
+    pub fn replace(&self, filter: IpFilter) {
+        *self.filter.write().unwrap() = filter;
+        self.version.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the version of the filter, which changes each time it's
+    /// replaced.
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the address is blocked, without counting it.
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.filter.read().unwrap().is_blocked(ip)
+    }
+
+    /// Returns true if we may not connect to the address, counting it if so.
+    pub fn blocks_outbound(&self, ip: IpAddr) -> bool {
+        let is_blocked = self.is_blocked(ip);
+        if is_blocked {
+            self.outbound_blocked_count.fetch_add(1, Ordering::Relaxed);
+        }
+        is_blocked
+    }
+
+    /// Returns true if we may not accept a connection from the address,
+    /// counting it if so.
+    pub fn blocks_inbound(&self, ip: IpAddr) -> bool {
+        let is_blocked = self.is_blocked(ip);
+        if is_blocked {
+            self.inbound_blocked_count.fetch_add(1, Ordering::Relaxed);
+        }
+        is_blocked
+    }
+
+    pub fn stats(&self) -> IpFilterStats {
+        IpFilterStats {
+            outbound_blocked_count: self
+                .outbound_blocked_count
+                .load(Ordering::Relaxed),
+            inbound_blocked_count: self
+                .inbound_blocked_count
+                .load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_add_rule() {
+        let mut filter = IpFilter::new();
+        assert!(filter.is_empty());
+        filter.add_rule(ip("10.0.0.0"), ip("10.0.0.255")).unwrap();
+        filter.add_rule(ip("10.0.1.0"), ip("10.0.1.10")).unwrap();
+        filter
+            .add_rule(ip("2001:db8::"), ip("2001:db8::ffff"))
+            .unwrap();
+
+        assert!(filter.is_blocked(ip("10.0.0.0")));
+        assert!(filter.is_blocked(ip("10.0.0.128")));
+        assert!(filter.is_blocked(ip("10.0.0.255")));
+        assert!(filter.is_blocked(ip("10.0.1.10")));
+        assert!(!filter.is_blocked(ip("10.0.1.11")));
+        assert!(!filter.is_blocked(ip("9.255.255.255")));
+        assert!(filter.is_blocked(ip("::ffff:10.0.0.1")));
+        assert!(filter.is_blocked(ip("2001:db8::1")));
+        assert!(!filter.is_blocked(ip("2001:db8::1:0")));
+
+        assert!(matches!(
+            filter.add_rule(ip("10.0.0.1"), ip("10.0.0.0")),
+            Err(IpFilterError::InvalidRange)
+        ));
+        assert!(matches!(
+            filter.add_rule(ip("10.0.0.1"), ip("2001:db8::")),
+            Err(IpFilterError::InvalidRange)
+        ));
+    }
+
+    #[test]
+    fn test_merge_overlapping_ranges() {
+        let mut filter = IpFilter::new();
+        filter.add_rule(ip("1.0.0.10"), ip("1.0.0.20")).unwrap();
+        filter.add_rule(ip("1.0.0.30"), ip("1.0.0.40")).unwrap();
+        filter.add_rule(ip("1.0.0.50"), ip("1.0.0.60")).unwrap();
+        // overlaps the first two
+        filter.add_rule(ip("1.0.0.15"), ip("1.0.0.35")).unwrap();
+        assert_eq!(filter.v4.0.len(), 2);
+        for last_octet in 10..=40 {
+            assert!(filter
+                .is_blocked(IpAddr::V4(Ipv4Addr::new(1, 0, 0, last_octet))));
+        }
+        assert!(!filter.is_blocked(ip("1.0.0.41")));
+        // contained in an existing range
+        filter.add_rule(ip("1.0.0.52"), ip("1.0.0.55")).unwrap();
+        assert_eq!(filter.v4.0.len(), 2);
+        // covers everything
+        filter
+            .add_rule(ip("0.0.0.0"), ip("255.255.255.255"))
+            .unwrap();
+        assert_eq!(filter.v4.0.len(), 1);
+        assert!(filter.is_blocked(ip("200.1.2.3")));
+    }
+
+    #[test]
+    fn test_load_emule_dat() {
+        let dat = "\
+            # comment\n\
+            \n\
+            001.002.003.000 - 001.002.003.255 , 000 , Some org, Inc.\n\
+            004.005.006.000 - 004.005.006.255 , 200 , Allowed\n\
+            010.000.000.001 - 010.000.000.002 , 127\n";
+        let mut filter = IpFilter::new();
+        assert_eq!(filter.load_emule_dat(dat.as_bytes()).unwrap(), 2);
+        assert!(filter.is_blocked(ip("1.2.3.4")));
+        assert!(!filter.is_blocked(ip("4.5.6.7")));
+        assert!(filter.is_blocked(ip("10.0.0.2")));
+
+        let dat = "1.2.3.0 - 1.2.3.255 , 000 , ok\n1.2.3 - 1.2.4.0 , 000\n";
+        assert!(matches!(
+            IpFilter::new().load_emule_dat(dat.as_bytes()),
+            Err(IpFilterError::InvalidRule { line: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_load_p2p() {
+        let p2p = "\
+            # comment\n\
+            Some org:1.2.3.0-1.2.3.255\n\
+            A: org with colons:5.6.7.8-5.6.7.8\n\
+            IPv6: org:2001:db8::-2001:db8::ffff\n";
+        let mut filter = IpFilter::new();
+        assert_eq!(filter.load_p2p(p2p.as_bytes()).unwrap(), 3);
+        assert!(filter.is_blocked(ip("1.2.3.100")));
+        assert!(filter.is_blocked(ip("5.6.7.8")));
+        assert!(!filter.is_blocked(ip("5.6.7.9")));
+        assert!(filter.is_blocked(ip("2001:db8::1")));
+        assert!(filter.is_blocked(ip("2001:db8::ffff")));
+        assert!(!filter.is_blocked(ip("2001:db8::1:0")));
+
+        assert!(matches!(
+            IpFilter::new().load_p2p("no range here\n".as_bytes()),
+            Err(IpFilterError::InvalidRule { line: 1 })
+        ));
+        assert!(matches!(
+            IpFilter::new().load_p2p("reversed:1.2.3.4-1.2.3.0\n".as_bytes()),
+            Err(IpFilterError::InvalidRule { line: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_shared_filter_counters() {
+        let mut filter = IpFilter::new();
+        filter.add_rule(ip("1.2.3.0"), ip("1.2.3.255")).unwrap();
+        let shared = SharedIpFilter::new(filter);
+        assert!(shared.blocks_outbound(ip("1.2.3.4")));
+        assert!(!shared.blocks_outbound(ip("1.2.4.4")));
+        assert!(shared.blocks_inbound(ip("1.2.3.5")));
+        assert_eq!(
+            shared.stats(),
+            IpFilterStats {
+                outbound_blocked_count: 1,
+                inbound_blocked_count: 1,
+            }
+        );
+
+        let version = shared.version();
+        shared.replace(IpFilter::new());
+        assert_ne!(shared.version(), version);
+        assert!(!shared.blocks_inbound(ip("1.2.3.5")));
+        assert_eq!(shared.stats().inbound_blocked_count, 1);
+    }
+}
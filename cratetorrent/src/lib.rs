@@ -1,5 +1,5 @@
 //! `cratetorrent` is a peer-to-peer file-sharing engine implementing the
-//! BitTorrent version 1 protocol.
+//! BitTorrent version 1 and version 2 protocols.
 //!
 //! It is built on top of [`tokio`](https://docs.rs/tokio/0.2.16/tokio/) for
 //! async IO.
@@ -11,8 +11,7 @@
 //!
 //! It also lacks most features present in battle-hardened torrent engines, such
 //! as [libtorrent](https://github.com/arvidn/libtorrent). These include: DHT
-//! for peer exchange, magnet links, stream encryption, UDP trackers, and many
-//! more.
+//! for peer exchange, magnet links, UDP trackers, and many more.
 //!
 //! Therefore in the current state of the project, this should only be viewed as
 //! a toy program.
@@ -87,6 +86,7 @@
 //!         metainfo,
 //!         // tell the engine to assign a randomly chosen free port
 //!         listen_addr: None,
+//!         queued: false,
 //!         mode: Mode::Download { seeds: Vec::new() },
 //!         conf: None,
 //!     })?;
@@ -130,6 +130,10 @@
 //!
 //! Therefore the application must make sure to provide its own way of stopping
 //! the download.
+//!
+//! To seed files that are not yet a torrent, their metainfo can be created with
+//! [`MetainfoBuilder`](crate::metainfo::MetainfoBuilder), which also returns
+//! the bencoded metainfo to be shared with others.
 
 // needed by the `select!` macro reaching the default recursion limit
 #![recursion_limit = "256"]
@@ -151,19 +155,26 @@ pub use storage_info::FileInfo;
 pub mod alert;
 mod avg;
 pub mod conf;
+mod conn_limiter;
 mod counter;
 mod disk;
 mod download;
 pub mod engine;
 pub mod error;
 pub mod iovecs;
+pub mod ip_filter;
+mod lsd;
+mod merkle;
 pub mod metainfo;
+mod net;
 pub mod peer;
 mod piece_picker;
 pub mod prelude;
 pub mod storage_info;
 pub mod torrent;
 mod tracker;
+mod utp;
+mod web_seed;
 
 /// Each torrent gets a randomly assigned ID that is unique within the
 /// engine. This id is used in engine APIs to interact with torrents.
@@ -207,6 +218,9 @@ pub type PeerId = [u8; 20];
 /// A SHA-1 hash digest, 20 bytes long.
 pub type Sha1Hash = [u8; 20];
 
+/// A SHA-256 hash digest, 32 bytes long, used by v2 torrents.
+pub type Sha256Hash = [u8; 32];
+
 /// The bitfield represents the piece availability of a peer.
 ///
 /// It is a compact bool vector of most significant bits to least significants
@@ -294,6 +308,17 @@ pub(crate) fn block_count(piece_len: u32) -> usize {
     (piece_len as usize + (BLOCK_LEN as usize - 1)) / BLOCK_LEN as usize
 }
 
+/// Returns the number of pieces of the given length needed to hold a file of
+/// the given length that starts at a piece boundary, as files in v2 torrents
+/// do.
+pub(crate) fn file_piece_count(file_len: u64, piece_len: u32) -> usize {
+    if file_len == 0 {
+        0
+    } else {
+        ((file_len - 1) / piece_len as u64 + 1) as usize
+    }
+}
+
 /// A piece block that contains the block's metadata and data.
 pub(crate) struct Block {
     /// The index of the piece of which this is a block.
@@ -413,4 +438,12 @@ mod tests {
 
         assert_eq!(block_count(UNEVEN_PIECE_LEN), 3);
     }
+
+    #[test]
+    fn test_file_piece_count() {
+        assert_eq!(file_piece_count(0, BLOCK_LEN), 0);
+        assert_eq!(file_piece_count(1, BLOCK_LEN), 1);
+        assert_eq!(file_piece_count(BLOCK_LEN as u64, BLOCK_LEN), 1);
+        assert_eq!(file_piece_count(BLOCK_LEN as u64 + 1, BLOCK_LEN), 2);
+    }
 }
@@ -0,0 +1,459 @@
+//! Local service discovery, as described in
+//! [BEP 14](http://bittorrent.org/beps/bep_0014.html).
+//!
+//! The LSD task is spawned by the engine and is shared by all its torrents.
+//! Torrents that opt in register their info hash and listen port with the
+//! task, which then periodically multicasts `BT-SEARCH` announcements for them
+//! on the local network. At the same time the task listens for the
+//! announcements of other hosts and forwards the peers it finds to the torrent
+//! with the matching info hash.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    str,
+};
+
+use bytes::BytesMut;
+use futures::{
+    select,
+    sink::SinkExt,
+    stream::{Fuse, SplitSink, StreamExt},
+};
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task, time,
+};
+use tokio_util::{
+    codec::{Decoder, Encoder},
+    udp::UdpFramed,
+};
+
+use crate::{
+    conf::LsdConf,
+    torrent::{self, stats::PeerSource},
+    Sha1Hash,
+};
+
+/// The channel on which the engine and torrents send commands to the LSD task.
+pub(crate) type Sender = UnboundedSender<Command>;
+/// The channel on which the LSD task listens for commands.
+type Receiver = UnboundedReceiver<Command>;
+
+pub(crate) type JoinHandle = task::JoinHandle<()>;
+
+/// The commands the LSD task can receive.
+#[derive(Debug)]
+pub(crate) enum Command {
+    /// Starts announcing the torrent on the local network and forwarding the
+    /// peers found for it to the torrent.
+    Register {
+        info_hash: Sha1Hash,
+        /// The port on which the torrent accepts peer connections.
+        port: u16,
+        /// The channel on which to send the torrent the peers found.
+        torrent_tx: torrent::Sender,
+    },
+    /// Stops announcing the torrent.
+    Unregister { info_hash: Sha1Hash },
+    /// Shuts down the LSD task.
+    Shutdown,
+}
+
+/// Binds the multicast socket and spawns the LSD task.
+///
+/// The returned sender may be used to register torrents with the task.
+pub(crate) fn spawn(conf: LsdConf) -> io::Result<(JoinHandle, Sender)> {
+    log::info!("Spawning LSD task on {}", conf.multicast_addr);
+    let socket = bind(&conf)?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut lsd = Lsd::new(conf, rx);
+    let join_handle = task::spawn(async move { lsd.run(socket).await });
+    Ok((join_handle, tx))
+}
+
+/// Creates the UDP socket that is bound to the multicast port and has joined
+/// the multicast group on the configured interface.
+///
+/// Other clients on the same host listen on the same port, so the port must be
+/// bound with address and port reuse enabled.
+fn bind(conf: &LsdConf) -> io::Result<UdpSocket> {
+    let socket =
+        Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    let bind_addr =
+        SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, conf.multicast_addr.port());
+    socket.bind(&bind_addr.into())?;
+    socket.join_multicast_v4(conf.multicast_addr.ip(), &conf.interface)?;
+    socket.set_multicast_if_v4(&conf.interface)?;
+    // other clients on the same host need to receive our announcements too
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into_udp_socket())
+}
+
+type Sink = SplitSink<UdpFramed<LsdCodec>, (Announce, SocketAddr)>;
+
+struct Lsd {
+    /// The torrents that are announced on the local network, keyed by their
+    /// info hash.
+    torrents: HashMap<Sha1Hash, TorrentEntry>,
+    /// A random value included in our announcements, so that we can recognize
+    /// and ignore our own announcements that are looped back to us.
+    cookie: String,
+    cmd_rx: Fuse<Receiver>,
+    conf: LsdConf,
+}
+
+struct TorrentEntry {
+    port: u16,
+    tx: torrent::Sender,
+}
+
+impl Lsd {
+    fn new(conf: LsdConf, cmd_rx: Receiver) -> Self {
+        let cookie = hex::encode(rand::thread_rng().gen::<[u8; 8]>());
+        Self {
+            torrents: HashMap::new(),
+            cookie,
+            cmd_rx: cmd_rx.fuse(),
+            conf,
+        }
+    }
+
+    /// Runs the LSD task until it is shut down.
+    async fn run(&mut self, socket: UdpSocket) {
+        let codec = LsdCodec {
+            multicast_addr: self.conf.multicast_addr,
+        };
+        let (mut sink, stream) = UdpFramed::new(socket, codec).split();
+        let mut stream = stream.fuse();
+        let mut announce_timer =
+            time::interval(self.conf.announce_interval).fuse();
+
+        loop {
+            select! {
+                _ = announce_timer.select_next_some() => {
+                    let torrents: Vec<_> = self
+                        .torrents
+                        .iter()
+                        .map(|(info_hash, t)| (*info_hash, t.port))
+                        .collect();
+                    for (info_hash, port) in torrents {
+                        self.announce(&mut sink, info_hash, port).await;
+                    }
+                }
+                msg = stream.select_next_some() => match msg {
+                    Ok((announce, addr)) => {
+                        self.handle_announce(announce, addr);
+                    }
+                    Err(e) => {
+                        log::debug!("Invalid LSD message: {}", e);
+                    }
+                },
+                cmd = self.cmd_rx.select_next_some() => match cmd {
+                    Command::Register { info_hash, port, torrent_tx } => {
+                        log::info!(
+                            "Announcing torrent {} on local network",
+                            hex::encode(info_hash)
+                        );
+                        self.torrents.insert(
+                            info_hash,
+                            TorrentEntry { port, tx: torrent_tx },
+                        );
+                        self.announce(&mut sink, info_hash, port).await;
+                    }
+                    Command::Unregister { info_hash } => {
+                        self.torrents.remove(&info_hash);
+                    }
+                    Command::Shutdown => break,
+                },
+            }
+        }
+
+        log::info!("LSD task stopped");
+    }
+
+    /// Multicasts an announcement for a single torrent.
+    ///
+    /// Failing to send is not fatal, as the network may come up later.
+    async fn announce(
+        &self,
+        sink: &mut Sink,
+        info_hash: Sha1Hash,
+        port: u16,
+    ) {
+        let announce = Announce {
+            port,
+            info_hashes: vec![info_hash],
+            cookie: Some(self.cookie.clone()),
+        };
+        let addr = SocketAddr::V4(self.conf.multicast_addr);
+        if let Err(e) = sink.send((announce, addr)).await {
+            log::warn!("Error sending LSD announce: {}", e);
+        }
+    }
+
+    /// Forwards the announcing host to the torrents whose info hash was
+    /// announced, if we have any such torrents.
+    fn handle_announce(&self, announce: Announce, addr: SocketAddr) {
+        if announce.cookie.as_ref() == Some(&self.cookie) {
+            return;
+        }
+        let peer_addr = SocketAddr::new(addr.ip(), announce.port);
+        for info_hash in announce.info_hashes.iter() {
+            if let Some(torrent) = self.torrents.get(info_hash) {
+                log::debug!(
+                    "Found local peer {} for torrent {}",
+                    peer_addr,
+                    hex::encode(info_hash)
+                );
+                // the torrent may have stopped without unregistering
+                torrent
+                    .tx
+                    .send(torrent::Command::AddPeers {
+                        peers: vec![peer_addr],
+                        info_hash: *info_hash,
+                        source: PeerSource::Lsd,
+                    })
+                    .ok();
+            }
+        }
+    }
+}
+
+/// A `BT-SEARCH` message, announcing that the sender is in the swarms of the
+/// given torrents.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Announce {
+    /// The port on which the sender accepts peer connections.
+    pub port: u16,
+    /// The info hashes of the torrents announced. There is always at least one.
+    pub info_hashes: Vec<Sha1Hash>,
+    /// An opaque value used by the sender to filter out its own messages.
+    pub cookie: Option<String>,
+}
+
+/// The request line with which every LSD message starts.
+const REQUEST_LINE: &str = "BT-SEARCH * HTTP/1.1";
+
+/// Codec for encoding and decoding LSD announcements.
+///
+/// Each UDP datagram contains exactly one message, so unlike with stream
+/// codecs, the decoder always consumes the whole buffer.
+struct LsdCodec {
+    /// Sent as the `Host` header of the announcements.
+    multicast_addr: SocketAddrV4,
+}
+
+impl Encoder<Announce> for LsdCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Announce, buf: &mut BytesMut) -> io::Result<()> {
+        let mut s = format!(
+            "{}\r\nHost: {}\r\nPort: {}\r\n",
+            REQUEST_LINE, self.multicast_addr, msg.port
+        );
+        for info_hash in msg.info_hashes.iter() {
+            s.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &msg.cookie {
+            s.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        s.push_str("\r\n\r\n");
+        buf.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+impl Decoder for LsdCodec {
+    type Item = Announce;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Announce>> {
+        let invalid =
+            |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        let msg = buf.split();
+        let msg = str::from_utf8(&msg)
+            .map_err(|_| invalid("LSD message is not valid UTF-8"))?;
+        let mut lines = msg.split("\r\n");
+        if lines.next() != Some(REQUEST_LINE) {
+            return Err(invalid("Not a BT-SEARCH message"));
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|l| !l.is_empty()) {
+            let mut header = line.splitn(2, ':');
+            let name = header.next().unwrap_or_default().trim();
+            let value = header.next().unwrap_or_default().trim();
+            // header names are case insensitive, as in HTTP
+            if name.eq_ignore_ascii_case("port") {
+                port = Some(
+                    value.parse().map_err(|_| invalid("Invalid LSD port"))?,
+                );
+            } else if name.eq_ignore_ascii_case("infohash") {
+                let mut info_hash = [0; 20];
+                hex::decode_to_slice(value, &mut info_hash)
+                    .map_err(|_| invalid("Invalid LSD info hash"))?;
+                info_hashes.push(info_hash);
+            } else if name.eq_ignore_ascii_case("cookie") {
+                cookie = Some(value.to_string());
+            }
+        }
+
+        let port = port.ok_or_else(|| invalid("LSD message has no port"))?;
+        if info_hashes.is_empty() {
+            return Err(invalid("LSD message has no info hash"));
+        }
+
+        Ok(Some(Announce {
+            port,
+            info_hashes,
+            cookie,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn codec() -> LsdCodec {
+        LsdCodec {
+            multicast_addr: LsdConf::default().multicast_addr,
+        }
+    }
+
+    #[test]
+    fn test_encode_announce() {
+        let announce = Announce {
+            port: 6881,
+            info_hashes: vec![[0xab; 20]],
+            cookie: Some("c00k1e".into()),
+        };
+        let mut buf = BytesMut::new();
+        codec().encode(announce, &mut buf).unwrap();
+        assert_eq!(
+            &buf[..],
+            &b"BT-SEARCH * HTTP/1.1\r\n\
+            Host: 239.192.152.143:6771\r\n\
+            Port: 6881\r\n\
+            Infohash: abababababababababababababababababababab\r\n\
+            cookie: c00k1e\r\n\
+            \r\n\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_decode_announce() {
+        // header names should be case insensitive and there may be multiple
+        // info hashes in a message
+        let mut buf = BytesMut::from(
+            &b"BT-SEARCH * HTTP/1.1\r\n\
+            Host: 239.192.152.143:6771\r\n\
+            port: 51413\r\n\
+            Infohash: 0101010101010101010101010101010101010101\r\n\
+            INFOHASH: 0202020202020202020202020202020202020202\r\n\
+            \r\n\r\n"[..],
+        );
+        let announce = codec().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(
+            announce,
+            Announce {
+                port: 51413,
+                info_hashes: vec![[1; 20], [2; 20]],
+                cookie: None,
+            }
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_invalid_announce() {
+        let invalid_msgs: &[&[u8]] = &[
+            b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n\r\n",
+            // no info hash
+            b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n\r\n",
+            // no port
+            b"BT-SEARCH * HTTP/1.1\r\n\
+            Infohash: 0101010101010101010101010101010101010101\r\n\r\n\r\n",
+            // info hash too short
+            b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\nInfohash: 0101\r\n\r\n\r\n",
+        ];
+        for msg in invalid_msgs {
+            let mut buf = BytesMut::from(*msg);
+            assert!(codec().decode(&mut buf).is_err());
+        }
+    }
+
+    /// Tests that two LSD tasks on the same host find each other's torrents
+    /// over loopback multicast.
+    #[tokio::test]
+    async fn test_find_local_peer() {
+        let conf = LsdConf {
+            // use a different port than the default so as not to interfere
+            // with other clients on the host
+            multicast_addr: SocketAddrV4::new(
+                Ipv4Addr::new(239, 192, 152, 143),
+                16771,
+            ),
+            interface: Ipv4Addr::LOCALHOST,
+            announce_interval: Duration::from_millis(100),
+        };
+        let info_hash = [7; 20];
+
+        let (join_handle1, lsd_tx1) = spawn(conf.clone()).unwrap();
+        let (join_handle2, lsd_tx2) = spawn(conf).unwrap();
+
+        let (torrent_tx1, mut torrent_rx1) = mpsc::unbounded_channel();
+        let (torrent_tx2, _torrent_rx2) = mpsc::unbounded_channel();
+        lsd_tx1
+            .send(Command::Register {
+                info_hash,
+                port: 1111,
+                torrent_tx: torrent_tx1,
+            })
+            .unwrap();
+        lsd_tx2
+            .send(Command::Register {
+                info_hash,
+                port: 2222,
+                torrent_tx: torrent_tx2,
+            })
+            .unwrap();
+
+        // the first torrent should only learn about the second one, not
+        // itself
+        let cmd = time::timeout(Duration::from_secs(5), torrent_rx1.recv())
+            .await
+            .expect("no local peer found")
+            .unwrap();
+        match cmd {
+            torrent::Command::AddPeers {
+                peers,
+                info_hash: hash,
+                source,
+            } => {
+                assert_eq!(peers, vec!["127.0.0.1:2222".parse().unwrap()]);
+                assert_eq!(hash, info_hash);
+                assert_eq!(source, PeerSource::Lsd);
+            }
+            _ => panic!("unexpected torrent command"),
+        }
+
+        lsd_tx1.send(Command::Shutdown).unwrap();
+        lsd_tx2.send(Command::Shutdown).unwrap();
+        join_handle1.await.unwrap();
+        join_handle2.await.unwrap();
+    }
+}
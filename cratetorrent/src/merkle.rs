@@ -0,0 +1,318 @@
+//! The SHA-256 merkle hash trees of BitTorrent v2 torrents, as described in
+//! [BEP 52](http://bittorrent.org/beps/bep_0052.html).
+//!
+//! Each file in a v2 torrent has its own merkle tree. The leaves of the tree are
+//! the hashes of the file's 16 KiB blocks, and the number of leaves is always
+//! a power of two: the leaves past the end of the file are all zeros. Layers
+//! are numbered from the bottom, so layer 0 is the leaf layer and the layer in
+//! which each node covers a single piece is the "piece layer".
+
+use sha2::{Digest, Sha256};
+
+use crate::{file_piece_count, Sha256Hash, BLOCK_LEN};
+
+/// Returns the hash of a block, which is a leaf in a file's merkle tree.
+///
+/// The last block of a file may be shorter than 16 KiB, but it's hashed as is,
+/// without padding.
+pub(crate) fn hash_block(data: &[u8]) -> Sha256Hash {
+    to_hash(&Sha256::digest(data))
+}
+
+/// Returns the parent node of two sibling nodes.
+fn hash_pair(left: &Sha256Hash, right: &Sha256Hash) -> Sha256Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    to_hash(&hasher.finalize())
+}
+
+fn to_hash(digest: &[u8]) -> Sha256Hash {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(digest);
+    hash
+}
+
+/// Returns the hash of a node in the given layer of a subtree that covers only
+/// the padding past the end of a file, i.e. whose leaves are all zeros.
+pub(crate) fn pad_hash(layer: u32) -> Sha256Hash {
+    let mut hash = [0; 32];
+    for _ in 0..layer {
+        hash = hash_pair(&hash, &hash);
+    }
+    hash
+}
+
+/// Returns the root of the subtree whose bottom layer starts with the given
+/// hashes and is `width` nodes wide, where the missing nodes are padding.
+///
+/// The width must be a power of two at least as large as the number of
+/// hashes. The bottom layer's index in the whole tree, `layer`, determines the
+/// hash of the padding nodes.
+pub(crate) fn root(
+    hashes: &[Sha256Hash],
+    width: usize,
+    layer: u32,
+) -> Sha256Hash {
+    debug_assert!(width.is_power_of_two());
+    debug_assert!(hashes.len() <= width);
+    let mut nodes = hashes.to_vec();
+    let mut width = width;
+    let mut pad = pad_hash(layer);
+    while width > 1 {
+        nodes = nodes
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pad)))
+            .collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    nodes.first().copied().unwrap_or(pad)
+}
+
+/// Returns the layer in which each node covers a single piece of the given
+/// length.
+pub(crate) fn piece_layer(piece_len: u32) -> u32 {
+    debug_assert!(piece_len.is_power_of_two());
+    debug_assert!(piece_len >= BLOCK_LEN);
+    (piece_len / BLOCK_LEN).trailing_zeros()
+}
+
+/// Returns the number of leaves of the merkle tree of a file of the given
+/// length.
+pub(crate) fn leaf_count(file_len: u64) -> usize {
+    file_piece_count(file_len, BLOCK_LEN).next_power_of_two()
+}
+
+/// Returns the range of leaves that cover a piece of a file, as the index of
+/// the first leaf and the number of leaves.
+///
+/// This is a piece's worth of leaves, unless the file is not larger than a
+/// piece, in which case it's all of the file's leaves. Either way the range is
+/// covered by a single node of the tree: the piece's hash or the file's root.
+pub(crate) fn piece_leaves(
+    file_len: u64,
+    piece_len: u32,
+    index_in_file: usize,
+) -> (usize, usize) {
+    let len = ((piece_len / BLOCK_LEN) as usize).min(leaf_count(file_len));
+    (index_in_file * len, len)
+}
+
+/// The upper part of a file's merkle tree, from a given layer up to its root.
+///
+/// The leaf layer is not kept, as it would take up 1/512th of the file's
+/// size in memory and is not needed to verify pieces, only to verify single
+/// blocks.
+#[derive(Clone, Debug)]
+pub(crate) struct MerkleTree {
+    /// The layer of the first entry in `layers`.
+    base_layer: u32,
+    /// The layers of the tree, from the base layer to the root. Each layer is
+    /// padded to a power of two.
+    layers: Vec<Vec<Sha256Hash>>,
+}
+
+impl MerkleTree {
+    /// Builds the tree from the hashes of one of its layers, given without
+    /// padding.
+    ///
+    /// The layer is `width` nodes wide including padding, which must be
+    /// a power of two, and `layer` is its index in the whole tree.
+    pub fn from_layer(hashes: &[Sha256Hash], width: usize, layer: u32) -> Self {
+        debug_assert!(width.is_power_of_two());
+        let mut nodes = hashes.to_vec();
+        nodes.resize(width, pad_hash(layer));
+        let mut layers = vec![nodes];
+        while layers.last().unwrap().len() > 1 {
+            let parents = layers
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| hash_pair(&pair[0], &pair[1]))
+                .collect();
+            layers.push(parents);
+        }
+        Self {
+            base_layer: layer,
+            layers,
+        }
+    }
+
+    /// Returns the root hash of the tree.
+    pub fn root(&self) -> Sha256Hash {
+        self.layers.last().unwrap()[0]
+    }
+
+    /// Returns true if the leaves, starting at the given index in the leaf
+    /// layer, hash to the node of the tree that covers them.
+    ///
+    /// This is how the hashes of a piece's blocks, received from a peer, are
+    /// verified. The number of leaves must be a power of two, and the index
+    /// a multiple of it.
+    pub fn verify_leaves(&self, index: usize, leaves: &[Sha256Hash]) -> bool {
+        let len = leaves.len();
+        if !len.is_power_of_two() || index & (len - 1) != 0 {
+            return false;
+        }
+        let layer = len.trailing_zeros();
+        if layer < self.base_layer {
+            return false;
+        }
+        let node = self
+            .layers
+            .get((layer - self.base_layer) as usize)
+            .and_then(|nodes| nodes.get(index / len));
+        node == Some(&root(leaves, len, 0))
+    }
+
+    /// Returns the nodes in the range of the layer, followed by the hashes
+    /// needed to prove them against the root.
+    ///
+    /// This is the payload of a v2 `hashes` message: the uncle hashes start
+    /// from the layer in which the requested range is covered by a single
+    /// node, and at most `proof_layers` of them are returned.
+    ///
+    /// Returns `None` if the tree doesn't have the layer or the range is
+    /// invalid: its length must be a power of two, and its start a multiple of
+    /// its length.
+    pub fn hashes(
+        &self,
+        layer: u32,
+        index: usize,
+        len: usize,
+        proof_layers: u32,
+    ) -> Option<Vec<Sha256Hash>> {
+        if layer < self.base_layer
+            || !len.is_power_of_two()
+            || index & (len - 1) != 0
+        {
+            return None;
+        }
+        let mut depth = (layer - self.base_layer) as usize;
+        let nodes = self.layers.get(depth)?.get(index..index + len)?;
+        let mut hashes = nodes.to_vec();
+
+        // go up to the layer in which the range is a single node, then add the
+        // sibling of that node and then of each of its ancestors
+        depth += len.trailing_zeros() as usize;
+        let mut node = index / len;
+        for layer in self.layers[depth..self.layers.len() - 1]
+            .iter()
+            .take(proof_layers as usize)
+        {
+            hashes.push(layer[node ^ 1]);
+            node /= 2;
+        }
+
+        Some(hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that padding a tree yields the same root as explicitly adding
+    /// zero leaves to it.
+    #[test]
+    fn test_root_padding() {
+        let leaves = [hash_block(b"a"), hash_block(b"b"), hash_block(b"c")];
+        let mut padded = leaves.to_vec();
+        padded.resize(8, [0; 32]);
+        let expected = hash_pair(
+            &hash_pair(
+                &hash_pair(&padded[0], &padded[1]),
+                &hash_pair(&padded[2], &padded[3]),
+            ),
+            &hash_pair(
+                &hash_pair(&padded[4], &padded[5]),
+                &hash_pair(&padded[6], &padded[7]),
+            ),
+        );
+        assert_eq!(root(&leaves, 8, 0), expected);
+        assert_eq!(root(&padded, 8, 0), expected);
+
+        // building the tree from an upper layer must give the same root, where
+        // the padding nodes are the hashes of zero subtrees
+        let layer1 = [
+            hash_pair(&padded[0], &padded[1]),
+            hash_pair(&padded[2], &padded[3]),
+        ];
+        assert_eq!(root(&layer1, 4, 1), expected);
+        assert_eq!(MerkleTree::from_layer(&layer1, 4, 1).root(), expected);
+        assert_eq!(root(&[leaves[0]], 1, 0), leaves[0]);
+    }
+
+    #[test]
+    fn test_hashes_with_proof() {
+        let leaves: Vec<_> = (0..6u8).map(|i| hash_block(&[i; 10])).collect();
+        let tree = MerkleTree::from_layer(&leaves, 8, 0);
+        let l1 = &tree.layers[1];
+        let l2 = &tree.layers[2];
+
+        // a range of two leaves and its full proof
+        let hashes = tree.hashes(0, 2, 2, 10).unwrap();
+        assert_eq!(hashes, vec![leaves[2], leaves[3], l1[0], l2[1]]);
+        // the proof may be cut short
+        let hashes = tree.hashes(0, 2, 2, 1).unwrap();
+        assert_eq!(hashes, vec![leaves[2], leaves[3], l1[0]]);
+        // a range in an upper layer
+        let hashes = tree.hashes(1, 0, 4, 1).unwrap();
+        assert_eq!(hashes, l1.clone());
+
+        // misaligned ranges and ranges past the end are invalid
+        assert!(tree.hashes(0, 1, 2, 0).is_none());
+        assert!(tree.hashes(0, 0, 3, 0).is_none());
+        assert!(tree.hashes(0, 8, 2, 0).is_none());
+        // as are layers below the tree's base
+        let tree = MerkleTree::from_layer(l1, 4, 1);
+        assert!(tree.hashes(0, 0, 2, 0).is_none());
+        assert_eq!(tree.hashes(1, 2, 2, 1).unwrap(), vec![l1[2], l1[3], l2[0]]);
+    }
+
+    #[test]
+    fn test_verify_leaves() {
+        let leaves: Vec<_> = (0..6u8).map(|i| hash_block(&[i; 10])).collect();
+        let mut padded = leaves.clone();
+        padded.resize(8, [0; 32]);
+        // a tree from its piece layer, where a piece has four blocks
+        let pieces = [root(&leaves[..4], 4, 0), root(&leaves[4..], 4, 0)];
+        let tree = MerkleTree::from_layer(&pieces, 2, 2);
+
+        assert!(tree.verify_leaves(0, &leaves[..4]));
+        // the last piece's leaves include the padding
+        assert!(tree.verify_leaves(4, &padded[4..]));
+        assert!(!tree.verify_leaves(4, &leaves[..4]));
+        let mut corrupt = padded.clone();
+        corrupt[1] = hash_block(b"corrupt");
+        assert!(!tree.verify_leaves(0, &corrupt[..4]));
+
+        // leaves that are not covered by a node in the tree can't be verified
+        assert!(!tree.verify_leaves(0, &leaves[..2]));
+        assert!(!tree.verify_leaves(2, &padded[2..6]));
+        assert!(!tree.verify_leaves(0, &leaves[..3]));
+        assert!(!tree.verify_leaves(8, &leaves[..4]));
+    }
+
+    #[test]
+    fn test_piece_leaves() {
+        let piece_len = 4 * BLOCK_LEN;
+        let file_len = 10 * BLOCK_LEN as u64;
+        assert_eq!(piece_leaves(file_len, piece_len, 0), (0, 4));
+        assert_eq!(piece_leaves(file_len, piece_len, 2), (8, 4));
+        // a file not larger than a piece has fewer leaves than a piece
+        assert_eq!(piece_leaves(BLOCK_LEN as u64 + 1, piece_len, 0), (0, 2));
+        assert_eq!(piece_leaves(1, piece_len, 0), (0, 1));
+    }
+
+    #[test]
+    fn test_layer_sizes() {
+        assert_eq!(piece_layer(BLOCK_LEN), 0);
+        assert_eq!(piece_layer(4 * BLOCK_LEN), 2);
+        assert_eq!(leaf_count(1), 1);
+        assert_eq!(leaf_count(BLOCK_LEN as u64), 1);
+        assert_eq!(leaf_count(BLOCK_LEN as u64 + 1), 2);
+        assert_eq!(leaf_count(5 * BLOCK_LEN as u64), 8);
+    }
+}
@@ -2,15 +2,29 @@
 //! well as utilities to construct it.
 
 use std::{
-    fmt,
-    path::{Path, PathBuf},
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    fmt, io,
+    path::PathBuf,
 };
 
 use reqwest::Url;
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
 
-use crate::{FileInfo, Sha1Hash};
+use crate::{
+    disk::PieceHashes,
+    file_piece_count,
+    merkle::{self, MerkleTree},
+    tracker, FileInfo, Sha1Hash, Sha256Hash, BLOCK_LEN,
+};
+
+pub use builder::MetainfoBuilder;
+pub use serde_bencode::{value::Value as BencodeValue, Error as BencodeError};
 
-pub use serde_bencode::Error as BencodeError;
+mod bencode;
+mod builder;
 
 pub(crate) type Result<T> = crate::error::Result<T, MetainfoError>;
 
@@ -24,8 +38,26 @@ pub enum MetainfoError {
     /// a multiple of 20, or is otherwise invalid and thus the torrent could not
     /// be started.
     InvalidPieces,
+    /// The piece layer of a file in a v2 torrent is missing, or its hashes
+    /// don't add up to the file's merkle root.
+    InvalidPieceLayer,
     /// The tracker URL is not a valid URL.
     InvalidTrackerUrl,
+    /// A file path or the torrent's name is empty, or has an empty component.
+    EmptyPathComponent,
+    /// A file path or the torrent's name has a `.` or `..` component, with
+    /// which it could point outside of the torrent's download directory.
+    PathTraversal,
+    /// A component of a file path or the torrent's name contains a path
+    /// separator, with which it could be an absolute path or point outside
+    /// of the torrent's download directory. Besides `/`, this includes `\`
+    /// and `:`, which are separators on Windows.
+    PathSeparator,
+    /// A file path or the torrent's name contains a NUL byte, which can't be
+    /// in file names.
+    NulInPath,
+    /// The files of a new torrent could not be read.
+    Io(io::Error),
 }
 
 impl From<BencodeError> for MetainfoError {
@@ -34,6 +66,12 @@ impl From<BencodeError> for MetainfoError {
     }
 }
 
+impl From<io::Error> for MetainfoError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
 impl From<url::ParseError> for MetainfoError {
     fn from(_: url::ParseError) -> Self {
         Self::InvalidTrackerUrl
@@ -47,7 +85,13 @@ impl fmt::Display for MetainfoError {
             Bencode(e) => e.fmt(f),
             InvalidMetainfo => write!(f, "invalid metainfo"),
             InvalidPieces => write!(f, "invalid pieces"),
+            InvalidPieceLayer => write!(f, "invalid piece layer"),
             InvalidTrackerUrl => write!(f, "invalid tracker URL"),
+            EmptyPathComponent => write!(f, "empty path component"),
+            PathTraversal => write!(f, "path traversal"),
+            PathSeparator => write!(f, "path separator in path component"),
+            NulInPath => write!(f, "NUL byte in path"),
+            Io(e) => e.fmt(f),
         }
     }
 }
@@ -56,6 +100,7 @@ impl std::error::Error for MetainfoError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Self::Bencode(e) => Some(e),
+            Self::Io(e) => Some(e),
             _ => None,
         }
     }
@@ -69,19 +114,99 @@ pub struct Metainfo {
     /// path.
     pub name: String,
     /// This hash is used to identify a torrent with trackers and peers.
+    ///
+    /// For v2 torrents this is the v2 info hash truncated to 20 bytes, as
+    /// that is what is used in handshakes and tracker announces. Hybrid
+    /// torrents are identified by both, so for them this is the v1 info hash
+    /// (see [`Self::info_hashes`]).
     pub info_hash: Sha1Hash,
+    /// The SHA-256 hash of the info dictionary, if this is a v2 torrent, as
+    /// described in [BEP 52](http://bittorrent.org/beps/bep_0052.html).
+    pub info_hash_v2: Option<Sha256Hash>,
     /// The concatenation of the 20 byte SHA-1 hash of each piece in torrent.
     /// This is used to verify the data sent to us by peers.
+    ///
+    /// This is empty for v2 torrents, whose pieces are verified with
+    /// `file_hashes`. Hybrid torrents have both.
     pub pieces: Vec<u8>,
     /// The nominal lengths of a piece, that is, the length of all but
     /// potentially the last piece, which may be smaller.
     pub piece_len: u32,
     /// The paths and lenths of the files in torrent.
+    ///
+    /// In v2 torrents each file starts at a piece boundary, so there may be
+    /// gaps between the files' torrent offsets.
     pub files: Vec<FileInfo>,
-    /// The trackers that we can announce to.
-    /// The tier information is not currently present in this field as
-    /// cratetorrent doesn't use it. In the future it may be added.
-    pub trackers: Vec<Url>,
+    /// The merkle tree hashes of each file, in the same order as `files`, if
+    /// this is a v2 torrent, or `None` for empty files, which have no merkle
+    /// tree. It's empty otherwise.
+    pub file_hashes: Vec<Option<FileHashes>>,
+    /// The length of the torrent in the v1 view of a hybrid torrent, in which
+    /// the gaps between files are padding files. This is what the v1 piece
+    /// hashes cover, and it may be longer than the end of the last file if
+    /// that is padded too.
+    ///
+    /// This is only set for hybrid torrents.
+    pub padded_len: Option<u64>,
+    /// The trackers that we can announce to, grouped into tiers as described
+    /// in [BEP 12](http://bittorrent.org/beps/bep_0012.html).
+    ///
+    /// Tiers are in order of priority. If the metainfo only has the single
+    /// `announce` key, this contains a single tier with that tracker.
+    pub trackers: Vec<Vec<Url>>,
+    /// The HTTP servers from which the torrent's files may also be downloaded,
+    /// known as web seeds, as described in
+    /// [BEP 19](http://bittorrent.org/beps/bep_0019.html).
+    pub url_list: Vec<Url>,
+    /// Whether the torrent is private, as described in
+    /// [BEP 27](http://bittorrent.org/beps/bep_0027.html), in which case peers
+    /// may only be obtained from its trackers.
+    pub private: bool,
+    /// The optional properties of each file, in the same order as `files`.
+    pub file_attrs: Vec<FileAttrs>,
+    /// The symbolic links in the torrent, as described in
+    /// [BEP 47](http://bittorrent.org/beps/bep_0047.html). These have no data,
+    /// so they are not among the `files`.
+    pub symlinks: Vec<Symlink>,
+    /// The HTTP servers from which the torrent's pieces may also be
+    /// downloaded, as described in
+    /// [BEP 17](http://bittorrent.org/beps/bep_0017.html).
+    pub http_seeds: Vec<Url>,
+    /// The hosts and ports of DHT nodes that may be used to find peers, as
+    /// described in [BEP 5](http://bittorrent.org/beps/bep_0005.html).
+    pub nodes: Vec<(String, u16)>,
+    /// A free-form comment of the torrent's author.
+    pub comment: Option<String>,
+    /// The name and version of the program that created the torrent.
+    pub created_by: Option<String>,
+    /// The time the torrent was created, in seconds since the Unix epoch.
+    pub creation_date: Option<i64>,
+    /// The fields of the metainfo that are not known to us, or that could not
+    /// be parsed, keyed by their names. These are kept so that they are not
+    /// lost when the metainfo is encoded again.
+    pub extra_fields: HashMap<Vec<u8>, BencodeValue>,
+    /// The encoded info dictionary, which is what the info hash identifies.
+    info: Vec<u8>,
+    /// The components of the paths of the `files`, as they are in the
+    /// metainfo. The storage paths are joined from these once each of them is
+    /// sanitized.
+    pub(crate) file_paths: Vec<Vec<String>>,
+    /// The components of the paths of the `symlinks` and of their targets, as
+    /// they are in the metainfo.
+    pub(crate) symlink_paths: Vec<(Vec<String>, Vec<String>)>,
+    /// The trackers and web seeds as they were parsed, and the fields they
+    /// were parsed from.
+    source: SourceFields,
+}
+
+/// The fields of the metainfo from which the trackers and web seeds are
+/// parsed. These are encoded as they are, unless the trackers or the web seeds
+/// are changed, so that the ones we can't use are not lost.
+#[derive(Clone)]
+struct SourceFields {
+    trackers: Vec<Vec<Url>>,
+    url_list: Vec<Url>,
+    fields: HashMap<Vec<u8>, BencodeValue>,
 }
 
 impl Metainfo {
@@ -95,16 +220,64 @@ impl Metainfo {
         // parse metainfo, but correctly parsing is not enough, we need to
         // verify it afterwards
         let metainfo: raw::Metainfo = serde_bencode::from_bytes(buf)?;
+        let is_v2 = metainfo.info.meta_version == Some(2);
+        // hybrid torrents can be downloaded by v1 peers too, so they also have
+        // the v1 keys
+        let is_hybrid = is_v2 && metainfo.info.pieces.is_some();
+
+        // the name is the download path of a single file torrent, and the
+        // directory of an archive's files
+        validate_path(std::slice::from_ref(&metainfo.info.name))?;
 
         // the pieces field is a concatenation of 20 byte SHA-1 hashes, so it
-        // must be a multiple of 20
-        if metainfo.info.pieces.len() % 20 != 0 {
-            return Err(MetainfoError::InvalidPieces);
-        }
+        // must be a multiple of 20 (v2 torrents don't need it)
+        let pieces = if is_v2 && !is_hybrid {
+            Vec::new()
+        } else {
+            match &metainfo.info.pieces {
+                Some(pieces) if pieces.len() % 20 == 0 => pieces.clone(),
+                _ => return Err(MetainfoError::InvalidPieces),
+            }
+        };
 
         // verify download structure and build up files metadata
         let mut files = Vec::new();
-        if let Some(len) = metainfo.info.len {
+        let mut file_hashes = Vec::new();
+        let mut file_attrs = Vec::new();
+        let mut symlinks = Vec::new();
+        let mut file_paths = Vec::new();
+        let mut symlink_paths = Vec::new();
+        let mut padded_len = None;
+        if is_v2 {
+            let v2 = v2_files(&metainfo.info, &metainfo.piece_layers)?;
+            files = v2.files;
+            file_hashes = v2.file_hashes;
+            file_attrs = v2.file_attrs;
+            symlinks = v2.symlinks;
+            file_paths = v2.file_paths;
+            symlink_paths = v2.symlink_paths;
+
+            // the files of a hybrid torrent are those of the file tree, and
+            // the v1 files must be the same, with padding files between them
+            // so that both versions have the same pieces
+            if is_hybrid {
+                let len = hybrid_padded_len(&metainfo.info, &files)?;
+                let piece_count = files
+                    .iter()
+                    .map(|f| file_piece_count(f.len, metainfo.info.piece_len))
+                    .sum::<usize>();
+                if file_piece_count(len, metainfo.info.piece_len) != piece_count
+                {
+                    log::warn!("Hybrid torrent has too much padding");
+                    return Err(MetainfoError::InvalidMetainfo);
+                }
+                if pieces.len() / 20 != piece_count {
+                    log::warn!("Hybrid torrent v1 and v2 pieces differ");
+                    return Err(MetainfoError::InvalidPieces);
+                }
+                padded_len = Some(len);
+            }
+        } else if let Some(len) = metainfo.info.len {
             if metainfo.info.files.is_some() {
                 log::warn!("Metainfo cannot contain both `length` and `files`");
                 return Err(MetainfoError::InvalidMetainfo);
@@ -120,6 +293,11 @@ impl Metainfo {
                 len,
                 torrent_offset: 0,
             });
+            file_attrs.push(FileAttrs {
+                attr: None,
+                md5sum: metainfo.info.md5sum.clone(),
+            });
+            file_paths.push(vec![metainfo.info.name.clone()]);
         } else if let Some(raw_files) = &metainfo.info.files {
             if raw_files.is_empty() {
                 log::warn!("Metainfo files must not be empty");
@@ -127,32 +305,26 @@ impl Metainfo {
             }
 
             files.reserve_exact(raw_files.len());
+            file_attrs.reserve_exact(raw_files.len());
 
             // and sum up the file offsets in the torrent
             let mut torrent_offset = 0;
             for file in raw_files.iter() {
-                // verify that the file length is non-zero
-                if file.len == 0 {
-                    log::warn!("File {:?} length is 0", file.path);
-                    return Err(MetainfoError::InvalidMetainfo);
-                }
-
-                // verify that the path is not empty
+                validate_path(&file.path)?;
                 let path: PathBuf = file.path.iter().collect();
-                if path == PathBuf::new() {
-                    log::warn!("Path in metainfo is empty");
-                    return Err(MetainfoError::InvalidMetainfo);
-                }
 
-                // verify that the path is not absolute
-                if path.is_absolute() {
-                    log::warn!("Path {:?} is absolute", path);
-                    return Err(MetainfoError::InvalidMetainfo);
+                if file.is_symlink() {
+                    symlinks.push(symlink(path, file.len, &file.symlink_path)?);
+                    symlink_paths.push((
+                        file.path.clone(),
+                        file.symlink_path.clone().unwrap_or_default(),
+                    ));
+                    continue;
                 }
 
-                // verify that the path is not the root
-                if path == Path::new("/") {
-                    log::warn!("Path {:?} is root", path);
+                // verify that the file length is non-zero
+                if file.len == 0 {
+                    log::warn!("File {:?} length is 0", path);
                     return Err(MetainfoError::InvalidMetainfo);
                 }
 
@@ -162,58 +334,348 @@ impl Metainfo {
                     torrent_offset,
                     len: file.len,
                 });
+                file_attrs.push(FileAttrs {
+                    attr: file.attr.clone(),
+                    md5sum: file.md5sum.clone(),
+                });
+                file_paths.push(file.path.clone());
 
                 // advance offset for next file
                 torrent_offset += file.len;
             }
+
+            if files.is_empty() {
+                log::warn!("Metainfo has no files besides symlinks");
+                return Err(MetainfoError::InvalidMetainfo);
+            }
         } else {
             log::warn!("No `length` or `files` key present in metainfo");
             return Err(MetainfoError::InvalidMetainfo);
         }
 
+        // if the announce list is present, the single `announce` key must be
+        // ignored, as per BEP 12
         let mut trackers = Vec::new();
         if !metainfo.announce_list.is_empty() {
-            let tracker_count = metainfo
-                .announce_list
-                .iter()
-                .map(|t| t.len())
-                .sum::<usize>()
-                + metainfo.announce.as_ref().map(|_| 1).unwrap_or_default();
-            trackers.reserve(tracker_count);
-
+            trackers.reserve(metainfo.announce_list.len());
             for tier in metainfo.announce_list.iter() {
+                let mut tier_trackers = Vec::with_capacity(tier.len());
                 for tracker in tier.iter() {
                     let url = Url::parse(&tracker)?;
-                    // the tracker may be over UDP, which we don't support (yet)
-                    if url.scheme() == "http" || url.scheme() == "https" {
-                        trackers.push(url);
+                    if tracker::is_supported_url(&url) {
+                        tier_trackers.push(url);
                     }
                 }
+                // don't keep tiers that have no trackers we can use
+                if !tier_trackers.is_empty() {
+                    trackers.push(tier_trackers);
+                }
             }
         } else if let Some(tracker) = &metainfo.announce {
             let url = Url::parse(&tracker)?;
-            if url.scheme() == "http" || url.scheme() == "https" {
-                trackers.push(url);
+            if tracker::is_supported_url(&url) {
+                trackers.push(vec![url]);
             }
         }
 
         if trackers.is_empty() {
-            log::warn!("No HTTP trackers in metainfo");
+            log::warn!("No supported trackers in metainfo");
         }
 
+        // the url list may be a single URL or a list of them, and it's
+        // commonly an empty string if there are no web seeds
+        let urls = match &metainfo.url_list {
+            Some(Value::Bytes(url)) => vec![url],
+            Some(Value::List(urls)) => urls
+                .iter()
+                .filter_map(|url| match url {
+                    Value::Bytes(url) => Some(url),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut url_list = Vec::with_capacity(urls.len());
+        for url in urls {
+            if url.is_empty() {
+                continue;
+            }
+            // a bad web seed is not fatal as the torrent can be downloaded
+            // without it
+            match std::str::from_utf8(url).ok().map(Url::parse) {
+                Some(Ok(url))
+                    if url.scheme() == "http" || url.scheme() == "https" =>
+                {
+                    url_list.push(url);
+                }
+                _ => log::warn!(
+                    "Invalid web seed URL {}",
+                    String::from_utf8_lossy(url)
+                ),
+            }
+        }
+
+        let private = metainfo.info.private == Some(1);
+
+        // the info hash is the hash of the info dictionary exactly as it is in
+        // the metainfo, as encoding it again would not give back the same
+        // bytes if it had keys that we don't know about
+        let info = match bencode::dict_value_span(buf, b"info")? {
+            Some(span) => buf[span].to_vec(),
+            None => return Err(MetainfoError::InvalidMetainfo),
+        };
+
+        // the fields that are not needed to download the torrent are parsed
+        // leniently from the generic form of the metainfo, the rest of which
+        // is kept as is
+        let mut fields = match serde_bencode::from_bytes(buf)? {
+            Value::Dict(fields) => fields,
+            _ => return Err(MetainfoError::InvalidMetainfo),
+        };
+        for key in &["info", "piece layers"] {
+            fields.remove(key.as_bytes());
+        }
+        let mut source_fields = HashMap::new();
+        for key in &["announce", "announce-list", "url-list"] {
+            if let Some(value) = fields.remove(key.as_bytes()) {
+                source_fields.insert(key.as_bytes().to_vec(), value);
+            }
+        }
+        let source = SourceFields {
+            trackers: trackers.clone(),
+            url_list: url_list.clone(),
+            fields: source_fields,
+        };
+        let http_seeds =
+            take_field(&mut fields, "httpseeds", |value| match value {
+                Value::List(urls) => urls
+                    .iter()
+                    .map(|url| match url {
+                        Value::Bytes(url) => std::str::from_utf8(url)
+                            .ok()
+                            .and_then(|url| Url::parse(url).ok()),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => None,
+            })
+            .unwrap_or_default();
+        let nodes = take_field(&mut fields, "nodes", |value| match value {
+            Value::List(nodes) => nodes
+                .iter()
+                .map(|node| match node {
+                    Value::List(node) => match node.as_slice() {
+                        [Value::Bytes(host), Value::Int(port)] => Some((
+                            String::from_utf8(host.clone()).ok()?,
+                            u16::try_from(*port).ok()?,
+                        )),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        })
+        .unwrap_or_default();
+        let comment = take_field(&mut fields, "comment", string_field);
+        let created_by = take_field(&mut fields, "created by", string_field);
+        let creation_date =
+            take_field(&mut fields, "creation date", |value| match value {
+                Value::Int(date) => Some(*date),
+                _ => None,
+            });
+
         // create info hash as a last step
-        let info_hash = metainfo.create_info_hash()?;
+        let (info_hash, info_hash_v2) = if is_hybrid {
+            (sha1_hash(&info), Some(sha256_hash(&info)))
+        } else if is_v2 {
+            // v2 peers and trackers identify the torrent by the first 20
+            // bytes of the v2 info hash
+            let info_hash_v2 = sha256_hash(&info);
+            let mut info_hash = [0; 20];
+            info_hash.copy_from_slice(&info_hash_v2[..20]);
+            (info_hash, Some(info_hash_v2))
+        } else {
+            (sha1_hash(&info), None)
+        };
 
         Ok(Self {
             name: metainfo.info.name,
             info_hash,
-            pieces: metainfo.info.pieces,
+            info_hash_v2,
+            pieces,
             piece_len: metainfo.info.piece_len,
             files,
+            file_hashes,
+            padded_len,
             trackers,
+            url_list,
+            private,
+            file_attrs,
+            symlinks,
+            http_seeds,
+            nodes,
+            comment,
+            created_by,
+            creation_date,
+            extra_fields: fields,
+            info,
+            file_paths,
+            symlink_paths,
+            source,
         })
     }
 
+    /// Encodes the metainfo, e.g. to save it as a `.torrent` file.
+    ///
+    /// The info dictionary is encoded as it was parsed, as changing it would
+    /// change the torrent's identity, so changes to the fields that describe
+    /// the torrent's contents are not encoded. The other fields are encoded
+    /// with their current values, along with the `extra_fields`.
+    ///
+    /// Unless they are changed, the trackers and web seeds are encoded as they
+    /// were parsed, including the ones that we can't use, such as trackers
+    /// with unsupported protocols.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        fn url_value(url: &Url) -> Value {
+            Value::Bytes(url.as_str().as_bytes().to_vec())
+        }
+
+        let mut fields = self.extra_fields.clone();
+        if self.trackers == self.source.trackers {
+            for key in &["announce", "announce-list"] {
+                if let Some(value) = self.source.fields.get(key.as_bytes()) {
+                    fields.insert(key.as_bytes().to_vec(), value.clone());
+                }
+            }
+        } else {
+            // clients that don't support tiers only use the `announce` key
+            if let Some(tracker) = self.trackers.first().and_then(|t| t.first())
+            {
+                fields.insert(b"announce".to_vec(), url_value(tracker));
+            }
+            if self.trackers.iter().map(Vec::len).sum::<usize>() > 1 {
+                let tiers = self
+                    .trackers
+                    .iter()
+                    .map(|tier| {
+                        Value::List(tier.iter().map(url_value).collect())
+                    })
+                    .collect();
+                fields.insert(b"announce-list".to_vec(), Value::List(tiers));
+            }
+        }
+        if self.is_v2() {
+            let piece_layers = self
+                .file_hashes
+                .iter()
+                .flatten()
+                .filter(|hashes| !hashes.piece_hashes.is_empty())
+                .map(|hashes| {
+                    (
+                        hashes.root.to_vec(),
+                        Value::Bytes(hashes.piece_hashes.concat()),
+                    )
+                })
+                .collect();
+            fields.insert(b"piece layers".to_vec(), Value::Dict(piece_layers));
+        }
+        if self.url_list == self.source.url_list {
+            if let Some(value) = self.source.fields.get(&b"url-list"[..]) {
+                fields.insert(b"url-list".to_vec(), value.clone());
+            }
+        } else if !self.url_list.is_empty() {
+            let urls = self.url_list.iter().map(url_value).collect();
+            fields.insert(b"url-list".to_vec(), Value::List(urls));
+        }
+        if !self.http_seeds.is_empty() {
+            let urls = self.http_seeds.iter().map(url_value).collect();
+            fields.insert(b"httpseeds".to_vec(), Value::List(urls));
+        }
+        if !self.nodes.is_empty() {
+            let nodes = self
+                .nodes
+                .iter()
+                .map(|(host, port)| {
+                    Value::List(vec![
+                        Value::Bytes(host.as_bytes().to_vec()),
+                        Value::Int(*port as i64),
+                    ])
+                })
+                .collect();
+            fields.insert(b"nodes".to_vec(), Value::List(nodes));
+        }
+        if let Some(comment) = &self.comment {
+            fields.insert(
+                b"comment".to_vec(),
+                Value::Bytes(comment.as_bytes().to_vec()),
+            );
+        }
+        if let Some(created_by) = &self.created_by {
+            fields.insert(
+                b"created by".to_vec(),
+                Value::Bytes(created_by.as_bytes().to_vec()),
+            );
+        }
+        if let Some(creation_date) = self.creation_date {
+            fields.insert(b"creation date".to_vec(), Value::Int(creation_date));
+        }
+
+        // the info dictionary can't be a `Value` without re-encoding it, so
+        // the top level dictionary is encoded by hand, with its keys in sorted
+        // order as bencode requires
+        let mut encoded = BTreeMap::new();
+        for (key, value) in fields.iter() {
+            if key != b"info" {
+                encoded.insert(&key[..], serde_bencode::to_bytes(value)?);
+            }
+        }
+        encoded.insert(&b"info"[..], self.info.clone());
+        let mut buf = b"d".to_vec();
+        for (key, value) in encoded {
+            buf.extend_from_slice(key.len().to_string().as_bytes());
+            buf.push(b':');
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(&value);
+        }
+        buf.push(b'e');
+        Ok(buf)
+    }
+
+    /// Returns the encoded info dictionary of the torrent.
+    pub fn info_bytes(&self) -> &[u8] {
+        &self.info
+    }
+
+    /// Returns true if this is a v2 torrent, whose pieces are verified with
+    /// the merkle trees of its files.
+    pub fn is_v2(&self) -> bool {
+        self.info_hash_v2.is_some()
+    }
+
+    /// Returns true if this is a hybrid torrent, which has both v1 and v2
+    /// metadata and so can be shared with both v1 and v2 peers.
+    pub fn is_hybrid(&self) -> bool {
+        self.padded_len.is_some()
+    }
+
+    /// Returns the info hashes that identify the torrent in its swarms.
+    ///
+    /// This is just the `info_hash`, unless this is a hybrid torrent, which is
+    /// in both a v1 and a v2 swarm: in that case the v2 info hash truncated to
+    /// 20 bytes follows the v1 info hash.
+    pub fn info_hashes(&self) -> Vec<Sha1Hash> {
+        let mut info_hashes = vec![self.info_hash];
+        if let Some(info_hash_v2) =
+            self.info_hash_v2.filter(|_| self.is_hybrid())
+        {
+            let mut info_hash = [0; 20];
+            info_hash.copy_from_slice(&info_hash_v2[..20]);
+            info_hashes.push(info_hash);
+        }
+        info_hashes
+    }
+
     /// Returns true if the download is for an archive.
     pub fn is_archive(&self) -> bool {
         self.files.len() > 1
@@ -229,8 +691,516 @@ impl Metainfo {
 
     /// Returns the number of pieces in this torrent.
     pub fn piece_count(&self) -> usize {
-        self.pieces.len() / 20
+        if self.is_v2() {
+            // each file starts at a new piece
+            self.files
+                .iter()
+                .map(|f| file_piece_count(f.len, self.piece_len))
+                .sum()
+        } else {
+            self.pieces.len() / 20
+        }
+    }
+
+    /// Returns the expected hashes of the torrent's pieces.
+    pub(crate) fn piece_hashes(&self) -> PieceHashes {
+        if self.is_v2() {
+            let mut hashes = Vec::with_capacity(self.piece_count());
+            for file in self.file_hashes.iter().flatten() {
+                // a file that fits in a single piece has no piece layer, the
+                // piece's hash being the root of the file's tree
+                if file.piece_hashes.is_empty() {
+                    hashes.push(file.root);
+                } else {
+                    hashes.extend_from_slice(&file.piece_hashes);
+                }
+            }
+            // hybrid pieces are verified as both v1 and v2 pieces, so that v1
+            // and v2 peers can't be given different data
+            if self.padded_len.is_some() {
+                PieceHashes::Hybrid {
+                    v1: self.pieces.clone(),
+                    v2: hashes,
+                }
+            } else {
+                PieceHashes::V2(hashes)
+            }
+        } else {
+            PieceHashes::V1(self.pieces.clone())
+        }
+    }
+
+    /// Returns the merkle trees of the files of a v2 torrent, from their piece
+    /// layers up, or `None` for empty files.
+    pub(crate) fn merkle_trees(&self) -> Vec<Option<MerkleTree>> {
+        if !self.is_v2() {
+            return Vec::new();
+        }
+        let piece_layer = merkle::piece_layer(self.piece_len);
+        self.files
+            .iter()
+            .zip(self.file_hashes.iter())
+            .map(|(file, hashes)| {
+                let hashes = hashes.as_ref()?;
+                let leaf_count = merkle::leaf_count(file.len);
+                if hashes.piece_hashes.is_empty() {
+                    // the tree of a file not larger than a piece is only known
+                    // by its root
+                    let root_layer = leaf_count.trailing_zeros();
+                    Some(MerkleTree::from_layer(&[hashes.root], 1, root_layer))
+                } else {
+                    Some(MerkleTree::from_layer(
+                        &hashes.piece_hashes,
+                        leaf_count >> piece_layer,
+                        piece_layer,
+                    ))
+                }
+            })
+            .collect()
+    }
+}
+
+/// The hashes with which the contents of a file in a v2 torrent are verified.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileHashes {
+    /// The root of the file's merkle tree, called "pieces root" in the
+    /// metainfo. This also identifies the file when exchanging hashes with
+    /// peers.
+    pub root: Sha256Hash,
+    /// The hashes of the file's pieces, that is the piece layer of its merkle
+    /// tree.
+    ///
+    /// This is empty if the file is not larger than a piece, in which case its
+    /// root is the hash of its only piece.
+    pub piece_hashes: Vec<Sha256Hash>,
+}
+
+/// Creates the SHA-1 hash of the encoded info dictionary.
+fn sha1_hash(info: &[u8]) -> Sha1Hash {
+    let digest = Sha1::digest(info);
+    let mut info_hash = [0; 20];
+    info_hash.copy_from_slice(&digest);
+    info_hash
+}
+
+/// Creates the SHA-256 hash of the encoded info dictionary, used by v2
+/// torrents.
+fn sha256_hash(info: &[u8]) -> Sha256Hash {
+    let digest = Sha256::digest(info);
+    let mut info_hash = [0; 32];
+    info_hash.copy_from_slice(&digest);
+    info_hash
+}
+
+/// The optional properties of a file in the metainfo.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileAttrs {
+    /// The file's attributes, as described in
+    /// [BEP 47](http://bittorrent.org/beps/bep_0047.html).
+    pub attr: Option<String>,
+    /// The hex encoded MD5 hash of the file, which is not used by BitTorrent,
+    /// but some torrents have it anyway.
+    pub md5sum: Option<String>,
+}
+
+impl FileAttrs {
+    /// Returns true if this is a padding file, whose contents are all zeros.
+    /// It only serves to align the next file to a piece boundary, and so it is
+    /// not stored.
+    pub fn is_padding(&self) -> bool {
+        self.has_attr('p')
+    }
+
+    /// Returns true if the file is meant to be executable.
+    pub fn is_executable(&self) -> bool {
+        self.has_attr('x')
+    }
+
+    /// Returns true if the file is meant to be hidden.
+    pub fn is_hidden(&self) -> bool {
+        self.has_attr('h')
+    }
+
+    fn has_attr(&self, attr: char) -> bool {
+        matches!(&self.attr, Some(attrs) if attrs.contains(attr))
+    }
+}
+
+/// A symbolic link in the torrent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symlink {
+    /// The path of the link, which is relative to the download directory like
+    /// the paths of files.
+    pub path: PathBuf,
+    /// The path of the file or directory the link points to, relative to the
+    /// torrent's root.
+    pub target: PathBuf,
+}
+
+/// Removes the field from the metainfo's fields and parses it.
+///
+/// If the field is not valid, it is kept among the fields, so that it is not
+/// lost if the metainfo is encoded again.
+fn take_field<T>(
+    fields: &mut HashMap<Vec<u8>, Value>,
+    key: &str,
+    parse: impl FnOnce(&Value) -> Option<T>,
+) -> Option<T> {
+    let value = fields.remove(key.as_bytes())?;
+    let parsed = parse(&value);
+    if parsed.is_none() {
+        log::warn!("Invalid {:?} field in metainfo", key);
+        fields.insert(key.as_bytes().to_vec(), value);
+    }
+    parsed
+}
+
+/// Parses a UTF-8 string field.
+fn string_field(value: &Value) -> Option<String> {
+    match value {
+        Value::Bytes(s) => String::from_utf8(s.clone()).ok(),
+        _ => None,
+    }
+}
+
+/// Checks that the components of a file's path can only form a path within
+/// the torrent's download directory.
+///
+/// Names that are merely not valid on some file systems are not rejected here,
+/// but are sanitized when the torrent's storage is set up.
+fn validate_path(path: &[String]) -> Result<()> {
+    if path.is_empty() {
+        log::warn!("Path in metainfo is empty");
+        return Err(MetainfoError::EmptyPathComponent);
+    }
+
+    for component in path.iter() {
+        if component.is_empty() {
+            log::warn!("Path {:?} has an empty component", path);
+            return Err(MetainfoError::EmptyPathComponent);
+        }
+        if component == "." || component == ".." {
+            log::warn!("Path {:?} has a traversal component", path);
+            return Err(MetainfoError::PathTraversal);
+        }
+        // a separator would make the component itself a path, which could
+        // be absolute or have traversal components, and the separators of
+        // Windows (including the colon of drive letters) are rejected on every
+        // platform, so that a torrent is valid regardless of where it's
+        // downloaded
+        if component.contains(&['/', '\\', ':'][..]) {
+            log::warn!("Path {:?} has a separator in a component", path);
+            return Err(MetainfoError::PathSeparator);
+        }
+        if component.contains('\0') {
+            log::warn!("Path {:?} contains a NUL byte", path);
+            return Err(MetainfoError::NulInPath);
+        }
+    }
+
+    Ok(())
+}
+
+/// Verifies that the v1 files of a hybrid torrent are the same as the files of
+/// its file tree, and returns the length of the v1 view of the torrent.
+///
+/// In the v1 view there are padding files between the files, so that each
+/// file starts at the same offset, and thus at the same piece, as in the v2
+/// view.
+fn hybrid_padded_len(info: &raw::Info, files: &[FileInfo]) -> Result<u64> {
+    if let Some(len) = info.len {
+        // a single file torrent can't have padding
+        if files.len() != 1 || files[0].len != len {
+            log::warn!("Hybrid torrent file doesn't match file tree");
+            return Err(MetainfoError::InvalidMetainfo);
+        }
+        return Ok(len);
+    }
+
+    let raw_files = match &info.files {
+        Some(raw_files) => raw_files,
+        None => {
+            log::warn!("No `length` or `files` key present in metainfo");
+            return Err(MetainfoError::InvalidMetainfo);
+        }
+    };
+    let mut files = files.iter();
+    let mut torrent_offset = 0;
+    for raw_file in raw_files.iter() {
+        // neither padding files nor symlinks are in the file tree
+        if !raw_file.is_padding() && !raw_file.is_symlink() {
+            let path: PathBuf = raw_file.path.iter().collect();
+            match files.next() {
+                Some(file)
+                    if file.path == path
+                        && file.len == raw_file.len
+                        && file.torrent_offset == torrent_offset => {}
+                _ => {
+                    log::warn!(
+                        "Hybrid torrent file {:?} doesn't match file tree",
+                        path
+                    );
+                    return Err(MetainfoError::InvalidMetainfo);
+                }
+            }
+        }
+        torrent_offset += raw_file.len;
+    }
+
+    if files.next().is_some() {
+        log::warn!("Hybrid torrent is missing files of its file tree");
+        return Err(MetainfoError::InvalidMetainfo);
+    }
+
+    Ok(torrent_offset)
+}
+
+/// The files of a v2 torrent, along with their hashes and attributes, and the
+/// torrent's symlinks.
+struct V2Files {
+    files: Vec<FileInfo>,
+    file_hashes: Vec<Option<FileHashes>>,
+    file_attrs: Vec<FileAttrs>,
+    symlinks: Vec<Symlink>,
+    file_paths: Vec<Vec<String>>,
+    symlink_paths: Vec<(Vec<String>, Vec<String>)>,
+}
+
+/// Builds up the files and their hashes from the file tree of a v2 torrent,
+/// verifying each file's piece layer.
+fn v2_files(
+    info: &raw::Info,
+    piece_layers: &HashMap<serde_bytes::ByteBuf, serde_bytes::ByteBuf>,
+) -> Result<V2Files> {
+    let piece_len = info.piece_len;
+    if !piece_len.is_power_of_two() || piece_len < BLOCK_LEN {
+        log::warn!("Piece length {} is invalid for a v2 torrent", piece_len);
+        return Err(MetainfoError::InvalidMetainfo);
+    }
+    let file_tree = match &info.file_tree {
+        Some(file_tree) => file_tree,
+        None => {
+            log::warn!("No `file tree` key present in v2 metainfo");
+            return Err(MetainfoError::InvalidMetainfo);
+        }
+    };
+
+    let mut tree_files = Vec::new();
+    walk_file_tree(file_tree, &mut Vec::new(), &mut tree_files)?;
+    if tree_files.is_empty() {
+        log::warn!("Metainfo file tree must not be empty");
+        return Err(MetainfoError::InvalidMetainfo);
+    }
+
+    let piece_layer = merkle::piece_layer(piece_len);
+    let mut files = Vec::with_capacity(tree_files.len());
+    let mut file_hashes = Vec::with_capacity(tree_files.len());
+    let mut file_attrs = Vec::with_capacity(tree_files.len());
+    let mut symlinks = Vec::new();
+    let mut file_paths = Vec::with_capacity(tree_files.len());
+    let mut symlink_paths = Vec::new();
+    let mut torrent_offset = 0;
+    for TreeFile {
+        path,
+        len,
+        root,
+        attrs,
+    } in tree_files
+    {
+        validate_path(&path)?;
+        let path_components = path;
+        let path: PathBuf = path_components.iter().collect();
+
+        if attrs.is_symlink() {
+            symlinks.push(symlink(path, len, &attrs.symlink_path)?);
+            symlink_paths.push((
+                path_components,
+                attrs.symlink_path.unwrap_or_default(),
+            ));
+            continue;
+        }
+
+        // empty files have no pieces and so neither a pieces root nor a merkle
+        // tree, while all other files must have a root
+        let hashes = if len == 0 {
+            None
+        } else {
+            let root = root.ok_or_else(|| {
+                log::warn!("File {:?} has no pieces root", path);
+                MetainfoError::InvalidMetainfo
+            })?;
+
+            // files larger than a piece must have their piece layer in the
+            // metainfo, which must add up to the file's root
+            let mut piece_hashes = Vec::new();
+            if len > piece_len as u64 {
+                let layer = piece_layers
+                    .get(&serde_bytes::ByteBuf::from(root.to_vec()))
+                    .ok_or(MetainfoError::InvalidPieceLayer)?;
+                let piece_count = file_piece_count(len, piece_len);
+                if layer.len() != piece_count * 32 {
+                    return Err(MetainfoError::InvalidPieceLayer);
+                }
+                piece_hashes = layer
+                    .chunks_exact(32)
+                    .map(|hash| {
+                        let mut piece_hash = [0; 32];
+                        piece_hash.copy_from_slice(hash);
+                        piece_hash
+                    })
+                    .collect();
+                let width = merkle::leaf_count(len) >> piece_layer;
+                if merkle::root(&piece_hashes, width, piece_layer) != root {
+                    log::warn!(
+                        "File {:?} piece layer doesn't match root",
+                        path
+                    );
+                    return Err(MetainfoError::InvalidPieceLayer);
+                }
+            }
+            Some(FileHashes { root, piece_hashes })
+        };
+
+        files.push(FileInfo {
+            path,
+            torrent_offset,
+            len,
+        });
+        file_hashes.push(hashes);
+        file_attrs.push(FileAttrs {
+            attr: attrs.attr,
+            md5sum: None,
+        });
+        file_paths.push(path_components);
+
+        // advance offset for next file, which starts at the next piece
+        // boundary
+        torrent_offset +=
+            file_piece_count(len, piece_len) as u64 * piece_len as u64;
+    }
+
+    // a torrent without any pieces can't be downloaded
+    if files.iter().all(|file| file.len == 0) {
+        log::warn!("Metainfo has no files with content");
+        return Err(MetainfoError::InvalidMetainfo);
     }
+
+    Ok(V2Files {
+        files,
+        file_hashes,
+        file_attrs,
+        symlinks,
+        file_paths,
+        symlink_paths,
+    })
+}
+
+/// A file in the file tree of a v2 torrent.
+struct TreeFile {
+    path: Vec<String>,
+    len: u64,
+    root: Option<Sha256Hash>,
+    attrs: TreeFileAttrs,
+}
+
+/// The attributes of a file in the file tree of a v2 torrent.
+struct TreeFileAttrs {
+    attr: Option<String>,
+    symlink_path: Option<Vec<String>>,
+}
+
+impl TreeFileAttrs {
+    fn is_symlink(&self) -> bool {
+        matches!(&self.attr, Some(attr) if attr.contains('l'))
+    }
+}
+
+/// Returns the symlink at the path, which must have no data and must point to
+/// a valid path within the torrent.
+fn symlink(
+    path: PathBuf,
+    len: u64,
+    target: &Option<Vec<String>>,
+) -> Result<Symlink> {
+    if len != 0 {
+        log::warn!("Symlink {:?} has {} bytes of data", path, len);
+        return Err(MetainfoError::InvalidMetainfo);
+    }
+    let target = target.as_ref().ok_or_else(|| {
+        log::warn!("Symlink {:?} has no target", path);
+        MetainfoError::InvalidMetainfo
+    })?;
+    validate_path(target)?;
+    Ok(Symlink {
+        path,
+        target: target.iter().collect(),
+    })
+}
+
+/// Collects the files in the file tree node, in the order of their paths.
+///
+/// A file is a node with a single empty key, under which its properties are
+/// found, while all other nodes are directories keyed by their entries'
+/// names.
+fn walk_file_tree(
+    node: &Value,
+    path: &mut Vec<String>,
+    files: &mut Vec<TreeFile>,
+) -> Result<()> {
+    let entries = match node {
+        Value::Dict(entries) => entries,
+        _ => return Err(MetainfoError::InvalidMetainfo),
+    };
+
+    if let Some(Value::Dict(props)) = entries.get(&b""[..]) {
+        let len = match props.get(&b"length"[..]) {
+            Some(Value::Int(len)) if *len >= 0 => *len as u64,
+            _ => return Err(MetainfoError::InvalidMetainfo),
+        };
+        let root = match props.get(&b"pieces root"[..]) {
+            Some(Value::Bytes(root)) if root.len() == 32 => {
+                let mut hash = [0; 32];
+                hash.copy_from_slice(root);
+                Some(hash)
+            }
+            Some(_) => return Err(MetainfoError::InvalidMetainfo),
+            None => None,
+        };
+        let attr = props.get(&b"attr"[..]).and_then(string_field);
+        let symlink_path = match props.get(&b"symlink path"[..]) {
+            Some(Value::List(components)) => Some(
+                components
+                    .iter()
+                    .map(string_field)
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or(MetainfoError::InvalidMetainfo)?,
+            ),
+            Some(_) => return Err(MetainfoError::InvalidMetainfo),
+            None => None,
+        };
+        files.push(TreeFile {
+            path: path.clone(),
+            len,
+            root,
+            attrs: TreeFileAttrs { attr, symlink_path },
+        });
+        return Ok(());
+    }
+
+    // bencoded dictionaries are sorted by their keys, which defines the order
+    // of files in torrent
+    let mut names: Vec<_> = entries.keys().collect();
+    names.sort();
+    for name in names {
+        let entry = &entries[name];
+        let name = String::from_utf8(name.clone())
+            .map_err(|_| MetainfoError::InvalidMetainfo)?;
+        path.push(name);
+        walk_file_tree(entry, path, files)?;
+        path.pop();
+    }
+
+    Ok(())
 }
 
 impl fmt::Debug for Metainfo {
@@ -238,9 +1208,22 @@ impl fmt::Debug for Metainfo {
         f.debug_struct("Metainfo")
             .field("name", &self.name)
             .field("info_hash", &self.info_hash)
+            .field("info_hash_v2", &self.info_hash_v2)
             .field("pieces", &"<pieces...>")
             .field("piece_len", &self.piece_len)
             .field("structure", &self.files)
+            .field("padded_len", &self.padded_len)
+            .field("trackers", &self.trackers)
+            .field("url_list", &self.url_list)
+            .field("private", &self.private)
+            .field("file_attrs", &self.file_attrs)
+            .field("symlinks", &self.symlinks)
+            .field("http_seeds", &self.http_seeds)
+            .field("nodes", &self.nodes)
+            .field("comment", &self.comment)
+            .field("created_by", &self.created_by)
+            .field("creation_date", &self.creation_date)
+            .field("extra_fields", &self.extra_fields)
             .finish()
     }
 }
@@ -253,9 +1236,10 @@ mod raw {
     //! [`Metainfo`], but with semantic requirements encoded in the type
     //! system.
 
-    use sha1::{Digest, Sha1};
+    use std::collections::HashMap;
 
-    use super::{Result, Sha1Hash};
+    use serde_bencode::value::Value;
+    use serde_bytes::ByteBuf;
 
     #[derive(Debug, Deserialize)]
     pub struct Metainfo {
@@ -264,41 +1248,896 @@ mod raw {
         #[serde(default)]
         #[serde(rename = "announce-list")]
         pub announce_list: Vec<Vec<String>>,
+        /// The piece layers of the files of a v2 torrent, keyed by their
+        /// merkle roots.
+        #[serde(default)]
+        #[serde(rename = "piece layers")]
+        pub piece_layers: HashMap<ByteBuf, ByteBuf>,
+        /// The web seeds of the torrent, which is either a single URL or
+        /// a list of URLs.
+        #[serde(rename = "url-list")]
+        pub url_list: Option<Value>,
     }
 
-    impl Metainfo {
-        /// Creates a SHA-1 hash of the encoded `info` field's value.
-        pub fn create_info_hash(&self) -> Result<Sha1Hash> {
-            let info = serde_bencode::to_bytes(&self.info)?;
-            let digest = Sha1::digest(&info);
-            let mut info_hash = [0; 20];
-            info_hash.copy_from_slice(&digest);
-            Ok(info_hash)
-        }
-    }
-
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug, Deserialize)]
     pub struct Info {
         pub name: String,
+        #[serde(default)]
         #[serde(with = "serde_bytes")]
-        pub pieces: Vec<u8>,
+        pub pieces: Option<Vec<u8>>,
         #[serde(rename = "piece length")]
         pub piece_len: u32,
         #[serde(rename = "length")]
         pub len: Option<u64>,
         pub files: Option<Vec<File>>,
-        /// This is not currently used but needs to be kept in here so that we
-        /// can encode back a valid info hash for hashing.
+        /// The hex encoded MD5 hash of a single file torrent's file.
+        pub md5sum: Option<String>,
+        /// Whether the torrent is private.
         pub private: Option<u8>,
+        /// The version of the torrent's metainfo format, which is 2 for v2
+        /// and hybrid torrents.
+        #[serde(rename = "meta version")]
+        pub meta_version: Option<u8>,
+        /// The file and directory structure of a v2 torrent.
+        #[serde(rename = "file tree")]
+        pub file_tree: Option<Value>,
     }
 
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug, Deserialize)]
     pub struct File {
         pub path: Vec<String>,
         #[serde(rename = "length")]
         pub len: u64,
+        /// The file's attributes, as described in
+        /// [BEP 47](http://bittorrent.org/beps/bep_0047.html).
+        pub attr: Option<String>,
+        /// The hex encoded MD5 hash of the file.
+        pub md5sum: Option<String>,
+        /// The path of the file within the torrent that this file links to,
+        /// if it's a symlink.
+        #[serde(rename = "symlink path")]
+        pub symlink_path: Option<Vec<String>>,
+    }
+
+    impl File {
+        /// Returns true if this is a padding file, whose contents are all
+        /// zeros and which is not stored.
+        pub fn is_padding(&self) -> bool {
+            matches!(&self.attr, Some(attr) if attr.contains('p'))
+        }
+
+        /// Returns true if this is a symlink, which has no data.
+        pub fn is_symlink(&self) -> bool {
+            matches!(&self.attr, Some(attr) if attr.contains('l'))
+        }
     }
 }
 
-// TODO(https://github.com/mandreyel/cratetorrent/issues/8): add metainfo
-// parsing tests
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    // TODO(https://github.com/mandreyel/cratetorrent/issues/8): add more
+    // metainfo parsing tests
+
+    /// Encodes a single file torrent with the given tracker fields.
+    fn encode_metainfo(
+        announce: Option<&str>,
+        announce_list: &[&[&str]],
+    ) -> Vec<u8> {
+        fn encode_str(buf: &mut Vec<u8>, s: &str) {
+            buf.extend_from_slice(format!("{}:{}", s.len(), s).as_bytes());
+        }
+
+        let mut buf = b"d".to_vec();
+        if let Some(announce) = announce {
+            encode_str(&mut buf, "announce");
+            encode_str(&mut buf, announce);
+        }
+        if !announce_list.is_empty() {
+            encode_str(&mut buf, "announce-list");
+            buf.push(b'l');
+            for tier in announce_list {
+                buf.push(b'l');
+                for tracker in tier.iter() {
+                    encode_str(&mut buf, tracker);
+                }
+                buf.push(b'e');
+            }
+            buf.push(b'e');
+        }
+        buf.extend_from_slice(
+            b"4:infod6:lengthi10e4:name4:test12:piece lengthi16384e6:pieces20:",
+        );
+        buf.extend_from_slice(&[0; 20]);
+        buf.extend_from_slice(b"ee");
+        buf
+    }
+
+    /// Encodes a v2 torrent with the given files and their contents, and
+    /// returns it along with the expected hashes of each file, which empty
+    /// files don't have.
+    ///
+    /// If `hybrid` is set, the torrent also has the v1 keys, where each file
+    /// but the last is followed by a padding file.
+    fn encode_v2_metainfo(
+        piece_len: u32,
+        files: &[(&str, Vec<u8>)],
+        hybrid: bool,
+    ) -> (Vec<u8>, Vec<Option<FileHashes>>) {
+        let mut file_tree = HashMap::new();
+        let mut piece_layers = HashMap::new();
+        let mut file_hashes = Vec::new();
+        let mut v1_files = Vec::new();
+        let mut v1_data = Vec::new();
+        for (i, (name, data)) in files.iter().enumerate() {
+            let mut props = HashMap::new();
+            props.insert(b"length".to_vec(), Value::Int(data.len() as i64));
+            // empty files have no pieces root
+            if data.is_empty() {
+                file_hashes.push(None);
+            } else {
+                let leaves: Vec<_> = data
+                    .chunks(BLOCK_LEN as usize)
+                    .map(merkle::hash_block)
+                    .collect();
+                let root =
+                    merkle::root(&leaves, leaves.len().next_power_of_two(), 0);
+                let mut piece_hashes = Vec::new();
+                if data.len() > piece_len as usize {
+                    let blocks_per_piece = (piece_len / BLOCK_LEN) as usize;
+                    piece_hashes = leaves
+                        .chunks(blocks_per_piece)
+                        .map(|leaves| merkle::root(leaves, blocks_per_piece, 0))
+                        .collect();
+                    piece_layers.insert(
+                        root.to_vec(),
+                        Value::Bytes(piece_hashes.concat()),
+                    );
+                }
+                file_hashes.push(Some(FileHashes { root, piece_hashes }));
+                props.insert(
+                    b"pieces root".to_vec(),
+                    Value::Bytes(root.to_vec()),
+                );
+            }
+            let mut file = HashMap::new();
+            file.insert(Vec::new(), Value::Dict(props));
+            file_tree.insert(name.as_bytes().to_vec(), Value::Dict(file));
+
+            v1_data.extend_from_slice(data);
+            v1_files.push(encode_v1_file(name, data.len(), false));
+            let pad_len = (piece_len as usize
+                - data.len() % piece_len as usize)
+                % piece_len as usize;
+            if i + 1 < files.len() && pad_len > 0 {
+                v1_data.resize(v1_data.len() + pad_len, 0);
+                v1_files.push(encode_v1_file(".pad", pad_len, true));
+            }
+        }
+
+        let mut info = HashMap::new();
+        info.insert(b"file tree".to_vec(), Value::Dict(file_tree));
+        info.insert(b"meta version".to_vec(), Value::Int(2));
+        info.insert(b"name".to_vec(), Value::Bytes(b"test".to_vec()));
+        info.insert(b"piece length".to_vec(), Value::Int(piece_len as i64));
+        if hybrid {
+            let pieces: Vec<u8> = v1_data
+                .chunks(piece_len as usize)
+                .flat_map(|piece| Sha1::digest(piece).to_vec())
+                .collect();
+            info.insert(b"files".to_vec(), Value::List(v1_files));
+            info.insert(b"pieces".to_vec(), Value::Bytes(pieces));
+        }
+        let mut metainfo = HashMap::new();
+        metainfo.insert(b"info".to_vec(), Value::Dict(info));
+        metainfo.insert(b"piece layers".to_vec(), Value::Dict(piece_layers));
+        let buf = serde_bencode::to_bytes(&Value::Dict(metainfo)).unwrap();
+        (buf, file_hashes)
+    }
+
+    /// Returns an entry of the v1 files list, which is a padding file if
+    /// `is_padding` is set.
+    fn encode_v1_file(path: &str, len: usize, is_padding: bool) -> Value {
+        let mut file = HashMap::new();
+        file.insert(b"length".to_vec(), Value::Int(len as i64));
+        file.insert(
+            b"path".to_vec(),
+            Value::List(
+                path.split('/')
+                    .map(|p| Value::Bytes(p.as_bytes().to_vec()))
+                    .collect(),
+            ),
+        );
+        if is_padding {
+            file.insert(b"attr".to_vec(), Value::Bytes(b"p".to_vec()));
+        }
+        Value::Dict(file)
+    }
+
+    #[test]
+    fn test_v2_metainfo() {
+        let piece_len = 2 * BLOCK_LEN;
+        // the first file spans two pieces and so has a piece layer, the second
+        // one is empty and has no hashes at all, while the last one fits in
+        // a single piece
+        let a: Vec<u8> = (0..3 * BLOCK_LEN + 100).map(|i| i as u8).collect();
+        let c = vec![7; 100];
+        let (buf, file_hashes) = encode_v2_metainfo(
+            piece_len,
+            &[("a", a.clone()), ("b", Vec::new()), ("c", c)],
+            false,
+        );
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+
+        assert!(metainfo.is_v2());
+        assert!(!metainfo.is_hybrid());
+        let info_hash_v2 = metainfo.info_hash_v2.unwrap();
+        assert_eq!(metainfo.info_hash, info_hash_v2[..20]);
+        assert!(metainfo.pieces.is_empty());
+        assert_eq!(metainfo.file_hashes, file_hashes);
+        let a_hashes = file_hashes[0].as_ref().unwrap();
+        let c_hashes = file_hashes[2].as_ref().unwrap();
+        assert_eq!(a_hashes.piece_hashes.len(), 2);
+        assert!(file_hashes[1].is_none());
+
+        // each file starts at a piece boundary, and the empty file takes up
+        // no pieces
+        assert!(metainfo.is_archive());
+        assert_eq!(metainfo.files[0].path, Path::new("a"));
+        assert_eq!(metainfo.files[0].torrent_offset, 0);
+        assert_eq!(metainfo.files[1].len, 0);
+        assert_eq!(metainfo.files[1].torrent_offset, 2 * piece_len as u64);
+        assert_eq!(metainfo.files[2].torrent_offset, 2 * piece_len as u64);
+        assert_eq!(metainfo.piece_count(), 3);
+        assert_eq!(metainfo.download_len(), a.len() as u64 + 100);
+
+        match metainfo.piece_hashes() {
+            PieceHashes::V2(hashes) => assert_eq!(
+                hashes,
+                vec![
+                    a_hashes.piece_hashes[0],
+                    a_hashes.piece_hashes[1],
+                    c_hashes.root,
+                ]
+            ),
+            _ => panic!("v2 torrent must have v2 piece hashes"),
+        }
+        let trees = metainfo.merkle_trees();
+        assert_eq!(trees.len(), 3);
+        assert_eq!(trees[0].as_ref().unwrap().root(), a_hashes.root);
+        assert!(trees[1].is_none());
+        assert_eq!(trees[2].as_ref().unwrap().root(), c_hashes.root);
+
+        // the piece layers are encoded from the file hashes
+        assert_eq!(metainfo.to_bytes().unwrap(), buf);
+    }
+
+    #[test]
+    fn test_v2_no_pieces_root() {
+        let piece_len = BLOCK_LEN;
+        let (buf, _) =
+            encode_v2_metainfo(piece_len, &[("a", vec![1; 100])], false);
+        // only empty files may be without a pieces root
+        let mut metainfo: Value = serde_bencode::from_bytes(&buf).unwrap();
+        if let Value::Dict(metainfo) = &mut metainfo {
+            if let Some(Value::Dict(info)) = metainfo.get_mut(&b"info"[..]) {
+                if let Some(Value::Dict(tree)) = info.get_mut(&b"file tree"[..])
+                {
+                    if let Some(Value::Dict(file)) = tree.get_mut(&b"a"[..]) {
+                        if let Some(Value::Dict(props)) = file.get_mut(&b""[..])
+                        {
+                            props.remove(&b"pieces root"[..]);
+                        }
+                    }
+                }
+            }
+        }
+        let buf = serde_bencode::to_bytes(&metainfo).unwrap();
+        assert!(matches!(
+            Metainfo::from_bytes(&buf),
+            Err(MetainfoError::InvalidMetainfo)
+        ));
+
+        // nor may all files be empty
+        let (buf, _) = encode_v2_metainfo(piece_len, &[("a", vec![])], false);
+        assert!(matches!(
+            Metainfo::from_bytes(&buf),
+            Err(MetainfoError::InvalidMetainfo)
+        ));
+    }
+
+    #[test]
+    fn test_v2_invalid_piece_layer() {
+        let piece_len = BLOCK_LEN;
+        let a = vec![1; 2 * BLOCK_LEN as usize];
+        let (buf, file_hashes) =
+            encode_v2_metainfo(piece_len, &[("a", a)], false);
+        // corrupt the second piece hash
+        let mut layer = file_hashes[0].as_ref().unwrap().piece_hashes.concat();
+        let pos = buf
+            .windows(layer.len())
+            .position(|w| w == &layer[..])
+            .unwrap();
+        layer[40] ^= 1;
+        let mut buf = buf;
+        buf[pos..pos + layer.len()].copy_from_slice(&layer);
+        assert!(matches!(
+            Metainfo::from_bytes(&buf),
+            Err(MetainfoError::InvalidPieceLayer)
+        ));
+    }
+
+    #[test]
+    fn test_hybrid_metainfo() {
+        let piece_len = 2 * BLOCK_LEN;
+        let a: Vec<u8> = (0..3 * BLOCK_LEN + 100).map(|i| i as u8).collect();
+        let b = vec![7; 100];
+        let (buf, file_hashes) =
+            encode_v2_metainfo(piece_len, &[("a", a.clone()), ("b", b)], true);
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+
+        assert!(metainfo.is_v2());
+        assert!(metainfo.is_hybrid());
+        // the torrent is in both the v1 and the v2 swarms
+        let info_hash_v2 = metainfo.info_hash_v2.unwrap();
+        let info_hashes = metainfo.info_hashes();
+        assert_eq!(info_hashes.len(), 2);
+        assert_eq!(info_hashes[0], metainfo.info_hash);
+        assert_eq!(info_hashes[1], info_hash_v2[..20]);
+        assert_ne!(info_hashes[0], info_hashes[1]);
+
+        // the files are those of the file tree, without the padding file
+        assert_eq!(metainfo.files.len(), 2);
+        assert_eq!(metainfo.files[1].torrent_offset, 2 * piece_len as u64);
+        assert_eq!(metainfo.padded_len, Some(2 * piece_len as u64 + 100));
+        assert_eq!(metainfo.piece_count(), 3);
+        assert_eq!(metainfo.pieces.len(), 3 * 20);
+
+        match metainfo.piece_hashes() {
+            PieceHashes::Hybrid { v1, v2 } => {
+                assert_eq!(v1, metainfo.pieces);
+                assert_eq!(v2[2], file_hashes[1].as_ref().unwrap().root);
+            }
+            _ => panic!("hybrid torrent must have hybrid piece hashes"),
+        }
+    }
+
+    #[test]
+    fn test_hybrid_empty_file() {
+        let piece_len = BLOCK_LEN;
+        let files = [("a", vec![1; 100]), ("b", vec![]), ("c", vec![2; 100])];
+        let (buf, file_hashes) = encode_v2_metainfo(piece_len, &files, true);
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+
+        // the empty file is in both views of the torrent, after the padding
+        // of the file before it
+        assert!(metainfo.is_hybrid());
+        assert_eq!(metainfo.files.len(), 3);
+        assert_eq!(metainfo.files[1].torrent_offset, piece_len as u64);
+        assert_eq!(metainfo.files[2].torrent_offset, piece_len as u64);
+        assert_eq!(metainfo.padded_len, Some(piece_len as u64 + 100));
+        assert_eq!(metainfo.piece_count(), 2);
+        assert!(file_hashes[1].is_none());
+        match metainfo.piece_hashes() {
+            PieceHashes::Hybrid { v2, .. } => assert_eq!(v2.len(), 2),
+            _ => panic!("hybrid torrent must have hybrid piece hashes"),
+        }
+    }
+
+    #[test]
+    fn test_hybrid_mismatched_files() {
+        let piece_len = BLOCK_LEN;
+        let files = [("a", vec![1; 100]), ("b", vec![2; 100])];
+        let (buf, _) = encode_v2_metainfo(piece_len, &files, true);
+
+        // without the padding file the second file is not where the file
+        // tree puts it
+        let mut metainfo: Value = serde_bencode::from_bytes(&buf).unwrap();
+        if let Value::Dict(metainfo) = &mut metainfo {
+            if let Some(Value::Dict(info)) = metainfo.get_mut(&b"info"[..]) {
+                if let Some(Value::List(files)) = info.get_mut(&b"files"[..]) {
+                    files.remove(1);
+                }
+            }
+        }
+        let buf = serde_bencode::to_bytes(&metainfo).unwrap();
+        assert!(matches!(
+            Metainfo::from_bytes(&buf),
+            Err(MetainfoError::InvalidMetainfo)
+        ));
+    }
+
+    #[test]
+    fn test_single_tracker() {
+        let buf = encode_metainfo(Some("http://tracker.com/announce"), &[]);
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+        assert_eq!(
+            metainfo.trackers,
+            vec![vec![Url::parse("http://tracker.com/announce").unwrap()]]
+        );
+    }
+
+    #[test]
+    fn test_tracker_tiers() {
+        // the announce key should be ignored if there is an announce list,
+        // the tier order and the order of trackers within tiers preserved, and
+        // the trackers with unsupported protocols dropped along with the tiers
+        // that become empty
+        let buf = encode_metainfo(
+            Some("http://ignored.com/announce"),
+            &[
+                &["http://a.com/announce", "udp://b.com:80"],
+                &["wss://c.com/announce"],
+                &["https://d.com/announce", "http://e.com/announce"],
+            ],
+        );
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+        assert_eq!(
+            metainfo.trackers,
+            vec![
+                vec![
+                    Url::parse("http://a.com/announce").unwrap(),
+                    Url::parse("udp://b.com:80").unwrap(),
+                ],
+                vec![
+                    Url::parse("https://d.com/announce").unwrap(),
+                    Url::parse("http://e.com/announce").unwrap(),
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_url_list() {
+        fn with_url_list(url_list: Value) -> Metainfo {
+            let buf = encode_metainfo(None, &[]);
+            let mut metainfo: Value = serde_bencode::from_bytes(&buf).unwrap();
+            if let Value::Dict(metainfo) = &mut metainfo {
+                metainfo.insert(b"url-list".to_vec(), url_list);
+            }
+            let buf = serde_bencode::to_bytes(&metainfo).unwrap();
+            Metainfo::from_bytes(&buf).unwrap()
+        }
+        fn url(s: &str) -> Value {
+            Value::Bytes(s.as_bytes().to_vec())
+        }
+
+        // a single URL
+        let metainfo = with_url_list(url("http://a.com/files/"));
+        assert_eq!(
+            metainfo.url_list,
+            vec![Url::parse("http://a.com/files/").unwrap()]
+        );
+
+        // a list of URLs, of which the empty and non-HTTP ones are dropped
+        let metainfo = with_url_list(Value::List(vec![
+            url("https://a.com/file"),
+            url(""),
+            url("ftp://b.com/file"),
+            url("http://c.com/file"),
+        ]));
+        assert_eq!(
+            metainfo.url_list,
+            vec![
+                Url::parse("https://a.com/file").unwrap(),
+                Url::parse("http://c.com/file").unwrap(),
+            ]
+        );
+
+        // an empty string means there are no web seeds
+        let metainfo = with_url_list(url(""));
+        assert!(metainfo.url_list.is_empty());
+    }
+
+    #[test]
+    fn test_to_bytes_round_trip() {
+        fn bytes(s: &str) -> Value {
+            Value::Bytes(s.as_bytes().to_vec())
+        }
+        fn url(s: &str) -> Url {
+            Url::parse(s).unwrap()
+        }
+
+        let mut a = encode_v1_file("dir/a", 10, false);
+        if let Value::Dict(a) = &mut a {
+            a.insert(b"attr".to_vec(), bytes("x"));
+            a.insert(
+                b"md5sum".to_vec(),
+                bytes("0123456789abcdef0123456789abcdef"),
+            );
+        }
+        let mut info = HashMap::new();
+        info.insert(b"name".to_vec(), bytes("test"));
+        info.insert(b"piece length".to_vec(), Value::Int(16384));
+        info.insert(b"pieces".to_vec(), Value::Bytes(vec![0; 20]));
+        info.insert(
+            b"files".to_vec(),
+            Value::List(vec![a, encode_v1_file("b", 10, false)]),
+        );
+        // keys that we don't know about must be kept too
+        info.insert(b"source".to_vec(), bytes("example"));
+        let mut metainfo = HashMap::new();
+        metainfo.insert(b"info".to_vec(), Value::Dict(info));
+        metainfo.insert(b"announce".to_vec(), bytes("http://a.com/announce"));
+        metainfo.insert(
+            b"announce-list".to_vec(),
+            Value::List(vec![
+                Value::List(vec![
+                    bytes("http://a.com/announce"),
+                    bytes("udp://b.com:80"),
+                ]),
+                Value::List(vec![bytes("http://c.com/announce")]),
+            ]),
+        );
+        metainfo.insert(
+            b"url-list".to_vec(),
+            Value::List(vec![bytes("http://d.com/files/")]),
+        );
+        metainfo.insert(
+            b"httpseeds".to_vec(),
+            Value::List(vec![bytes("http://e.com/seed")]),
+        );
+        metainfo.insert(
+            b"nodes".to_vec(),
+            Value::List(vec![Value::List(vec![
+                bytes("router.example.com"),
+                Value::Int(6881),
+            ])]),
+        );
+        metainfo.insert(b"comment".to_vec(), bytes("a comment"));
+        metainfo.insert(b"created by".to_vec(), bytes("cratetorrent"));
+        metainfo.insert(b"creation date".to_vec(), Value::Int(1_600_000_000));
+        metainfo.insert(b"encoding".to_vec(), bytes("UTF-8"));
+        let buf = serde_bencode::to_bytes(&Value::Dict(metainfo)).unwrap();
+
+        let mut metainfo = Metainfo::from_bytes(&buf).unwrap();
+        assert_eq!(
+            metainfo.file_attrs,
+            vec![
+                FileAttrs {
+                    attr: Some("x".into()),
+                    md5sum: Some("0123456789abcdef0123456789abcdef".into()),
+                },
+                FileAttrs::default(),
+            ]
+        );
+        assert_eq!(metainfo.http_seeds, vec![url("http://e.com/seed")]);
+        assert_eq!(metainfo.nodes, vec![("router.example.com".into(), 6881)]);
+        assert_eq!(metainfo.comment.as_deref(), Some("a comment"));
+        assert_eq!(metainfo.created_by.as_deref(), Some("cratetorrent"));
+        assert_eq!(metainfo.creation_date, Some(1_600_000_000));
+        assert_eq!(metainfo.extra_fields.len(), 1);
+        assert_eq!(metainfo.extra_fields[&b"encoding"[..]], bytes("UTF-8"));
+        assert_eq!(metainfo.to_bytes().unwrap(), buf);
+
+        // edited fields are encoded, but the info dictionary is kept as is
+        metainfo.trackers = vec![vec![url("http://f.com/announce")]];
+        metainfo.comment = None;
+        let edited =
+            Metainfo::from_bytes(&metainfo.to_bytes().unwrap()).unwrap();
+        assert_eq!(edited.trackers, metainfo.trackers);
+        assert!(edited.comment.is_none());
+        assert_eq!(edited.info_bytes(), metainfo.info_bytes());
+        assert_eq!(edited.info_hash, metainfo.info_hash);
+    }
+
+    #[test]
+    fn test_to_bytes_unsupported_trackers() {
+        fn bytes(s: &str) -> Value {
+            Value::Bytes(s.as_bytes().to_vec())
+        }
+        fn url(s: &str) -> Url {
+            Url::parse(s).unwrap()
+        }
+
+        let buf = encode_metainfo(
+            Some("wss://a.com/announce"),
+            &[&["wss://a.com/announce"], &["http://b.com/announce"]],
+        );
+        let mut metainfo: Value = serde_bencode::from_bytes(&buf).unwrap();
+        if let Value::Dict(metainfo) = &mut metainfo {
+            metainfo.insert(
+                b"url-list".to_vec(),
+                Value::List(vec![
+                    bytes("http://c.com/files/"),
+                    bytes("ftp://d.com/files/"),
+                    bytes("not a url"),
+                ]),
+            );
+        }
+        let buf = serde_bencode::to_bytes(&metainfo).unwrap();
+
+        // the trackers and web seeds that we can't use are not lost when the
+        // metainfo is encoded again
+        let mut metainfo = Metainfo::from_bytes(&buf).unwrap();
+        assert_eq!(metainfo.trackers, vec![vec![url("http://b.com/announce")]]);
+        assert_eq!(metainfo.url_list, vec![url("http://c.com/files/")]);
+        assert_eq!(metainfo.to_bytes().unwrap(), buf);
+
+        // but the trackers that are changed are encoded as they are now,
+        // without affecting the web seeds
+        metainfo.trackers = vec![vec![url("http://e.com/announce")]];
+        let encoded: Value =
+            serde_bencode::from_bytes(&metainfo.to_bytes().unwrap()).unwrap();
+        let fields = match encoded {
+            Value::Dict(fields) => fields,
+            _ => panic!("metainfo is not a dictionary"),
+        };
+        assert_eq!(fields[&b"announce"[..]], bytes("http://e.com/announce"));
+        assert!(!fields.contains_key(&b"announce-list"[..]));
+        assert_eq!(
+            fields[&b"url-list"[..]],
+            Value::List(vec![
+                bytes("http://c.com/files/"),
+                bytes("ftp://d.com/files/"),
+                bytes("not a url"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_invalid_optional_fields() {
+        // fields that are not needed for the download are kept as they are if
+        // they are invalid, rather than failing the whole metainfo
+        let buf = encode_metainfo(None, &[]);
+        let mut metainfo: Value = serde_bencode::from_bytes(&buf).unwrap();
+        if let Value::Dict(metainfo) = &mut metainfo {
+            metainfo
+                .insert(b"comment".to_vec(), Value::Bytes(vec![0xff, 0xfe]));
+            metainfo.insert(b"creation date".to_vec(), Value::List(Vec::new()));
+        }
+        let buf = serde_bencode::to_bytes(&metainfo).unwrap();
+
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+        assert!(metainfo.comment.is_none());
+        assert!(metainfo.creation_date.is_none());
+        assert_eq!(metainfo.extra_fields.len(), 2);
+        assert_eq!(metainfo.to_bytes().unwrap(), buf);
+    }
+
+    #[test]
+    fn test_info_hash_with_extra_keys() {
+        // keys that we don't model must be part of the info hash
+        let mut info = HashMap::new();
+        info.insert(b"name".to_vec(), Value::Bytes(b"test".to_vec()));
+        info.insert(b"piece length".to_vec(), Value::Int(16384));
+        info.insert(b"pieces".to_vec(), Value::Bytes(vec![0; 20]));
+        info.insert(
+            b"files".to_vec(),
+            Value::List(vec![
+                encode_v1_file("a", 10, false),
+                encode_v1_file(".pad/0", 10, true),
+            ]),
+        );
+        info.insert(b"source".to_vec(), Value::Bytes(b"example".to_vec()));
+        info.insert(b"x-vendor".to_vec(), Value::Int(1));
+        let info = serde_bencode::to_bytes(&Value::Dict(info)).unwrap();
+        let mut buf = b"d4:info".to_vec();
+        buf.extend_from_slice(&info);
+        buf.push(b'e');
+
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+        assert_eq!(metainfo.info_bytes(), &info[..]);
+        assert_eq!(metainfo.info_hash, Sha1::digest(&info)[..]);
+    }
+
+    #[test]
+    fn test_info_hash_with_unsorted_keys() {
+        // the info dictionary is hashed as is, even if it's not encoded
+        // canonically
+        let mut info =
+            b"d4:name4:test6:lengthi10e12:piece lengthi16384e".to_vec();
+        info.extend_from_slice(b"6:pieces20:");
+        info.extend_from_slice(&[0; 20]);
+        info.push(b'e');
+        let mut buf =
+            b"d8:announce27:http://tracker.com/announce4:info".to_vec();
+        buf.extend_from_slice(&info);
+        buf.push(b'e');
+
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+        assert_eq!(metainfo.info_hash, Sha1::digest(&info)[..]);
+        assert_ne!(
+            metainfo.info_hash,
+            Sha1::digest(
+                &serde_bencode::to_bytes(
+                    &serde_bencode::from_bytes::<Value>(&info).unwrap()
+                )
+                .unwrap()
+            )[..]
+        );
+        assert_eq!(metainfo.to_bytes().unwrap(), buf);
+    }
+
+    #[test]
+    fn test_file_attrs() {
+        fn parse(files: Vec<Value>) -> Result<Metainfo> {
+            let mut info = HashMap::new();
+            info.insert(b"name".to_vec(), Value::Bytes(b"test".to_vec()));
+            info.insert(b"piece length".to_vec(), Value::Int(16384));
+            info.insert(b"pieces".to_vec(), Value::Bytes(vec![0; 20]));
+            info.insert(b"files".to_vec(), Value::List(files));
+            let mut metainfo = HashMap::new();
+            metainfo.insert(b"info".to_vec(), Value::Dict(info));
+            Metainfo::from_bytes(
+                &serde_bencode::to_bytes(&Value::Dict(metainfo)).unwrap(),
+            )
+        }
+        fn with_attr(file: Value, attr: &str, target: Option<&str>) -> Value {
+            let mut file = match file {
+                Value::Dict(file) => file,
+                _ => unreachable!(),
+            };
+            file.insert(
+                b"attr".to_vec(),
+                Value::Bytes(attr.as_bytes().to_vec()),
+            );
+            if let Some(target) = target {
+                file.insert(
+                    b"symlink path".to_vec(),
+                    Value::List(
+                        target
+                            .split('/')
+                            .map(|p| Value::Bytes(p.as_bytes().to_vec()))
+                            .collect(),
+                    ),
+                );
+            }
+            Value::Dict(file)
+        }
+
+        let metainfo = parse(vec![
+            with_attr(encode_v1_file("bin/run", 10, false), "xh", None),
+            encode_v1_file(".pad/16374", 16374, true),
+            with_attr(encode_v1_file("run", 0, false), "l", Some("bin/run")),
+            encode_v1_file("data", 10, false),
+        ])
+        .unwrap();
+        assert_eq!(metainfo.files.len(), 3);
+        assert_eq!(metainfo.files[2].torrent_offset, 16384);
+        assert!(metainfo.file_attrs[0].is_executable());
+        assert!(metainfo.file_attrs[0].is_hidden());
+        assert!(!metainfo.file_attrs[0].is_padding());
+        assert!(metainfo.file_attrs[1].is_padding());
+        assert!(!metainfo.file_attrs[2].is_executable());
+        assert_eq!(
+            metainfo.symlinks,
+            vec![Symlink {
+                path: PathBuf::from("run"),
+                target: PathBuf::from("bin/run"),
+            }]
+        );
+
+        // symlinks must have a valid target and no data
+        let invalid = vec![
+            with_attr(encode_v1_file("run", 0, false), "l", None),
+            with_attr(encode_v1_file("run", 10, false), "l", Some("bin/run")),
+        ];
+        for link in invalid {
+            assert!(matches!(
+                parse(vec![encode_v1_file("data", 10, false), link]),
+                Err(MetainfoError::InvalidMetainfo)
+            ));
+        }
+        assert!(matches!(
+            parse(vec![
+                encode_v1_file("data", 10, false),
+                with_attr(encode_v1_file("run", 0, false), "l", Some("../a")),
+            ]),
+            Err(MetainfoError::PathTraversal)
+        ));
+        // a torrent of only symlinks has nothing to download
+        assert!(matches!(
+            parse(vec![with_attr(
+                encode_v1_file("run", 0, false),
+                "l",
+                Some("data")
+            )]),
+            Err(MetainfoError::InvalidMetainfo)
+        ));
+    }
+
+    #[test]
+    fn test_invalid_paths() {
+        fn parse(name: &str, paths: &[&[&str]]) -> Result<Metainfo> {
+            let files = paths
+                .iter()
+                .map(|path| {
+                    let mut file = HashMap::new();
+                    file.insert(b"length".to_vec(), Value::Int(10));
+                    file.insert(
+                        b"path".to_vec(),
+                        Value::List(
+                            path.iter()
+                                .map(|p| Value::Bytes(p.as_bytes().to_vec()))
+                                .collect(),
+                        ),
+                    );
+                    Value::Dict(file)
+                })
+                .collect();
+            let mut info = HashMap::new();
+            info.insert(
+                b"name".to_vec(),
+                Value::Bytes(name.as_bytes().to_vec()),
+            );
+            info.insert(b"piece length".to_vec(), Value::Int(16384));
+            info.insert(b"pieces".to_vec(), Value::Bytes(vec![0; 20]));
+            info.insert(b"files".to_vec(), Value::List(files));
+            let mut metainfo = HashMap::new();
+            metainfo.insert(b"info".to_vec(), Value::Dict(info));
+            Metainfo::from_bytes(
+                &serde_bencode::to_bytes(&Value::Dict(metainfo)).unwrap(),
+            )
+        }
+
+        assert!(parse("test", &[&["a", "b"], &["..a", "b.."]]).is_ok());
+
+        assert!(matches!(
+            parse("test", &[&["..", "etc", "passwd"]]),
+            Err(MetainfoError::PathTraversal)
+        ));
+        assert!(matches!(
+            parse("test", &[&["a", "."]]),
+            Err(MetainfoError::PathTraversal)
+        ));
+        assert!(matches!(
+            parse("test", &[&["/etc/passwd"]]),
+            Err(MetainfoError::PathSeparator)
+        ));
+        assert!(matches!(
+            parse("test", &[&["a/../../b"]]),
+            Err(MetainfoError::PathSeparator)
+        ));
+        // Windows separators are rejected on every platform
+        assert!(matches!(
+            parse("test", &[&["..\\", "windows"]]),
+            Err(MetainfoError::PathSeparator)
+        ));
+        assert!(matches!(
+            parse("test", &[&["a\\..\\..\\b"]]),
+            Err(MetainfoError::PathSeparator)
+        ));
+        assert!(matches!(
+            parse("test", &[&["C:", "a"]]),
+            Err(MetainfoError::PathSeparator)
+        ));
+        assert!(matches!(
+            parse("test", &[&["a", ""]]),
+            Err(MetainfoError::EmptyPathComponent)
+        ));
+        assert!(matches!(
+            parse("test", &[&[]]),
+            Err(MetainfoError::EmptyPathComponent)
+        ));
+        assert!(matches!(
+            parse("test", &[&["a\0b"]]),
+            Err(MetainfoError::NulInPath)
+        ));
+
+        // the name is the directory of the files
+        assert!(matches!(
+            parse("..", &[&["a"]]),
+            Err(MetainfoError::PathTraversal)
+        ));
+        assert!(matches!(
+            parse("../a", &[&["a"]]),
+            Err(MetainfoError::PathSeparator)
+        ));
+        assert!(matches!(
+            parse("", &[&["a"]]),
+            Err(MetainfoError::EmptyPathComponent)
+        ));
+
+        // and the files of v2 torrents are validated the same way
+        let (buf, _) =
+            encode_v2_metainfo(BLOCK_LEN, &[("..", vec![1; 100])], false);
+        assert!(matches!(
+            Metainfo::from_bytes(&buf),
+            Err(MetainfoError::PathTraversal)
+        ));
+    }
+}
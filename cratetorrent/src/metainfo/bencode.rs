@@ -0,0 +1,185 @@
+//! A bencode parser that only finds where values are in an encoded buffer.
+//!
+//! This is needed where the exact bytes of a value matter, rather than what
+//! they decode to, such as for hashing the info dictionary: decoding and
+//! encoding it again would not give back the same bytes if it had keys we
+//! don't know about, or keys that are not in sorted order.
+
+use std::ops::Range;
+
+use super::BencodeError;
+
+type Result<T> = std::result::Result<T, BencodeError>;
+
+/// Returns the byte range of the value under the key in the encoded
+/// dictionary, or `None` if the dictionary doesn't have the key.
+///
+/// The whole buffer is validated to be a single dictionary, and the key must
+/// not be present more than once.
+pub(super) fn dict_value_span(
+    buf: &[u8],
+    key: &[u8],
+) -> Result<Option<Range<usize>>> {
+    let mut parser = Parser { buf, pos: 0 };
+    if parser.peek()? != b'd' {
+        return Err(BencodeError::InvalidType("expected dictionary".into()));
+    }
+    parser.pos += 1;
+
+    let mut span = None;
+    while parser.peek()? != b'e' {
+        let entry_key = parser.parse_bytes()?;
+        let start = parser.pos;
+        parser.skip_value()?;
+        if entry_key == key {
+            if span.is_some() {
+                return Err(BencodeError::DuplicateField(
+                    String::from_utf8_lossy(key).into(),
+                ));
+            }
+            span = Some(start..parser.pos);
+        }
+    }
+    parser.pos += 1;
+
+    if parser.pos != buf.len() {
+        return Err(BencodeError::InvalidLength(
+            "trailing bytes after dictionary".into(),
+        ));
+    }
+    Ok(span)
+}
+
+struct Parser<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Result<u8> {
+        self.buf
+            .get(self.pos)
+            .copied()
+            .ok_or(BencodeError::EndOfStream)
+    }
+
+    /// Advances past the next value, verifying that it's well-formed.
+    fn skip_value(&mut self) -> Result<()> {
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                self.parse_int(b'e')?;
+            }
+            b'l' => {
+                self.pos += 1;
+                while self.peek()? != b'e' {
+                    self.skip_value()?;
+                }
+                self.pos += 1;
+            }
+            b'd' => {
+                self.pos += 1;
+                while self.peek()? != b'e' {
+                    self.parse_bytes()?;
+                    self.skip_value()?;
+                }
+                self.pos += 1;
+            }
+            b'0'..=b'9' => {
+                self.parse_bytes()?;
+            }
+            c => {
+                return Err(BencodeError::InvalidValue(format!(
+                    "unexpected byte {:?} at {}",
+                    c as char, self.pos
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses a byte string, which is its length followed by a colon and its
+    /// bytes.
+    fn parse_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.parse_int(b':')?;
+        if len < 0 {
+            return Err(BencodeError::InvalidLength(format!(
+                "negative string length at {}",
+                self.pos
+            )));
+        }
+        let end = self
+            .pos
+            .checked_add(len as usize)
+            .filter(|end| *end <= self.buf.len())
+            .ok_or(BencodeError::EndOfStream)?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Parses a decimal integer up to and including the terminator.
+    fn parse_int(&mut self, terminator: u8) -> Result<i64> {
+        let start = self.pos;
+        let end = self.buf[start..]
+            .iter()
+            .position(|b| *b == terminator)
+            .map(|len| start + len)
+            .ok_or(BencodeError::EndOfStream)?;
+        let int = std::str::from_utf8(&self.buf[start..end])
+            .ok()
+            .filter(|s| s.bytes().all(|b| b == b'-' || b.is_ascii_digit()))
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| {
+                BencodeError::InvalidValue(format!(
+                    "invalid integer at {}",
+                    start
+                ))
+            })?;
+        self.pos = end + 1;
+        Ok(int)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dict_value_span() {
+        let buf = b"d3:fooi-42e4:infod1:ai1e1:bl3:abci0eee1:z0:e";
+        let span = dict_value_span(buf, b"info").unwrap().unwrap();
+        assert_eq!(&buf[span], &b"d1:ai1e1:bl3:abci0eee"[..]);
+        let span = dict_value_span(buf, b"foo").unwrap().unwrap();
+        assert_eq!(&buf[span], &b"i-42e"[..]);
+        let span = dict_value_span(buf, b"z").unwrap().unwrap();
+        assert_eq!(&buf[span], &b"0:"[..]);
+        assert!(dict_value_span(buf, b"bar").unwrap().is_none());
+
+        // nested keys are not top level keys
+        let buf = b"d1:ad4:infoi1eee";
+        assert!(dict_value_span(buf, b"info").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_dict_value_span_invalid() {
+        let invalid: &[&[u8]] = &[
+            b"",
+            b"le",
+            b"d4:info",
+            b"d4:infoi1e",
+            b"d4:infoi1x2ee",
+            b"d4:info10:abcee",
+            b"d4:infoi1eex",
+            b"d4:infox1ee",
+            b"d4:infoi1e4:infoi2ee",
+        ];
+        for buf in invalid {
+            assert!(
+                dict_value_span(buf, b"info").is_err(),
+                "{:?} must be invalid",
+                String::from_utf8_lossy(buf)
+            );
+        }
+    }
+}
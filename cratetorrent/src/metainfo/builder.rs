@@ -0,0 +1,685 @@
+//! Creation of new torrents from local files.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+};
+
+use reqwest::Url;
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+
+use super::{Metainfo, MetainfoError, Result};
+use crate::{file_piece_count, tracker, BLOCK_LEN};
+
+/// The piece length is chosen such that the torrent has about this many
+/// pieces, which keeps the metainfo small without making pieces so large that
+/// a single bad piece is costly to download again.
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+/// The largest piece length that is chosen automatically.
+const MAX_AUTO_PIECE_LEN: u32 = 16 * 1024 * 1024;
+
+/// The number of threads that hash pieces, unless configured otherwise.
+const DEFAULT_THREAD_COUNT: usize = 4;
+
+/// The function to which hashing progress is reported.
+type ProgressFn = Box<dyn FnMut(usize, usize) + Send>;
+
+/// Creates the metainfo of a new torrent from a file or a directory on the
+/// local file system.
+///
+/// The metainfo is created by [`Self::build`], which reads and hashes all
+/// files of the torrent, so it blocks for as long as that takes. In an async
+/// context it should be run with `tokio::task::spawn_blocking`.
+///
+/// # Example
+///
+/// ```no_run
+/// use cratetorrent::metainfo::MetainfoBuilder;
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let (buf, metainfo) = MetainfoBuilder::new("/tmp/release")
+///     .tracker_tier(vec!["udp://tracker.example.com:6969".parse()?])
+///     .comment("release artifacts")
+///     .on_progress(|hashed, total| println!("{}/{}", hashed, total))
+///     .build()?;
+/// std::fs::write("/tmp/release.torrent", &buf)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct MetainfoBuilder {
+    path: PathBuf,
+    piece_len: Option<u32>,
+    trackers: Vec<Vec<Url>>,
+    url_list: Vec<Url>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    private: bool,
+    thread_count: usize,
+    on_progress: Option<ProgressFn>,
+}
+
+impl MetainfoBuilder {
+    /// Creates a builder for the torrent of the file or directory at the
+    /// path.
+    ///
+    /// The torrent is named after the file or directory. A directory is
+    /// walked recursively, and its files are added in the order of their
+    /// paths. Empty files are left out, as the metainfo can't contain them.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            piece_len: None,
+            trackers: Vec::new(),
+            url_list: Vec::new(),
+            comment: None,
+            created_by: None,
+            private: false,
+            thread_count: DEFAULT_THREAD_COUNT,
+            on_progress: None,
+        }
+    }
+
+    /// Sets the length of the torrent's pieces, which must be a power of two
+    /// and at least 16 KiB.
+    ///
+    /// If not set, it is chosen based on the size of the torrent.
+    pub fn piece_len(mut self, piece_len: u32) -> Self {
+        self.piece_len = Some(piece_len);
+        self
+    }
+
+    /// Adds a tier of trackers, as described in
+    /// [BEP 12](http://bittorrent.org/beps/bep_0012.html).
+    ///
+    /// Tiers are announced to in the order they are added.
+    pub fn tracker_tier(mut self, tier: Vec<Url>) -> Self {
+        if !tier.is_empty() {
+            self.trackers.push(tier);
+        }
+        self
+    }
+
+    /// Adds an HTTP server from which the torrent's files may also be
+    /// downloaded, as described in
+    /// [BEP 19](http://bittorrent.org/beps/bep_0019.html).
+    pub fn web_seed(mut self, url: Url) -> Self {
+        self.url_list.push(url);
+        self
+    }
+
+    /// Sets the free-form comment of the torrent.
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    /// Sets the name of the program that created the torrent.
+    pub fn created_by(mut self, created_by: impl Into<String>) -> Self {
+        self.created_by = Some(created_by.into());
+        self
+    }
+
+    /// Sets whether the torrent is private, as described in
+    /// [BEP 27](http://bittorrent.org/beps/bep_0027.html).
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Sets the number of threads that hash the torrent's pieces.
+    pub fn thread_count(mut self, thread_count: usize) -> Self {
+        self.thread_count = thread_count.max(1);
+        self
+    }
+
+    /// Sets the function that is called with the number of hashed pieces and
+    /// the total number of pieces every time a piece is hashed.
+    ///
+    /// It is called on the thread that calls [`Self::build`].
+    pub fn on_progress(
+        mut self,
+        on_progress: impl FnMut(usize, usize) + Send + 'static,
+    ) -> Self {
+        self.on_progress = Some(Box::new(on_progress));
+        self
+    }
+
+    /// Hashes the torrent's files and returns the bencoded metainfo, which
+    /// can be saved as a `.torrent` file, along with its parsed form, with
+    /// which the torrent may be seeded right away.
+    pub fn build(mut self) -> Result<(Vec<u8>, Metainfo)> {
+        for url in self.trackers.iter().flatten() {
+            if !tracker::is_supported_url(url) {
+                log::warn!("Tracker URL {} is not supported", url);
+                return Err(MetainfoError::InvalidTrackerUrl);
+            }
+        }
+        for url in self.url_list.iter() {
+            if url.scheme() != "http" && url.scheme() != "https" {
+                log::warn!("Web seed URL {} is not HTTP", url);
+                return Err(MetainfoError::InvalidMetainfo);
+            }
+        }
+
+        let root = fs::canonicalize(&self.path)?;
+        let name = match root.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => {
+                log::warn!("Torrent path {:?} has no valid name", root);
+                return Err(MetainfoError::InvalidMetainfo);
+            }
+        };
+        let is_archive = root.is_dir();
+        let files = collect_files(&root)?;
+        if files.is_empty() {
+            log::warn!("No files to create torrent from in {:?}", root);
+            return Err(MetainfoError::InvalidMetainfo);
+        }
+
+        let len = files.iter().map(|f| f.len).sum();
+        let piece_len = match self.piece_len {
+            Some(piece_len)
+                if piece_len.is_power_of_two() && piece_len >= BLOCK_LEN =>
+            {
+                piece_len
+            }
+            Some(piece_len) => {
+                log::warn!("Piece length {} is invalid", piece_len);
+                return Err(MetainfoError::InvalidMetainfo);
+            }
+            None => auto_piece_len(len),
+        };
+
+        log::info!(
+            "Creating torrent {} of {} bytes with piece length {}",
+            name,
+            len,
+            piece_len
+        );
+        let files = Arc::new(files);
+        let pieces = hash_pieces(
+            Arc::clone(&files),
+            len,
+            piece_len,
+            self.thread_count,
+            self.on_progress.take(),
+        )?;
+
+        let mut info = HashMap::new();
+        info.insert(b"name".to_vec(), Value::Bytes(name.into_bytes()));
+        info.insert(b"piece length".to_vec(), Value::Int(piece_len as i64));
+        info.insert(b"pieces".to_vec(), Value::Bytes(pieces));
+        if is_archive {
+            let files = files
+                .iter()
+                .map(|file| {
+                    let mut entry = HashMap::new();
+                    entry.insert(
+                        b"length".to_vec(),
+                        Value::Int(file.len as i64),
+                    );
+                    entry.insert(
+                        b"path".to_vec(),
+                        Value::List(
+                            file.torrent_path
+                                .iter()
+                                .map(|p| Value::Bytes(p.as_bytes().to_vec()))
+                                .collect(),
+                        ),
+                    );
+                    Value::Dict(entry)
+                })
+                .collect();
+            info.insert(b"files".to_vec(), Value::List(files));
+        } else {
+            info.insert(b"length".to_vec(), Value::Int(len as i64));
+        }
+        if self.private {
+            info.insert(b"private".to_vec(), Value::Int(1));
+        }
+
+        let mut metainfo = HashMap::new();
+        metainfo.insert(b"info".to_vec(), Value::Dict(info));
+        // clients that don't support tiers only use the `announce` key, so it
+        // is set to the first tracker
+        if let Some(tracker) = self.trackers.first().and_then(|t| t.first()) {
+            metainfo.insert(b"announce".to_vec(), url_value(tracker));
+        }
+        if self.trackers.iter().map(Vec::len).sum::<usize>() > 1 {
+            let tiers = self
+                .trackers
+                .iter()
+                .map(|tier| Value::List(tier.iter().map(url_value).collect()))
+                .collect();
+            metainfo.insert(b"announce-list".to_vec(), Value::List(tiers));
+        }
+        if !self.url_list.is_empty() {
+            let urls = self.url_list.iter().map(url_value).collect();
+            metainfo.insert(b"url-list".to_vec(), Value::List(urls));
+        }
+        if let Some(comment) = self.comment {
+            metainfo.insert(
+                b"comment".to_vec(),
+                Value::Bytes(comment.into_bytes()),
+            );
+        }
+        if let Some(created_by) = self.created_by {
+            metainfo.insert(
+                b"created by".to_vec(),
+                Value::Bytes(created_by.into_bytes()),
+            );
+        }
+
+        let buf = serde_bencode::to_bytes(&Value::Dict(metainfo))?;
+        let metainfo = Metainfo::from_bytes(&buf)?;
+        Ok((buf, metainfo))
+    }
+}
+
+/// A file of the torrent being created.
+struct SourceFile {
+    /// The path of the file on the local file system.
+    path: PathBuf,
+    /// The path of the file within the torrent, which is empty if the torrent
+    /// is a single file.
+    torrent_path: Vec<String>,
+    len: u64,
+    /// Where the file starts in the torrent.
+    torrent_offset: u64,
+}
+
+/// Returns the file at the path, or the files in the directory at the path.
+fn collect_files(root: &Path) -> Result<Vec<SourceFile>> {
+    let mut files = Vec::new();
+    if root.is_dir() {
+        walk_dir(root, &mut Vec::new(), &mut files)?;
+    } else {
+        let len = fs::metadata(root)?.len();
+        if len > 0 {
+            files.push(SourceFile {
+                path: root.to_path_buf(),
+                torrent_path: Vec::new(),
+                len,
+                torrent_offset: 0,
+            });
+        }
+    }
+
+    let mut torrent_offset = 0;
+    for file in files.iter_mut() {
+        file.torrent_offset = torrent_offset;
+        torrent_offset += file.len;
+    }
+
+    Ok(files)
+}
+
+/// Collects the non-empty files in the directory and its subdirectories, in
+/// the order of their paths.
+fn walk_dir(
+    dir: &Path,
+    path: &mut Vec<String>,
+    files: &mut Vec<SourceFile>,
+) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name().into_string().map_err(|name| {
+            log::warn!("File name {:?} is not valid UTF-8", name);
+            MetainfoError::InvalidMetainfo
+        })?;
+        // symlinked files are followed, but symlinked directories may form
+        // cycles, so they are not
+        let metadata = fs::metadata(entry.path())?;
+        if metadata.is_dir() {
+            if entry.file_type()?.is_symlink() {
+                log::warn!("Skipping symlinked directory {:?}", entry.path());
+                continue;
+            }
+            path.push(name);
+            walk_dir(&entry.path(), path, files)?;
+            path.pop();
+        } else if metadata.len() > 0 {
+            let mut torrent_path = path.clone();
+            torrent_path.push(name);
+            files.push(SourceFile {
+                path: entry.path(),
+                torrent_path,
+                len: metadata.len(),
+                torrent_offset: 0,
+            });
+        } else {
+            log::debug!("Skipping empty file {:?}", entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Returns the power of two piece length with which the torrent has about
+/// [`TARGET_PIECE_COUNT`] pieces.
+fn auto_piece_len(len: u64) -> u32 {
+    let piece_len = (len / TARGET_PIECE_COUNT).next_power_of_two();
+    piece_len
+        .max(BLOCK_LEN as u64)
+        .min(MAX_AUTO_PIECE_LEN as u64) as u32
+}
+
+/// Hashes the pieces of the torrent made up of the files, and returns the
+/// concatenation of their hashes.
+///
+/// Each thread hashes a contiguous range of the pieces, while the progress is
+/// reported on the calling thread.
+fn hash_pieces(
+    files: Arc<Vec<SourceFile>>,
+    len: u64,
+    piece_len: u32,
+    thread_count: usize,
+    mut on_progress: Option<ProgressFn>,
+) -> Result<Vec<u8>> {
+    let piece_count = file_piece_count(len, piece_len);
+    let thread_count = thread_count.min(piece_count);
+    // there is at least one piece as empty torrents are not created
+    let thread_piece_count = (piece_count - 1) / thread_count + 1;
+
+    let (progress_tx, progress_rx) = mpsc::channel();
+    // if a thread fails the others stop too, as the torrent can't be created
+    let abort = Arc::new(AtomicBool::new(false));
+    let mut handles = Vec::with_capacity(thread_count);
+    for first_index in (0..piece_count).step_by(thread_piece_count) {
+        let end_index = (first_index + thread_piece_count).min(piece_count);
+        let files = Arc::clone(&files);
+        let abort = Arc::clone(&abort);
+        let progress_tx = progress_tx.clone();
+        handles.push(thread::spawn(move || -> io::Result<Vec<u8>> {
+            let mut reader = PieceReader::new(&files);
+            let mut buf = vec![0; piece_len as usize];
+            let mut hashes = Vec::with_capacity((end_index - first_index) * 20);
+            for index in first_index..end_index {
+                if abort.load(Ordering::Relaxed) {
+                    break;
+                }
+                let offset = index as u64 * piece_len as u64;
+                let piece_len = (len - offset).min(piece_len as u64) as usize;
+                let piece = &mut buf[..piece_len];
+                if let Err(e) = reader.read_exact_at(offset, piece) {
+                    abort.store(true, Ordering::Relaxed);
+                    return Err(e);
+                }
+                hashes.extend_from_slice(&Sha1::digest(piece));
+                progress_tx.send(()).ok();
+            }
+            Ok(hashes)
+        }));
+    }
+    // the progress channel closes once all threads are done
+    drop(progress_tx);
+
+    let mut hashed_count = 0;
+    for _ in progress_rx {
+        hashed_count += 1;
+        if let Some(on_progress) = &mut on_progress {
+            on_progress(hashed_count, piece_count);
+        }
+    }
+
+    let mut pieces = Vec::with_capacity(piece_count * 20);
+    for handle in handles {
+        let hashes = handle.join().expect("piece hasher thread panicked")?;
+        pieces.extend_from_slice(&hashes);
+    }
+    Ok(pieces)
+}
+
+/// Reads pieces of the torrent from its files, keeping the last used file
+/// open, as consecutive pieces are mostly in the same file.
+struct PieceReader<'a> {
+    files: &'a [SourceFile],
+    open_file: Option<(usize, File)>,
+}
+
+impl<'a> PieceReader<'a> {
+    fn new(files: &'a [SourceFile]) -> Self {
+        Self {
+            files,
+            open_file: None,
+        }
+    }
+
+    /// Fills the buffer with the torrent's data at the offset, which may span
+    /// multiple files.
+    fn read_exact_at(
+        &mut self,
+        offset: u64,
+        mut buf: &mut [u8],
+    ) -> io::Result<()> {
+        let mut index = self
+            .files
+            .iter()
+            .position(|f| f.torrent_offset + f.len > offset)
+            .expect("piece offset past end of torrent");
+        let mut file_offset = offset - self.files[index].torrent_offset;
+        while !buf.is_empty() {
+            let file_len = self.files[index].len;
+            let read_len = (file_len - file_offset).min(buf.len() as u64);
+            let (chunk, rest) = buf.split_at_mut(read_len as usize);
+            let file = self.open(index)?;
+            file.seek(SeekFrom::Start(file_offset))?;
+            file.read_exact(chunk)?;
+
+            buf = rest;
+            index += 1;
+            file_offset = 0;
+        }
+        Ok(())
+    }
+
+    /// Returns the file at the index, opening it if it's not yet open.
+    fn open(&mut self, index: usize) -> io::Result<&mut File> {
+        match &self.open_file {
+            Some((open_index, _)) if *open_index == index => {}
+            _ => {
+                let file = File::open(&self.files[index].path)?;
+                self.open_file = Some((index, file));
+            }
+        }
+        match &mut self.open_file {
+            Some((_, file)) => Ok(file),
+            None => unreachable!(),
+        }
+    }
+}
+
+fn url_value(url: &Url) -> Value {
+    Value::Bytes(url.as_str().as_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Creates a fresh directory for a test's files.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "cratetorrent-metainfo-builder-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn expected_pieces(data: &[u8], piece_len: u32) -> Vec<u8> {
+        data.chunks(piece_len as usize)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_build_single_file() {
+        let dir = test_dir("single");
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let path = dir.join("file.bin");
+        fs::write(&path, &data).unwrap();
+
+        let tracker = Url::parse("http://tracker.com/announce").unwrap();
+        let web_seed = Url::parse("https://example.com/file.bin").unwrap();
+        let (buf, metainfo) = MetainfoBuilder::new(&path)
+            .piece_len(BLOCK_LEN)
+            .tracker_tier(vec![tracker.clone()])
+            .web_seed(web_seed.clone())
+            .comment("a comment")
+            .created_by("cratetorrent")
+            .private(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(metainfo.name, "file.bin");
+        assert!(!metainfo.is_archive());
+        assert_eq!(metainfo.download_len(), data.len() as u64);
+        assert_eq!(metainfo.piece_len, BLOCK_LEN);
+        assert_eq!(metainfo.pieces, expected_pieces(&data, BLOCK_LEN));
+        assert_eq!(metainfo.trackers, vec![vec![tracker]]);
+        assert_eq!(metainfo.url_list, vec![web_seed]);
+        assert!(metainfo.private);
+
+        // the returned bytes are those of the metainfo
+        assert_eq!(metainfo.to_bytes().unwrap(), buf);
+        assert_eq!(metainfo.comment.as_deref(), Some("a comment"));
+        assert_eq!(metainfo.created_by.as_deref(), Some("cratetorrent"));
+        let value: Value = serde_bencode::from_bytes(&buf).unwrap();
+        match value {
+            Value::Dict(dict) => {
+                assert!(matches!(
+                    dict.get(&b"comment"[..]),
+                    Some(Value::Bytes(c)) if c == b"a comment"
+                ));
+                assert!(matches!(
+                    dict.get(&b"created by"[..]),
+                    Some(Value::Bytes(c)) if c == b"cratetorrent"
+                ));
+                // a single tracker needs no announce list
+                assert!(!dict.contains_key(&b"announce-list"[..]));
+            }
+            _ => panic!("metainfo must be a dictionary"),
+        }
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_build_directory() {
+        let dir = test_dir("directory");
+        let root = dir.join("release");
+        fs::create_dir_all(root.join("b/c")).unwrap();
+        let a: Vec<u8> = (0..40_000u32).map(|i| (i % 7) as u8).collect();
+        let b: Vec<u8> = (0..10_000u32).map(|i| (i % 13) as u8).collect();
+        let c: Vec<u8> = (0..30_000u32).map(|i| (i % 17) as u8).collect();
+        fs::write(root.join("a"), &a).unwrap();
+        fs::write(root.join("b/c/c"), &c).unwrap();
+        fs::write(root.join("b/b"), &b).unwrap();
+        // empty files can't be in a torrent
+        fs::write(root.join("empty"), b"").unwrap();
+
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let (_, metainfo) = {
+            let progress = Arc::clone(&progress);
+            MetainfoBuilder::new(&root)
+                .piece_len(BLOCK_LEN)
+                .tracker_tier(vec![
+                    Url::parse("http://a.com/announce").unwrap(),
+                    Url::parse("udp://b.com:80").unwrap(),
+                ])
+                .tracker_tier(
+                    vec![Url::parse("http://c.com/announce").unwrap()],
+                )
+                .thread_count(3)
+                .on_progress(move |hashed, total| {
+                    progress.lock().unwrap().push((hashed, total));
+                })
+                .build()
+                .unwrap()
+        };
+
+        assert_eq!(metainfo.name, "release");
+        assert!(metainfo.is_archive());
+        let paths: Vec<_> = metainfo.files.iter().map(|f| &f.path).collect();
+        assert_eq!(
+            paths,
+            vec![Path::new("a"), Path::new("b/b"), Path::new("b/c/c")]
+        );
+        assert_eq!(metainfo.files[1].torrent_offset, a.len() as u64);
+        assert_eq!(
+            metainfo.files[2].torrent_offset,
+            (a.len() + b.len()) as u64
+        );
+
+        // pieces span file boundaries
+        let data = [a, b, c].concat();
+        assert_eq!(metainfo.pieces, expected_pieces(&data, BLOCK_LEN));
+        assert_eq!(metainfo.trackers.len(), 2);
+        assert_eq!(metainfo.trackers[0].len(), 2);
+
+        // every piece is reported once, in order
+        let piece_count = metainfo.piece_count();
+        let progress = progress.lock().unwrap();
+        assert_eq!(
+            *progress,
+            (1..=piece_count)
+                .map(|i| (i, piece_count))
+                .collect::<Vec<_>>()
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_auto_piece_len() {
+        assert_eq!(auto_piece_len(100), BLOCK_LEN);
+        assert_eq!(auto_piece_len(1024 * 1024 * 1024), 1024 * 1024);
+        assert_eq!(auto_piece_len(u64::MAX / 2), MAX_AUTO_PIECE_LEN);
+    }
+
+    #[test]
+    fn test_build_invalid() {
+        let dir = test_dir("invalid");
+        let path = dir.join("file");
+        fs::write(&path, b"data").unwrap();
+
+        assert!(matches!(
+            MetainfoBuilder::new(&path).piece_len(BLOCK_LEN + 1).build(),
+            Err(MetainfoError::InvalidMetainfo)
+        ));
+        assert!(matches!(
+            MetainfoBuilder::new(&path)
+                .tracker_tier(vec![Url::parse("wss://a.com").unwrap()])
+                .build(),
+            Err(MetainfoError::InvalidTrackerUrl)
+        ));
+        assert!(matches!(
+            MetainfoBuilder::new(dir.join("missing")).build(),
+            Err(MetainfoError::Io(_))
+        ));
+
+        // a directory with no files
+        let empty = dir.join("empty");
+        fs::create_dir_all(&empty).unwrap();
+        assert!(matches!(
+            MetainfoBuilder::new(&empty).build(),
+            Err(MetainfoError::InvalidMetainfo)
+        ));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
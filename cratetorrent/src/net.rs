@@ -0,0 +1,219 @@
+//! Helpers for listening on and connecting over both IPv4 and IPv6.
+//!
+//! A socket bound to the unspecified IPv6 address (`[::]`) is made dual-stack,
+//! which means it also accepts IPv4 traffic. The IPv4 peers then show up as
+//! IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`), which are converted back to
+//! plain IPv4 addresses so that the same peer always has the same address in
+//! the engine, regardless of the socket on which it was seen.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket as StdUdpSocket},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, UdpSocket};
+
+/// The backlog of pending connections of TCP listeners, the same as the
+/// standard library's.
+const LISTEN_BACKLOG: i32 = 128;
+
+/// Binds a TCP listener to the address.
+///
+/// If the address is the unspecified IPv6 address, the listener accepts both
+/// IPv6 and IPv4 connections. If the host doesn't support IPv6, it falls back
+/// to listening on all IPv4 interfaces.
+pub(crate) fn bind_tcp_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    // the port may be rebound while connections from a previous run are in
+    // `TIME_WAIT`
+    let socket = bind(addr, Type::stream(), Protocol::tcp(), true)?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into_tcp_listener())
+}
+
+/// Binds a UDP socket to the address, with the same dual-stack behavior as
+/// [`bind_tcp_listener`].
+pub(crate) fn bind_udp_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    // on Linux, reusing the address would let another socket bind to the same
+    // port and silently take part of its traffic
+    let socket = bind(addr, Type::dgram(), Protocol::udp(), false)?;
+    UdpSocket::from_std(socket.into_udp_socket())
+}
+
+fn bind(
+    addr: SocketAddr,
+    ty: Type,
+    protocol: Protocol,
+    reuse_address: bool,
+) -> io::Result<Socket> {
+    let new_socket = |domain| {
+        let socket = Socket::new(domain, ty, Some(protocol))?;
+        if reuse_address {
+            socket.set_reuse_address(true)?;
+        }
+        Ok::<_, io::Error>(socket)
+    };
+    let result = if addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        new_socket(Domain::ipv6()).and_then(|socket| {
+            socket.set_only_v6(false)?;
+            socket.bind(&addr.into())?;
+            Ok(socket)
+        })
+    } else {
+        let domain = if addr.is_ipv6() {
+            Domain::ipv6()
+        } else {
+            Domain::ipv4()
+        };
+        let socket = new_socket(domain)?;
+        socket.bind(&addr.into())?;
+        Ok(socket)
+    };
+
+    let socket = match result {
+        Ok(socket) => socket,
+        Err(e) if addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) => {
+            log::info!(
+                "Cannot bind to {} ({}), falling back to IPv4 only",
+                addr,
+                e
+            );
+            let addr =
+                SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), addr.port());
+            let socket = new_socket(Domain::ipv4())?;
+            socket.bind(&addr.into())?;
+            socket
+        }
+        Err(e) => return Err(e),
+    };
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Converts an IPv4-mapped IPv6 address to the IPv4 address it represents,
+/// and returns any other address unchanged.
+pub(crate) fn to_canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match to_ipv4_mapped(v6.ip()) {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        addr => addr,
+    }
+}
+
+/// Converts an IPv4 address to an IPv4-mapped IPv6 address, which is how
+/// IPv4 peers must be addressed on a dual-stack socket.
+pub(crate) fn to_ipv6_mapped(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) => {
+            SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+        }
+        addr => addr,
+    }
+}
+
+/// Returns the IPv4 address if this is an IPv4-mapped IPv6 address.
+pub(crate) fn to_ipv4_mapped(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.octets() {
+        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+            Some(Ipv4Addr::new(a, b, c, d))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the IPv6 address of this host that is used to reach the internet,
+/// if the host has one.
+///
+/// No packets are sent: connecting a UDP socket only selects the route, and
+/// thereby the local address, to the destination.
+pub(crate) fn local_ipv6_addr() -> Option<Ipv6Addr> {
+    // an arbitrary global address, that of a public DNS server
+    let remote = SocketAddr::new(
+        Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888).into(),
+        53,
+    );
+    let socket =
+        StdUdpSocket::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0))
+            .ok()?;
+    socket.connect(remote).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if is_routable_ipv6(&ip) => Some(ip),
+        _ => None,
+    }
+}
+
+/// Returns false for the IPv6 addresses that other hosts can't reach us on.
+fn is_routable_ipv6(ip: &Ipv6Addr) -> bool {
+    let is_link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+    !ip.is_unspecified()
+        && !ip.is_loopback()
+        && !is_link_local
+        && to_ipv4_mapped(ip).is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{net::TcpStream, stream::StreamExt};
+
+    use super::*;
+
+    #[test]
+    fn test_canonical_addr() {
+        let v4: SocketAddr = "1.2.3.4:5".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:1.2.3.4]:5".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:5".parse().unwrap();
+        assert_eq!(to_canonical(mapped), v4);
+        assert_eq!(to_canonical(v4), v4);
+        assert_eq!(to_canonical(v6), v6);
+        assert_eq!(to_ipv6_mapped(v4), mapped);
+        assert_eq!(to_ipv6_mapped(v6), v6);
+    }
+
+    #[test]
+    fn test_routable_ipv6() {
+        assert!(is_routable_ipv6(&"2001:db8::1".parse().unwrap()));
+        assert!(is_routable_ipv6(&"fd00::2".parse().unwrap()));
+        assert!(!is_routable_ipv6(&Ipv6Addr::LOCALHOST));
+        assert!(!is_routable_ipv6(&Ipv6Addr::UNSPECIFIED));
+        assert!(!is_routable_ipv6(&"fe80::1".parse().unwrap()));
+        assert!(!is_routable_ipv6(&"::ffff:1.2.3.4".parse().unwrap()));
+    }
+
+    /// Tests that a listener bound to the unspecified IPv6 address accepts
+    /// connections over both IPv4 and IPv6.
+    #[tokio::test]
+    async fn test_dual_stack_listener() {
+        let addr = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0);
+        let mut listener = bind_tcp_listener(addr).unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let port = local_addr.port();
+        let mut incoming = listener.incoming();
+
+        let v4 = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+        let _v4_conn = TcpStream::connect(v4).await.unwrap();
+        let peer_addr = incoming.next().await.unwrap().unwrap().peer_addr();
+        let peer_addr = to_canonical(peer_addr.unwrap());
+        assert_eq!(peer_addr.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        // the host may not support IPv6 at all, in which case the listener
+        // fell back to IPv4
+        if local_addr.is_ipv6() {
+            let v6 = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port);
+            let _v6_conn = TcpStream::connect(v6).await.unwrap();
+            let peer_addr = incoming.next().await.unwrap().unwrap().peer_addr();
+            let peer_addr = to_canonical(peer_addr.unwrap());
+            assert_eq!(peer_addr.ip(), IpAddr::V6(Ipv6Addr::LOCALHOST));
+        }
+    }
+
+    /// Tests that a UDP port can't be shared with another socket.
+    #[tokio::test]
+    async fn test_udp_port_not_shared() {
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let socket = bind_udp_socket(addr).unwrap();
+        let addr = socket.local_addr().unwrap();
+        assert!(bind_udp_socket(addr).is_err());
+    }
+}
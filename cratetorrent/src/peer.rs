@@ -9,13 +9,15 @@
 //! one, due to making use of shared data in torrent.
 
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
+    io,
     net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use futures::{
+    future::{self, FutureExt},
     select,
     stream::{Fuse, SplitSink},
     SinkExt, StreamExt,
@@ -32,22 +34,35 @@ use tokio_util::codec::{Framed, FramedParts};
 
 use crate::{
     alert::Alert,
+    conf::{EncryptionPolicy, TransportPolicy},
     counter::ThruputCounters,
     disk,
     download::{BlockStatus, PieceDownload},
     error::Error,
+    merkle,
     torrent::{self, TorrentContext},
-    Bitfield, Block, BlockInfo, PeerId, PieceIndex,
+    utp::UtpSocket,
+    Bitfield, Block, BlockInfo, PeerId, PieceIndex, Sha1Hash, Sha256Hash,
 };
 use codec::*;
 use error::*;
+use mse::CryptoStream;
 use state::*;
+pub(crate) use transport::Stream;
 
-pub use state::{ConnectionState, SessionState};
+pub use state::{ConnectionState, DisconnectReason, SessionState};
+pub use transport::Transport;
 
 mod codec;
 pub mod error;
+mod mse;
 mod state;
+mod transport;
+
+/// The connection with the peer, which may be encrypted.
+type Socket = CryptoStream<Stream>;
+/// The sending half of the connection, after the handshake.
+type Sink = SplitSink<Framed<Socket, PeerCodec>, Message>;
 
 /// The most essential information of a peer session that is sent to torrent
 /// with each session tick.
@@ -76,6 +91,10 @@ pub(crate) enum Command {
         /// Tell the session to enter endgame mode.
         in_endgame: bool,
     },
+    /// Choke the peer, as decided by torrent's choke algorithm.
+    Choke,
+    /// Unchoke the peer, as decided by torrent's choke algorithm.
+    Unchoke,
     /// Eventually shut down the peer session.
     Shutdown,
 }
@@ -169,6 +188,15 @@ pub(crate) struct PeerSession {
     /// or when the peer cancels it. If a peer sends a request and cancels it
     /// before the disk read is done, the read block is dropped.
     incoming_requests: HashSet<BlockInfo>,
+    /// In v2 sessions, the hashes of the blocks of the pieces we download
+    /// from the peer, with which each block is verified as it arrives.
+    ///
+    /// An entry is `None` while its hashes are requested, or if the peer
+    /// rejected the request, in which case the piece's blocks are only
+    /// verified together, once the piece is complete.
+    block_hashes: HashMap<PieceIndex, Option<Vec<Sha256Hash>>>,
+    /// Whether we have ever unchoked the peer.
+    was_peer_unchoked: bool,
 }
 
 /// Information about the peer we're connected to.
@@ -176,6 +204,11 @@ pub(crate) struct PeerSession {
 pub(super) struct PeerInfo {
     /// The IP-port pair of the peer.
     pub addr: SocketAddr,
+    /// The info hash of the swarm in which we know the peer, which is one of
+    /// the torrent's info hashes (of which hybrid torrents have two).
+    ///
+    /// For inbound sessions this is set from the peer's handshake.
+    pub info_hash: Sha1Hash,
     /// Peer's 20 byte BitTorrent id. Updated when the peer sends us its peer
     /// id, in the handshake.
     pub id: Option<PeerId>,
@@ -203,6 +236,7 @@ impl PeerSession {
     ) -> (Self, Sender) {
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
         let piece_count = torrent.storage.piece_count;
+        let info_hash = torrent.info_hashes[0];
         let log_target =
             format!("cratetorrent::peer [{}][{}]", torrent.id, addr);
         (
@@ -212,6 +246,7 @@ impl PeerSession {
                 cmd_rx: cmd_rx.fuse(),
                 peer: PeerInfo {
                     addr,
+                    info_hash,
                     pieces: Bitfield::repeat(false, piece_count),
                     piece_count: 0,
                     id: Default::default(),
@@ -222,6 +257,8 @@ impl PeerSession {
                 },
                 outgoing_requests: HashSet::new(),
                 incoming_requests: HashSet::new(),
+                block_hashes: HashMap::new(),
+                was_peer_unchoked: false,
             },
             cmd_tx,
         )
@@ -232,44 +269,265 @@ impl PeerSession {
     /// This method tries to connect to the peer at the address given in the
     /// constructor, send a handshake, and start the session.
     /// It returns if the connection is closed or an error occurs.
-    pub async fn start_outbound(&mut self) -> Result<()> {
+    ///
+    /// If the torrent has a uTP socket, it is used to connect to the peer as
+    /// allowed by the torrent's transport policy. The info hash is that of the
+    /// swarm in which the peer was found.
+    pub async fn start_outbound(
+        &mut self,
+        utp: Option<UtpSocket>,
+        info_hash: Sha1Hash,
+    ) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Starting outbound session");
+        self.peer.info_hash = info_hash;
 
-        // establish the TCP connection
-        log::info!(target: &self.ctx.log_target, "Connecting to peer");
-        self.ctx.set_connection_state(ConnectionState::Connecting);
-        let socket = TcpStream::connect(self.peer.addr).await?;
-        log::info!(target: &self.ctx.log_target, "Connected to peer");
+        let socket = match self.connect(utp.as_ref()).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                self.disconnect(self.disconnect_reason(&e)).await?;
+                return Err(e);
+            }
+        };
 
         let socket = Framed::new(socket, HandshakeCodec);
         self.start(socket, Direction::Outbound).await
     }
 
-    /// Starts an inbound peer session from an existing TCP connection.
+    /// Connects to the peer and, depending on the torrent's encryption policy,
+    /// performs the encrypted handshake.
+    async fn connect(&mut self, utp: Option<&UtpSocket>) -> Result<Socket> {
+        log::info!(target: &self.ctx.log_target, "Connecting to peer");
+        self.ctx.set_connection_state(ConnectionState::Connecting);
+        let socket = self.connect_transport(utp).await?;
+        let transport = socket.transport();
+        log::info!(
+            target: &self.ctx.log_target,
+            "Connected to peer over {:?}",
+            transport
+        );
+
+        match self.torrent.encryption {
+            EncryptionPolicy::Disabled => {
+                Ok(CryptoStream::plaintext(socket, Default::default()))
+            }
+            EncryptionPolicy::Enabled => {
+                match self.encrypt_outbound(socket).await {
+                    Ok(socket) => Ok(socket),
+                    Err(e) => {
+                        // the peer may not support encryption, so we retry
+                        // in plaintext on a new connection
+                        log::info!(
+                            target: &self.ctx.log_target,
+                            "Encrypted handshake failed ({}), reconnecting \
+                            in plaintext",
+                            e
+                        );
+                        self.ctx
+                            .set_connection_state(ConnectionState::Connecting);
+                        let socket =
+                            connect_over(self.peer.addr, transport, utp)
+                                .await?;
+                        Ok(CryptoStream::plaintext(socket, Default::default()))
+                    }
+                }
+            }
+            EncryptionPolicy::Forced => self.encrypt_outbound(socket).await,
+        }
+    }
+
+    /// Opens a connection to the peer over the transport allowed by the
+    /// torrent's transport policy.
+    async fn connect_transport(
+        &self,
+        utp: Option<&UtpSocket>,
+    ) -> io::Result<Stream> {
+        let addr = self.peer.addr;
+        // without a uTP socket only TCP may be used
+        if utp.is_none() {
+            return connect_over(addr, Transport::Tcp, utp).await;
+        }
+
+        let (first, second) = match self.torrent.transport {
+            TransportPolicy::PreferTcp => (Transport::Tcp, Transport::Utp),
+            TransportPolicy::PreferUtp => (Transport::Utp, Transport::Tcp),
+            TransportPolicy::Both => {
+                // the losing attempt is dropped, which closes its connection
+                let (socket, _) = future::select_ok(vec![
+                    connect_over(addr, Transport::Tcp, utp).boxed(),
+                    connect_over(addr, Transport::Utp, utp).boxed(),
+                ])
+                .await?;
+                return Ok(socket);
+            }
+        };
+
+        match connect_over(addr, first, utp).await {
+            Ok(socket) => Ok(socket),
+            Err(e) => {
+                log::info!(
+                    target: &self.ctx.log_target,
+                    "Connecting over {:?} failed ({}), trying {:?}",
+                    first,
+                    e,
+                    second
+                );
+                connect_over(addr, second, utp).await
+            }
+        }
+    }
+
+    /// Performs the encrypted handshake on an outbound connection.
+    async fn encrypt_outbound(&mut self, socket: Stream) -> Result<Socket> {
+        log::info!(target: &self.ctx.log_target, "Starting encrypted handshake");
+        self.ctx.set_connection_state(ConnectionState::Handshaking);
+        let handshake = mse::handshake_outbound(
+            socket,
+            self.peer.info_hash,
+            self.torrent.encryption,
+        );
+        match time::timeout(mse::HANDSHAKE_TIMEOUT, handshake).await {
+            Ok(result) => result,
+            Err(_) => Err(PeerError::HandshakeTimeout),
+        }
+    }
+
+    /// Starts an inbound peer session from an existing TCP or uTP connection.
     ///
     /// The method waits for the peer to send its handshake, responds
     /// with a handshake, and starts the session.
     /// It returns if the connection is closed or an error occurs.
-    pub async fn start_inbound(&mut self, socket: TcpStream) -> Result<()> {
+    pub async fn start_inbound(&mut self, socket: Stream) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Starting inbound session");
-        self.ctx.set_connection_state(ConnectionState::Connecting);
+        self.ctx.set_connection_state(ConnectionState::Handshaking);
+        // the peer may or may not start with an encrypted handshake, which is
+        // detected here
+        let handshake = mse::handshake_inbound(
+            socket,
+            &self.torrent.info_hashes,
+            self.torrent.encryption,
+        );
+        let socket =
+            match time::timeout(mse::HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(Ok(socket)) => socket,
+                Ok(Err(e)) => {
+                    self.disconnect(self.disconnect_reason(&e)).await?;
+                    return Err(e);
+                }
+                Err(_) => {
+                    self.disconnect(DisconnectReason::Timeout).await?;
+                    return Err(PeerError::HandshakeTimeout);
+                }
+            };
+        log::info!(
+            target: &self.ctx.log_target,
+            "Connection is encrypted: {}",
+            socket.is_encrypted()
+        );
         let socket = Framed::new(socket, HandshakeCodec);
         self.start(socket, Direction::Inbound).await
     }
 
+    /// Ends the session: cancels any pending requests and tells torrent why
+    /// the session ended, so that the peer is removed from torrent.
+    async fn disconnect(&mut self, reason: DisconnectReason) -> Result<()> {
+        log::info!(
+            target: &self.ctx.log_target,
+            "Disconnecting ({:?})",
+            reason
+        );
+
+        // cancel any pending requests to not block other peers from completing
+        // the piece
+        if !self.outgoing_requests.is_empty() {
+            log::info!(
+                target: &self.ctx.log_target,
+                "Cancelling remaining {} request(s)",
+                self.outgoing_requests.len()
+            );
+            self.free_pending_blocks().await;
+        }
+
+        // the final state update also actualizes possible download stats
+        // changes
+        self.ctx.set_connection_state(ConnectionState::Disconnected);
+        self.torrent
+            .cmd_tx
+            .send(torrent::Command::PeerDisconnected {
+                addr: self.peer.addr,
+                reason,
+                info: self.session_info(),
+            })?;
+        Ok(())
+    }
+
+    /// Returns why the session ends with the error, see
+    /// [`disconnect_reason`].
+    fn disconnect_reason(&self, error: &PeerError) -> DisconnectReason {
+        disconnect_reason(self.ctx.state.connection, error)
+    }
+
+    /// Returns our handshake, which advertises v2 protocol support if this is
+    /// a v2 torrent.
+    fn handshake(&self) -> Handshake {
+        let mut handshake =
+            Handshake::new(self.peer.info_hash, self.torrent.client_id);
+        if !self.torrent.merkle_trees.is_empty() {
+            handshake.set_v2();
+        }
+        handshake
+    }
+
     /// Helper method for the common steps of setting up a session.
     async fn start(
         &mut self,
-        mut socket: Framed<TcpStream, HandshakeCodec>,
+        socket: Framed<Socket, HandshakeCodec>,
         direction: Direction,
     ) -> Result<()> {
+        let socket = match self.exchange_handshakes(socket, direction).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                self.disconnect(self.disconnect_reason(&e)).await?;
+                return Err(e);
+            }
+        };
+
+        // run the session
+        let reason = match self.run(socket).await {
+            Ok(reason) => reason,
+            Err(e) => {
+                log::error!(
+                    target: &self.ctx.log_target,
+                    "Session stopped due to an error: {}",
+                    e
+                );
+                let reason = self.disconnect_reason(&e);
+                self.torrent.alert_tx.send(Alert::Error(Error::Peer {
+                    id: self.torrent.id,
+                    addr: self.peer.addr,
+                    error: e,
+                }))?;
+                reason
+            }
+        };
+
+        // session exited as a result of a clean shutdown or an error, perform
+        // some cleanup before exiting
+        self.disconnect(reason).await
+    }
+
+    /// Exchanges the BitTorrent handshakes with the peer and returns the
+    /// connection on which the rest of the messages are exchanged.
+    async fn exchange_handshakes(
+        &mut self,
+        mut socket: Framed<Socket, HandshakeCodec>,
+        direction: Direction,
+    ) -> Result<Framed<Socket, PeerCodec>> {
         self.ctx.set_connection_state(ConnectionState::Handshaking);
 
         // if this is an outbound connection, we have to send the first
         // handshake
         if direction == Direction::Outbound {
-            let handshake =
-                Handshake::new(self.torrent.info_hash, self.torrent.client_id);
+            let handshake = self.handshake();
             log::info!(target: &self.ctx.log_target, "Sending handshake");
             self.ctx.counters.protocol.up += handshake.len();
             socket.send(handshake).await?;
@@ -277,120 +535,86 @@ impl PeerSession {
 
         // receive peer's handshake
         log::info!(target: &self.ctx.log_target, "Waiting for peer handshake");
-        if let Some(peer_handshake) = socket.next().await {
-            let peer_handshake = peer_handshake?;
-            log::info!(target: &self.ctx.log_target, "Peer sent handshake");
-            log::trace!(target: &self.ctx.log_target, "Peer handshake: {:?}", peer_handshake);
-            // codec should only return handshake if the protocol string in it
-            // is valid
-            debug_assert_eq!(peer_handshake.prot, PROTOCOL_STRING.as_bytes());
-
-            self.ctx.counters.protocol.down += peer_handshake.len();
-
-            // verify that the advertised torrent info hash is the same as ours
-            if peer_handshake.info_hash != self.torrent.info_hash {
-                log::info!(target: &self.ctx.log_target, "Peer handshake invalid info hash");
-                // abort session, info hash is invalid
-                return Err(PeerError::InvalidInfoHash);
+        let peer_handshake = match socket.next().await {
+            Some(peer_handshake) => peer_handshake?,
+            None => {
+                log::error!(target: &self.ctx.log_target, "No handshake received");
+                return Err(PeerError::Io(io::ErrorKind::UnexpectedEof.into()));
             }
-
-            // set the peer's id
-            self.peer.id = Some(peer_handshake.peer_id);
-
-            // if this is an inbound connection, we reply with the handshake
-            if direction == Direction::Inbound {
-                let handshake = Handshake::new(
-                    self.torrent.info_hash,
-                    self.torrent.client_id,
-                );
-                log::info!(target: &self.ctx.log_target, "Sending handshake");
-                self.ctx.counters.protocol.up += handshake.len();
-                socket.send(handshake).await?;
+        };
+        log::info!(target: &self.ctx.log_target, "Peer sent handshake");
+        log::trace!(target: &self.ctx.log_target, "Peer handshake: {:?}", peer_handshake);
+        // codec should only return handshake if the protocol string in it
+        // is valid
+        debug_assert_eq!(peer_handshake.prot, PROTOCOL_STRING.as_bytes());
+
+        self.ctx.counters.protocol.down += peer_handshake.len();
+
+        // verify that the advertised torrent info hash is the same as
+        // ours: an inbound peer may be in any of the torrent's swarms, and
+        // we reply with the info hash of that swarm
+        let is_info_hash_valid = match direction {
+            Direction::Outbound => {
+                peer_handshake.info_hash == self.peer.info_hash
             }
-
-            // now that we have the handshake, we need to switch to the peer
-            // message codec and save the socket in self (note that we need to
-            // keep the buffer from the original codec as it may contain bytes
-            // of any potential message the peer may have sent after the
-            // handshake)
-            let old_parts = socket.into_parts();
-            let mut new_parts = FramedParts::new(old_parts.io, PeerCodec);
-            // reuse buffers of previous codec
-            new_parts.read_buf = old_parts.read_buf;
-            new_parts.write_buf = old_parts.write_buf;
-            let socket = Framed::from_parts(new_parts);
-
-            // update torrent of connection
-            self.torrent.cmd_tx.send(torrent::Command::PeerConnected {
-                addr: self.peer.addr,
-                id: peer_handshake.peer_id,
-            })?;
-
-            // enter the piece availability exchange state
-            self.ctx
-                .set_connection_state(ConnectionState::AvailabilityExchange);
-            log::info!(target: &self.ctx.log_target, "Session state: {:?}", self.ctx.state.connection);
-
-            // run the session
-            if let Err(e) = self.run(socket).await {
-                log::error!(
-                    target: &self.ctx.log_target,
-                    "Session stopped due to an error: {}",
-                    e
-                );
-                self.ctx.set_connection_state(ConnectionState::Disconnected);
-                self.torrent.cmd_tx.send(torrent::Command::PeerState {
-                    addr: self.peer.addr,
-                    info: self.session_info(),
-                })?;
-                self.torrent.alert_tx.send(Alert::Error(Error::Peer {
-                    id: self.torrent.id,
-                    addr: self.peer.addr,
-                    error: e,
-                }))?;
+            Direction::Inbound => {
+                self.torrent.info_hashes.contains(&peer_handshake.info_hash)
             }
-        } else {
-            log::error!(target: &self.ctx.log_target, "No handshake received");
-            self.ctx.set_connection_state(ConnectionState::Disconnected);
-            self.torrent.cmd_tx.send(torrent::Command::PeerState {
-                addr: self.peer.addr,
-                info: self.session_info(),
-            })?;
+        };
+        if !is_info_hash_valid {
+            log::info!(target: &self.ctx.log_target, "Peer handshake invalid info hash");
+            // abort session, info hash is invalid
+            return Err(PeerError::InvalidInfoHash);
         }
+        self.peer.info_hash = peer_handshake.info_hash;
 
-        // session exited as a result of a clean shutdown or an error, perform
-        // some cleanup before exiting
+        // set the peer's id
+        self.peer.id = Some(peer_handshake.peer_id);
 
-        // cancel any pending requests to not block other peers from completing
-        // the piece
-        if !self.outgoing_requests.is_empty() {
-            log::info!(
-                target: &self.ctx.log_target,
-                "Cancelling remaining {} request(s)",
-                self.outgoing_requests.len()
-            );
-            self.free_pending_blocks().await;
+        // if this is an inbound connection, we reply with the handshake
+        if direction == Direction::Inbound {
+            let handshake = self.handshake();
+            log::info!(target: &self.ctx.log_target, "Sending handshake");
+            self.ctx.counters.protocol.up += handshake.len();
+            socket.send(handshake).await?;
         }
 
-        // send a state update message to torrent to actualize possible download
-        // stats changes
-        self.ctx.set_connection_state(ConnectionState::Disconnected);
-        self.torrent.cmd_tx.send(torrent::Command::PeerState {
+        // now that we have the handshake, we need to switch to the peer
+        // message codec and save the socket in self (note that we need to
+        // keep the buffer from the original codec as it may contain bytes
+        // of any potential message the peer may have sent after the
+        // handshake)
+        let old_parts = socket.into_parts();
+        let mut new_parts = FramedParts::new(old_parts.io, PeerCodec);
+        // reuse buffers of previous codec
+        new_parts.read_buf = old_parts.read_buf;
+        new_parts.write_buf = old_parts.write_buf;
+        let socket = Framed::from_parts(new_parts);
+
+        // update torrent of connection
+        self.torrent.cmd_tx.send(torrent::Command::PeerConnected {
             addr: self.peer.addr,
-            info: self.session_info(),
+            id: peer_handshake.peer_id,
+            transport: socket.get_ref().get_ref().transport(),
         })?;
 
-        Ok(())
+        // enter the piece availability exchange state
+        self.ctx
+            .set_connection_state(ConnectionState::AvailabilityExchange);
+        log::info!(target: &self.ctx.log_target, "Session state: {:?}", self.ctx.state.connection);
+
+        Ok(socket)
     }
 
-    /// Runs the session after connection to peer is established.
+    /// Runs the session after connection to peer is established, and returns
+    /// why it ended if it wasn't due to an error.
     ///
     /// This is the main session "loop" and performs the core of the session
     /// logic: exchange of messages, timeout logic, etc.
     async fn run(
         &mut self,
-        socket: Framed<TcpStream, PeerCodec>,
-    ) -> Result<()> {
+        socket: Framed<Socket, PeerCodec>,
+    ) -> Result<DisconnectReason> {
         self.ctx.connected_time = Some(Instant::now());
 
         // split the sink and stream so that we can pass the sink while holding
@@ -421,8 +645,12 @@ impl PeerSession {
                 now = tick_timer.select_next_some() => {
                     self.tick(&mut sink, now.into_std()).await?;
                 }
-                msg = stream.select_next_some() => {
-                    let msg = msg?;
+                msg = stream.next() => {
+                    let msg = match msg {
+                        Some(msg) => msg?,
+                        // the peer closed the connection
+                        None => return Ok(DisconnectReason::ConnectionLost),
+                    };
 
                     // handle bitfield message separately as it may only be
                     // received directly after the handshake (later once we
@@ -452,7 +680,7 @@ impl PeerSession {
                                 target: &self.ctx.log_target,
                                 "Neither side of connection has any pieces, disconnecting"
                             );
-                            return Ok(());
+                            return Ok(DisconnectReason::NoPieces);
                         }
 
                         // enter connected state
@@ -474,19 +702,23 @@ impl PeerSession {
                             self.ctx.in_endgame = in_endgame;
                             self.handle_piece_completion(&mut sink, index).await?;
                         }
+                        Command::Choke => {
+                            self.choke_peer(&mut sink).await?;
+                        }
+                        Command::Unchoke => {
+                            self.unchoke_peer(&mut sink).await?;
+                        }
                         Command::Shutdown => {
                             log::info!(
                                 target: &self.ctx.log_target,
                                 "Shutting down session"
                             );
-                            break;
+                            return Ok(DisconnectReason::Shutdown);
                         }
                     }
                 }
             }
         }
-
-        Ok(())
     }
 
     /// The session tick, as in "the tick of a clock", which runs every second
@@ -496,11 +728,7 @@ impl PeerSession {
     /// perhaps to the user directly, if requested), when the session leaves
     /// slow-start, when it checks various timeouts, and when it updates the
     /// target request queue size.
-    async fn tick(
-        &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
-        now: Instant,
-    ) -> Result<()> {
+    async fn tick(&mut self, sink: &mut Sink, now: Instant) -> Result<()> {
         // if we haven't become interested in each other for too long,
         // disconnect
         if !self.ctx.state.is_interested
@@ -522,17 +750,17 @@ impl PeerSession {
         // TODO(https://github.com/mandreyel/cratetorrent/issues/42): send
         // keep-alive
 
-        // if there was any state change, notify torrent
-        if self.ctx.changed {
-            log::debug!(
-                target: &self.ctx.log_target,
-                "State changed, updating torrent",
-            );
-            self.torrent.cmd_tx.send(torrent::Command::PeerState {
-                addr: self.peer.addr,
-                info: self.session_info(),
-            })?;
-        }
+        // notify torrent of our state, even if it hasn't changed, as the
+        // choke algorithm relies on the transfer rates being up to date
+        log::debug!(
+            target: &self.ctx.log_target,
+            "Updating torrent (state changed: {})",
+            self.ctx.changed
+        );
+        self.torrent.cmd_tx.send(torrent::Command::PeerState {
+            addr: self.peer.addr,
+            info: self.session_info(),
+        })?;
 
         // update session context
         let prev_queue_len = self.ctx.target_request_queue_len;
@@ -576,10 +804,7 @@ impl PeerSession {
     }
 
     /// Times out the peer if it hasn't sent a request in too long.
-    async fn check_request_timeout(
-        &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
-    ) -> Result<()> {
+    async fn check_request_timeout(&mut self, sink: &mut Sink) -> Result<()> {
         if let Some(last_outgoing_request_time) =
             self.ctx.last_outgoing_request_time
         {
@@ -653,7 +878,7 @@ impl PeerSession {
     /// (currently only the bitfield message).
     async fn handle_bitfield_msg(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut Sink,
         mut bitfield: Bitfield,
     ) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Handling peer Bitfield message");
@@ -702,7 +927,7 @@ impl PeerSession {
     /// Handles messages from peer that are expected in the `Connected` state.
     async fn handle_msg(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut Sink,
         msg: Message,
     ) -> Result<()> {
         // record protocol message size
@@ -743,17 +968,13 @@ impl PeerSession {
                 }
             }
             Message::Interested => {
+                // whether the peer is unchoked is decided by torrent's choke
+                // algorithm
                 if !self.ctx.state.is_peer_interested {
-                    // TODO(https://github.com/mandreyel/cratetorrent/issues/60):
-                    // we currently unchkoe peer unconditionally, but we should
-                    // implement the proper unchoke algorithm in `Torrent`
                     log::info!(target: &self.ctx.log_target, "Peer became interested");
-                    log::info!(target: &self.ctx.log_target, "Unchoking peer");
                     self.ctx.update_state(|state| {
                         state.is_peer_interested = true;
-                        state.is_peer_choked = false;
                     });
-                    sink.send(Message::Unchoke).await?;
                 }
             }
             Message::NotInterested => {
@@ -792,6 +1013,17 @@ impl PeerSession {
                 log::info!(target: &self.ctx.log_target, "Peer cancelled block {}", block_info);
                 self.incoming_requests.remove(&block_info);
             }
+            Message::HashRequest(request) => {
+                self.handle_hash_request_msg(sink, request).await?;
+            }
+            Message::Hashes { request, hashes } => {
+                self.handle_hashes_msg(request, hashes)?;
+            }
+            Message::HashReject(request) => {
+                // the piece's blocks can still be verified together, so the
+                // request is not retried
+                log::info!(target: &self.ctx.log_target, "Peer rejected hash request: {:?}", request);
+            }
         }
 
         Ok(())
@@ -802,10 +1034,7 @@ impl PeerSession {
     ///
     /// To see what this means, please refer to the
     /// `Status::best_request_queue_len` or the relevant section in DESIGN.md.
-    async fn make_requests(
-        &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
-    ) -> Result<()> {
+    async fn make_requests(&mut self, sink: &mut Sink) -> Result<()> {
         log::trace!(target: &self.ctx.log_target, "Making requests");
 
         if self.ctx.state.is_choked {
@@ -823,6 +1052,13 @@ impl PeerSession {
         let target_request_queue_len =
             self.ctx.target_request_queue_len.unwrap_or_default();
 
+        // forget the block hashes of pieces that are no longer downloaded
+        {
+            let downloads = self.torrent.downloads.read().await;
+            self.block_hashes
+                .retain(|index, _| downloads.contains_key(index));
+        }
+
         // If we have active downloads, prefer to continue those. This will
         // result in less in-progress pieces.
         for download in self.torrent.downloads.write().await.values_mut() {
@@ -900,6 +1136,22 @@ impl PeerSession {
             }
         }
 
+        // ask for the hashes of the blocks of the pieces we haven't asked for
+        // yet, before the blocks themselves, so that the blocks can be
+        // verified as they arrive
+        for req in requests.iter() {
+            if self.block_hashes.contains_key(&req.piece_index) {
+                continue;
+            }
+            if let Some(request) = self.block_hash_request(req.piece_index) {
+                log::debug!(target: &self.ctx.log_target, "Requesting hashes {:?}", request);
+                self.block_hashes.insert(req.piece_index, None);
+                sink.send(Message::HashRequest(request)).await?;
+                self.ctx.counters.protocol.up +=
+                    MessageId::HashRequest.header_len();
+            }
+        }
+
         if !requests.is_empty() {
             log::info!(
                 target: &self.ctx.log_target,
@@ -932,6 +1184,20 @@ impl PeerSession {
         block_info: BlockInfo,
         data: Vec<u8>,
     ) -> Result<()> {
+        // if we have the hashes of the piece's blocks, the block is verified
+        // now, so that a corrupt block is attributed to the peer that sent
+        // it (the block's request is freed when the session ends)
+        if let Some(Some(hashes)) =
+            self.block_hashes.get(&block_info.piece_index)
+        {
+            let hash = merkle::hash_block(&data);
+            if hashes.get(block_info.index_in_piece()) != Some(&hash) {
+                log::warn!(target: &self.ctx.log_target, "Peer sent corrupt block {}", block_info);
+                self.ctx.record_waste(block_info.len);
+                return Err(PeerError::CorruptBlock);
+            }
+        }
+
         // remove pending block request
         self.outgoing_requests.remove(&block_info);
 
@@ -1000,6 +1266,7 @@ impl PeerSession {
                 id: self.torrent.id,
                 block_info,
                 data,
+                sender: Some(self.peer.addr),
             })?;
         }
 
@@ -1024,8 +1291,14 @@ impl PeerSession {
 
         // check if peer is not choked: if they are, they can't request blocks
         if self.ctx.state.is_peer_choked {
-            log::warn!(target: &self.ctx.log_target, "Choked peer sent request");
-            return Err(PeerError::RequestWhileChoked);
+            // the peer may have sent the request before learning that we
+            // choked them, but if we never unchoked them, they have no excuse
+            if !self.was_peer_unchoked {
+                log::warn!(target: &self.ctx.log_target, "Choked peer sent request");
+                return Err(PeerError::RequestWhileChoked);
+            }
+            log::info!(target: &self.ctx.log_target, "Ignoring request of choked peer");
+            return Ok(());
         }
 
         // check if peer is not already requesting this block
@@ -1049,11 +1322,133 @@ impl PeerSession {
         Ok(())
     }
 
+    /// Returns whether the peer is in the v2 swarm of the torrent, in which
+    /// case we may exchange the hashes of the files' merkle trees.
+    fn is_v2(&self) -> bool {
+        !self.torrent.merkle_trees.is_empty()
+            && self.torrent.info_hashes.last() == Some(&self.peer.info_hash)
+    }
+
+    /// Returns the request for the hashes of the blocks of the piece, which is
+    /// only made in v2 sessions, and only if the piece has multiple blocks.
+    ///
+    /// The hashes are those of the leaves under the piece's node in its file's
+    /// merkle tree, which we already have, so no proof is requested.
+    fn block_hash_request(&self, index: PieceIndex) -> Option<HashRequest> {
+        if !self.is_v2() {
+            return None;
+        }
+        let storage = &self.torrent.storage;
+        let offset = storage.torrent_piece_offset(index);
+        let file_index = storage
+            .files
+            .iter()
+            .position(|file| file.byte_range().contains(&offset))?;
+        let file = &storage.files[file_index];
+        let tree = self.torrent.merkle_trees.get(file_index)?.as_ref()?;
+        let index_in_file = ((offset - file.torrent_offset)
+            / storage.piece_len as u64) as usize;
+        let (first, len) =
+            merkle::piece_leaves(file.len, storage.piece_len, index_in_file);
+        // a single block is verified as the whole piece
+        if len < 2 {
+            return None;
+        }
+        Some(HashRequest {
+            pieces_root: tree.root(),
+            base_layer: 0,
+            index: first as u32,
+            len: len as u32,
+            proof_layers: 0,
+        })
+    }
+
+    /// Handles the hashes of a piece's blocks that we requested, which are
+    /// verified against the piece's hash and kept to verify the blocks.
+    ///
+    /// Hashes that don't match the piece's hash end the session.
+    fn handle_hashes_msg(
+        &mut self,
+        request: HashRequest,
+        hashes: Vec<Sha256Hash>,
+    ) -> Result<()> {
+        let index = self
+            .block_hashes
+            .iter()
+            .filter(|(_, hashes)| hashes.is_none())
+            .map(|(index, _)| *index)
+            .find(|index| self.block_hash_request(*index) == Some(request));
+        let index = match index {
+            Some(index) => index,
+            None => {
+                log::info!(target: &self.ctx.log_target, "Peer sent unrequested hashes: {:?}", request);
+                return Ok(());
+            }
+        };
+
+        let leaves = hashes.get(..request.len as usize).unwrap_or_default();
+        let is_valid = self
+            .torrent
+            .merkle_trees
+            .iter()
+            .flatten()
+            .find(|tree| tree.root() == request.pieces_root)
+            .map(|tree| tree.verify_leaves(request.index as usize, leaves))
+            .unwrap_or_default();
+        if !is_valid {
+            log::warn!(target: &self.ctx.log_target, "Peer sent invalid hashes: {:?}", request);
+            return Err(PeerError::InvalidHashes);
+        }
+
+        log::info!(target: &self.ctx.log_target, "Got hashes of piece {} blocks", index);
+        self.block_hashes.insert(index, Some(leaves.to_vec()));
+        Ok(())
+    }
+
+    /// Handles the peer's request for hashes of a file's merkle tree in a v2
+    /// torrent.
+    ///
+    /// We only keep the layers of the trees from the piece layer up, so
+    /// requests for lower layers are rejected.
+    async fn handle_hash_request_msg(
+        &mut self,
+        sink: &mut Sink,
+        request: HashRequest,
+    ) -> Result<()> {
+        log::info!(target: &self.ctx.log_target, "Got hash request: {:?}", request);
+
+        let hashes = self
+            .torrent
+            .merkle_trees
+            .iter()
+            .flatten()
+            .find(|tree| tree.root() == request.pieces_root)
+            .and_then(|tree| {
+                tree.hashes(
+                    request.base_layer,
+                    request.index as usize,
+                    request.len as usize,
+                    request.proof_layers,
+                )
+            });
+        match hashes {
+            Some(hashes) => {
+                sink.send(Message::Hashes { request, hashes }).await?;
+            }
+            None => {
+                log::info!(target: &self.ctx.log_target, "Rejecting hash request");
+                sink.send(Message::HashReject(request)).await?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Sends the block to peer if the peer still wants it (hasn't canceled the
     /// request).
     async fn send_block(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut Sink,
         block: Block,
     ) -> Result<()> {
         let info = block.info();
@@ -1084,11 +1479,40 @@ impl PeerSession {
         Ok(())
     }
 
+    /// Chokes the peer, if it's not already choked.
+    ///
+    /// The peer's pending requests are dropped, as it won't expect them to be
+    /// served anymore.
+    async fn choke_peer(&mut self, sink: &mut Sink) -> Result<()> {
+        if self.ctx.state.is_peer_choked {
+            return Ok(());
+        }
+        log::info!(target: &self.ctx.log_target, "Choking peer");
+        self.ctx.counters.protocol.up += MessageId::Choke.header_len();
+        self.ctx.update_state(|state| state.is_peer_choked = true);
+        self.incoming_requests.clear();
+        sink.send(Message::Choke).await?;
+        Ok(())
+    }
+
+    /// Unchokes the peer, if it's not already unchoked.
+    async fn unchoke_peer(&mut self, sink: &mut Sink) -> Result<()> {
+        if !self.ctx.state.is_peer_choked {
+            return Ok(());
+        }
+        log::info!(target: &self.ctx.log_target, "Unchoking peer");
+        self.ctx.counters.protocol.up += MessageId::Unchoke.header_len();
+        self.ctx.update_state(|state| state.is_peer_choked = false);
+        self.was_peer_unchoked = true;
+        sink.send(Message::Unchoke).await?;
+        Ok(())
+    }
+
     /// Handles the announcement of a new piece that peer has. This may cause us
     /// to become interested in peer and start making requests.
     async fn handle_have_msg(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut Sink,
         piece_index: PieceIndex,
     ) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Peer has piece {}", piece_index);
@@ -1121,7 +1545,7 @@ impl PeerSession {
     /// Checks whether we have become or stopped being interested in the peer.
     async fn update_interest(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut Sink,
         is_interested: bool,
     ) -> Result<()> {
         // we may have become interested in peer
@@ -1149,7 +1573,13 @@ impl PeerSession {
     fn validate_block_info(&self, info: &BlockInfo) -> Result<()> {
         log::trace!(target: &self.ctx.log_target, "Validating {}", info);
         self.validate_piece_index(info.piece_index)?;
-        let piece_len = self.torrent.storage.piece_len(info.piece_index);
+        // v1 peers of a hybrid torrent see the padding after each file, which
+        // they may request too
+        let piece_len = if self.is_v2() {
+            self.torrent.storage.piece_len(info.piece_index)
+        } else {
+            self.torrent.storage.padded_piece_len(info.piece_index)
+        };
         if info.len > 0 && info.offset + info.len <= piece_len {
             Ok(())
         } else {
@@ -1179,7 +1609,7 @@ impl PeerSession {
     /// that we need to cancel. If peer doesn't have the piece, we announce it.
     async fn handle_piece_completion(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut Sink,
         piece_index: PieceIndex,
     ) -> Result<()> {
         // if peer doesn't have the piece, announce it
@@ -1216,6 +1646,122 @@ impl PeerSession {
     }
 }
 
+/// Returns why a session in the connection state ends with the error.
+fn disconnect_reason(
+    connection: ConnectionState,
+    error: &PeerError,
+) -> DisconnectReason {
+    let reason = error.disconnect_reason();
+    match connection {
+        ConnectionState::Connecting if matches!(error, PeerError::Io(_)) => {
+            DisconnectReason::ConnectFailed
+        }
+        // the peer closing the connection or sending garbage during the
+        // handshake means it refused the connection
+        ConnectionState::Handshaking
+            if reason != DisconnectReason::Timeout
+                && reason != DisconnectReason::Shutdown =>
+        {
+            DisconnectReason::HandshakeFailed
+        }
+        _ => reason,
+    }
+}
+
+/// Opens a connection to the peer over the given transport.
+async fn connect_over(
+    addr: SocketAddr,
+    transport: Transport,
+    utp: Option<&UtpSocket>,
+) -> io::Result<Stream> {
+    match (transport, utp) {
+        (Transport::Tcp, _) => TcpStream::connect(addr).await.map(Stream::Tcp),
+        (Transport::Utp, Some(utp)) => utp.connect(addr).await.map(Stream::Utp),
+        (Transport::Utp, None) => Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "uTP socket not available",
+        )),
+    }
+}
+
 /// After this timeout if the peers haven't become intereseted in each other,
 /// the connection is severed.
 const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disconnect_reason() {
+        use ConnectionState::*;
+        use DisconnectReason::*;
+
+        let io_error = |kind| PeerError::Io(io::Error::new(kind, "test"));
+        let reasons = vec![
+            // any IO error while connecting means the connection failed
+            (
+                Connecting,
+                io_error(io::ErrorKind::ConnectionRefused),
+                ConnectFailed,
+            ),
+            (Connecting, io_error(io::ErrorKind::TimedOut), ConnectFailed),
+            (
+                Connecting,
+                io_error(io::ErrorKind::InvalidInput),
+                ConnectFailed,
+            ),
+            (Connecting, PeerError::Channel, Shutdown),
+            // errors during the handshake mean it failed, unless it timed out
+            // or we stopped the session
+            (
+                Handshaking,
+                io_error(io::ErrorKind::UnexpectedEof),
+                HandshakeFailed,
+            ),
+            (
+                Handshaking,
+                io_error(io::ErrorKind::InvalidInput),
+                HandshakeFailed,
+            ),
+            (Handshaking, PeerError::InvalidInfoHash, HandshakeFailed),
+            (Handshaking, PeerError::EncryptionRequired, HandshakeFailed),
+            (Handshaking, PeerError::HandshakeTimeout, Timeout),
+            (Handshaking, io_error(io::ErrorKind::TimedOut), Timeout),
+            (Handshaking, PeerError::Channel, Shutdown),
+            // after the handshake the reason depends only on the error
+            (
+                AvailabilityExchange,
+                PeerError::BitfieldNotAfterHandshake,
+                ProtocolViolation,
+            ),
+            (
+                AvailabilityExchange,
+                io_error(io::ErrorKind::UnexpectedEof),
+                ConnectionLost,
+            ),
+            (
+                Connected,
+                io_error(io::ErrorKind::ConnectionReset),
+                ConnectionLost,
+            ),
+            (
+                Connected,
+                io_error(io::ErrorKind::InvalidInput),
+                ProtocolViolation,
+            ),
+            (Connected, PeerError::CorruptBlock, ProtocolViolation),
+            (Connected, PeerError::InactivityTimeout, Timeout),
+            (Connected, PeerError::Channel, Shutdown),
+        ];
+        for (connection, error, reason) in reasons.iter() {
+            assert_eq!(
+                disconnect_reason(*connection, error),
+                *reason,
+                "{:?} in {:?}",
+                error,
+                connection
+            );
+        }
+    }
+}
@@ -6,7 +6,7 @@ use std::{
 use bytes::{Buf, BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::{Bitfield, BlockData, BlockInfo};
+use crate::{Bitfield, BlockData, BlockInfo, Sha256Hash};
 
 /// The message sent at the beginning of a peer session by both sides of the
 /// connection.
@@ -39,6 +39,13 @@ impl Handshake {
         }
     }
 
+    /// Sets the reserved bit that tells the peer that we support the v2
+    /// protocol, as described in
+    /// [BEP 52](http://bittorrent.org/beps/bep_0052.html).
+    pub fn set_v2(&mut self) {
+        self.reserved[7] |= 0x10;
+    }
+
     /// Returns the length of the handshake, in bytes.
     pub const fn len(&self) -> u64 {
         19 + 8 + 20 + 20
@@ -172,8 +179,62 @@ pub(crate) enum Message {
         data: BlockData,
     },
     Cancel(BlockInfo),
+    HashRequest(HashRequest),
+    Hashes {
+        request: HashRequest,
+        /// The requested hashes followed by the uncle hashes needed to verify
+        /// them against the file's root.
+        hashes: Vec<Sha256Hash>,
+    },
+    HashReject(HashRequest),
+}
+
+/// A request for a range of hashes in a layer of a file's merkle tree, as
+/// described in [BEP 52](http://bittorrent.org/beps/bep_0052.html). This is
+/// also included in the responses to the request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct HashRequest {
+    /// The root of the merkle tree of the file whose hashes are requested.
+    pub pieces_root: Sha256Hash,
+    /// The layer of the tree from which hashes are requested, where layer 0
+    /// is the leaf layer.
+    pub base_layer: u32,
+    /// The index of the first requested hash in the layer.
+    pub index: u32,
+    /// The number of requested hashes.
+    pub len: u32,
+    /// The number of uncle hash layers requested to verify the hashes.
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    /// Encodes the request in the network binary protocol's format into the
+    /// given buffer.
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.extend_from_slice(&self.pieces_root);
+        buf.put_u32(self.base_layer);
+        buf.put_u32(self.index);
+        buf.put_u32(self.len);
+        buf.put_u32(self.proof_layers);
+    }
+
+    /// Decodes the request from the buffer, which must contain it in full.
+    fn decode(buf: &mut BytesMut) -> Self {
+        let mut pieces_root = [0; 32];
+        buf.copy_to_slice(&mut pieces_root);
+        Self {
+            pieces_root,
+            base_layer: buf.get_u32(),
+            index: buf.get_u32(),
+            len: buf.get_u32(),
+            proof_layers: buf.get_u32(),
+        }
+    }
 }
 
+/// The length of an encoded [`HashRequest`].
+const HASH_REQUEST_LEN: usize = 32 + 4 * 4;
+
 impl Message {
     /// Returns the ID of the message, if it has one (e.g. keep alive doesn't).
     pub fn id(&self) -> Option<MessageId> {
@@ -188,6 +249,9 @@ impl Message {
             Self::Request(_) => Some(MessageId::Request),
             Self::Block { .. } => Some(MessageId::Block),
             Self::Cancel(_) => Some(MessageId::Cancel),
+            Self::HashRequest(_) => Some(MessageId::HashRequest),
+            Self::Hashes { .. } => Some(MessageId::Hashes),
+            Self::HashReject(_) => Some(MessageId::HashReject),
         }
     }
 
@@ -219,6 +283,9 @@ pub(crate) enum MessageId {
     Request = 6,
     Block = 7,
     Cancel = 8,
+    HashRequest = 21,
+    Hashes = 22,
+    HashReject = 23,
 }
 
 impl MessageId {
@@ -237,6 +304,9 @@ impl MessageId {
             Self::Request => 4 + 1 + 3 * 4,
             Self::Block => 4 + 1 + 2 * 4,
             Self::Cancel => 4 + 1 + 3 * 4,
+            Self::HashRequest => 4 + 1 + HASH_REQUEST_LEN as u64,
+            Self::Hashes => 4 + 1 + HASH_REQUEST_LEN as u64,
+            Self::HashReject => 4 + 1 + HASH_REQUEST_LEN as u64,
         }
     }
 }
@@ -256,6 +326,9 @@ impl TryFrom<u8> for MessageId {
             k if k == Request as u8 => Ok(Request),
             k if k == Block as u8 => Ok(Block),
             k if k == Cancel as u8 => Ok(Cancel),
+            k if k == HashRequest as u8 => Ok(HashRequest),
+            k if k == Hashes as u8 => Ok(Hashes),
+            k if k == HashReject as u8 => Ok(HashReject),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "Unknown message id",
@@ -398,6 +471,37 @@ impl Encoder<Message> for PeerCodec {
                 // payload
                 block.encode(buf)?;
             }
+            HashRequest(request) => {
+                // message length prefix: 1 byte message id and the request
+                let msg_len = 1 + HASH_REQUEST_LEN;
+                buf.put_u32(msg_len as u32);
+                // message id
+                buf.put_u8(MessageId::HashRequest as u8);
+                // payload
+                request.encode(buf);
+            }
+            Hashes { request, hashes } => {
+                // message length prefix:
+                // 1 byte message id, the request, and n 32 byte hashes
+                let msg_len = 1 + HASH_REQUEST_LEN + hashes.len() * 32;
+                buf.put_u32(msg_len as u32);
+                // message id
+                buf.put_u8(MessageId::Hashes as u8);
+                // payload
+                request.encode(buf);
+                for hash in hashes.iter() {
+                    buf.extend_from_slice(hash);
+                }
+            }
+            HashReject(request) => {
+                // message length prefix: 1 byte message id and the request
+                let msg_len = 1 + HASH_REQUEST_LEN;
+                buf.put_u32(msg_len as u32);
+                // message id
+                buf.put_u8(MessageId::HashReject as u8);
+                // payload
+                request.encode(buf);
+            }
         }
 
         Ok(())
@@ -524,6 +628,40 @@ impl Decoder for PeerCodec {
                     len,
                 })
             }
+            MessageId::HashRequest | MessageId::HashReject => {
+                if msg_len != 1 + HASH_REQUEST_LEN {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Invalid hash request length",
+                    ));
+                }
+                let request = HashRequest::decode(buf);
+                if msg_id == MessageId::HashRequest {
+                    Message::HashRequest(request)
+                } else {
+                    Message::HashReject(request)
+                }
+            }
+            MessageId::Hashes => {
+                // the hashes follow the request and fill the rest of the
+                // message
+                let hashes_len = msg_len.checked_sub(1 + HASH_REQUEST_LEN);
+                let hashes_len = match hashes_len {
+                    Some(len) if len % 32 == 0 => len,
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "Invalid hashes message length",
+                        ))
+                    }
+                };
+                let request = HashRequest::decode(buf);
+                let mut hashes = vec![[0; 32]; hashes_len / 32];
+                for hash in hashes.iter_mut() {
+                    buf.copy_to_slice(hash);
+                }
+                Message::Hashes { request, hashes }
+            }
         };
 
         Ok(Some(msg))
@@ -809,6 +947,38 @@ mod tests {
         assert_message_codec(msg, expected_encoded);
     }
 
+    /// Tests the encoding and subsequent decoding of valid v2 hash request,
+    /// hashes, and hash reject messages.
+    #[test]
+    fn test_hash_msgs_codec() {
+        let (request, encoded_request) = make_hash_request();
+        assert_message_codec(
+            Message::HashRequest(request),
+            make_hash_msg_encoded_payload(MessageId::HashRequest, &[]),
+        );
+        assert_message_codec(
+            Message::HashReject(request),
+            make_hash_msg_encoded_payload(MessageId::HashReject, &[]),
+        );
+        let hashes = vec![[1; 32], [2; 32], [3; 32]];
+        assert_message_codec(
+            Message::Hashes {
+                request,
+                hashes: hashes.clone(),
+            },
+            make_hash_msg_encoded_payload(MessageId::Hashes, &hashes),
+        );
+
+        // a hashes message whose hashes aren't a multiple of 32 bytes is
+        // invalid
+        let mut encoded = BytesMut::new();
+        encoded.put_u32(1 + encoded_request.len() as u32 + 31);
+        encoded.put_u8(MessageId::Hashes as u8);
+        encoded.extend_from_slice(&encoded_request);
+        encoded.extend_from_slice(&[0; 31]);
+        assert!(PeerCodec.decode(&mut encoded).is_err());
+    }
+
     /// Helper function that asserts that a message is encoded and subsequently
     /// decoded correctly.
     fn assert_message_codec(msg: Message, expected_encoded: Bytes) {
@@ -984,6 +1154,44 @@ mod tests {
         (msg, encoded)
     }
 
+    /// Returns a `HashRequest` and its expected encoded variant.
+    fn make_hash_request() -> (HashRequest, Bytes) {
+        let request = HashRequest {
+            pieces_root: [0xab; 32],
+            base_layer: 2,
+            index: 8,
+            len: 4,
+            proof_layers: 3,
+        };
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&[0xab; 32]);
+        buf.put_u32(2);
+        buf.put_u32(8);
+        buf.put_u32(4);
+        buf.put_u32(3);
+        (request, buf.into())
+    }
+
+    /// Helper used to create 'hash request', 'hashes', and 'hash reject'
+    /// encoded messages that have the same format, except that only the
+    /// 'hashes' message has hashes.
+    fn make_hash_msg_encoded_payload(
+        id: MessageId,
+        hashes: &[Sha256Hash],
+    ) -> Bytes {
+        let (_, request) = make_hash_request();
+        // 1 byte message id, the request, and the hashes
+        let msg_len = 1 + request.len() + hashes.len() * 32;
+        let mut buf = BytesMut::with_capacity(4 + msg_len);
+        buf.put_u32(msg_len as u32);
+        buf.put_u8(id as u8);
+        buf.extend_from_slice(&request);
+        for hash in hashes {
+            buf.extend_from_slice(hash);
+        }
+        buf.into()
+    }
+
     /// Helper used to create 'request' and 'cancel' encoded messages that have
     /// the same format.
     fn make_block_info_encoded_msg_payload(
@@ -1,7 +1,9 @@
-use std::fmt;
+use std::{fmt, io::ErrorKind};
 
 pub use tokio::{io::Error as IoError, sync::mpsc::error::SendError};
 
+use super::DisconnectReason;
+
 pub(crate) type Result<T, E = PeerError> = std::result::Result<T, E>;
 
 /// Error type returned on failed peer sessions.
@@ -30,6 +32,19 @@ pub enum PeerError {
     InvalidPieceIndex,
     /// Peer's torrent info hash did not match ours.
     InvalidInfoHash,
+    /// The hashes of a piece's blocks that the peer sent us don't add up to
+    /// the piece's hash.
+    InvalidHashes,
+    /// A block that the peer sent us doesn't match its hash.
+    CorruptBlock,
+    /// The encrypted handshake failed because the peer sent invalid data or
+    /// the two sides couldn't agree on how to continue the connection.
+    EncryptionHandshake,
+    /// Encryption is required but the peer connected in plaintext or did not
+    /// offer encryption.
+    EncryptionRequired,
+    /// The peer didn't complete the encrypted handshake in time.
+    HandshakeTimeout,
     /// An IO error ocurred.
     Io(std::io::Error),
 }
@@ -49,11 +64,45 @@ impl fmt::Display for PeerError {
             InvalidBlockInfo => write!(fmt, "invalid block info"),
             InvalidPieceIndex => write!(fmt, "invalid piece index"),
             InvalidInfoHash => write!(fmt, "invalid info hash"),
+            InvalidHashes => write!(fmt, "invalid block hashes"),
+            CorruptBlock => write!(fmt, "corrupt block"),
+            EncryptionHandshake => write!(fmt, "encryption handshake failed"),
+            EncryptionRequired => write!(fmt, "peer doesn't use encryption"),
+            HandshakeTimeout => write!(fmt, "handshake timeout"),
             Io(e) => write!(fmt, "{}", e),
         }
     }
 }
 
+impl PeerError {
+    /// Returns why a session that is past the handshake ends with this error.
+    pub(crate) fn disconnect_reason(&self) -> DisconnectReason {
+        use PeerError::*;
+        match self {
+            BitfieldNotAfterHandshake
+            | RequestWhileChoked
+            | InvalidBlockInfo
+            | InvalidPieceIndex
+            | InvalidHashes
+            | CorruptBlock => DisconnectReason::ProtocolViolation,
+            InvalidInfoHash | EncryptionHandshake | EncryptionRequired => {
+                DisconnectReason::HandshakeFailed
+            }
+            InactivityTimeout | HandshakeTimeout => DisconnectReason::Timeout,
+            // the torrent is gone
+            Channel => DisconnectReason::Shutdown,
+            // the codecs return this kind for invalid messages
+            Io(e) if e.kind() == ErrorKind::InvalidInput => {
+                DisconnectReason::ProtocolViolation
+            }
+            Io(e) if e.kind() == ErrorKind::TimedOut => {
+                DisconnectReason::Timeout
+            }
+            Io(_) => DisconnectReason::ConnectionLost,
+        }
+    }
+}
+
 impl From<IoError> for PeerError {
     fn from(e: IoError) -> Self {
         // the pieces field is a concatenation of 20 byte SHA-1 hashes, so it
@@ -67,3 +116,37 @@ impl<T> From<SendError<T>> for PeerError {
         Self::Channel
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disconnect_reason() {
+        use DisconnectReason::*;
+        use PeerError::*;
+
+        let io_error = |kind| Io(IoError::new(kind, "test"));
+        let reasons = vec![
+            (BitfieldNotAfterHandshake, ProtocolViolation),
+            (RequestWhileChoked, ProtocolViolation),
+            (InvalidBlockInfo, ProtocolViolation),
+            (InvalidPieceIndex, ProtocolViolation),
+            (InvalidHashes, ProtocolViolation),
+            (CorruptBlock, ProtocolViolation),
+            (InvalidInfoHash, HandshakeFailed),
+            (EncryptionHandshake, HandshakeFailed),
+            (EncryptionRequired, HandshakeFailed),
+            (InactivityTimeout, Timeout),
+            (HandshakeTimeout, Timeout),
+            (Channel, Shutdown),
+            (io_error(ErrorKind::InvalidInput), ProtocolViolation),
+            (io_error(ErrorKind::TimedOut), Timeout),
+            (io_error(ErrorKind::UnexpectedEof), ConnectionLost),
+            (io_error(ErrorKind::ConnectionReset), ConnectionLost),
+        ];
+        for (error, reason) in reasons.iter() {
+            assert_eq!(error.disconnect_reason(), *reason, "{:?}", error);
+        }
+    }
+}
@@ -0,0 +1,699 @@
+//! Message Stream Encryption, also known as protocol encryption.
+//!
+//! Before the BitTorrent handshake, the two sides of the connection perform
+//! a Diffie-Hellman key exchange, from which RC4 keys are derived for each
+//! direction of the connection. After that, depending on what the two sides
+//! agreed on, either the rest of the connection is RC4 encrypted or it
+//! continues in plaintext.
+//!
+//! The handshake functions in this module return a [`CryptoStream`], which
+//! transparently encrypts and decrypts the underlying socket, so the rest of
+//! the session can use it just like a TCP socket.
+//!
+//! The specification:
+//! <https://wiki.vuze.com/w/Message_Stream_Encryption>
+
+use std::{
+    convert::TryInto,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Buf, BytesMut};
+use futures::ready;
+use num_bigint::BigUint;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{codec::PROTOCOL_STRING, error::*};
+use crate::{conf::EncryptionPolicy, Sha1Hash};
+
+/// How long the encrypted handshake may take before it is aborted.
+///
+/// Some peers that don't support encryption don't close the connection when
+/// they receive the public key, so we need this to fall back to plaintext in
+/// time.
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static::lazy_static! {
+    /// The 768 bit Diffie-Hellman prime used by all MSE implementations.
+    static ref PRIME: BigUint = BigUint::parse_bytes(
+        b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+        020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374\
+        FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563",
+        16,
+    )
+    .unwrap();
+}
+
+/// The Diffie-Hellman generator.
+const GENERATOR: u32 = 2;
+/// The length of the public keys and the shared secret, in bytes.
+const KEY_LEN: usize = 96;
+/// The maximum length of each of the random paddings in the handshake.
+const MAX_PAD_LEN: usize = 512;
+/// The verification constant, which is sent encrypted so that the other side
+/// can verify the keys and find the position of the encrypted data.
+const VC: [u8; 8] = [0; 8];
+/// The crypto method bits: plaintext.
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+/// The crypto method bits: RC4.
+const CRYPTO_RC4: u32 = 0x02;
+
+/// Performs the encrypted handshake as the initiator of the connection.
+///
+/// RC4 is always offered, and plaintext too, unless encryption is forced by
+/// the policy.
+pub(crate) async fn handshake_outbound<S>(
+    mut socket: S,
+    info_hash: Sha1Hash,
+    policy: EncryptionPolicy,
+) -> Result<CryptoStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    debug_assert_ne!(policy, EncryptionPolicy::Disabled);
+    let mut buf = BytesMut::new();
+
+    // 1. A->B: Diffie Hellman Ya, PadA
+    let keys = KeyPair::new();
+    let mut msg = keys.public.to_vec();
+    msg.extend_from_slice(&random_pad());
+    socket.write_all(&msg).await?;
+
+    // 2. B->A: Diffie Hellman Yb, PadB
+    let peer_public = read_exact(&mut socket, &mut buf, KEY_LEN).await?;
+    let secret = keys.shared_secret(&peer_public);
+
+    // 3. A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S),
+    // ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA)), ENCRYPT(IA)
+    let mut encryptor = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut decryptor = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+    let crypto_provide = if policy == EncryptionPolicy::Forced {
+        CRYPTO_RC4
+    } else {
+        CRYPTO_RC4 | CRYPTO_PLAINTEXT
+    };
+    let mut msg = hash(&[b"req1", &secret]).to_vec();
+    msg.extend_from_slice(&skey_hash(&info_hash, &secret));
+    let mut payload = VC.to_vec();
+    payload.extend_from_slice(&crypto_provide.to_be_bytes());
+    // we send neither padding nor an initial payload, the BitTorrent
+    // handshake is sent after this
+    payload.extend_from_slice(&0u16.to_be_bytes());
+    payload.extend_from_slice(&0u16.to_be_bytes());
+    encryptor.apply(&mut payload);
+    msg.extend_from_slice(&payload);
+    socket.write_all(&msg).await?;
+
+    // 4. B->A: ENCRYPT(VC, crypto_select, len(padD), padD)
+    //
+    // PadB is of unknown length, so we need to look for the encrypted VC to
+    // know where the encrypted part begins.
+    let mut encrypted_vc = VC;
+    decryptor.apply(&mut encrypted_vc);
+    sync(&mut socket, &mut buf, &encrypted_vc, MAX_PAD_LEN).await?;
+    let mut msg = read_exact(&mut socket, &mut buf, 4 + 2).await?;
+    decryptor.apply(&mut msg);
+    let crypto_select = u32::from_be_bytes(msg[..4].try_into().unwrap());
+    let pad_len = u16::from_be_bytes(msg[4..].try_into().unwrap()) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(PeerError::EncryptionHandshake);
+    }
+    let mut pad = read_exact(&mut socket, &mut buf, pad_len).await?;
+    decryptor.apply(&mut pad);
+
+    // the peer must have selected exactly one of the methods we provided
+    if crypto_select == CRYPTO_RC4 {
+        decryptor.apply(&mut buf);
+        Ok(CryptoStream::encrypted(socket, buf, encryptor, decryptor))
+    } else if crypto_select == CRYPTO_PLAINTEXT
+        && crypto_provide & CRYPTO_PLAINTEXT != 0
+    {
+        Ok(CryptoStream::plaintext(socket, buf))
+    } else {
+        Err(PeerError::EncryptionHandshake)
+    }
+}
+
+/// Performs the encrypted handshake as the receiver of the connection.
+///
+/// The peer may not be using encryption, which is detected by the first bytes
+/// it sends being the start of the BitTorrent handshake. Whether this is
+/// accepted depends on the policy: plaintext peers are rejected if encryption
+/// is forced, and encrypted peers are not recognized if encryption is
+/// disabled.
+///
+/// The peer may want any of the given info hashes, as hybrid torrents are in
+/// two swarms.
+pub(crate) async fn handshake_inbound<S>(
+    mut socket: S,
+    info_hashes: &[Sha1Hash],
+    policy: EncryptionPolicy,
+) -> Result<CryptoStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::new();
+
+    // the BitTorrent handshake starts with the length prefixed protocol
+    // string, which is what a plaintext peer sends first
+    fill(&mut socket, &mut buf, 1 + PROTOCOL_STRING.len()).await?;
+    let is_plaintext = buf[0] as usize == PROTOCOL_STRING.len()
+        && &buf[1..1 + PROTOCOL_STRING.len()] == PROTOCOL_STRING.as_bytes();
+    if is_plaintext || policy == EncryptionPolicy::Disabled {
+        if policy == EncryptionPolicy::Forced {
+            return Err(PeerError::EncryptionRequired);
+        }
+        return Ok(CryptoStream::plaintext(socket, buf));
+    }
+
+    // 1. A->B: Diffie Hellman Ya, PadA
+    let peer_public = read_exact(&mut socket, &mut buf, KEY_LEN).await?;
+
+    // 2. B->A: Diffie Hellman Yb, PadB
+    let keys = KeyPair::new();
+    let secret = keys.shared_secret(&peer_public);
+    let mut msg = keys.public.to_vec();
+    msg.extend_from_slice(&random_pad());
+    socket.write_all(&msg).await?;
+
+    // 3. A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S),
+    // ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA)), ENCRYPT(IA)
+    //
+    // PadA is of unknown length, so we need to look for the first hash to
+    // know where the rest of the message begins.
+    let req1 = hash(&[b"req1", &secret]);
+    sync(&mut socket, &mut buf, &req1, MAX_PAD_LEN).await?;
+    let peer_skey_hash = read_exact(&mut socket, &mut buf, 20).await?;
+    // each torrent has its own listener so we only need to check against our
+    // own info hashes
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| peer_skey_hash[..] == skey_hash(info_hash, &secret))
+        .ok_or(PeerError::InvalidInfoHash)?;
+
+    let mut encryptor = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+    let mut decryptor = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut msg = read_exact(&mut socket, &mut buf, 8 + 4 + 2).await?;
+    decryptor.apply(&mut msg);
+    if msg[..8] != VC {
+        return Err(PeerError::EncryptionHandshake);
+    }
+    let crypto_provide = u32::from_be_bytes(msg[8..12].try_into().unwrap());
+    let pad_len = u16::from_be_bytes(msg[12..].try_into().unwrap()) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(PeerError::EncryptionHandshake);
+    }
+    let mut msg = read_exact(&mut socket, &mut buf, pad_len + 2).await?;
+    decryptor.apply(&mut msg);
+    let ia_len = u16::from_be_bytes(msg[pad_len..].try_into().unwrap());
+    // the initial payload is usually the peer's BitTorrent handshake
+    let mut ia = read_exact(&mut socket, &mut buf, ia_len as usize).await?;
+    decryptor.apply(&mut ia);
+
+    // prefer RC4, as that's what the peer is most likely after
+    let crypto_select = if crypto_provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if crypto_provide & CRYPTO_PLAINTEXT != 0
+        && policy != EncryptionPolicy::Forced
+    {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(PeerError::EncryptionRequired);
+    };
+
+    // 4. B->A: ENCRYPT(VC, crypto_select, len(padD), padD)
+    let mut msg = VC.to_vec();
+    msg.extend_from_slice(&crypto_select.to_be_bytes());
+    msg.extend_from_slice(&0u16.to_be_bytes());
+    encryptor.apply(&mut msg);
+    socket.write_all(&msg).await?;
+
+    // the rest of what the peer sent is the start of the payload stream,
+    // which follows the initial payload
+    if crypto_select == CRYPTO_RC4 {
+        decryptor.apply(&mut buf);
+        ia.unsplit(buf);
+        Ok(CryptoStream::encrypted(socket, ia, encryptor, decryptor))
+    } else {
+        ia.unsplit(buf);
+        Ok(CryptoStream::plaintext(socket, ia))
+    }
+}
+
+/// A Diffie-Hellman key pair.
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    /// Generates a new key pair with a random 160 bit private key.
+    fn new() -> Self {
+        let private =
+            BigUint::from_bytes_be(&rand::thread_rng().gen::<[u8; 20]>());
+        let public = BigUint::from(GENERATOR).modpow(&private, &PRIME);
+        Self {
+            private,
+            public: to_key_bytes(&public),
+        }
+    }
+
+    /// Returns the secret shared with the owner of the other public key.
+    fn shared_secret(&self, peer_public: &[u8]) -> [u8; KEY_LEN] {
+        let peer_public = BigUint::from_bytes_be(peer_public);
+        to_key_bytes(&peer_public.modpow(&self.private, &PRIME))
+    }
+}
+
+/// Encodes the number as a big endian, zero padded key.
+fn to_key_bytes(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    debug_assert!(bytes.len() <= KEY_LEN);
+    let mut key = [0; KEY_LEN];
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+/// Returns the SHA-1 hash of the concatenation of the parts.
+fn hash(parts: &[&[u8]]) -> Sha1Hash {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Returns `HASH('req2', SKEY) xor HASH('req3', S)`, with which the
+/// initiator tells the receiver which torrent it wants, without revealing the
+/// info hash to observers.
+fn skey_hash(info_hash: &Sha1Hash, secret: &[u8]) -> Sha1Hash {
+    let mut req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", secret]);
+    for (a, b) in req2.iter_mut().zip(req3.iter()) {
+        *a ^= b;
+    }
+    req2
+}
+
+/// Returns random bytes of random length, to be used as the padding after the
+/// public key.
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0..=MAX_PAD_LEN);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+/// Reads from the socket into the buffer until the buffer has at least `len`
+/// bytes.
+async fn fill<S: AsyncRead + Unpin>(
+    socket: &mut S,
+    buf: &mut BytesMut,
+    len: usize,
+) -> Result<()> {
+    while buf.len() < len {
+        buf.reserve(len - buf.len());
+        if socket.read_buf(buf).await? == 0 {
+            return Err(PeerError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+    }
+    Ok(())
+}
+
+/// Reads exactly `len` bytes, first from the buffer and then from the socket.
+async fn read_exact<S: AsyncRead + Unpin>(
+    socket: &mut S,
+    buf: &mut BytesMut,
+    len: usize,
+) -> Result<BytesMut> {
+    fill(socket, buf, len).await?;
+    Ok(buf.split_to(len))
+}
+
+/// Reads until the pattern is found within `max_skip` bytes, and consumes
+/// everything up to and including the pattern.
+async fn sync<S: AsyncRead + Unpin>(
+    socket: &mut S,
+    buf: &mut BytesMut,
+    pattern: &[u8],
+    max_skip: usize,
+) -> Result<()> {
+    let mut len = pattern.len();
+    loop {
+        fill(socket, buf, len).await?;
+        if let Some(pos) = buf.windows(pattern.len()).position(|w| w == pattern)
+        {
+            buf.advance(pos + pattern.len());
+            return Ok(());
+        }
+        if buf.len() >= max_skip + pattern.len() {
+            return Err(PeerError::EncryptionHandshake);
+        }
+        len = buf.len() + 1;
+    }
+}
+
+/// The RC4 stream cipher.
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    /// Creates the cipher from the key, and discards the first 1024 bytes of
+    /// its key stream, as required by MSE.
+    fn new(key: &[u8]) -> Self {
+        let mut rc4 = Self::with_key(key);
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    /// Creates the cipher from the key.
+    fn with_key(key: &[u8]) -> Self {
+        let mut state = [0; 256];
+        for (i, s) in state.iter_mut().enumerate() {
+            *s = i as u8;
+        }
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// Encrypts or decrypts the buffer in place.
+    fn apply(&mut self, buf: &mut [u8]) {
+        for b in buf.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state[self.i as usize]
+                .wrapping_add(self.state[self.j as usize]);
+            *b ^= self.state[k as usize];
+        }
+    }
+}
+
+/// The ciphers of an encrypted stream.
+struct Ciphers {
+    encryptor: Rc4,
+    decryptor: Rc4,
+}
+
+/// A socket that is either RC4 encrypted or plaintext, depending on the
+/// outcome of the handshake.
+pub(crate) struct CryptoStream<S> {
+    socket: S,
+    /// The ciphers, if the stream is encrypted.
+    ciphers: Option<Ciphers>,
+    /// Already decrypted payload that was read from the socket during the
+    /// handshake. This is returned before reading from the socket again.
+    read_buf: BytesMut,
+    /// Encrypted bytes that could not yet be written to the socket.
+    ///
+    /// Since the encryptor's state advances with each byte encrypted, all
+    /// bytes that are encrypted must be written out before encrypting more.
+    write_buf: BytesMut,
+}
+
+impl<S> CryptoStream<S> {
+    /// Creates a stream that doesn't encrypt anything, for when encryption is
+    /// not used or the peer chose plaintext.
+    pub fn plaintext(socket: S, read_buf: BytesMut) -> Self {
+        Self {
+            socket,
+            ciphers: None,
+            read_buf,
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    fn encrypted(
+        socket: S,
+        read_buf: BytesMut,
+        encryptor: Rc4,
+        decryptor: Rc4,
+    ) -> Self {
+        Self {
+            socket,
+            ciphers: Some(Ciphers {
+                encryptor,
+                decryptor,
+            }),
+            read_buf,
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    /// Returns whether the stream is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.ciphers.is_some()
+    }
+
+    /// Returns the underlying socket.
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+}
+
+impl<S: AsyncWrite + Unpin> CryptoStream<S> {
+    /// Writes out all pending encrypted bytes.
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(
+                Pin::new(&mut self.socket).poll_write(cx, &self.write_buf)
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CryptoStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.read_buf.is_empty() {
+            let n = buf.len().min(this.read_buf.len());
+            buf[..n].copy_from_slice(&this.read_buf[..n]);
+            this.read_buf.advance(n);
+            return Poll::Ready(Ok(n));
+        }
+        let n = ready!(Pin::new(&mut this.socket).poll_read(cx, buf))?;
+        if let Some(ciphers) = &mut this.ciphers {
+            ciphers.decryptor.apply(&mut buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CryptoStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.ciphers.is_none() {
+            return Pin::new(&mut this.socket).poll_write(cx, buf);
+        }
+
+        ready!(this.poll_write_buf(cx))?;
+        let start = this.write_buf.len();
+        this.write_buf.extend_from_slice(buf);
+        if let Some(ciphers) = &mut this.ciphers {
+            ciphers.encryptor.apply(&mut this.write_buf[start..]);
+        }
+        // Try to write the bytes right away. Whatever is not written now is
+        // written on the next write or flush, and we've registered to be woken
+        // up when that's possible.
+        if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.socket).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.socket).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn test_rc4() {
+        // test vectors from https://en.wikipedia.org/wiki/RC4#Test_vectors
+        let mut rc4 = Rc4::with_key(b"Key");
+        let mut buf = b"Plaintext".to_vec();
+        rc4.apply(&mut buf);
+        assert_eq!(buf, hex::decode("bbf316e8d940af0ad3").unwrap());
+
+        let mut rc4 = Rc4::with_key(b"Secret");
+        let mut buf = b"Attack at dawn".to_vec();
+        rc4.apply(&mut buf);
+        assert_eq!(buf, hex::decode("45a01f645fc35b383552544b9bf5").unwrap());
+    }
+
+    #[test]
+    fn test_shared_secret() {
+        let a = KeyPair::new();
+        let b = KeyPair::new();
+        assert_eq!(a.shared_secret(&b.public), b.shared_secret(&a.public));
+    }
+
+    /// Returns a connected pair of TCP sockets.
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let mut listener =
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (outbound, inbound) =
+            futures::join!(TcpStream::connect(addr), listener.accept());
+        (outbound.unwrap(), inbound.unwrap().0)
+    }
+
+    /// Runs the handshake with the given policies on both sides and returns
+    /// the resulting streams.
+    async fn handshake(
+        outbound_policy: EncryptionPolicy,
+        inbound_policy: EncryptionPolicy,
+    ) -> (
+        Result<CryptoStream<TcpStream>>,
+        Result<CryptoStream<TcpStream>>,
+    ) {
+        let info_hashes = [[5; 20]];
+        let (outbound, inbound) = socket_pair().await;
+        futures::join!(
+            handshake_outbound(outbound, info_hashes[0], outbound_policy),
+            handshake_inbound(inbound, &info_hashes, inbound_policy),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_stream() {
+        for (outbound_policy, inbound_policy) in &[
+            (EncryptionPolicy::Enabled, EncryptionPolicy::Enabled),
+            (EncryptionPolicy::Forced, EncryptionPolicy::Enabled),
+            (EncryptionPolicy::Enabled, EncryptionPolicy::Forced),
+        ] {
+            let (outbound, inbound) =
+                handshake(*outbound_policy, *inbound_policy).await;
+            let mut outbound = outbound.unwrap();
+            let mut inbound = inbound.unwrap();
+            assert!(outbound.is_encrypted());
+            assert!(inbound.is_encrypted());
+
+            // exchange data in both directions
+            let msg = (0..100_000).map(|i| i as u8).collect::<Vec<_>>();
+            let mut received = vec![0; msg.len()];
+            let (write_result, read_result) = futures::join!(
+                async {
+                    outbound.write_all(&msg).await?;
+                    outbound.flush().await
+                },
+                inbound.read_exact(&mut received),
+            );
+            write_result.unwrap();
+            read_result.unwrap();
+            assert_eq!(received, msg);
+
+            inbound.write_all(b"pong").await.unwrap();
+            inbound.flush().await.unwrap();
+            let mut received = [0; 4];
+            outbound.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"pong");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_inbound() {
+        let (mut outbound, inbound) = socket_pair().await;
+        let mut handshake = vec![PROTOCOL_STRING.len() as u8];
+        handshake.extend_from_slice(PROTOCOL_STRING.as_bytes());
+        handshake.extend_from_slice(&[0; 48]);
+        outbound.write_all(&handshake).await.unwrap();
+
+        // a plaintext peer is accepted if encryption is not forced, and the
+        // bytes read while detecting that are not lost
+        let mut inbound =
+            handshake_inbound(inbound, &[[5; 20]], EncryptionPolicy::Enabled)
+                .await
+                .unwrap();
+        assert!(!inbound.is_encrypted());
+        let mut received = vec![0; handshake.len()];
+        inbound.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, handshake);
+
+        let (mut outbound, inbound) = socket_pair().await;
+        outbound.write_all(&handshake).await.unwrap();
+        assert!(matches!(
+            handshake_inbound(inbound, &[[5; 20]], EncryptionPolicy::Forced)
+                .await,
+            Err(PeerError::EncryptionRequired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_info_hash() {
+        let (outbound, inbound) = socket_pair().await;
+        let (_, inbound) = futures::join!(
+            tokio::time::timeout(
+                Duration::from_secs(1),
+                handshake_outbound(
+                    outbound,
+                    [1; 20],
+                    EncryptionPolicy::Enabled
+                ),
+            ),
+            handshake_inbound(inbound, &[[2; 20]], EncryptionPolicy::Enabled),
+        );
+        assert!(matches!(inbound, Err(PeerError::InvalidInfoHash)));
+    }
+
+    #[tokio::test]
+    async fn test_inbound_any_info_hash() {
+        // a hybrid torrent accepts peers from both its swarms
+        let (outbound, inbound) = socket_pair().await;
+        let (outbound, inbound) = futures::join!(
+            handshake_outbound(outbound, [2; 20], EncryptionPolicy::Enabled),
+            handshake_inbound(
+                inbound,
+                &[[1; 20], [2; 20]],
+                EncryptionPolicy::Enabled
+            ),
+        );
+        assert!(outbound.unwrap().is_encrypted());
+        assert!(inbound.unwrap().is_encrypted());
+    }
+}
@@ -60,6 +60,30 @@ impl Default for ConnectionState {
     }
 }
 
+/// Why a peer session ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DisconnectReason {
+    /// We stopped the session, because the torrent is stopping or the peer was
+    /// banned or filtered.
+    Shutdown,
+    /// The connection with the peer couldn't be established.
+    ConnectFailed,
+    /// The connection was established but the handshake failed: the peer
+    /// closed the connection, sent an invalid handshake or info hash, or the
+    /// two sides couldn't agree on encryption.
+    HandshakeFailed,
+    /// The handshake took too long, or neither side became interested in the
+    /// other in time.
+    Timeout,
+    /// Neither side had any pieces, so there was nothing to exchange.
+    NoPieces,
+    /// The peer sent a message that is invalid or not allowed at that point
+    /// of the session.
+    ProtocolViolation,
+    /// The connection failed after the handshake.
+    ConnectionLost,
+}
+
 /// Holds and provides facilities to modify the state of a peer session.
 #[derive(Default)]
 pub(super) struct SessionContext {
@@ -1,6 +1,14 @@
-use std::{ops::Range, path::PathBuf};
-
-use crate::{metainfo::Metainfo, FileIndex, PieceIndex};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::PathBuf,
+};
+
+use crate::{
+    conf::PathRules,
+    metainfo::{FileAttrs, Metainfo, Symlink},
+    FileIndex, PieceIndex,
+};
 
 /// Information about a torrent's file.
 #[derive(Clone, Debug)]
@@ -98,24 +106,75 @@ pub struct StorageInfo {
     pub download_dir: PathBuf,
     /// All files in torrent.
     pub files: Vec<FileInfo>,
+    /// The attributes of each file, in the same order as `files`.
+    pub file_attrs: Vec<FileAttrs>,
+    /// The symlinks in torrent, which are created alongside the files.
+    pub symlinks: Vec<Symlink>,
+    /// Whether each file starts at a piece boundary, as is the case in v2
+    /// torrents. No piece then spans multiple files, and the last piece of
+    /// each file may be shorter than the nominal piece length.
+    pub is_file_aligned: bool,
+    /// The length of the torrent in the v1 view of a hybrid torrent, in which
+    /// each file is followed by padding up to the next piece boundary. This is
+    /// what v1 peers see, and it's only set for hybrid torrents.
+    pub padded_len: Option<u64>,
 }
 
 impl StorageInfo {
     /// Extracts storage related information from the torrent metainfo.
-    pub fn new(metainfo: &Metainfo, download_dir: PathBuf) -> Self {
+    ///
+    /// The names of the torrent's files and directories are sanitized with
+    /// the path rules. Names that end up the same as another one in the same
+    /// directory once sanitized, such as `a*b` and `a_b`, are numbered so that
+    /// each file is still stored separately.
+    pub fn new(
+        metainfo: &Metainfo,
+        download_dir: PathBuf,
+        path_rules: &PathRules,
+    ) -> Self {
         let piece_count = metainfo.piece_count();
         let download_len = metainfo.download_len();
         let piece_len = metainfo.piece_len;
+        // files may not be contiguous if they are aligned to pieces, so the
+        // last piece's length is determined by where the last file ends,
+        // ignoring empty files, which start past the last piece
+        let torrent_end_offset = metainfo
+            .files
+            .iter()
+            .rev()
+            .find(|f| f.len > 0)
+            .map(|f| f.torrent_end_offset())
+            .unwrap_or_default();
         let last_piece_len =
-            download_len - piece_len as u64 * (piece_count - 1) as u64;
+            torrent_end_offset - piece_len as u64 * (piece_count - 1) as u64;
         let last_piece_len = last_piece_len as u32;
 
         // if this is an archive, download files into torrent's own dir
         let download_dir = if metainfo.is_archive() {
-            download_dir.join(&metainfo.name)
+            download_dir.join(sanitize_name(&metainfo.name, path_rules))
         } else {
             download_dir
         };
+        debug_assert_eq!(metainfo.files.len(), metainfo.file_paths.len());
+        debug_assert_eq!(metainfo.symlinks.len(), metainfo.symlink_paths.len());
+        let mut paths = PathSanitizer::new(path_rules);
+        let files = metainfo
+            .files
+            .iter()
+            .zip(metainfo.file_paths.iter())
+            .map(|(file, path)| FileInfo {
+                path: paths.sanitize(path),
+                ..file.clone()
+            })
+            .collect();
+        let symlinks = metainfo
+            .symlink_paths
+            .iter()
+            .map(|(path, target)| Symlink {
+                path: paths.sanitize(path),
+                target: paths.sanitize(target),
+            })
+            .collect();
 
         Self {
             piece_count,
@@ -123,7 +182,11 @@ impl StorageInfo {
             last_piece_len,
             download_len,
             download_dir,
-            files: metainfo.files.clone(),
+            files,
+            file_attrs: metainfo.file_attrs.clone(),
+            symlinks,
+            is_file_aligned: metainfo.is_v2(),
+            padded_len: metainfo.padded_len,
         }
     }
 
@@ -223,10 +286,161 @@ impl StorageInfo {
         assert!(index < self.piece_count, "piece index out of range");
         if index == self.piece_count - 1 {
             self.last_piece_len
+        } else if self.is_file_aligned {
+            // the piece may be the last one in its file
+            let offset = self.torrent_piece_offset(index);
+            let file = &self.files
+                [self.files_intersecting_bytes(offset..offset + 1).start];
+            (self.piece_len as u64).min(file.torrent_end_offset() - offset)
+                as u32
         } else {
             self.piece_len
         }
     }
+
+    /// Returns the length of the piece at the given index in the v1 view of
+    /// a hybrid torrent, in which a piece ending a file is padded with zeros
+    /// up to the next piece boundary.
+    ///
+    /// For other torrents this is the same as [`Self::piece_len`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the piece index is invalid.
+    pub fn padded_piece_len(&self, index: PieceIndex) -> u32 {
+        match self.padded_len {
+            Some(padded_len) => {
+                assert!(index < self.piece_count, "piece index out of range");
+                let offset = self.torrent_piece_offset(index);
+                (self.piece_len as u64).min(padded_len - offset) as u32
+            }
+            None => self.piece_len(index),
+        }
+    }
+}
+
+/// Makes the paths of a torrent's files valid paths, without letting two
+/// different paths become the same.
+struct PathSanitizer<'a> {
+    rules: &'a PathRules,
+    /// The sanitized paths of the paths seen so far and of their directories,
+    /// keyed by their components.
+    paths: HashMap<Vec<String>, PathBuf>,
+    /// The sanitized paths that are in use.
+    taken: HashSet<PathBuf>,
+}
+
+impl<'a> PathSanitizer<'a> {
+    fn new(rules: &'a PathRules) -> Self {
+        Self {
+            rules,
+            paths: HashMap::new(),
+            taken: HashSet::new(),
+        }
+    }
+
+    /// Returns the path joined from its components, each of which is made
+    /// a valid file name. A name that is the same as that of a different file
+    /// or directory seen before, once sanitized, is numbered.
+    ///
+    /// The same components always result in the same path, so that symlinks
+    /// point to the sanitized paths of their targets.
+    fn sanitize(&mut self, path: &[String]) -> PathBuf {
+        let mut sanitized = PathBuf::new();
+        for (i, component) in path.iter().enumerate() {
+            let prefix = &path[..=i];
+            if let Some(path) = self.paths.get(prefix) {
+                sanitized = path.clone();
+                continue;
+            }
+            let name = sanitize_name(component, self.rules);
+            let mut candidate = sanitized.join(&name);
+            let mut n = 1;
+            while self.taken.contains(&candidate) {
+                let name = numbered_name(&name, n, self.rules.max_name_len);
+                candidate = sanitized.join(name);
+                n += 1;
+            }
+            self.taken.insert(candidate.clone());
+            self.paths.insert(prefix.to_vec(), candidate.clone());
+            sanitized = candidate;
+        }
+        sanitized
+    }
+}
+
+/// Returns the name made a valid file name according to the rules.
+fn sanitize_name(name: &str, rules: &PathRules) -> String {
+    let mut name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || rules.invalid_chars.contains(&c) {
+                rules.replacement
+            } else {
+                c
+            }
+        })
+        .collect();
+    if rules.rename_reserved_names && is_reserved_name(&name) {
+        name.insert(0, rules.replacement);
+    }
+    if name.len() > rules.max_name_len {
+        name = shorten_name(&name, rules.max_name_len);
+    }
+    name
+}
+
+/// Returns true if the name is that of a device on Windows, regardless of
+/// its extension.
+fn is_reserved_name(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name);
+    let stem = stem.trim_end().to_ascii_uppercase();
+    match stem.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" => true,
+        _ => {
+            (stem.starts_with("COM") || stem.starts_with("LPT"))
+                && stem.len() == 4
+                && matches!(stem.as_bytes()[3], b'1'..=b'9')
+        }
+    }
+}
+
+/// Shortens the name to at most the given length in bytes, keeping its
+/// extension if it's short, so that the file's type is still recognized.
+fn shorten_name(name: &str, max_len: usize) -> String {
+    let ext = match name.rfind('.') {
+        Some(pos)
+            if pos > 0
+                && name.len() - pos <= 16.min(max_len.saturating_sub(1)) =>
+        {
+            &name[pos..]
+        }
+        _ => "",
+    };
+    let mut stem_len = max_len - ext.len();
+    while !name.is_char_boundary(stem_len) {
+        stem_len -= 1;
+    }
+    format!("{}{}", &name[..stem_len], ext)
+}
+
+/// Returns the name with the number appended to its stem, which is shortened
+/// if needed so that the name is at most the given length in bytes.
+fn numbered_name(name: &str, n: usize, max_len: usize) -> String {
+    let suffix = format!("_{}", n);
+    let (stem, ext) = match name.rfind('.') {
+        Some(pos) if pos > 0 && name.len() - pos + suffix.len() < max_len => {
+            name.split_at(pos)
+        }
+        _ => (name, ""),
+    };
+    let mut stem_len = stem
+        .len()
+        .min(max_len.saturating_sub(suffix.len() + ext.len()));
+    while !stem.is_char_boundary(stem_len) {
+        stem_len -= 1;
+    }
+    format!("{}{}{}", &stem[..stem_len], suffix, ext)
 }
 
 #[cfg(test)]
@@ -320,7 +534,11 @@ mod tests {
             last_piece_len,
             download_len,
             download_dir: PathBuf::from("/"),
+            file_attrs: vec![FileAttrs::default(); files.len()],
             files,
+            symlinks: Vec::new(),
+            is_file_aligned: false,
+            padded_len: None,
         };
         // all 4 pieces are in the same file
         assert_eq!(info.files_intersecting_piece(0), 0..1);
@@ -400,7 +618,11 @@ mod tests {
             last_piece_len,
             download_len,
             download_dir: PathBuf::from("/"),
+            file_attrs: vec![FileAttrs::default(); files.len()],
             files,
+            symlinks: Vec::new(),
+            is_file_aligned: false,
+            padded_len: None,
         };
         // piece 0 intersects with files 0 and 1
         assert_eq!(info.files_intersecting_piece(0), 0..2);
@@ -414,6 +636,54 @@ mod tests {
         assert_eq!(info.files_intersecting_piece(4), 6..7);
     }
 
+    #[test]
+    fn test_file_aligned_pieces() {
+        // files: (index:first byte offset,last byte offset)
+        // --------------------------------------------------------------------
+        // |0:0,39                                  |pad  |1:48,57  |
+        // --------------------------------------------------------------------
+        // the first file's last piece is shorter as it ends before the piece
+        // boundary at which the next file starts
+        let files = vec![
+            FileInfo {
+                path: PathBuf::from("/0"),
+                torrent_offset: 0,
+                len: 40,
+            },
+            FileInfo {
+                path: PathBuf::from("/1"),
+                torrent_offset: 48,
+                len: 10,
+            },
+        ];
+        let mut info = StorageInfo {
+            piece_count: 4,
+            piece_len: 16,
+            last_piece_len: 10,
+            download_len: 50,
+            download_dir: PathBuf::from("/"),
+            file_attrs: vec![FileAttrs::default(); files.len()],
+            files,
+            symlinks: Vec::new(),
+            is_file_aligned: true,
+            padded_len: Some(64),
+        };
+        assert_eq!(info.piece_len(0), 16);
+        assert_eq!(info.piece_len(1), 16);
+        assert_eq!(info.piece_len(2), 8);
+        assert_eq!(info.piece_len(3), 10);
+        assert_eq!(info.files_intersecting_piece(2), 0..1);
+        assert_eq!(info.files_intersecting_piece(3), 1..2);
+        assert_eq!(info.torrent_piece_offset(3), 48);
+
+        // v1 peers of a hybrid torrent see the padding at the end of pieces
+        assert_eq!(info.padded_piece_len(2), 16);
+        assert_eq!(info.padded_piece_len(3), 16);
+        info.padded_len = None;
+        assert_eq!(info.padded_piece_len(2), 8);
+        assert_eq!(info.padded_piece_len(3), 10);
+    }
+
     #[test]
     fn test_files_intersecting_bytes() {
         let download_len = 12341234;
@@ -429,7 +699,11 @@ mod tests {
             last_piece_len: 2,
             download_len,
             download_dir: PathBuf::from("/"),
+            file_attrs: vec![FileAttrs::default(); files.len()],
             files,
+            symlinks: Vec::new(),
+            is_file_aligned: false,
+            padded_len: None,
         };
         assert_eq!(info.files_intersecting_bytes(0..0), 0..1);
         assert_eq!(info.files_intersecting_bytes(0..1), 0..1);
@@ -466,7 +740,11 @@ mod tests {
             last_piece_len: 2,
             download_len,
             download_dir: PathBuf::from("/"),
+            file_attrs: vec![FileAttrs::default(); files.len()],
             files,
+            symlinks: Vec::new(),
+            is_file_aligned: false,
+            padded_len: None,
         };
 
         // bytes only in the first file
@@ -492,4 +770,134 @@ mod tests {
         // bytes not intersecting any files
         assert_eq!(info.files_intersecting_bytes(30..38), 0..0);
     }
+
+    #[test]
+    fn test_sanitize_name() {
+        let rules = PathRules::default();
+        assert_eq!(sanitize_name("file.txt", &rules), "file.txt");
+        assert_eq!(sanitize_name("a:b*c?.txt", &rules), "a_b_c_.txt");
+        assert_eq!(sanitize_name("tab\there\n", &rules), "tab_here_");
+
+        // reserved names are renamed regardless of case and extension
+        assert_eq!(sanitize_name("CON", &rules), "_CON");
+        assert_eq!(sanitize_name("nul.tar.gz", &rules), "_nul.tar.gz");
+        assert_eq!(sanitize_name("Lpt9.txt", &rules), "_Lpt9.txt");
+        assert_eq!(sanitize_name("LPT0", &rules), "LPT0");
+        assert_eq!(sanitize_name("console", &rules), "console");
+
+        // long names are shortened at character boundaries, keeping their
+        // extension
+        let rules = PathRules {
+            max_name_len: 10,
+            ..PathRules::default()
+        };
+        assert_eq!(sanitize_name("abcdefghijkl.txt", &rules), "abcdef.txt");
+        assert_eq!(sanitize_name("abcdefghijkl", &rules), "abcdefghij");
+        assert_eq!(sanitize_name("aéééééé.txt", &rules), "aéé.txt");
+        assert_eq!(
+            sanitize_name("abc.verylongextension", &rules),
+            "abc.verylo"
+        );
+
+        let rules = PathRules {
+            replacement: '-',
+            invalid_chars: vec!['#'],
+            rename_reserved_names: false,
+            ..PathRules::default()
+        };
+        assert_eq!(sanitize_name("a#b:c", &rules), "a-b:c");
+        assert_eq!(sanitize_name("con", &rules), "con");
+
+        // the shortest allowed names still fit any character
+        let rules = PathRules {
+            max_name_len: 4,
+            ..PathRules::default()
+        };
+        assert_eq!(sanitize_name("a.txt", &rules), "a.tx");
+        assert_eq!(sanitize_name("😀😀", &rules), "😀");
+        assert_eq!(shorten_name("a.b", 1), "a");
+    }
+
+    #[test]
+    fn test_path_rules_is_valid() {
+        assert!(PathRules::default().is_valid());
+        let invalid = [
+            PathRules {
+                max_name_len: 3,
+                ..PathRules::default()
+            },
+            PathRules {
+                replacement: ':',
+                ..PathRules::default()
+            },
+            PathRules {
+                replacement: '/',
+                ..PathRules::default()
+            },
+            PathRules {
+                replacement: '\0',
+                ..PathRules::default()
+            },
+        ];
+        for rules in invalid.iter() {
+            assert!(!rules.is_valid(), "{:?}", rules);
+        }
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        fn path(components: &[&str]) -> Vec<String> {
+            components.iter().map(|c| c.to_string()).collect()
+        }
+
+        let rules = PathRules::default();
+        let mut paths = PathSanitizer::new(&rules);
+        assert_eq!(
+            paths.sanitize(&path(&["dir*1", "aux", "file?"])),
+            PathBuf::from("dir_1/_aux/file_")
+        );
+        // the same directory
+        assert_eq!(
+            paths.sanitize(&path(&["dir*1", "aux", "other"])),
+            PathBuf::from("dir_1/_aux/other")
+        );
+        assert_eq!(
+            paths.sanitize(&path(&["dir*1", "aux", "file?"])),
+            PathBuf::from("dir_1/_aux/file_")
+        );
+
+        // names that become the same as other names are numbered, whether they
+        // are of files or of directories
+        assert_eq!(
+            paths.sanitize(&path(&["dir*1", "aux", "file_"])),
+            PathBuf::from("dir_1/_aux/file__1")
+        );
+        assert_eq!(
+            paths.sanitize(&path(&["dir*1", "aux", "file|"])),
+            PathBuf::from("dir_1/_aux/file__2")
+        );
+        assert_eq!(
+            paths.sanitize(&path(&["dir?1", "file"])),
+            PathBuf::from("dir_1_1/file")
+        );
+        assert_eq!(
+            paths.sanitize(&path(&["a*b.txt"])),
+            PathBuf::from("a_b.txt")
+        );
+        assert_eq!(
+            paths.sanitize(&path(&["a_b.txt"])),
+            PathBuf::from("a_b_1.txt")
+        );
+    }
+
+    #[test]
+    fn test_numbered_name() {
+        assert_eq!(numbered_name("file.txt", 1, 255), "file_1.txt");
+        assert_eq!(numbered_name(".hidden", 2, 255), ".hidden_2");
+        // the stem is shortened to fit the number, and the extension is
+        // dropped if there is no room for it
+        assert_eq!(numbered_name("abcdefgh.txt", 10, 10), "abc_10.txt");
+        assert_eq!(numbered_name("ééé.txt", 1, 8), "é_1.txt");
+        assert_eq!(numbered_name("abc.txt", 1, 5), "abc_1");
+    }
 }
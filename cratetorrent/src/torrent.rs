@@ -1,16 +1,17 @@
 use std::{
     collections::HashMap,
-    net::SocketAddr,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use futures::{
-    select,
+    future, select,
     stream::{Fuse, StreamExt},
 };
+use rand::seq::SliceRandom;
+use reqwest::Url;
 use tokio::{
-    net::{TcpListener, TcpStream},
     sync::{
         mpsc::{self, UnboundedReceiver, UnboundedSender},
         RwLock,
@@ -20,7 +21,8 @@ use tokio::{
 
 use crate::{
     alert::{Alert, AlertSender},
-    conf::TorrentConf,
+    conf::{EncryptionPolicy, TorrentConf, TransportPolicy},
+    conn_limiter::{self, ConnectionLimiter},
     counter::ThruputCounters,
     disk::{
         self,
@@ -28,16 +30,32 @@ use crate::{
     },
     download::PieceDownload,
     error::Error,
-    peer::{self, ConnectionState, PeerSession, SessionState, SessionTick},
+    ip_filter::SharedIpFilter,
+    lsd,
+    merkle::MerkleTree,
+    net,
+    peer::{
+        self, ConnectionState, DisconnectReason, PeerSession, SessionState,
+        SessionTick, Transport,
+    },
     piece_picker::PiecePicker,
     storage_info::StorageInfo,
-    tracker::{Announce, Event, Tracker},
+    tracker::{self, Announce, Event, Response, Scrape, Tracker, TrackerError},
+    utp::UtpSocket,
+    web_seed::{self, WebSeedSession},
     Bitfield, BlockInfo, PeerId, PieceIndex, Sha1Hash, TorrentId,
 };
+use choker::{Candidate, Choker};
 use error::*;
-use stats::{Peers, PieceStats, ThruputStats, TorrentStats};
+use peer_list::PeerList;
+use stats::{
+    PeerSource, Peers, PieceStats, ThruputStats, TorrentStats, TrackerStats,
+    TrackerStatus, WebSeedStats,
+};
 
+mod choker;
 pub mod error;
+mod peer_list;
 pub mod stats;
 
 /// The channel for communicating with torrent.
@@ -60,10 +78,71 @@ pub(crate) enum Command {
         error: ReadError,
     },
     /// A message sent only once, after the peer has been connected.
-    PeerConnected { addr: SocketAddr, id: PeerId },
-    /// Peer sessions periodically send this message when they have a state
-    /// change.
+    PeerConnected {
+        addr: SocketAddr,
+        id: PeerId,
+        transport: Transport,
+    },
+    /// Peer sessions send this message with each of their ticks, so that
+    /// torrent knows their state and their current transfer rates.
     PeerState { addr: SocketAddr, info: SessionTick },
+    /// Peer sessions send this when they end, with their final state.
+    PeerDisconnected {
+        addr: SocketAddr,
+        reason: DisconnectReason,
+        info: SessionTick,
+    },
+    /// Peers discovered by means other than the torrent's trackers, such as
+    /// local service discovery, that the torrent may connect to.
+    AddPeers {
+        peers: Vec<SocketAddr>,
+        /// The info hash of the swarm in which the peers were found.
+        info_hash: Sha1Hash,
+        /// How the peers were found.
+        source: PeerSource,
+    },
+    /// Web seed sessions send this after each downloaded piece, with the
+    /// number of bytes that were new to us and the number of bytes that had
+    /// already been downloaded from others.
+    WebSeedTransfer {
+        id: usize,
+        downloaded: u64,
+        wasted: u64,
+    },
+    /// Sent when a web seed session stopped, either because there was nothing
+    /// left to download from it, or due to an error.
+    WebSeedStopped { id: usize, is_error: bool },
+    /// Start the torrent, if it's queued.
+    Start,
+    /// Scrape all trackers of the torrent now, regardless of when they were
+    /// last scraped.
+    Scrape,
+    /// Announce to the tracker with the URL, or to all trackers, now.
+    ///
+    /// Unless forced, the trackers' minimum announce intervals are respected.
+    Announce {
+        tracker_url: Option<Url>,
+        force: bool,
+    },
+    /// Add the tracker to the tier at the index, or to a new tier if there is
+    /// no such tier.
+    AddTracker { url: Url, tier: usize },
+    /// Remove the tracker with the URL.
+    RemoveTracker { url: Url },
+    /// Replace all trackers with the given tiers of trackers.
+    ReplaceTrackers { trackers: Vec<Vec<Url>> },
+    /// The result of an announce to the tracker with the URL, for each swarm
+    /// that was announced.
+    Announced {
+        url: Url,
+        event: Option<Event>,
+        results: Vec<(Sha1Hash, tracker::Result<Response>)>,
+    },
+    /// The result of a scrape of the tracker with the URL.
+    Scraped {
+        url: Url,
+        result: tracker::Result<Vec<Scrape>>,
+    },
     /// Gracefully shut down the torrent.
     ///
     /// This command tells all active peer sessions of torrent to do the same,
@@ -78,6 +157,22 @@ pub(crate) struct PieceCompletion {
     pub index: PieceIndex,
     /// Whether the piece is valid. If it's not, it's not written to disk.
     pub is_valid: bool,
+    /// The hashes of the piece's blocks, if the piece is invalid, or if it
+    /// had been invalid before. Otherwise this is empty.
+    pub blocks: Vec<BlockHash>,
+}
+
+/// The hash of a block of a completed piece, along with the peer that sent
+/// the block, with which the peers that sent corrupt blocks are found.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BlockHash {
+    /// The offset of the block within the piece.
+    pub offset: u32,
+    /// The peer that sent the block, or `None` if it was downloaded from a web
+    /// seed.
+    pub sender: Option<SocketAddr>,
+    /// The SHA-1 hash of the block's data.
+    pub hash: Sha1Hash,
 }
 
 /// Information and methods shared with peer sessions in the torrent.
@@ -88,9 +183,13 @@ pub(crate) struct PieceCompletion {
 pub(crate) struct TorrentContext {
     /// The torrent ID, unique in this engine.
     pub id: TorrentId,
-    /// The info hash of the torrent, derived from its metainfo. This is used to
-    /// identify the torrent with other peers and trackers.
-    pub info_hash: Sha1Hash,
+    /// The info hashes of the torrent, derived from its metainfo. These are
+    /// used to identify the torrent with other peers and trackers.
+    ///
+    /// There is a single info hash, unless this is a hybrid torrent, which is
+    /// in both a v1 and a v2 swarm, identified by the v1 info hash and the
+    /// truncated v2 info hash, in this order.
+    pub info_hashes: Vec<Sha1Hash>,
     /// The arbitrary client id, chosen by the user of this library. This is
     /// advertised to peers and trackers.
     pub client_id: PeerId,
@@ -124,20 +223,42 @@ pub(crate) struct TorrentContext {
     pub disk_tx: disk::Sender,
     /// Info about the torrent's storage (piece length, download length, etc).
     pub storage: StorageInfo,
+    /// The merkle trees of the files of a v2 torrent, from their piece layers
+    /// up, in the same order as the files, or `None` for empty files. Peers
+    /// may request hashes from these. This is empty for v1 torrents.
+    pub merkle_trees: Vec<Option<MerkleTree>>,
+
+    /// Whether peer connections are encrypted.
+    pub encryption: EncryptionPolicy,
+    /// The transports over which peers are connected.
+    pub transport: TransportPolicy,
 }
 
 /// Parameters for the torrent constructor.
 pub(crate) struct Params {
     pub id: TorrentId,
     pub disk_tx: disk::Sender,
-    pub info_hash: Sha1Hash,
+    pub info_hashes: Vec<Sha1Hash>,
     pub storage_info: StorageInfo,
+    pub merkle_trees: Vec<Option<MerkleTree>>,
     pub own_pieces: Bitfield,
-    pub trackers: Vec<Tracker>,
+    /// The trackers of the torrent, grouped into tiers.
+    pub trackers: Vec<Vec<Tracker>>,
+    /// The web seeds of the torrent, with the URL of each of the torrent's
+    /// files on the web seed, or `None` for padding files.
+    pub web_seeds: Vec<(Url, Vec<Option<Url>>)>,
     pub client_id: PeerId,
     pub listen_addr: SocketAddr,
     pub conf: TorrentConf,
     pub alert_tx: AlertSender,
+    /// If set, the torrent is announced on the local network through this
+    /// channel.
+    pub lsd_tx: Option<lsd::Sender>,
+    /// The engine's IP filter, which blocks connections with peers at the
+    /// addresses it contains.
+    pub ip_filter: Arc<SharedIpFilter>,
+    /// Divides the engine-wide connection limits among the torrents.
+    pub conn_limiter: Arc<ConnectionLimiter>,
 }
 
 /// Represents a torrent upload or download.
@@ -149,8 +270,11 @@ pub(crate) struct Params {
 pub(crate) struct Torrent {
     /// The peers in this torrent.
     peers: HashMap<SocketAddr, PeerSessionEntry>,
-    /// The peers returned by tracker to which we can connect.
-    available_peers: Vec<SocketAddr>,
+    /// All peers known to the torrent, including the connected ones, from
+    /// which the peers to connect to are picked.
+    peer_list: PeerList,
+    /// The number of peer sessions that ended, by the reason they ended.
+    disconnect_counts: HashMap<DisconnectReason, usize>,
     /// Information that is shared with peer sessions.
     ctx: Arc<TorrentContext>,
     /// The port on which other entities in the engine send this torrent
@@ -159,11 +283,33 @@ pub(crate) struct Torrent {
     /// The channel has to be wrapped in a `stream::Fuse` so that we can
     /// `select!` on it in the torrent event loop.
     cmd_rx: Fuse<Receiver>,
-    /// The trackers we can announce to.
-    trackers: Vec<TrackerEntry>,
+    /// The trackers we can announce to, grouped into tiers in order of
+    /// priority.
+    ///
+    /// The trackers within a tier are shuffled when the torrent is created
+    /// and a tracker that responds to an announce is moved to the front of
+    /// its tier.
+    trackers: Vec<Vec<TrackerEntry>>,
+    /// The event with which the torrent's start is announced to each tracker,
+    /// which is none if the torrent started out as a seed.
+    start_event: Option<Event>,
+    /// The HTTP servers from which the torrent's pieces may be downloaded, in
+    /// the order in which they appear in the metainfo. Their index in this
+    /// list identifies their sessions.
+    web_seeds: Vec<WebSeedEntry>,
 
     /// The address on which torrent should listen for new peers.
     listen_addr: SocketAddr,
+    /// The uTP socket bound to the same port as the TCP listener, used to
+    /// connect to peers over uTP. This is not set if the socket could not be
+    /// bound, in which case only TCP is used.
+    utp: Option<UtpSocket>,
+    /// Our IPv6 address that is sent to trackers, if we listen on IPv6.
+    ipv6_addr: Option<Ipv6Addr>,
+
+    /// The channel to the local service discovery task, if the torrent is to
+    /// be announced on the local network.
+    lsd_tx: Option<lsd::Sender>,
 
     /// The time the torrent was first started.
     start_time: Option<Instant>,
@@ -189,6 +335,22 @@ pub(crate) struct Torrent {
     /// Measures various transfer statistics.
     counters: ThruputCounters,
 
+    /// Decides which peers we upload to.
+    choker: Choker,
+
+    /// The blocks of the pieces that failed their hash checks and haven't
+    /// passed since. Once such a piece passes, the blocks that differ from the
+    /// valid ones identify the peers that sent corrupt data.
+    failed_pieces: HashMap<PieceIndex, Vec<BlockHash>>,
+    /// The engine's IP filter, which blocks connections with peers at the
+    /// addresses it contains.
+    ip_filter: Arc<SharedIpFilter>,
+    /// The version of the IP filter against which the connected peers were
+    /// last checked.
+    ip_filter_version: u64,
+    /// Divides the engine-wide connection limits among the torrents.
+    conn_limiter: Arc<ConnectionLimiter>,
+
     /// The configuration of this particular torrent.
     conf: TorrentConf,
 
@@ -212,20 +374,40 @@ impl Torrent {
         let Params {
             id,
             disk_tx,
-            info_hash,
+            info_hashes,
             storage_info,
+            merkle_trees,
             own_pieces,
             trackers,
+            web_seeds,
             client_id,
             listen_addr,
             conf,
             alert_tx,
+            lsd_tx,
+            ip_filter,
+            conn_limiter,
         } = params;
 
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
         let piece_picker = PiecePicker::new(own_pieces);
         let cmd_rx = cmd_rx.fuse();
-        let trackers = trackers.into_iter().map(TrackerEntry::new).collect();
+        // as per BEP 12, the trackers within each tier are shuffled
+        let mut rng = rand::thread_rng();
+        let trackers = trackers
+            .into_iter()
+            .map(|tier| {
+                let mut tier: Vec<_> =
+                    tier.into_iter().map(TrackerEntry::new).collect();
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .collect();
+        let web_seeds = web_seeds
+            .into_iter()
+            .map(|(url, file_urls)| WebSeedEntry::new(url, file_urls))
+            .collect();
+        let choker = Choker::new(&conf, storage_info.piece_count);
         let completed_pieces = if conf.alerts.completed_pieces {
             Some(Vec::new())
         } else {
@@ -235,25 +417,39 @@ impl Torrent {
         (
             Self {
                 peers: HashMap::new(),
-                available_peers: Vec::new(),
+                peer_list: PeerList::new(&conf),
+                disconnect_counts: HashMap::new(),
                 ctx: Arc::new(TorrentContext {
                     id,
                     cmd_tx: cmd_tx.clone(),
                     piece_picker: Arc::new(RwLock::new(piece_picker)),
                     downloads: RwLock::new(HashMap::new()),
-                    info_hash,
+                    info_hashes,
                     client_id,
                     alert_tx,
                     disk_tx,
                     storage: storage_info,
+                    merkle_trees,
+                    encryption: conf.encryption,
+                    transport: conf.transport,
                 }),
                 start_time: None,
                 run_duration: Duration::default(),
                 cmd_rx,
                 trackers,
+                start_event: None,
+                web_seeds,
                 in_endgame: false,
                 counters: Default::default(),
                 listen_addr,
+                utp: None,
+                ipv6_addr: listen_ipv6_addr(listen_addr),
+                lsd_tx,
+                choker,
+                failed_pieces: HashMap::new(),
+                ip_filter_version: ip_filter.version(),
+                ip_filter,
+                conn_limiter,
                 conf,
                 completed_pieces,
             },
@@ -262,36 +458,46 @@ impl Torrent {
     }
 
     /// Starts the torrent and runs until an error is encountered.
-    pub async fn start(&mut self, peers: &[SocketAddr]) -> Result<()> {
+    ///
+    /// If the torrent is queued, it first waits to be started.
+    pub async fn start(
+        &mut self,
+        peers: &[SocketAddr],
+        queued: bool,
+    ) -> Result<()> {
+        if queued && !self.wait_in_queue().await {
+            return Ok(());
+        }
         log::info!("Starting torrent");
 
-        self.available_peers.extend_from_slice(peers);
+        // the peers given by the user are assumed to be in the torrent's main
+        // swarm
+        let info_hash = self.ctx.info_hashes[0];
+        self.add_peers(peers.to_vec(), info_hash, PeerSource::Manual);
 
         // record the torrent starttime
         self.start_time = Some(Instant::now());
 
+        // web seeds have all pieces, so they are registered with the piece
+        // picker only once
+        if !self.web_seeds.is_empty() {
+            let mut piece_picker = self.ctx.piece_picker.write().await;
+            let piece_count = self.ctx.storage.piece_count;
+            for _ in self.web_seeds.iter() {
+                piece_picker
+                    .register_peer_pieces(&Bitfield::repeat(true, piece_count));
+            }
+        }
+
         // if the torrent is a seed, don't send the started event, just an
         // empty announce
-        let tracker_event =
+        self.start_event =
             if self.ctx.piece_picker.read().await.missing_piece_count() == 0 {
                 None
             } else {
                 Some(Event::Started)
             };
-        if let Err(e) = self
-            .announce_to_trackers(Instant::now(), tracker_event)
-            .await
-        {
-            // this is a torrent error, not a tracker error, as that is handled
-            // inside the function
-            self.ctx
-                .alert_tx
-                .send(Alert::Error(Error::Torrent {
-                    id: self.ctx.id,
-                    error: e,
-                }))
-                .ok();
-        }
+        self.announce_to_trackers(Instant::now(), self.start_event);
 
         if let Err(e) = self.run().await {
             // send alert of torrent failure to user
@@ -307,17 +513,100 @@ impl Torrent {
         Ok(())
     }
 
+    /// Waits for the queued torrent to be started, in the meantime only
+    /// scraping its trackers and reporting its stats, so that the user can see
+    /// the health of its swarm.
+    ///
+    /// Returns false if the torrent is shut down before being started.
+    async fn wait_in_queue(&mut self) -> bool {
+        log::info!("Torrent queued");
+        let mut tick_timer = time::interval(Duration::from_secs(1)).fuse();
+        loop {
+            select! {
+                tick_time = tick_timer.select_next_some() => {
+                    self.scrape_trackers(tick_time.into_std(), false);
+                    let stats = self.build_stats().await;
+                    self.ctx
+                        .alert_tx
+                        .send(Alert::TorrentStats {
+                            id: self.ctx.id,
+                            stats: Box::new(stats),
+                        })
+                        .ok();
+                }
+                cmd = self.cmd_rx.select_next_some() => {
+                    match cmd {
+                        Command::Start => return true,
+                        Command::Shutdown => return false,
+                        Command::Scrape => {
+                            self.scrape_trackers(Instant::now(), true);
+                        }
+                        Command::Scraped { url, result } => {
+                            self.handle_scrape_result(url, result);
+                        }
+                        Command::AddTracker { url, tier } => {
+                            self.add_tracker(url, tier);
+                        }
+                        Command::RemoveTracker { url } => {
+                            self.remove_tracker(&url);
+                        }
+                        Command::ReplaceTrackers { trackers } => {
+                            self.replace_trackers(trackers);
+                        }
+                        // queued torrents have no peers and don't announce
+                        cmd => {
+                            log::debug!("Ignoring command of queued torrent: {:?}", cmd);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     /// Starts the torrent and runs until an error is encountered.
     async fn run(&mut self) -> Result<()> {
         let mut tick_timer = time::interval(Duration::from_secs(1)).fuse();
         let mut last_tick_time = None;
 
-        let mut listener = TcpListener::bind(&self.listen_addr).await?;
+        let mut listener = net::bind_tcp_listener(self.listen_addr)?;
         // the bind port may have been 0, so we need to get the actual port in
-        // use
+        // use (and if the host has no IPv6 support, the listener may have
+        // fallen back to IPv4)
         self.listen_addr = listener.local_addr()?;
         let mut incoming = listener.incoming().fuse();
 
+        // peers may also connect to us over uTP on the same port
+        let mut utp_incoming = match UtpSocket::bind(self.listen_addr).await {
+            Ok((utp, incoming)) => {
+                log::info!(
+                    "Listening for uTP connections on {}",
+                    utp.local_addr()
+                );
+                self.utp = Some(utp);
+                incoming.fuse()
+            }
+            Err(e) => {
+                log::warn!("Error binding uTP socket: {}", e);
+                // a channel without a sender is immediately terminated, so
+                // it's never selected
+                mpsc::unbounded_channel().1.fuse()
+            }
+        };
+
+        // now that the listen port is known, the torrent can be announced on
+        // the local network
+        if let Some(lsd_tx) = &self.lsd_tx {
+            for info_hash in self.ctx.info_hashes.iter() {
+                lsd_tx
+                    .send(lsd::Command::Register {
+                        info_hash: *info_hash,
+                        port: self.listen_addr.port(),
+                        torrent_tx: self.ctx.cmd_tx.clone(),
+                    })
+                    .ok();
+            }
+        }
+
         // the torrent loop is triggered every second by the loop timer and by
         // disk IO events
         loop {
@@ -334,12 +623,29 @@ impl Torrent {
                         }
                     };
                     let addr = match socket.peer_addr() {
-                        Ok(addr) => addr,
+                        Ok(addr) => net::to_canonical(addr),
                         Err(e) => {
                             log::info!("Error getting socket address of peer: {}", e);
                             continue;
                         }
                     };
+                    if self.peer_list.is_banned(addr.ip()) {
+                        log::debug!("Rejecting connection from banned peer {}", addr);
+                        continue;
+                    }
+                    if self.ip_filter.blocks_inbound(addr.ip()) {
+                        log::debug!("Rejecting connection from filtered peer {}", addr);
+                        continue;
+                    }
+                    if !self.peer_list.add_incoming(addr) {
+                        log::debug!("Rejecting connection from {} already connected or with no room in peer list", addr);
+                        continue;
+                    }
+                    if !self.conn_limiter.reserve_inbound(self.ctx.id) {
+                        log::debug!("Rejecting connection from {} over connection limit", addr);
+                        self.peer_list.disconnected(&addr, DisconnectReason::Shutdown, false);
+                        continue;
+                    }
                     log::info!("New connection {:?}", addr);
 
                     // start inbound session
@@ -347,23 +653,84 @@ impl Torrent {
                         Arc::clone(&self.ctx),
                         addr,
                     );
-                    self.peers.insert(addr, PeerSessionEntry::start_inbound(socket, session, tx));
+                    self.peers.insert(addr, PeerSessionEntry::start_inbound(peer::Stream::Tcp(socket), session, tx));
+                }
+                stream = utp_incoming.select_next_some() => {
+                    let addr = stream.peer_addr();
+                    if self.peer_list.is_banned(addr.ip()) {
+                        log::debug!("Rejecting uTP connection from banned peer {}", addr);
+                        continue;
+                    }
+                    if self.ip_filter.blocks_inbound(addr.ip()) {
+                        log::debug!("Rejecting uTP connection from filtered peer {}", addr);
+                        continue;
+                    }
+                    if !self.peer_list.add_incoming(addr) {
+                        log::debug!("Rejecting uTP connection from {} already connected or with no room in peer list", addr);
+                        continue;
+                    }
+                    if !self.conn_limiter.reserve_inbound(self.ctx.id) {
+                        log::debug!("Rejecting uTP connection from {} over connection limit", addr);
+                        self.peer_list.disconnected(&addr, DisconnectReason::Shutdown, false);
+                        continue;
+                    }
+                    log::info!("New uTP connection {:?}", addr);
+
+                    // start inbound session
+                    let (session, tx) = PeerSession::new(
+                        Arc::clone(&self.ctx),
+                        addr,
+                    );
+                    self.peers.insert(addr, PeerSessionEntry::start_inbound(peer::Stream::Utp(stream), session, tx));
                 }
                 cmd = self.cmd_rx.select_next_some() => {
                     match cmd {
-                        Command::PeerConnected { addr, id } => {
-                            if let Some(peer) = self.peers.get_mut(&addr) {
-                                log::debug!(
-                                    "Peer {} connected with client '{}', \
-                                    updating state",
-                                    addr, String::from_utf8_lossy(&id)
-                                );
-                                peer.id = Some(id);
-                            }
+                        Command::PeerConnected { addr, id, transport } => {
+                            self.handle_peer_connected(addr, id, transport);
                         }
                         Command::PeerState { addr, info } => {
                             self.handle_peer_state_change(addr, info);
                         }
+                        Command::PeerDisconnected { addr, reason, info } => {
+                            self.handle_peer_disconnected(addr, reason, info);
+                        }
+                        Command::AddPeers { peers, info_hash, source } => {
+                            self.add_peers(peers, info_hash, source);
+                        }
+                        Command::WebSeedTransfer { id, downloaded, wasted } => {
+                            if let Some(web_seed) = self.web_seeds.get_mut(id) {
+                                web_seed.counters.payload.down += downloaded;
+                                web_seed.counters.waste += wasted;
+                                web_seed.error_count = 0;
+                            }
+                        }
+                        Command::WebSeedStopped { id, is_error } => {
+                            self.handle_web_seed_stopped(id, is_error).await;
+                        }
+                        Command::Start => {
+                            log::debug!("Torrent already started");
+                        }
+                        Command::Scrape => {
+                            self.scrape_trackers(Instant::now(), true);
+                        }
+                        Command::Announce { tracker_url, force } => {
+                            self.reannounce(tracker_url, force);
+                        }
+                        Command::AddTracker { url, tier } => {
+                            self.add_tracker(url, tier);
+                        }
+                        Command::RemoveTracker { url } => {
+                            self.remove_tracker(&url);
+                        }
+                        Command::ReplaceTrackers { trackers } => {
+                            self.replace_trackers(trackers);
+                        }
+                        Command::Announced { url, event, results } => {
+                            self.handle_announce_result(url, event, results)?;
+                        }
+                        Command::Scraped { url, result } => {
+                            self.handle_scrape_result(url, result);
+                        }
                         Command::PieceCompletion(write_result) => {
                             log::debug!("Disk write result {:?}", write_result);
                             match write_result {
@@ -421,14 +788,21 @@ impl Torrent {
         self.run_duration += elapsed_since_last_tick;
         *last_tick_time = Some(now);
 
+        let is_seed =
+            self.ctx.piece_picker.read().await.missing_piece_count() == 0;
+
         // check if we can connect some peers
-        // NOTE: do this before announcing as we don't want to block new
-        // connections with the potentially long running announce requests
-        self.connect_peers();
+        self.disconnect_filtered_peers();
+        let candidates = self.peer_list.connect_candidates(now, is_seed);
+        self.update_conn_usage(is_seed, candidates.len());
+        self.connect_peers(now, candidates);
+        self.start_web_seeds(now).await;
+        self.run_choker(now, is_seed);
 
         // check if we need to announce to some trackers
         let event = None;
-        self.announce_to_trackers(now, event).await?;
+        self.announce_to_trackers(now, event);
+        self.scrape_trackers(now, false);
 
         log::debug!(
             "Stats: \
@@ -465,6 +839,12 @@ impl Torrent {
             }
         }
 
+        // web seed transfers are only accounted for in torrent once a tick,
+        // like those of peers
+        for web_seed in self.web_seeds.iter() {
+            self.counters += &web_seed.counters;
+        }
+
         // send periodic stats update to api user
         let stats = self.build_stats().await;
         self.ctx
@@ -476,205 +856,880 @@ impl Torrent {
             .ok();
 
         self.counters.reset();
+        for web_seed in self.web_seeds.iter_mut() {
+            web_seed.counters.reset();
+        }
 
         Ok(())
     }
 
-    /// Attempts to connect available peers, if we have any.
-    fn connect_peers(&mut self) {
+    /// Chokes and unchokes peers, if the choker decides that it's time to pick
+    /// the peers we upload to.
+    fn run_choker(&mut self, now: Instant, is_seed: bool) {
+        let candidates: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| {
+                peer.state.connection == ConnectionState::Connected
+            })
+            .map(|(addr, peer)| Candidate {
+                addr: *addr,
+                is_interested: peer.state.is_peer_interested,
+                is_choked: peer.state.is_peer_choked,
+                down_rate: peer.thruput.payload.down.rate,
+                up_rate: peer.thruput.payload.up.rate,
+                up_total: peer.thruput.payload.up.total,
+                piece_count: peer.piece_count,
+            })
+            .collect();
+        let unchoked = match self.choker.run(now, &candidates, is_seed) {
+            Some(unchoked) => unchoked,
+            None => return,
+        };
+
+        for candidate in candidates.iter() {
+            let peer = match self.peers.get(&candidate.addr) {
+                Some(peer) => peer,
+                None => continue,
+            };
+            let should_choke = !unchoked.contains(&candidate.addr);
+            if should_choke == peer.state.is_peer_choked {
+                continue;
+            }
+            if let Some(tx) = &peer.tx {
+                let cmd = if should_choke {
+                    peer::Command::Choke
+                } else {
+                    peer::Command::Unchoke
+                };
+                // the session may have stopped in the meantime
+                tx.send(cmd).ok();
+            }
+        }
+    }
+
+    /// Adds the peers found in the swarm of the info hash to the peer list,
+    /// unless they are already known to us.
+    fn add_peers(
+        &mut self,
+        peers: Vec<SocketAddr>,
+        info_hash: Sha1Hash,
+        source: PeerSource,
+    ) {
+        for addr in peers {
+            if self.peer_list.add(addr, source, info_hash) {
+                log::debug!("Adding peer {} from {:?}", addr, source);
+            }
+        }
+    }
+
+    /// Disconnects the peers that the IP filter blocks, if it was replaced
+    /// since the last check.
+    fn disconnect_filtered_peers(&mut self) {
+        let version = self.ip_filter.version();
+        if version == self.ip_filter_version {
+            return;
+        }
+        self.ip_filter_version = version;
+
+        for (addr, peer) in self.peers.iter() {
+            if self.ip_filter.is_blocked(addr.ip()) {
+                log::info!("Disconnecting filtered peer {}", addr);
+                if let Some(tx) = &peer.tx {
+                    // the session may have stopped in the meantime
+                    tx.send(peer::Command::Shutdown).ok();
+                }
+            }
+        }
+    }
+
+    /// Reports the torrent's connections, and the number of peers it could
+    /// connect to now, to the engine's connection limiter.
+    fn update_conn_usage(&self, is_seed: bool, candidate_count: usize) {
+        let half_open_count = self
+            .peers
+            .values()
+            .filter(|peer| peer.is_outbound && peer.transport.is_none())
+            .count();
+        let wanted_count = (self.peers.len() + candidate_count)
+            .min(self.conf.max_connected_peer_count);
+        self.conn_limiter.update(
+            self.ctx.id,
+            conn_limiter::Usage {
+                connected_count: self.peers.len(),
+                half_open_count,
+                wanted_count,
+                is_seed,
+            },
+        );
+    }
+
+    /// Attempts to connect to the candidate peers, the most promising ones
+    /// first, as far as the torrent's and the engine's connection limits
+    /// allow. The rest of the peers are tried later.
+    fn connect_peers(&mut self, now: Instant, candidates: Vec<SocketAddr>) {
+        // connections are only reserved for the peers that are dialed, so the
+        // ones that are skipped are weeded out first
+        let mut candidates: Vec<_> = candidates
+            .into_iter()
+            .filter_map(|addr| {
+                // the filter may have been replaced since the peer was added
+                if self.ip_filter.blocks_outbound(addr.ip()) {
+                    log::debug!("Not connecting to filtered peer {}", addr);
+                    self.peer_list.remove(&addr);
+                    return None;
+                }
+                // peers that connected to us aren't candidates, so the swarm
+                // of each is known
+                let info_hash = self.peer_list.get(&addr)?.info_hash?;
+                Some((addr, info_hash))
+            })
+            .collect();
         let connect_count = self
             .conf
             .max_connected_peer_count
             .saturating_sub(self.peers.len())
-            .min(self.available_peers.len());
+            .min(candidates.len());
+        let connect_count = if connect_count > 0 {
+            self.conn_limiter
+                .reserve_outbound(self.ctx.id, connect_count)
+        } else {
+            0
+        };
         if connect_count == 0 {
             log::trace!("Cannot connect to peers");
             return;
         }
 
         log::debug!("Connecting {} peer(s)", connect_count);
-        for addr in self.available_peers.drain(0..connect_count) {
+        candidates.truncate(connect_count);
+        for (addr, info_hash) in candidates {
             log::info!("Connecting to peer {}", addr);
+            self.peer_list.connecting(&addr, now);
             let (session, tx) = PeerSession::new(Arc::clone(&self.ctx), addr);
-            self.peers
-                .insert(addr, PeerSessionEntry::start_outbound(session, tx));
+            self.peers.insert(
+                addr,
+                PeerSessionEntry::start_outbound(
+                    session,
+                    tx,
+                    self.utp.clone(),
+                    info_hash,
+                ),
+            );
+        }
+    }
+
+    /// Starts a session with each web seed that isn't already downloading from
+    /// or backing off after an error, if we still need pieces and there are
+    /// too few peers to download them from.
+    async fn start_web_seeds(&mut self, now: Instant) {
+        if self.web_seeds.is_empty()
+            || self.peers.len() >= self.conf.web_seed_peer_threshold
+        {
+            return;
+        }
+        let piece_picker = self.ctx.piece_picker.read().await;
+        if piece_picker.missing_piece_count() == 0
+            || piece_picker.all_pieces_picked()
+        {
+            return;
+        }
+        drop(piece_picker);
+
+        for (id, web_seed) in self.web_seeds.iter_mut().enumerate() {
+            if web_seed.is_active() || !web_seed.can_retry(now) {
+                continue;
+            }
+            log::info!("Starting web seed {}", web_seed.url);
+            match WebSeedSession::new(
+                Arc::clone(&self.ctx),
+                id,
+                &web_seed.url,
+                web_seed.file_urls.clone(),
+            ) {
+                Ok((mut session, tx)) => {
+                    web_seed.tx = Some(tx);
+                    web_seed.join_handle =
+                        Some(task::spawn(async move { session.start().await }));
+                }
+                Err(e) => {
+                    log::warn!("Error creating web seed session: {}", e);
+                    web_seed.record_error(now, self.conf.web_seed_backoff);
+                }
+            }
+        }
+    }
+
+    /// Reaps the web seed session that stopped and, if it stopped due to an
+    /// error, makes it back off before it's retried.
+    async fn handle_web_seed_stopped(&mut self, id: usize, is_error: bool) {
+        let web_seed = match self.web_seeds.get_mut(id) {
+            Some(web_seed) => web_seed,
+            None => return,
+        };
+        web_seed.tx = None;
+        if let Some(join_handle) = web_seed.join_handle.take() {
+            // the session sends this command as the last thing before
+            // returning, so this doesn't block
+            join_handle.await.ok();
+        }
+        if is_error {
+            web_seed.record_error(Instant::now(), self.conf.web_seed_backoff);
+            log::info!(
+                "Web seed {} failed {} time(s), retrying at the earliest in \
+                {} s",
+                web_seed.url,
+                web_seed.error_count,
+                web_seed
+                    .retry_time
+                    .map(|t| t.saturating_duration_since(Instant::now()))
+                    .unwrap_or_default()
+                    .as_secs()
+            );
         }
     }
 
     /// Chacks whether we need to announce to any trackers of if we need to request
     /// peers.
-    async fn announce_to_trackers(
+    ///
+    /// Trackers are grouped into tiers, as described in
+    /// [BEP 12](http://bittorrent.org/beps/bep_0012.html). Tiers are tried in
+    /// order, and unless [`TorrentConf::announce_to_all_tiers`] is set, we stop
+    /// at the first tier that has a working tracker.
+    ///
+    /// Announces run on their own tasks, so this doesn't wait for trackers to
+    /// respond. Their results are handled in [`Self::handle_announce_result`].
+    fn announce_to_trackers(&mut self, now: Instant, event: Option<Event>) {
+        self.announce_from_tier(0, now, event);
+    }
+
+    /// Like [`Self::announce_to_trackers`], but skips the tiers before the
+    /// given one.
+    fn announce_from_tier(
         &mut self,
+        first_tier_index: usize,
         now: Instant,
         event: Option<Event>,
-    ) -> Result<()> {
-        // calculate transfer statistics in advance
-        let uploaded = self.counters.payload.up.total();
-        let downloaded = self.counters.payload.down.total();
-        let left = self.ctx.storage.download_len - downloaded;
+    ) {
+        for tier_index in first_tier_index..self.trackers.len() {
+            let is_tier_working = self.announce_to_tier(tier_index, now, event);
+            if is_tier_working && !self.conf.announce_to_all_tiers {
+                break;
+            }
+        }
+    }
+
+    /// Announces to the first tracker in the given tier, if needed, and
+    /// returns whether the tier has a working tracker.
+    ///
+    /// A tier with an announce in progress is considered working until the
+    /// announce fails, in which case the next tracker in the tier is tried.
+    fn announce_to_tier(
+        &mut self,
+        tier_index: usize,
+        now: Instant,
+        event: Option<Event>,
+    ) -> bool {
+        let tier = &mut self.trackers[tier_index];
+        if let Some(tracker) = tier.iter_mut().find(|t| t.is_updating) {
+            // the event can't be lost, so it's announced once the tracker
+            // responds
+            if event.is_some() {
+                tracker.queued_event = event;
+            }
+            return true;
+        }
 
         // skip trackers that errored too often
-        // TODO: introduce a retry timeout
         let tracker_error_threshold = self.conf.tracker_error_threshold;
-        for tracker in self
-            .trackers
-            .iter_mut()
-            .filter(|t| t.error_count < tracker_error_threshold)
+        let first = match tier
+            .iter()
+            .position(|t| t.error_count < tracker_error_threshold)
         {
-            // Check if the torrent's peer count has fallen below the minimum.
-            // But don't request new peers otherwise or if we're about to stop
-            // torrent.
-            let peer_count = self.peers.len() + self.available_peers.len();
-            let needed_peer_count = if peer_count
-                >= self.conf.min_requested_peer_count
-                || event == Some(Event::Stopped)
-            {
-                None
-            } else {
-                debug_assert!(self.conf.max_connected_peer_count >= peer_count);
-                let needed = self.conf.max_connected_peer_count - peer_count;
-                // Download at least this numbe of peers, even if we don't need
-                // as many. This is because later we may be able to connect to
-                // more peers and in that case we don't want to wait till the
-                // next tracker request.
-                Some(self.conf.min_requested_peer_count.max(needed))
-            };
+            Some(first) => first,
+            None => return false,
+        };
 
-            // we can override the normal annoucne interval if we need peers or
-            // if we have an event to announce
-            if event.is_some()
-                || (needed_peer_count > Some(0)
-                    && tracker.can_announce(now, self.conf.announce_interval))
-                || tracker.should_announce(now, self.conf.announce_interval)
-            {
-                let params = Announce {
-                    tracker_id: tracker.id.clone(),
-                    info_hash: self.ctx.info_hash,
-                    peer_id: self.ctx.client_id,
-                    port: self.listen_addr.port(),
-                    peer_count: needed_peer_count,
-                    uploaded,
-                    downloaded,
-                    left,
-                    ip: None,
-                    event,
-                };
-                // TODO: We probably don't want to block the torrent event loop
-                // here waiting on the tracker response. Instead, poll the
-                // future in the event loop select call, or spawn the tracker
-                // announce on a separate task and return the result as
-                // an mpsc message.
-                match tracker.client.announce(params).await {
-                    Ok(resp) => {
-                        log::info!(
-                            "Announced to tracker {}, response: {:?}",
-                            tracker.client,
-                            resp
-                        );
-                        if let Some(tracker_id) = resp.tracker_id {
-                            tracker.id = Some(tracker_id);
-                        }
-                        if let Some(failure_reason) = resp.failure_reason {
-                            log::warn!(
-                                "Error contacting tracker {}: {}",
-                                tracker.client,
-                                failure_reason
-                            );
-                        }
-                        if let Some(warning_message) = resp.warning_message {
-                            log::warn!(
-                                "Warning from tracker {}: {}",
-                                tracker.client,
-                                warning_message
-                            );
-                        }
-                        if let Some(interval) = resp.interval {
-                            log::info!(
-                                "Tracker {} interval: {} s",
-                                tracker.client,
-                                interval.as_secs()
-                            );
-                            tracker.interval = Some(interval);
-                        }
-                        if let Some(min_interval) = resp.min_interval {
-                            log::info!(
-                                "Tracker {} min min_interval: {} s",
-                                tracker.client,
-                                min_interval.as_secs()
-                            );
-                            tracker.min_interval = Some(min_interval);
-                        }
+        // The announce schedule of a tier is that of its first tracker, since
+        // that is the one that last responded (if any did).
+        // We can override the normal annoucne interval if we need peers or if
+        // we have an event to announce.
+        let needed_peer_count = self.needed_peer_count(event);
+        let tracker = &self.trackers[tier_index][first];
+        if !(event.is_some()
+            || (needed_peer_count > Some(0)
+                && tracker.can_announce(now, self.conf.announce_interval))
+            || tracker.should_announce(now, &self.conf))
+        {
+            return tracker.is_working;
+        }
 
-                        if let (Some(seeder_count), Some(leecher_count)) =
-                            (resp.seeder_count, resp.leecher_count)
-                        {
-                            log::debug!(
-                                "Torrent seeds: {} and leeches: {}",
-                                seeder_count,
-                                leecher_count
-                            );
-                        }
+        self.start_announce(tier_index, first, now, event, needed_peer_count);
+        true
+    }
 
-                        if !resp.peers.is_empty() {
-                            log::debug!(
-                                "Received peers from tracker {}: {:?}",
-                                tracker.client,
-                                resp.peers
-                            );
-                            self.available_peers.extend(resp.peers.into_iter());
-                        }
-                    }
-                    Err(e) => {
-                        log::warn!(
-                            "Error announcing to tracker {}: {}",
-                            tracker.client,
-                            e
-                        );
-                        tracker.error_count += 1;
-                        self.ctx.alert_tx.send(Alert::Error(
-                            Error::Tracker {
-                                id: self.ctx.id,
-                                error: e,
-                            },
-                        ))?;
+    /// Announces to the tracker with the URL, or to all trackers if no URL is
+    /// given, outside of the announce schedule.
+    ///
+    /// Unless forced, trackers whose minimum announce interval hasn't passed
+    /// yet are skipped. The error count of the trackers is reset, so that
+    /// disabled trackers are announced to again.
+    fn reannounce(&mut self, tracker_url: Option<Url>, force: bool) {
+        let now = Instant::now();
+        let needed_peer_count = self.needed_peer_count(None);
+        let mut is_found = false;
+        for tier_index in 0..self.trackers.len() {
+            for tracker_index in 0..self.trackers[tier_index].len() {
+                let tracker = &mut self.trackers[tier_index][tracker_index];
+                if let Some(url) = &tracker_url {
+                    if tracker.client.url() != url {
+                        continue;
                     }
                 }
-                tracker.last_announce_time = Some(now);
+                is_found = true;
+
+                tracker.error_count = 0;
+                if tracker.is_updating {
+                    log::info!(
+                        "Already announcing to tracker {}",
+                        tracker.client
+                    );
+                    continue;
+                }
+                if !force && !tracker.is_min_interval_over(now) {
+                    log::info!(
+                        "Not announcing to tracker {} before its min interval",
+                        tracker.client
+                    );
+                    continue;
+                }
+
+                log::info!("Announcing to tracker {}", tracker.client);
+                self.start_announce(
+                    tier_index,
+                    tracker_index,
+                    now,
+                    None,
+                    needed_peer_count,
+                );
             }
         }
 
-        Ok(())
+        if !is_found {
+            log::warn!("No tracker to announce to");
+        }
     }
 
-    /// Returns high-level statistics about the torrent for sending to the user.
-    async fn build_stats(&mut self) -> TorrentStats {
-        let missing_piece_count =
-            self.ctx.piece_picker.read().await.missing_piece_count();
-        let piece_count = self.ctx.storage.piece_count;
-        let completed_pieces = self
-            .completed_pieces
-            .as_mut()
-            .map(|p| std::mem::replace(p, Vec::new()));
-        let peers = if self.conf.alerts.peers {
-            let peers = self
-                .peers
-                .iter()
-                .map(|(addr, entry)| stats::PeerSessionStats {
-                    addr: *addr,
-                    id: entry.id,
-                    state: entry.state,
-                    piece_count: entry.piece_count,
-                    thruput: entry.thruput,
-                })
-                .collect();
-            Peers::Full(peers)
-        } else {
-            Peers::Count(self.peers.len())
-        };
-
-        TorrentStats {
-            start_time: self.start_time,
-            run_duration: self.run_duration,
+    /// Adds the tracker to the tier at the index, or as the last tier if there
+    /// is no such tier, unless the torrent already has the tracker.
+    fn add_tracker(&mut self, url: Url, tier: usize) {
+        if self
+            .trackers
+            .iter()
+            .flatten()
+            .any(|t| t.client.url() == &url)
+        {
+            log::info!("Tracker {} already added", url);
+            return;
+        }
+        log::info!("Adding tracker {}", url);
+        let tracker = TrackerEntry::new(Tracker::new(url));
+        match self.trackers.get_mut(tier) {
+            Some(tier) => tier.push(tracker),
+            None => self.trackers.push(vec![tracker]),
+        }
+    }
+
+    /// Removes the tracker with the URL, along with its tier if it becomes
+    /// empty.
+    fn remove_tracker(&mut self, url: &Url) {
+        log::info!("Removing tracker {}", url);
+        for tier in self.trackers.iter_mut() {
+            tier.retain(|t| t.client.url() != url);
+        }
+        self.trackers.retain(|tier| !tier.is_empty());
+    }
+
+    /// Replaces the trackers with the given tiers of trackers. The trackers
+    /// that are kept are moved to their new tier with their state.
+    fn replace_trackers(&mut self, trackers: Vec<Vec<Url>>) {
+        log::info!("Replacing trackers with {:?}", trackers);
+        let mut prev_trackers: Vec<_> =
+            self.trackers.drain(..).flatten().collect();
+        self.trackers = trackers
+            .into_iter()
+            .map(|tier| {
+                tier.into_iter()
+                    .map(|url| {
+                        match prev_trackers
+                            .iter()
+                            .position(|t| t.client.url() == &url)
+                        {
+                            Some(index) => prev_trackers.swap_remove(index),
+                            None => TrackerEntry::new(Tracker::new(url)),
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+    }
+
+    /// Check if the torrent's peer count has fallen below the minimum, and if
+    /// so, returns the number of peers to request from trackers.
+    ///
+    /// We don't request new peers otherwise or if we're about to stop the
+    /// torrent.
+    fn needed_peer_count(&self, event: Option<Event>) -> Option<usize> {
+        let peer_count = self.peers.len() + self.peer_list.available_count();
+        if peer_count >= self.conf.min_requested_peer_count
+            || event == Some(Event::Stopped)
+        {
+            None
+        } else {
+            debug_assert!(self.conf.max_connected_peer_count >= peer_count);
+            let needed = self.conf.max_connected_peer_count - peer_count;
+            // Download at least this numbe of peers, even if we don't need
+            // as many. This is because later we may be able to connect to
+            // more peers and in that case we don't want to wait till the
+            // next tracker request.
+            Some(self.conf.min_requested_peer_count.max(needed))
+        }
+    }
+
+    /// Starts announcing to a single tracker on a new task, which sends the
+    /// result back to the torrent.
+    ///
+    /// Hybrid torrents are announced once in each of their swarms.
+    fn start_announce(
+        &mut self,
+        tier_index: usize,
+        tracker_index: usize,
+        now: Instant,
+        event: Option<Event>,
+        peer_count: Option<usize>,
+    ) {
+        let tracker = &self.trackers[tier_index][tracker_index];
+        // the first announce to a tracker, including one that was added after
+        // the torrent started, announces the torrent's start
+        let event = if tracker.is_started || event == Some(Event::Stopped) {
+            event
+        } else {
+            self.start_event.or(event)
+        };
+        let params = self
+            .ctx
+            .info_hashes
+            .iter()
+            .map(|info_hash| {
+                self.announce_params(tracker, *info_hash, event, peer_count)
+            })
+            .collect();
+
+        let tracker = &mut self.trackers[tier_index][tracker_index];
+        tracker.is_updating = true;
+        tracker.last_announce_time = Some(now);
+
+        let client = Arc::clone(&tracker.client);
+        let timeout = self.conf.tracker_timeout;
+        let cmd_tx = self.ctx.cmd_tx.clone();
+        task::spawn(async move {
+            let url = client.url().clone();
+            let results = announce(&client, params, timeout).await;
+            // the torrent may have stopped in the meantime
+            cmd_tx
+                .send(Command::Announced {
+                    url,
+                    event,
+                    results,
+                })
+                .ok();
+        });
+    }
+
+    /// Returns the parameters with which the swarm of the info hash is
+    /// announced to the tracker.
+    fn announce_params(
+        &self,
+        tracker: &TrackerEntry,
+        info_hash: Sha1Hash,
+        event: Option<Event>,
+        peer_count: Option<usize>,
+    ) -> Announce {
+        // calculate transfer statistics in advance
+        let uploaded = self.counters.payload.up.total();
+        let downloaded = self.counters.payload.down.total();
+        let left = self.ctx.storage.download_len - downloaded;
+
+        Announce {
+            tracker_id: tracker.id.clone(),
+            info_hash,
+            peer_id: self.ctx.client_id,
+            port: self.listen_addr.port(),
+            peer_count,
+            uploaded,
+            downloaded,
+            left,
+            ip: None,
+            ipv6: self.ipv6_addr,
+            event,
+        }
+    }
+
+    /// Handles the result of an announce to a tracker.
+    ///
+    /// The tracker is only considered to be working if it responded in all
+    /// swarms of the torrent. If it is, it's moved to the front of its tier so
+    /// that it is tried first the next time. Otherwise the next tracker in
+    /// the tier is tried, or if there is none, the next tier.
+    fn handle_announce_result(
+        &mut self,
+        url: Url,
+        event: Option<Event>,
+        results: Vec<(Sha1Hash, tracker::Result<Response>)>,
+    ) -> Result<()> {
+        // the tracker may have been removed in the meantime
+        let (tier_index, tracker_index) = match self.tracker_position(&url) {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+
+        let tracker = &mut self.trackers[tier_index][tracker_index];
+        tracker.is_updating = false;
+        tracker.peer_count = 0;
+        tracker.warning_message = None;
+        let queued_event = tracker.queued_event.take();
+
+        let mut is_working = true;
+        for (info_hash, result) in results {
+            is_working &= self.handle_swarm_announce_result(
+                tier_index,
+                tracker_index,
+                info_hash,
+                result,
+            )?;
+        }
+
+        if is_working {
+            if event != Some(Event::Stopped) {
+                self.trackers[tier_index][tracker_index].is_started = true;
+            }
+            self.trackers[tier_index][..=tracker_index].rotate_right(1);
+        } else {
+            let now = Instant::now();
+            let tracker_error_threshold = self.conf.tracker_error_threshold;
+            let next = self.trackers[tier_index]
+                .iter()
+                .enumerate()
+                .skip(tracker_index + 1)
+                .find(|(_, t)| {
+                    t.error_count < tracker_error_threshold && !t.is_updating
+                })
+                .map(|(index, _)| index);
+            if let Some(next) = next {
+                let needed_peer_count = self.needed_peer_count(event);
+                self.start_announce(
+                    tier_index,
+                    next,
+                    now,
+                    event,
+                    needed_peer_count,
+                );
+            } else if !self.conf.announce_to_all_tiers {
+                self.announce_from_tier(tier_index + 1, now, event);
+            }
+        }
+
+        if let Some(event) = queued_event {
+            self.announce_to_trackers(Instant::now(), Some(event));
+        }
+
+        Ok(())
+    }
+
+    /// Handles the result of announcing the swarm of the info hash to a single
+    /// tracker and returns whether it responded.
+    fn handle_swarm_announce_result(
+        &mut self,
+        tier_index: usize,
+        tracker_index: usize,
+        info_hash: Sha1Hash,
+        result: tracker::Result<Response>,
+    ) -> Result<bool> {
+        let tracker = &mut self.trackers[tier_index][tracker_index];
+        match result {
+            Ok(mut resp) => {
+                log::info!(
+                    "Announced to tracker {}, response: {:?}",
+                    tracker.client,
+                    resp
+                );
+                tracker.is_working = true;
+                tracker.last_error = None;
+                if let Some(tracker_id) = resp.tracker_id {
+                    tracker.id = Some(tracker_id);
+                }
+                if let Some(warning_message) = resp.warning_message {
+                    log::warn!(
+                        "Warning from tracker {}: {}",
+                        tracker.client,
+                        warning_message
+                    );
+                    tracker.warning_message = Some(warning_message);
+                }
+                if let Some(interval) = resp.interval {
+                    log::info!(
+                        "Tracker {} interval: {} s",
+                        tracker.client,
+                        interval.as_secs()
+                    );
+                    tracker.interval = Some(interval);
+                }
+                if let Some(min_interval) = resp.min_interval {
+                    log::info!(
+                        "Tracker {} min min_interval: {} s",
+                        tracker.client,
+                        min_interval.as_secs()
+                    );
+                    tracker.min_interval = Some(min_interval);
+                }
+
+                if let (Some(seeder_count), Some(leecher_count)) =
+                    (resp.seeder_count, resp.leecher_count)
+                {
+                    log::debug!(
+                        "Torrent seeds: {} and leeches: {}",
+                        seeder_count,
+                        leecher_count
+                    );
+                }
+                // hybrid torrents are announced in both swarms, but peers that
+                // support them are in both, so the counts aren't added up
+                if info_hash == self.ctx.info_hashes[0] {
+                    tracker.seeder_count = resp.seeder_count;
+                    tracker.leecher_count = resp.leecher_count;
+                } else {
+                    tracker.seeder_count =
+                        resp.seeder_count.max(tracker.seeder_count);
+                    tracker.leecher_count =
+                        resp.leecher_count.max(tracker.leecher_count);
+                }
+                tracker.peer_count += resp.peers.len() + resp.peers6.len();
+
+                // the peers we may not connect to aren't even kept around
+                let ip_filter = &self.ip_filter;
+                resp.peers
+                    .retain(|addr| !ip_filter.blocks_outbound(addr.ip()));
+                resp.peers6
+                    .retain(|addr| !ip_filter.blocks_outbound(addr.ip()));
+
+                if !resp.peers.is_empty() {
+                    log::debug!(
+                        "Received peers from tracker {}: {:?}",
+                        tracker.client,
+                        resp.peers
+                    );
+                    for addr in resp.peers {
+                        self.peer_list.add(
+                            addr,
+                            PeerSource::Tracker,
+                            info_hash,
+                        );
+                    }
+                }
+                if !resp.peers6.is_empty() {
+                    log::debug!(
+                        "Received IPv6 peers from tracker {}: {:?}",
+                        tracker.client,
+                        resp.peers6
+                    );
+                    for addr in resp.peers6 {
+                        self.peer_list.add(
+                            addr,
+                            PeerSource::Tracker,
+                            info_hash,
+                        );
+                    }
+                }
+
+                Ok(true)
+            }
+            Err(e) => {
+                log::warn!(
+                    "Error announcing to tracker {}: {}",
+                    tracker.client,
+                    e
+                );
+                tracker.is_working = false;
+                tracker.error_count += 1;
+                tracker.last_error = Some(e.to_string());
+                self.ctx.alert_tx.send(Alert::Error(Error::Tracker {
+                    id: self.ctx.id,
+                    error: e,
+                }))?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Returns the tier and the index within the tier of the tracker with the
+    /// URL.
+    fn tracker_position(&self, url: &Url) -> Option<(usize, usize)> {
+        self.trackers
+            .iter()
+            .enumerate()
+            .find_map(|(tier_index, tier)| {
+                tier.iter()
+                    .position(|t| t.client.url() == url)
+                    .map(|tracker_index| (tier_index, tracker_index))
+            })
+    }
+
+    /// Scrapes the trackers that are due to be scraped, or all of them if
+    /// forced.
+    ///
+    /// Trackers are scraped periodically, if enabled with
+    /// [`TorrentConf::scrape_interval`], including those that we don't
+    /// announce to because a tracker in a higher priority tier is working.
+    /// Like announces, scrapes run on their own tasks.
+    fn scrape_trackers(&mut self, now: Instant, force: bool) {
+        let scrape_interval = self.conf.scrape_interval;
+        let tracker_error_threshold = self.conf.tracker_error_threshold;
+        for tracker in self.trackers.iter_mut().flatten() {
+            if tracker.error_count >= tracker_error_threshold
+                || !(force || tracker.should_scrape(now, scrape_interval))
+            {
+                continue;
+            }
+            tracker.last_scrape_time = Some(now);
+
+            let client = Arc::clone(&tracker.client);
+            let info_hashes = self.ctx.info_hashes.clone();
+            let timeout = self.conf.tracker_timeout;
+            let cmd_tx = self.ctx.cmd_tx.clone();
+            task::spawn(async move {
+                let url = client.url().clone();
+                let result =
+                    time::timeout(timeout, client.scrape(&info_hashes))
+                        .await
+                        .unwrap_or(Err(TrackerError::Timeout));
+                // the torrent may have stopped in the meantime
+                cmd_tx.send(Command::Scraped { url, result }).ok();
+            });
+        }
+    }
+
+    /// Handles the result of a scrape of a tracker.
+    fn handle_scrape_result(
+        &mut self,
+        url: Url,
+        result: tracker::Result<Vec<Scrape>>,
+    ) {
+        // the tracker may have been removed in the meantime
+        let tracker = match self
+            .trackers
+            .iter_mut()
+            .flatten()
+            .find(|t| t.client.url() == &url)
+        {
+            Some(tracker) => tracker,
+            None => return,
+        };
+        match result {
+            Ok(scrapes) => {
+                // peers that support hybrid torrents are in both swarms, so
+                // the counts of the swarms can't be added up
+                let scrape =
+                    scrapes.into_iter().fold(Scrape::default(), |a, b| {
+                        Scrape {
+                            seeder_count: a.seeder_count.max(b.seeder_count),
+                            leecher_count: a.leecher_count.max(b.leecher_count),
+                            download_count: a
+                                .download_count
+                                .max(b.download_count),
+                        }
+                    });
+                log::info!("Scraped tracker {}: {:?}", tracker.client, scrape);
+                tracker.scrape = Some(scrape);
+            }
+            Err(e) => {
+                log::warn!("Error scraping tracker {}: {}", tracker.client, e);
+            }
+        }
+    }
+
+    /// Returns high-level statistics about the torrent for sending to the user.
+    async fn build_stats(&mut self) -> TorrentStats {
+        let missing_piece_count =
+            self.ctx.piece_picker.read().await.missing_piece_count();
+        let piece_count = self.ctx.storage.piece_count;
+        let completed_pieces = self
+            .completed_pieces
+            .as_mut()
+            .map(|p| std::mem::replace(p, Vec::new()));
+        let peers = if self.conf.alerts.peers {
+            let peers = self
+                .peers
+                .iter()
+                .map(|(addr, entry)| stats::PeerSessionStats {
+                    addr: *addr,
+                    id: entry.id,
+                    transport: entry.transport,
+                    source: self
+                        .peer_list
+                        .get(addr)
+                        .map(|info| info.source)
+                        .unwrap_or(PeerSource::Incoming),
+                    state: entry.state,
+                    piece_count: entry.piece_count,
+                    thruput: entry.thruput,
+                })
+                .collect();
+            Peers::Full(peers)
+        } else {
+            Peers::Count(self.peers.len())
+        };
+
+        let web_seeds = if self.conf.alerts.peers {
+            Some(
+                self.web_seeds
+                    .iter()
+                    .map(|entry| WebSeedStats {
+                        url: entry.url.clone(),
+                        is_active: entry.is_active(),
+                        error_count: entry.error_count,
+                        thruput: ThruputStats::from(&entry.counters),
+                    })
+                    .collect(),
+            )
+        } else {
+            None
+        };
+
+        let trackers = self
+            .trackers
+            .iter()
+            .enumerate()
+            .flat_map(|(tier, entries)| {
+                entries.iter().map(move |entry| (tier, entry))
+            })
+            .map(|(tier, entry)| TrackerStats {
+                url: entry.client.url().clone(),
+                tier,
+                status: entry.status(self.conf.tracker_error_threshold),
+                last_announce_time: entry.last_announce_time,
+                next_announce_time: entry.next_announce_time(&self.conf),
+                seeder_count: entry.seeder_count,
+                leecher_count: entry.leecher_count,
+                warning_message: entry.warning_message.clone(),
+                peer_count: entry.peer_count,
+                scrape: entry.scrape,
+            })
+            .collect();
+
+        TorrentStats {
+            start_time: self.start_time,
+            run_duration: self.run_duration,
             pieces: PieceStats {
                 total: piece_count,
                 complete: piece_count - missing_piece_count,
@@ -683,16 +1738,18 @@ impl Torrent {
             },
             thruput: ThruputStats::from(&self.counters),
             peers,
+            disconnects: self.disconnect_counts.clone(),
+            web_seeds,
+            trackers,
         }
     }
 
-    /// Handles the message that peer sessions send to torrent when their state
-    /// changed.
+    /// Handles the message that peer sessions send to torrent with each of
+    /// their ticks.
     ///
     /// It simply updates the minimum copy of the peer's state that is kept in
     /// torrent in order to perform various pieces of logic (the choke
-    /// algorithm and detailed reporting to user, neither of which is done at
-    /// the moment).
+    /// algorithm and detailed reporting to user).
     fn handle_peer_state_change(
         &mut self,
         addr: SocketAddr,
@@ -704,19 +1761,127 @@ impl Torrent {
             peer.state = info.state;
             peer.piece_count = info.piece_count;
             peer.thruput = ThruputStats::from(&info.counters);
+            self.peer_list.set_seed(
+                &addr,
+                peer.piece_count == self.ctx.storage.piece_count,
+            );
 
             // update torrent thruput stats
             self.counters += &info.counters;
-
-            // if we disconnected peer, remove it
-            if peer.state.connection == ConnectionState::Disconnected {
-                self.peers.remove(&addr);
-            }
         } else {
             log::debug!("Tried updating non-existent peer {}", addr);
         }
     }
 
+    /// Handles the message that peer sessions send to torrent when they end.
+    ///
+    /// The peer is removed from torrent, the reason is recorded in the peer
+    /// list and the torrent's stats, and the user is alerted of it if they
+    /// asked to be.
+    fn handle_peer_disconnected(
+        &mut self,
+        addr: SocketAddr,
+        reason: DisconnectReason,
+        info: SessionTick,
+    ) {
+        let downloaded = info.counters.payload.down.total();
+        let uploaded = info.counters.payload.up.total();
+        let wasted = info.counters.waste.total();
+        self.handle_peer_state_change(addr, info);
+
+        let peer = match self.peers.remove(&addr) {
+            Some(peer) => peer,
+            None => return,
+        };
+        log::info!("Peer {} disconnected: {:?}", addr, reason);
+        *self.disconnect_counts.entry(reason).or_default() += 1;
+
+        // only a connection we couldn't make counts as a failure
+        let is_failure = peer.is_outbound && peer.transport.is_none();
+        self.peer_list.disconnected(&addr, reason, is_failure);
+        // we're connected to the peer over another connection, so this
+        // address isn't tried again
+        if peer.is_duplicate {
+            self.peer_list.remove(&addr);
+        }
+
+        if self.conf.alerts.peer_disconnects {
+            self.ctx
+                .alert_tx
+                .send(Alert::PeerDisconnected {
+                    id: self.ctx.id,
+                    addr,
+                    reason,
+                    downloaded,
+                    uploaded,
+                    wasted,
+                })
+                .ok();
+        }
+    }
+
+    /// Records that the handshake with the peer is done.
+    ///
+    /// If we already have a session with the same peer over another
+    /// connection, such as when we both connected to each other, one of the
+    /// two connections is closed. Both sides close the same one: the one that
+    /// wasn't made by the peer with the greater id. This can't be decided for
+    /// peers with the same id as ours, so all of their connections are kept.
+    fn handle_peer_connected(
+        &mut self,
+        addr: SocketAddr,
+        id: PeerId,
+        transport: Transport,
+    ) {
+        let peer = match self.peers.get_mut(&addr) {
+            Some(peer) => peer,
+            None => return,
+        };
+        log::debug!(
+            "Peer {} connected over {:?} with client '{}', updating state",
+            addr,
+            transport,
+            String::from_utf8_lossy(&id)
+        );
+        peer.id = Some(id);
+        peer.transport = Some(transport);
+        let is_outbound = peer.is_outbound;
+        self.peer_list.connected(&addr);
+        if id == self.ctx.client_id {
+            return;
+        }
+
+        let other_addr = self
+            .peers
+            .iter()
+            .find(|(other_addr, other)| {
+                **other_addr != addr
+                    && !other.is_duplicate
+                    && other.id == Some(id)
+            })
+            .map(|(other_addr, _)| *other_addr);
+        let other_addr = match other_addr {
+            Some(other_addr) => other_addr,
+            None => return,
+        };
+        let keep_outbound = self.ctx.client_id > id;
+        let duplicate_addr = if self.peers[&other_addr].is_outbound
+            != is_outbound
+            && is_outbound == keep_outbound
+        {
+            other_addr
+        } else {
+            addr
+        };
+        log::info!("Closing duplicate connection with peer {}", duplicate_addr);
+        if let Some(peer) = self.peers.get_mut(&duplicate_addr) {
+            peer.is_duplicate = true;
+            if let Some(tx) = &peer.tx {
+                tx.send(peer::Command::Shutdown).ok();
+            }
+        }
+    }
+
     /// Does some bookkeeping to mark the piece as finished. All peer sessions
     /// are notified of the newly downloaded piece.
     async fn handle_piece_completion(
@@ -729,6 +1894,13 @@ impl Torrent {
             // remove download entry
             self.ctx.downloads.write().await.remove(&piece.index);
 
+            // now that we have the valid blocks, those that differ from them
+            // in a previous attempt were corrupt
+            if let Some(failed_blocks) = self.failed_pieces.remove(&piece.index)
+            {
+                self.ban_corrupt_block_senders(&piece, &failed_blocks);
+            }
+
             // register piece in piece picker
             let mut piece_picker_write_guard =
                 self.ctx.piece_picker.write().await;
@@ -796,13 +1968,23 @@ impl Torrent {
                 self.announce_to_trackers(
                     Instant::now(),
                     Some(Event::Completed),
-                )
-                .await?;
+                );
             }
         } else {
-            // TODO(https://github.com/mandreyel/cratetorrent/issues/61):
-            // implement parole mode for the peers that sent corrupt data
             log::warn!("Piece {} is invalid", piece.index);
+
+            // If a single peer sent all blocks, it's the one that sent the
+            // corrupt data. Otherwise the blocks are kept until the piece
+            // passes, to find out which of the peers it was.
+            if sole_sender(&piece.blocks).is_some() {
+                self.ban_corrupt_block_senders(&piece, &[]);
+            } else {
+                self.failed_pieces
+                    .entry(piece.index)
+                    .or_default()
+                    .extend(piece.blocks);
+            }
+
             // mark all blocks free to be requested in piece
             if let Some(piece) =
                 self.ctx.downloads.read().await.get(&piece.index)
@@ -814,6 +1996,49 @@ impl Torrent {
         Ok(())
     }
 
+    /// Bans the peers that sent corrupt data in the piece, see
+    /// [`corrupt_block_senders`].
+    fn ban_corrupt_block_senders(
+        &mut self,
+        piece: &PieceCompletion,
+        failed_blocks: &[BlockHash],
+    ) {
+        let peer_list = &self.peer_list;
+        let senders = corrupt_block_senders(piece, failed_blocks, |ip| {
+            peer_list.is_banned(ip)
+        });
+        for addr in senders {
+            self.ban_peer(addr);
+        }
+    }
+
+    /// Bans the IP address of the peer that sent us corrupt data, disconnecting
+    /// all peers at that address, and alerts the user of it.
+    fn ban_peer(&mut self, addr: SocketAddr) {
+        let ip = addr.ip();
+        if !self.peer_list.ban(ip) {
+            return;
+        }
+        log::warn!("Banning peer {} for sending corrupt data", addr);
+
+        for (peer_addr, peer) in self.peers.iter() {
+            if peer_addr.ip() == ip {
+                if let Some(tx) = &peer.tx {
+                    // the session may have stopped in the meantime
+                    tx.send(peer::Command::Shutdown).ok();
+                }
+            }
+        }
+
+        self.ctx
+            .alert_tx
+            .send(Alert::PeerBanned {
+                id: self.ctx.id,
+                addr,
+            })
+            .ok();
+    }
+
     /// Shuts down torrent and all peer sessions, and also announces torrent's
     /// exit to tracker.
     async fn shutdown(&mut self) -> Result<()> {
@@ -837,10 +2062,161 @@ impl Torrent {
                 log::error!("Peer session error: {}", e);
             }
         }
+        // let other torrents use the connections
+        self.conn_limiter.remove(self.ctx.id);
+
+        for web_seed in self.web_seeds.iter_mut() {
+            if let Some(tx) = web_seed.tx.take() {
+                tx.send(web_seed::Command::Shutdown).ok();
+            }
+            if let Some(join_handle) = web_seed.join_handle.take() {
+                // errors are logged by the session itself
+                join_handle.await.ok();
+            }
+        }
 
-        // tell trackers we're leaving
-        self.announce_to_trackers(Instant::now(), Some(Event::Stopped))
+        if let Some(lsd_tx) = &self.lsd_tx {
+            for info_hash in self.ctx.info_hashes.iter() {
+                lsd_tx
+                    .send(lsd::Command::Unregister {
+                        info_hash: *info_hash,
+                    })
+                    .ok();
+            }
+        }
+
+        // tell the trackers that were told of our start that we're leaving,
+        // even if they have failed since, but don't let unresponsive ones hold
+        // up the shutdown (an announce in progress may be the one announcing
+        // our start)
+        let timeout = self.conf.tracker_timeout;
+        let announces = self
+            .trackers
+            .iter()
+            .flatten()
+            .filter(|t| t.is_started || t.is_updating)
+            .map(|tracker| {
+                let params = self
+                    .ctx
+                    .info_hashes
+                    .iter()
+                    .map(|info_hash| {
+                        self.announce_params(
+                            tracker,
+                            *info_hash,
+                            Some(Event::Stopped),
+                            None,
+                        )
+                    })
+                    .collect();
+                let client = Arc::clone(&tracker.client);
+                async move {
+                    for (_, result) in announce(&client, params, timeout).await
+                    {
+                        if let Err(e) = result {
+                            log::warn!(
+                                "Error announcing stop to tracker {}: {}",
+                                client,
+                                e
+                            );
+                        }
+                    }
+                }
+            });
+        let announces = future::join_all(announces);
+        if time::timeout(self.conf.stop_announce_timeout, announces)
             .await
+            .is_err()
+        {
+            log::warn!("Timed out announcing stop to trackers");
+        }
+
+        Ok(())
+    }
+}
+
+/// Announces the swarms of the info hashes in the parameters to the tracker in
+/// turn, stopping at the first swarm in which the tracker didn't respond.
+///
+/// Each announce fails if the tracker doesn't respond within the timeout, or
+/// if the tracker refused the announce.
+async fn announce(
+    tracker: &Tracker,
+    params: Vec<Announce>,
+    timeout: Duration,
+) -> Vec<(Sha1Hash, tracker::Result<Response>)> {
+    let mut results = Vec::with_capacity(params.len());
+    for params in params {
+        let info_hash = params.info_hash;
+        let result = time::timeout(timeout, tracker.announce(params))
+            .await
+            .unwrap_or(Err(TrackerError::Timeout))
+            // if the tracker refused the announce, no other field in the
+            // response is valid
+            .and_then(|resp| match resp.failure_reason {
+                Some(failure_reason) => {
+                    Err(TrackerError::Failure(failure_reason))
+                }
+                None => Ok(resp),
+            });
+        let is_error = result.is_err();
+        results.push((info_hash, result));
+        if is_error {
+            break;
+        }
+    }
+    results
+}
+
+/// Returns the peers that sent corrupt data in a piece whose hash was just
+/// checked, leaving out those whose IP address is already banned.
+///
+/// If the piece failed and a single peer sent all of its blocks, that peer
+/// sent the corrupt data. If the piece passed, the peers that sent blocks in
+/// its failed attempts that differ from the valid blocks did.
+fn corrupt_block_senders(
+    piece: &PieceCompletion,
+    failed_blocks: &[BlockHash],
+    is_banned: impl Fn(IpAddr) -> bool,
+) -> Vec<SocketAddr> {
+    let mut senders = Vec::new();
+    if !piece.is_valid {
+        senders.extend(sole_sender(&piece.blocks));
+    } else {
+        for failed in failed_blocks.iter() {
+            let is_corrupt = piece.blocks.iter().any(|valid| {
+                valid.offset == failed.offset && valid.hash != failed.hash
+            });
+            match failed.sender {
+                Some(addr) if is_corrupt && !senders.contains(&addr) => {
+                    senders.push(addr)
+                }
+                _ => {}
+            }
+        }
+    }
+    senders.retain(|addr| !is_banned(addr.ip()));
+    senders
+}
+
+/// Returns the peer that sent all of the blocks, if there is one.
+fn sole_sender(blocks: &[BlockHash]) -> Option<SocketAddr> {
+    let mut senders = blocks.iter().map(|block| block.sender);
+    match senders.next() {
+        Some(Some(first)) if senders.all(|s| s == Some(first)) => Some(first),
+        _ => None,
+    }
+}
+
+/// Returns the IPv6 address to announce to trackers, given the address on
+/// which the torrent listens.
+fn listen_ipv6_addr(listen_addr: SocketAddr) -> Option<Ipv6Addr> {
+    match listen_addr.ip() {
+        // when listening on all interfaces, we don't know which address peers
+        // can reach us on, so the one used for the default route is picked
+        IpAddr::V6(ip) if ip.is_unspecified() => net::local_ipv6_addr(),
+        IpAddr::V6(ip) if !ip.is_loopback() => Some(ip),
+        _ => None,
     }
 }
 
@@ -855,6 +2231,9 @@ struct PeerSessionEntry {
     /// Peer's 20 byte BitTorrent id. Updated when the peer sends us its peer
     /// id, in the handshake.
     id: Option<PeerId>,
+    /// The transport over which the peer is connected, set once the
+    /// BitTorrent handshake is done.
+    transport: Option<Transport>,
     /// Cached information about the session state. Updated every time peer
     /// updates us.
     state: SessionState,
@@ -863,50 +2242,123 @@ struct PeerSessionEntry {
 
     /// Most recent throughput statistics of this peer.
     thruput: ThruputStats,
+    /// Whether we connected to the peer, rather than the peer to us.
+    is_outbound: bool,
+    /// Whether we already have a session with the peer over another
+    /// connection, in which case this one is being closed.
+    is_duplicate: bool,
 
     /// The peer session task's join handle, used during shutdown.
     join_handle: Option<task::JoinHandle<peer::error::Result<()>>>,
 }
 
 impl PeerSessionEntry {
-    fn start_outbound(mut session: PeerSession, tx: peer::Sender) -> Self {
+    fn start_outbound(
+        mut session: PeerSession,
+        tx: peer::Sender,
+        utp: Option<UtpSocket>,
+        info_hash: Sha1Hash,
+    ) -> Self {
         let join_handle =
-            task::spawn(async move { session.start_outbound().await });
-        Self::new(tx, join_handle)
+            task::spawn(
+                async move { session.start_outbound(utp, info_hash).await },
+            );
+        Self::new(tx, join_handle, true)
     }
 
     fn start_inbound(
-        socket: TcpStream,
+        socket: peer::Stream,
         mut session: PeerSession,
         tx: peer::Sender,
     ) -> Self {
         let join_handle =
             task::spawn(async move { session.start_inbound(socket).await });
-        Self::new(tx, join_handle)
+        Self::new(tx, join_handle, false)
     }
 
     fn new(
         tx: peer::Sender,
         join_handle: task::JoinHandle<peer::error::Result<()>>,
+        is_outbound: bool,
     ) -> Self {
         Self {
             tx: Some(tx),
             id: None,
+            transport: None,
             state: SessionState {
                 connection: ConnectionState::Connecting,
                 ..Default::default()
             },
             piece_count: 0,
             thruput: Default::default(),
+            is_outbound,
+            is_duplicate: false,
             join_handle: Some(join_handle),
         }
     }
 }
 
+/// A web seed of the torrent, and its session, if one is running.
+struct WebSeedEntry {
+    url: Url,
+    /// The URL of each of the torrent's files on the web seed, or `None` for
+    /// padding files.
+    file_urls: Vec<Option<Url>>,
+    /// The channel on which to communicate with the session, if it's running.
+    tx: Option<web_seed::Sender>,
+    /// The session task's join handle, used during shutdown.
+    join_handle: Option<task::JoinHandle<web_seed::Result<()>>>,
+    /// The transfers of the web seed since the last torrent tick.
+    counters: ThruputCounters,
+    /// The number of consecutive failed sessions. This is reset when a piece is
+    /// downloaded from the web seed.
+    error_count: usize,
+    /// The earliest time the web seed may be retried after an error.
+    retry_time: Option<Instant>,
+}
+
+impl WebSeedEntry {
+    fn new(url: Url, file_urls: Vec<Option<Url>>) -> Self {
+        Self {
+            url,
+            file_urls,
+            tx: None,
+            join_handle: None,
+            counters: Default::default(),
+            error_count: 0,
+            retry_time: None,
+        }
+    }
+
+    /// Returns whether a session with the web seed is running.
+    fn is_active(&self) -> bool {
+        self.join_handle.is_some()
+    }
+
+    /// Returns whether the web seed's backoff, if any, is over at the given
+    /// time.
+    fn can_retry(&self, t: Instant) -> bool {
+        self.retry_time
+            .map(|retry_time| t >= retry_time)
+            .unwrap_or(true)
+    }
+
+    /// Records a failed session and doubles the time the web seed has to wait
+    /// before it's retried with each consecutive error, starting with the
+    /// given backoff.
+    fn record_error(&mut self, t: Instant, backoff: Duration) {
+        self.error_count += 1;
+        let exp = (self.error_count - 1).min(6) as u32;
+        self.retry_time = Some(t + backoff * 2u32.pow(exp));
+    }
+}
+
 /// Contains the tracker client as well as additional metadata about the
 /// tracker.
 struct TrackerEntry {
-    client: Tracker,
+    /// The tracker client, which is shared with the tasks that announce to
+    /// and scrape the tracker.
+    client: Arc<Tracker>,
     /// If a previous announce contained a tracker_id, it should be included in
     /// next announces. Therefore it is cached here.
     id: Option<String>,
@@ -921,17 +2373,52 @@ struct TrackerEntry {
     /// Each time we fail to requet from tracker, this counter is incremented.
     /// If it fails too often, we stop requesting from tracker.
     error_count: usize,
+    /// Whether the last announce to the tracker succeeded.
+    is_working: bool,
+    /// Whether the tracker responded to the announce of the torrent's start,
+    /// after which it's no longer sent the started event and it's sent the
+    /// stopped event when the torrent shuts down.
+    is_started: bool,
+    /// Whether an announce to the tracker is in progress.
+    is_updating: bool,
+    /// The event to announce once the announce in progress finishes.
+    queued_event: Option<Event>,
+    /// The error of the last announce, if it failed.
+    last_error: Option<String>,
+    /// The warning message in the last response, if any.
+    warning_message: Option<String>,
+    /// The number of seeders in the swarm, according to the last response.
+    seeder_count: Option<usize>,
+    /// The number of leechers in the swarm, according to the last response.
+    leecher_count: Option<usize>,
+    /// The number of peers returned in the last announce.
+    peer_count: usize,
+    /// The last time the tracker was scraped, successfully or not.
+    last_scrape_time: Option<Instant>,
+    /// The swarm statistics returned by the last successful scrape.
+    scrape: Option<Scrape>,
 }
 
 impl TrackerEntry {
     fn new(client: Tracker) -> Self {
         Self {
-            client,
+            client: Arc::new(client),
             id: None,
             last_announce_time: None,
             interval: None,
             min_interval: None,
             error_count: 0,
+            is_working: false,
+            is_started: false,
+            is_updating: false,
+            queued_event: None,
+            last_error: None,
+            warning_message: None,
+            seeder_count: None,
+            leecher_count: None,
+            peer_count: 0,
+            last_scrape_time: None,
+            scrape: None,
         }
     }
 
@@ -940,20 +2427,84 @@ impl TrackerEntry {
     ///
     /// Later this function should take into consideration the client's minimum
     /// announce frequency settings.
-    fn should_announce(
-        &self,
-        t: Instant,
-        default_announce_interval: Duration,
-    ) -> bool {
+    fn should_announce(&self, t: Instant, conf: &TorrentConf) -> bool {
         if let Some(last_announce_time) = self.last_announce_time {
-            let min_next_announce_time = last_announce_time
-                + self.interval.unwrap_or(default_announce_interval);
-            t > min_next_announce_time
+            t > last_announce_time + self.announce_interval(conf)
         } else {
             true
         }
     }
 
+    /// Returns how long to wait after the last announce before announcing
+    /// again.
+    ///
+    /// This is the tracker's announce interval, unless the last announce
+    /// failed, in which case the tracker is retried sooner, after
+    /// [`TorrentConf::tracker_backoff`] that is doubled with each error.
+    fn announce_interval(&self, conf: &TorrentConf) -> Duration {
+        let interval = self.interval.unwrap_or(conf.announce_interval);
+        if self.last_error.is_some() && self.error_count > 0 {
+            let exp = (self.error_count - 1).min(6) as u32;
+            interval.min(conf.tracker_backoff * 2u32.pow(exp))
+        } else {
+            interval
+        }
+    }
+
+    /// Returns when the tracker is next announced to, unless we need peers
+    /// sooner. This is not set if the tracker hasn't been contacted yet or if
+    /// it's disabled.
+    fn next_announce_time(&self, conf: &TorrentConf) -> Option<Instant> {
+        if self.error_count >= conf.tracker_error_threshold {
+            return None;
+        }
+        self.last_announce_time
+            .map(|t| t + self.announce_interval(conf))
+    }
+
+    /// Returns the status of the tracker, which is disabled if its errors
+    /// reached the given threshold.
+    fn status(&self, error_threshold: usize) -> TrackerStatus {
+        if self.error_count >= error_threshold {
+            TrackerStatus::Disabled
+        } else if self.is_updating {
+            TrackerStatus::Updating
+        } else if let Some(error) = &self.last_error {
+            TrackerStatus::Error(error.clone())
+        } else if self.is_working {
+            TrackerStatus::Working
+        } else {
+            TrackerStatus::NotContacted
+        }
+    }
+
+    /// Determines whether the tracker should be scraped at the given time,
+    /// which is never if there is no scrape interval.
+    fn should_scrape(
+        &self,
+        t: Instant,
+        scrape_interval: Option<Duration>,
+    ) -> bool {
+        match (scrape_interval, self.last_scrape_time) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(interval), Some(last_scrape_time)) => {
+                t >= last_scrape_time + interval
+            }
+        }
+    }
+
+    /// Determines whether the tracker's minimum announce interval, if it has
+    /// one, has passed at the given time.
+    fn is_min_interval_over(&self, t: Instant) -> bool {
+        match (self.last_announce_time, self.min_interval) {
+            (Some(last_announce_time), Some(min_interval)) => {
+                t >= last_announce_time + min_interval
+            }
+            _ => true,
+        }
+    }
+
     /// Determines whether we're allowed to announce at the given time.
     ///
     /// We may need peers before the next step in the announce interval.
@@ -976,3 +2527,244 @@ impl TrackerEntry {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker_entry() -> TrackerEntry {
+        TrackerEntry::new(Tracker::new(
+            "http://tracker.example.com/announce".parse().unwrap(),
+        ))
+    }
+
+    #[test]
+    fn test_tracker_status() {
+        let threshold = 3;
+        let mut tracker = tracker_entry();
+        assert_eq!(tracker.status(threshold), TrackerStatus::NotContacted);
+
+        // announcing
+        tracker.is_updating = true;
+        assert_eq!(tracker.status(threshold), TrackerStatus::Updating);
+
+        // the announce succeeded
+        tracker.is_updating = false;
+        tracker.is_working = true;
+        assert_eq!(tracker.status(threshold), TrackerStatus::Working);
+
+        // the next announce failed, which is reported while reannouncing
+        // until the tracker responds again
+        tracker.is_working = false;
+        tracker.error_count = 1;
+        tracker.last_error = Some("tracker timed out".into());
+        assert_eq!(
+            tracker.status(threshold),
+            TrackerStatus::Error("tracker timed out".into())
+        );
+        tracker.is_updating = true;
+        assert_eq!(tracker.status(threshold), TrackerStatus::Updating);
+
+        // the tracker recovered
+        tracker.is_updating = false;
+        tracker.is_working = true;
+        tracker.last_error = None;
+        assert_eq!(tracker.status(threshold), TrackerStatus::Working);
+
+        // too many errors disable the tracker, even if it's being announced
+        // to
+        tracker.is_working = false;
+        tracker.error_count = threshold;
+        tracker.last_error = Some("tracker timed out".into());
+        assert_eq!(tracker.status(threshold), TrackerStatus::Disabled);
+        tracker.is_updating = true;
+        assert_eq!(tracker.status(threshold), TrackerStatus::Disabled);
+    }
+
+    #[test]
+    fn test_next_announce_time() {
+        let conf = TorrentConf {
+            announce_interval: Duration::from_secs(3600),
+            tracker_backoff: Duration::from_secs(15),
+            tracker_error_threshold: 10,
+            ..TorrentConf::default()
+        };
+        let now = Instant::now();
+        let mut tracker = tracker_entry();
+        assert_eq!(tracker.next_announce_time(&conf), None);
+        assert!(tracker.should_announce(now, &conf));
+
+        // without an interval from the tracker, the default one is used
+        tracker.last_announce_time = Some(now);
+        tracker.is_working = true;
+        assert_eq!(
+            tracker.next_announce_time(&conf),
+            Some(now + Duration::from_secs(3600))
+        );
+        tracker.interval = Some(Duration::from_secs(1800));
+        assert_eq!(
+            tracker.next_announce_time(&conf),
+            Some(now + Duration::from_secs(1800))
+        );
+
+        // a failing tracker is retried after a backoff that doubles with each
+        // error, up to 64 times the initial backoff
+        tracker.is_working = false;
+        tracker.last_error = Some("tracker timed out".into());
+        let backoffs = [
+            (1, 15),
+            (2, 30),
+            (3, 60),
+            (4, 120),
+            (5, 240),
+            (6, 480),
+            (7, 960),
+            (8, 960),
+            (9, 960),
+        ];
+        for (error_count, backoff) in backoffs.iter() {
+            tracker.error_count = *error_count;
+            let next_announce_time = now + Duration::from_secs(*backoff);
+            assert_eq!(
+                tracker.next_announce_time(&conf),
+                Some(next_announce_time)
+            );
+            assert!(!tracker.should_announce(next_announce_time, &conf));
+            assert!(tracker.should_announce(
+                next_announce_time + Duration::from_millis(1),
+                &conf
+            ));
+        }
+
+        // the backoff is never longer than the tracker's interval
+        tracker.interval = Some(Duration::from_secs(300));
+        tracker.error_count = 6;
+        assert_eq!(
+            tracker.next_announce_time(&conf),
+            Some(now + Duration::from_secs(300))
+        );
+
+        // a recovered tracker is announced to at its interval again, even
+        // though its errors are still counted
+        tracker.is_working = true;
+        tracker.last_error = None;
+        assert_eq!(
+            tracker.next_announce_time(&conf),
+            Some(now + Duration::from_secs(300))
+        );
+
+        // a disabled tracker is no longer announced to
+        tracker.error_count = 10;
+        assert_eq!(tracker.next_announce_time(&conf), None);
+    }
+
+    fn block(offset: u32, sender: Option<u16>, hash: u8) -> BlockHash {
+        BlockHash {
+            offset,
+            sender: sender.map(addr),
+            hash: [hash; 20],
+        }
+    }
+
+    fn piece(is_valid: bool, blocks: Vec<BlockHash>) -> PieceCompletion {
+        PieceCompletion {
+            index: 0,
+            is_valid,
+            blocks,
+        }
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new([10, 0, 0, 1].into(), port)
+    }
+
+    #[test]
+    fn test_ban_sole_sender_of_failed_piece() {
+        let not_banned = |_| false;
+
+        // a single peer sent all blocks of the failed piece
+        let failed =
+            piece(false, vec![block(0, Some(1), 1), block(1, Some(1), 1)]);
+        assert_eq!(corrupt_block_senders(&failed, &[], not_banned), [addr(1)]);
+
+        // several peers, or a web seed, sent the blocks, so it's not yet known
+        // which of them sent the corrupt data
+        let failed =
+            piece(false, vec![block(0, Some(1), 1), block(1, Some(2), 1)]);
+        assert!(corrupt_block_senders(&failed, &[], not_banned).is_empty());
+        let failed = piece(false, vec![block(0, None, 1), block(1, None, 1)]);
+        assert!(corrupt_block_senders(&failed, &[], not_banned).is_empty());
+        let failed =
+            piece(false, vec![block(0, Some(1), 1), block(1, None, 1)]);
+        assert!(corrupt_block_senders(&failed, &[], not_banned).is_empty());
+        assert!(corrupt_block_senders(
+            &piece(false, Vec::new()),
+            &[],
+            not_banned
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_ban_senders_of_blocks_differing_from_valid_piece() {
+        let not_banned = |_| false;
+        let valid = piece(
+            true,
+            vec![
+                block(0, Some(3), 1),
+                block(1, Some(3), 2),
+                block(2, Some(3), 3),
+            ],
+        );
+        // the blocks of two failed attempts: peer 1 sent a corrupt block in
+        // both, peer 2 sent only valid blocks and peer 4 a corrupt one
+        let failed_blocks = [
+            block(0, Some(1), 9),
+            block(1, Some(2), 2),
+            block(2, None, 3),
+            block(0, Some(1), 8),
+            block(1, Some(2), 2),
+            block(2, Some(4), 7),
+        ];
+        assert_eq!(
+            corrupt_block_senders(&valid, &failed_blocks, not_banned),
+            [addr(1), addr(4)]
+        );
+
+        // a corrupt block from a web seed can't be blamed on a peer
+        assert!(corrupt_block_senders(
+            &valid,
+            &[block(0, None, 9)],
+            not_banned
+        )
+        .is_empty());
+        // blocks that weren't in the valid piece's hashes aren't compared
+        assert!(corrupt_block_senders(
+            &valid,
+            &[block(5, Some(1), 9)],
+            not_banned
+        )
+        .is_empty());
+        // and a piece that passed the first time has no corrupt blocks
+        assert!(corrupt_block_senders(&valid, &[], not_banned).is_empty());
+    }
+
+    #[test]
+    fn test_ban_skips_banned_ips() {
+        let failed = piece(false, vec![block(0, Some(1), 1)]);
+        assert!(corrupt_block_senders(&failed, &[], |_| true).is_empty());
+
+        let valid = piece(true, vec![block(0, Some(3), 1)]);
+        let failed_blocks = [block(0, Some(1), 9)];
+        let banned_ip = addr(1).ip();
+        assert!(corrupt_block_senders(&valid, &failed_blocks, |ip| ip
+            == banned_ip)
+        .is_empty());
+        // only the banned IP is left out
+        let other_ip = IpAddr::from([10, 0, 0, 2]);
+        assert_eq!(
+            corrupt_block_senders(&valid, &failed_blocks, |ip| ip == other_ip),
+            [addr(1)]
+        );
+    }
+}
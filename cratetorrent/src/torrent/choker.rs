@@ -0,0 +1,435 @@
+//! The choke algorithm, which decides which peers we upload to.
+//!
+//! Uploading to every interested peer would spread our upload capacity too
+//! thin and would reward peers that don't upload anything in return. Instead,
+//! at regular intervals, the peers that upload to us the fastest are unchoked
+//! (tit-for-tat), and one more peer is unchoked optimistically, so that peers
+//! we don't download from have a chance to start reciprocating, and so that
+//! we may find better peers than the ones we have.
+//!
+//! Once we have the whole torrent no one uploads to us, so the peers are
+//! instead ranked according to the torrent's [`SeedChokePolicy`].
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use rand::seq::SliceRandom;
+
+use crate::conf::{SeedChokePolicy, TorrentConf};
+
+/// A connected peer, as seen by the choker.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Candidate {
+    pub addr: SocketAddr,
+    /// Only interested peers are unchoked, as the others wouldn't download
+    /// anything anyway.
+    pub is_interested: bool,
+    /// Whether we're currently choking the peer.
+    pub is_choked: bool,
+    /// The rate at which the peer uploads to us, in bytes per second.
+    pub down_rate: u64,
+    /// The rate at which we upload to the peer, in bytes per second.
+    pub up_rate: u64,
+    /// The total number of bytes we uploaded to the peer.
+    pub up_total: u64,
+    /// The number of pieces the peer has.
+    pub piece_count: usize,
+}
+
+/// The last time a peer was unchoked.
+#[derive(Clone, Copy, Debug)]
+struct Unchoke {
+    time: Instant,
+    /// The total number of bytes uploaded to the peer at the time, from which
+    /// the bytes uploaded since being unchoked are derived.
+    up_total: u64,
+}
+
+pub(super) struct Choker {
+    /// The number of peers unchoked for their upload rates.
+    upload_slots: usize,
+    unchoke_interval: Duration,
+    optimistic_unchoke_interval: Duration,
+    seed_choke_policy: SeedChokePolicy,
+    /// The number of pieces in the torrent.
+    piece_count: usize,
+    /// When the unchoked peers were last picked.
+    last_unchoke_time: Option<Instant>,
+    /// When the optimistically unchoked peer was last rotated.
+    last_optimistic_unchoke_time: Option<Instant>,
+    /// The peer that is unchoked regardless of its upload rate.
+    optimistic: Option<SocketAddr>,
+    /// The last unchoke of each peer that has been unchoked, as long as it's
+    /// among the candidates.
+    unchokes: HashMap<SocketAddr, Unchoke>,
+}
+
+impl Choker {
+    pub fn new(conf: &TorrentConf, piece_count: usize) -> Self {
+        Self {
+            upload_slots: conf.upload_slots,
+            unchoke_interval: conf.unchoke_interval,
+            optimistic_unchoke_interval: conf.optimistic_unchoke_interval,
+            seed_choke_policy: conf.seed_choke_policy,
+            piece_count,
+            last_unchoke_time: None,
+            last_optimistic_unchoke_time: None,
+            optimistic: None,
+            unchokes: HashMap::new(),
+        }
+    }
+
+    /// Returns the peers that should be unchoked, if it's time to pick them,
+    /// all other peers should be choked. Otherwise `None` is returned and the
+    /// peers should be left as they are.
+    ///
+    /// The peers are picked at every unchoke interval, or sooner, if there are
+    /// free upload slots and peers waiting for one, so that new peers don't
+    /// have to wait for the next interval.
+    ///
+    /// If we're a seed, the peers are ranked according to the seed choke
+    /// policy, otherwise by the rate at which they upload to us.
+    pub fn run(
+        &mut self,
+        now: Instant,
+        peers: &[Candidate],
+        is_seed: bool,
+    ) -> Option<HashSet<SocketAddr>> {
+        // forget about the peers that are gone
+        self.unchokes
+            .retain(|addr, _| peers.iter().any(|peer| peer.addr == *addr));
+
+        if !is_due(self.last_unchoke_time, now, self.unchoke_interval)
+            && !self.has_free_slot(peers)
+        {
+            return None;
+        }
+        self.last_unchoke_time = Some(now);
+
+        let mut interested: Vec<_> =
+            peers.iter().filter(|peer| peer.is_interested).collect();
+        if is_seed {
+            self.rank_for_seed(&mut interested);
+        } else {
+            // those uploading to us the fastest first
+            interested.sort_by_key(|peer| Reverse(peer.down_rate));
+        }
+        let slot_count = self.upload_slots.min(interested.len());
+        let (regular, rest) = interested.split_at(slot_count);
+        let mut unchoked: HashSet<_> =
+            regular.iter().map(|peer| peer.addr).collect();
+
+        // The optimistic unchoke is rotated when it's due, or earlier, if the
+        // peer is no longer interested, has disconnected, or has earned
+        // a regular slot. In the latter cases the rotation isn't postponed,
+        // so as not to favor the new peer.
+        let is_optimistic_valid = match self.optimistic {
+            Some(addr) => rest.iter().any(|peer| peer.addr == addr),
+            None => false,
+        };
+        let is_rotation_due = is_due(
+            self.last_optimistic_unchoke_time,
+            now,
+            self.optimistic_unchoke_interval,
+        );
+        if is_rotation_due || !is_optimistic_valid {
+            let prev = self.optimistic;
+            // prefer another peer than the current one, if there is any
+            let others: Vec<_> =
+                rest.iter().filter(|peer| Some(peer.addr) != prev).collect();
+            self.optimistic = others
+                .choose(&mut rand::thread_rng())
+                .map(|peer| peer.addr)
+                .or_else(|| prev.filter(|_| is_optimistic_valid));
+            if is_rotation_due {
+                self.last_optimistic_unchoke_time = Some(now);
+            }
+            if self.optimistic != prev {
+                log::debug!("Optimistically unchoking {:?}", self.optimistic);
+            }
+        }
+        unchoked.extend(self.optimistic);
+
+        for peer in peers.iter() {
+            if peer.is_choked && unchoked.contains(&peer.addr) {
+                self.unchokes.insert(
+                    peer.addr,
+                    Unchoke {
+                        time: now,
+                        up_total: peer.up_total,
+                    },
+                );
+            }
+        }
+
+        Some(unchoked)
+    }
+
+    /// Sorts the peers by how much they deserve an upload slot, according to
+    /// the seed choke policy.
+    fn rank_for_seed(&self, peers: &mut Vec<&Candidate>) {
+        match self.seed_choke_policy {
+            SeedChokePolicy::RoundRobin { quota } => {
+                // The unchoked peers that haven't used up their quota come
+                // first, then the choked peers, those that have waited the
+                // longest first, and finally the unchoked peers that have used
+                // up their quota.
+                peers.sort_by_key(|peer| {
+                    let unchoke = self.unchokes.get(&peer.addr);
+                    let rank = match unchoke {
+                        Some(_) if peer.is_choked => 1,
+                        None => 1,
+                        Some(unchoke)
+                            if peer
+                                .up_total
+                                .saturating_sub(unchoke.up_total)
+                                < quota =>
+                        {
+                            0
+                        }
+                        Some(_) => 2,
+                    };
+                    (rank, unchoke.map(|unchoke| unchoke.time))
+                });
+            }
+            SeedChokePolicy::FastestUpload => {
+                peers.sort_by_key(|peer| Reverse(peer.up_rate));
+            }
+            SeedChokePolicy::AntiLeech => {
+                // the further away from having half of the torrent, the better,
+                // with the faster peers first among equals
+                let piece_count = self.piece_count;
+                peers.sort_by_key(|peer| {
+                    let have = 2 * peer.piece_count;
+                    let distance =
+                        have.max(piece_count) - have.min(piece_count);
+                    (Reverse(distance), Reverse(peer.up_rate))
+                });
+            }
+        }
+    }
+
+    /// Returns true if fewer peers are unchoked than there are upload slots,
+    /// including the optimistic one, and there is an interested peer that is
+    /// choked.
+    fn has_free_slot(&self, peers: &[Candidate]) -> bool {
+        let interested = peers.iter().filter(|peer| peer.is_interested);
+        let unchoked_count =
+            interested.clone().filter(|peer| !peer.is_choked).count();
+        unchoked_count < self.upload_slots + 1
+            && interested.clone().any(|peer| peer.is_choked)
+    }
+}
+
+/// Returns whether the interval has passed since the last time, or if there
+/// was no last time.
+fn is_due(last: Option<Instant>, now: Instant, interval: Duration) -> bool {
+    match last {
+        Some(last) => now.saturating_duration_since(last) >= interval,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(port: u16, is_interested: bool, down_rate: u64) -> Candidate {
+        Candidate {
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            is_interested,
+            is_choked: true,
+            down_rate,
+            up_rate: 0,
+            up_total: 0,
+            piece_count: 0,
+        }
+    }
+
+    /// Updates the peers' choke states to the choker's decision.
+    fn apply(peers: &mut [Candidate], unchoked: &HashSet<SocketAddr>) {
+        for peer in peers.iter_mut() {
+            peer.is_choked = !unchoked.contains(&peer.addr);
+        }
+    }
+
+    fn choker(upload_slots: usize) -> Choker {
+        seed_choker(upload_slots, SeedChokePolicy::FastestUpload)
+    }
+
+    fn seed_choker(upload_slots: usize, policy: SeedChokePolicy) -> Choker {
+        let conf = TorrentConf {
+            upload_slots,
+            unchoke_interval: Duration::from_secs(10),
+            optimistic_unchoke_interval: Duration::from_secs(30),
+            seed_choke_policy: policy,
+            ..Default::default()
+        };
+        Choker::new(&conf, 100)
+    }
+
+    #[test]
+    fn test_unchoke_fastest_interested_peers() {
+        let mut choker = choker(2);
+        let mut peers = vec![
+            candidate(1, true, 100),
+            candidate(2, true, 300),
+            candidate(3, false, 1000),
+            candidate(4, true, 200),
+            candidate(5, true, 0),
+        ];
+        let now = Instant::now();
+        let unchoked = choker.run(now, &peers, false).unwrap();
+        apply(&mut peers, &unchoked);
+
+        // the two fastest, and one of the slower ones optimistically
+        assert_eq!(unchoked.len(), 3);
+        assert!(unchoked.contains(&peers[1].addr));
+        assert!(unchoked.contains(&peers[3].addr));
+        assert!(!unchoked.contains(&peers[2].addr));
+        let optimistic = choker.optimistic.unwrap();
+        assert!(optimistic == peers[0].addr || optimistic == peers[4].addr);
+
+        // nothing is decided until the interval passes, as all slots are
+        // taken
+        assert!(choker
+            .run(now + Duration::from_secs(5), &peers, false)
+            .is_none());
+
+        // the optimistic unchoke is kept until it's due for rotation
+        let unchoked = choker
+            .run(now + Duration::from_secs(10), &peers, false)
+            .unwrap();
+        apply(&mut peers, &unchoked);
+        assert!(unchoked.contains(&optimistic));
+        assert_eq!(choker.optimistic, Some(optimistic));
+        let unchoked = choker
+            .run(now + Duration::from_secs(30), &peers, false)
+            .unwrap();
+        assert!(!unchoked.contains(&optimistic));
+        assert_ne!(choker.optimistic, Some(optimistic));
+    }
+
+    #[test]
+    fn test_unchoke_early_if_slot_is_free() {
+        let mut choker = choker(2);
+        let mut peers = vec![candidate(1, true, 100), candidate(2, false, 0)];
+        let now = Instant::now();
+        let unchoked = choker.run(now, &peers, false).unwrap();
+        apply(&mut peers, &unchoked);
+        assert_eq!(unchoked, vec![peers[0].addr].into_iter().collect());
+        assert!(choker
+            .run(now + Duration::from_secs(1), &peers, false)
+            .is_none());
+
+        // a newly interested peer needn't wait for the next interval
+        peers[1].is_interested = true;
+        let unchoked = choker
+            .run(now + Duration::from_secs(2), &peers, false)
+            .unwrap();
+        assert_eq!(unchoked.len(), 2);
+    }
+
+    #[test]
+    fn test_replace_invalid_optimistic_unchoke() {
+        let mut choker = choker(1);
+        let mut peers = vec![candidate(1, true, 100), candidate(2, true, 0)];
+        let now = Instant::now();
+        let unchoked = choker.run(now, &peers, false).unwrap();
+        apply(&mut peers, &unchoked);
+        assert_eq!(unchoked.len(), 2);
+        assert_eq!(choker.optimistic, Some(peers[1].addr));
+
+        // the optimistic peer has become the fastest, so the other peer is
+        // unchoked optimistically
+        peers[1].down_rate = 200;
+        let unchoked = choker
+            .run(now + Duration::from_secs(10), &peers, false)
+            .unwrap();
+        apply(&mut peers, &unchoked);
+        assert_eq!(unchoked.len(), 2);
+        assert_eq!(choker.optimistic, Some(peers[0].addr));
+
+        // with no one else to unchoke, there is no optimistic unchoke
+        peers[0].is_interested = false;
+        let unchoked = choker
+            .run(now + Duration::from_secs(20), &peers, false)
+            .unwrap();
+        assert_eq!(unchoked, vec![peers[1].addr].into_iter().collect());
+        assert_eq!(choker.optimistic, None);
+    }
+
+    #[test]
+    fn test_seed_round_robin() {
+        let mut choker =
+            seed_choker(1, SeedChokePolicy::RoundRobin { quota: 1000 });
+        let mut peers = vec![
+            candidate(1, true, 0),
+            candidate(2, true, 0),
+            candidate(3, true, 0),
+        ];
+        let now = Instant::now();
+        let unchoked = choker.run(now, &peers, true).unwrap();
+        apply(&mut peers, &unchoked);
+        let optimistic = choker.optimistic.unwrap();
+        let regular = *unchoked.iter().find(|a| **a != optimistic).unwrap();
+        let waiting = peers
+            .iter()
+            .map(|peer| peer.addr)
+            .find(|addr| !unchoked.contains(addr))
+            .unwrap();
+
+        // the peer keeps its slot until it has used up its quota
+        let peer = peers.iter_mut().find(|p| p.addr == regular).unwrap();
+        peer.up_total = 999;
+        let unchoked = choker
+            .run(now + Duration::from_secs(10), &peers, true)
+            .unwrap();
+        assert!(unchoked.contains(&regular));
+
+        // after which the slot goes to the peer that was left waiting, while
+        // the optimistic unchoke is kept until it's due for rotation
+        for peer in peers.iter_mut().filter(|p| p.addr != waiting) {
+            peer.up_total = 1000;
+        }
+        let unchoked = choker
+            .run(now + Duration::from_secs(20), &peers, true)
+            .unwrap();
+        assert!(!unchoked.contains(&regular));
+        assert!(unchoked.contains(&waiting));
+        assert!(unchoked.contains(&optimistic));
+    }
+
+    #[test]
+    fn test_seed_anti_leech() {
+        let mut choker = seed_choker(2, SeedChokePolicy::AntiLeech);
+        let mut peers = Vec::new();
+        for (port, piece_count) in [(1, 50), (2, 95), (3, 60), (4, 2)].iter() {
+            let mut peer = candidate(*port, true, 0);
+            peer.piece_count = *piece_count;
+            peers.push(peer);
+        }
+        let unchoked = choker.run(Instant::now(), &peers, true).unwrap();
+        // the ones just starting and about to finish
+        assert!(unchoked.contains(&peers[1].addr));
+        assert!(unchoked.contains(&peers[3].addr));
+    }
+
+    #[test]
+    fn test_seed_fastest_upload() {
+        let mut choker = seed_choker(1, SeedChokePolicy::FastestUpload);
+        let mut peers = vec![candidate(1, true, 500), candidate(2, true, 0)];
+        peers[1].up_rate = 100;
+        let unchoked = choker.run(Instant::now(), &peers, true).unwrap();
+        assert_eq!(unchoked.len(), 2);
+        assert_eq!(choker.optimistic, Some(peers[0].addr));
+        // but not as a seed
+        let mut choker = seed_choker(1, SeedChokePolicy::FastestUpload);
+        choker.run(Instant::now(), &peers, false).unwrap();
+        assert_eq!(choker.optimistic, Some(peers[1].addr));
+    }
+}
@@ -0,0 +1,504 @@
+//! The peers known to a torrent, both connected and not.
+//!
+//! Peers are added to the list as they are discovered, and are picked from it
+//! when the torrent has room for more connections. The list remembers how each
+//! connection attempt went: a peer that couldn't be connected to is retried
+//! with exponential backoff, until it has failed too many times, after which
+//! it's forgotten. A peer that violated the protocol is not connected to
+//! again, nor accepted when it connects to us.
+//!
+//! The list is capped in size. When full, the peers least likely to be useful
+//! are evicted to make room for new ones: banned peers first, then those that
+//! failed the most.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    conf::TorrentConf, peer::DisconnectReason, torrent::stats::PeerSource,
+    Sha1Hash,
+};
+
+/// A known peer.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct PeerInfo {
+    /// Where we learned about the peer.
+    pub source: PeerSource,
+    /// The info hash of the swarm in which the peer was found, or `None` if
+    /// the peer connected to us, in which case its swarm is only known from
+    /// its handshake.
+    pub info_hash: Option<Sha1Hash>,
+    /// The number of connection attempts in a row that failed.
+    pub failure_count: u32,
+    /// When we last tried to connect to the peer.
+    pub last_connect_attempt: Option<Instant>,
+    /// Whether the peer has all pieces, as of its last report.
+    pub is_seed: bool,
+    /// Whether the peer sent us corrupt data or violated the protocol. Banned
+    /// peers are not connected to.
+    pub is_banned: bool,
+    /// Whether we have a session with the peer.
+    pub is_connected: bool,
+}
+
+impl PeerInfo {
+    fn new(source: PeerSource, info_hash: Option<Sha1Hash>) -> Self {
+        Self {
+            source,
+            info_hash,
+            failure_count: 0,
+            last_connect_attempt: None,
+            is_seed: false,
+            is_banned: false,
+            is_connected: false,
+        }
+    }
+}
+
+pub(super) struct PeerList {
+    peers: HashMap<SocketAddr, PeerInfo>,
+    /// The IP addresses of banned peers. These are kept even if the peers
+    /// themselves are evicted, so that they aren't added again.
+    banned_ips: HashSet<IpAddr>,
+    /// The IP addresses of peers that violated the protocol, which are kept
+    /// for the same reason.
+    violator_ips: HashSet<IpAddr>,
+    max_len: usize,
+    retry_interval: Duration,
+    max_failure_count: u32,
+}
+
+impl PeerList {
+    pub fn new(conf: &TorrentConf) -> Self {
+        Self {
+            peers: HashMap::new(),
+            banned_ips: HashSet::new(),
+            violator_ips: HashSet::new(),
+            max_len: conf.max_peer_list_len,
+            retry_interval: conf.peer_retry_interval,
+            max_failure_count: conf.max_peer_failure_count,
+        }
+    }
+
+    /// Adds the peer if it's not yet known and returns whether it was added.
+    ///
+    /// If the list is full, the worst peer that is not connected is evicted
+    /// to make room, unless it's no worse than a new peer, in which case the
+    /// new peer is not added.
+    pub fn add(
+        &mut self,
+        addr: SocketAddr,
+        source: PeerSource,
+        info_hash: Sha1Hash,
+    ) -> bool {
+        if self.peers.contains_key(&addr) || self.is_banned(addr.ip()) {
+            return false;
+        }
+        if self.peers.len() >= self.max_len && !self.evict() {
+            log::trace!("Peer list full, not adding peer {}", addr);
+            return false;
+        }
+        self.peers
+            .insert(addr, PeerInfo::new(source, Some(info_hash)));
+        true
+    }
+
+    /// Adds a peer that connected to us, which is not connected to again once
+    /// it disconnects, as it's likely to have connected from a random port.
+    ///
+    /// Returns false if the peer is banned, if we already have a session with
+    /// it, or if there is no room for it.
+    pub fn add_incoming(&mut self, addr: SocketAddr) -> bool {
+        if self.is_banned(addr.ip()) {
+            return false;
+        }
+        // the peer may have been found elsewhere too
+        let info = match self.peers.get_mut(&addr) {
+            Some(info) => info,
+            None => {
+                if self.peers.len() >= self.max_len && !self.evict() {
+                    return false;
+                }
+                self.peers.entry(addr).or_insert_with(|| {
+                    PeerInfo::new(PeerSource::Incoming, None)
+                })
+            }
+        };
+        if info.is_connected {
+            return false;
+        }
+        info.is_connected = true;
+        true
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&PeerInfo> {
+        self.peers.get(addr)
+    }
+
+    /// Returns the number of peers that are neither connected nor banned.
+    pub fn available_count(&self) -> usize {
+        self.peers
+            .values()
+            .filter(|info| !info.is_connected && !info.is_banned)
+            .count()
+    }
+
+    /// Returns the peers that may be connected to now, the most promising
+    /// ones first: those that failed the fewest times, and among those the
+    /// ones tried the longest time ago.
+    ///
+    /// Peers are not retried until their backoff, which doubles with each
+    /// failure, has passed. If we're a seed, other seeds are skipped.
+    pub fn connect_candidates(
+        &self,
+        now: Instant,
+        is_seed: bool,
+    ) -> Vec<SocketAddr> {
+        let mut candidates: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, info)| {
+                !info.is_connected
+                    && !info.is_banned
+                    && (!is_seed || !info.is_seed)
+                    && self.can_retry(info, now)
+            })
+            .map(|(addr, info)| {
+                (*addr, info.failure_count, info.last_connect_attempt)
+            })
+            .collect();
+        candidates.sort_by_key(|(_, failure_count, last_connect_attempt)| {
+            (*failure_count, *last_connect_attempt)
+        });
+        candidates.into_iter().map(|(addr, ..)| addr).collect()
+    }
+
+    /// Marks that we're connecting to the peer.
+    pub fn connecting(&mut self, addr: &SocketAddr, now: Instant) {
+        if let Some(info) = self.peers.get_mut(addr) {
+            info.is_connected = true;
+            info.last_connect_attempt = Some(now);
+        }
+    }
+
+    /// Marks that the connection with the peer was established, which resets
+    /// its failures.
+    pub fn connected(&mut self, addr: &SocketAddr) {
+        if let Some(info) = self.peers.get_mut(addr) {
+            info.failure_count = 0;
+        }
+    }
+
+    /// Marks that the session with the peer has ended, why, and whether it's
+    /// because we couldn't connect to it.
+    ///
+    /// Peers that connected to us, and peers that failed too many times, are
+    /// removed. Peers that violated the protocol are banned by their IP
+    /// address, which is remembered even once they are removed.
+    pub fn disconnected(
+        &mut self,
+        addr: &SocketAddr,
+        reason: DisconnectReason,
+        is_failure: bool,
+    ) {
+        let info = match self.peers.get_mut(addr) {
+            Some(info) => info,
+            None => return,
+        };
+        info.is_connected = false;
+        if is_failure {
+            info.failure_count += 1;
+        }
+        let should_remove = info.source == PeerSource::Incoming
+            || info.failure_count >= self.max_failure_count;
+        if reason == DisconnectReason::ProtocolViolation {
+            log::debug!("Not reconnecting to misbehaving peer {}", addr);
+            self.mark_banned(addr.ip());
+            self.violator_ips.insert(addr.ip());
+        }
+        if should_remove {
+            log::debug!(
+                "Removing peer {} from peer list (failures: {})",
+                addr,
+                self.peers[addr].failure_count
+            );
+            self.peers.remove(addr);
+        }
+    }
+
+    /// Records whether the peer has all pieces.
+    pub fn set_seed(&mut self, addr: &SocketAddr, is_seed: bool) {
+        if let Some(info) = self.peers.get_mut(addr) {
+            info.is_seed = is_seed;
+        }
+    }
+
+    /// Bans the IP address and returns whether it wasn't banned before.
+    pub fn ban(&mut self, ip: IpAddr) -> bool {
+        self.mark_banned(ip);
+        self.banned_ips.insert(ip)
+    }
+
+    /// Returns whether a peer at the IP address sent us corrupt data or
+    /// violated the protocol, in which case no peer at the address is added
+    /// or connected to.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned_ips.contains(&ip) || self.violator_ips.contains(&ip)
+    }
+
+    /// Removes the peer if we're not connected to it.
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        if matches!(self.peers.get(addr), Some(info) if !info.is_connected) {
+            self.peers.remove(addr);
+        }
+    }
+
+    /// Marks the peers at the IP address as banned.
+    fn mark_banned(&mut self, ip: IpAddr) {
+        for (_, info) in self.peers.iter_mut().filter(|(a, _)| a.ip() == ip) {
+            info.is_banned = true;
+        }
+    }
+
+    /// Returns whether the peer's backoff has passed since the last attempt
+    /// to connect to it.
+    ///
+    /// The backoff is the retry interval after the first failure, and is
+    /// doubled with each further failure. A peer that hasn't failed, such as
+    /// one whose session ended normally, is also retried after the retry
+    /// interval.
+    fn can_retry(&self, info: &PeerInfo, now: Instant) -> bool {
+        match info.last_connect_attempt {
+            Some(last) => {
+                let exp = info.failure_count.saturating_sub(1).min(16);
+                let backoff = self.retry_interval * (1 << exp);
+                now.saturating_duration_since(last) >= backoff
+            }
+            None => true,
+        }
+    }
+
+    /// Removes the worst peer that is not connected, if it's worse than a new
+    /// peer would be, and returns whether one was removed.
+    fn evict(&mut self) -> bool {
+        let worst = self
+            .peers
+            .iter()
+            .filter(|(_, info)| !info.is_connected)
+            .max_by_key(|(_, info)| {
+                (
+                    info.is_banned,
+                    info.failure_count,
+                    info.last_connect_attempt.is_some(),
+                )
+            })
+            .map(|(addr, info)| (*addr, *info));
+        match worst {
+            Some((addr, info))
+                if info.is_banned
+                    || info.failure_count > 0
+                    || info.last_connect_attempt.is_some() =>
+            {
+                log::trace!("Evicting peer {} from peer list", addr);
+                self.peers.remove(&addr);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_list(max_len: usize) -> PeerList {
+        PeerList::new(&TorrentConf {
+            max_peer_list_len: max_len,
+            peer_retry_interval: Duration::from_secs(10),
+            max_peer_failure_count: 3,
+            ..Default::default()
+        })
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_retry_with_backoff() {
+        let mut peers = peer_list(10);
+        let now = Instant::now();
+        assert!(peers.add(addr(1), PeerSource::Tracker, [0; 20]));
+        assert!(!peers.add(addr(1), PeerSource::Lsd, [0; 20]));
+        assert_eq!(peers.connect_candidates(now, false), vec![addr(1)]);
+
+        // the first failure is retried after the retry interval
+        peers.connecting(&addr(1), now);
+        assert!(peers.connect_candidates(now, false).is_empty());
+        peers.disconnected(&addr(1), DisconnectReason::ConnectFailed, true);
+        let t = now + Duration::from_secs(9);
+        assert!(peers.connect_candidates(t, false).is_empty());
+        let t = now + Duration::from_secs(10);
+        assert_eq!(peers.connect_candidates(t, false), vec![addr(1)]);
+
+        // the second after twice as long
+        peers.connecting(&addr(1), t);
+        peers.disconnected(&addr(1), DisconnectReason::ConnectFailed, true);
+        assert!(peers
+            .connect_candidates(t + Duration::from_secs(19), false)
+            .is_empty());
+        assert_eq!(
+            peers.connect_candidates(t + Duration::from_secs(20), false),
+            vec![addr(1)]
+        );
+
+        // and after the third failure the peer is forgotten
+        peers.connecting(&addr(1), t);
+        peers.disconnected(&addr(1), DisconnectReason::ConnectFailed, true);
+        assert!(peers.get(&addr(1)).is_none());
+    }
+
+    #[test]
+    fn test_prefer_peers_with_fewer_failures() {
+        let mut peers = peer_list(10);
+        let now = Instant::now();
+        peers.add(addr(1), PeerSource::Tracker, [0; 20]);
+        peers.connecting(&addr(1), now);
+        peers.disconnected(&addr(1), DisconnectReason::ConnectFailed, true);
+        peers.add(addr(2), PeerSource::Tracker, [0; 20]);
+        peers.add(addr(3), PeerSource::Manual, [0; 20]);
+        peers.connecting(&addr(3), now);
+        peers.connected(&addr(3));
+        peers.disconnected(&addr(3), DisconnectReason::Shutdown, false);
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(
+            peers.connect_candidates(later, false),
+            vec![addr(2), addr(3), addr(1)]
+        );
+
+        // seeds don't connect to seeds
+        peers.set_seed(&addr(2), true);
+        assert_eq!(
+            peers.connect_candidates(later, true),
+            vec![addr(3), addr(1)]
+        );
+    }
+
+    #[test]
+    fn test_evict_worst_peer() {
+        let mut peers = peer_list(2);
+        let now = Instant::now();
+        peers.add(addr(1), PeerSource::Tracker, [0; 20]);
+        peers.add(addr(2), PeerSource::Tracker, [0; 20]);
+        // the peers are as good as a new one
+        assert!(!peers.add(addr(3), PeerSource::Tracker, [0; 20]));
+
+        peers.connecting(&addr(1), now);
+        peers.disconnected(&addr(1), DisconnectReason::ConnectFailed, true);
+        assert!(peers.add(addr(3), PeerSource::Tracker, [0; 20]));
+        assert!(peers.get(&addr(1)).is_none());
+
+        // connected peers are not evicted
+        peers.connecting(&addr(2), now);
+        peers.connecting(&addr(3), now);
+        assert!(!peers.add(addr(4), PeerSource::Tracker, [0; 20]));
+    }
+
+    #[test]
+    fn test_ban() {
+        let mut peers = peer_list(10);
+        peers.add(addr(1), PeerSource::Tracker, [0; 20]);
+        assert!(peers.ban(addr(1).ip()));
+        assert!(!peers.ban(addr(1).ip()));
+        assert!(peers.get(&addr(1)).unwrap().is_banned);
+        assert!(peers.connect_candidates(Instant::now(), false).is_empty());
+        // other ports of the same host are banned too
+        assert!(!peers.add(addr(2), PeerSource::Tracker, [0; 20]));
+        assert!(!peers.add_incoming(addr(3)));
+    }
+
+    #[test]
+    fn test_no_reconnect_after_protocol_violation() {
+        let mut peers = peer_list(10);
+        let now = Instant::now();
+        peers.add(addr(1), PeerSource::Tracker, [0; 20]);
+        peers.connecting(&addr(1), now);
+        peers.connected(&addr(1));
+        peers.disconnected(
+            &addr(1),
+            DisconnectReason::ProtocolViolation,
+            false,
+        );
+        assert!(peers.get(&addr(1)).unwrap().is_banned);
+        assert!(peers
+            .connect_candidates(now + Duration::from_secs(3600), false)
+            .is_empty());
+        // and it's not added back when found again
+        assert!(!peers.add(addr(1), PeerSource::Tracker, [0; 20]));
+    }
+
+    #[test]
+    fn test_no_reconnect_after_evicting_protocol_violator() {
+        let mut peers = peer_list(1);
+        let now = Instant::now();
+        peers.add(addr(1), PeerSource::Tracker, [0; 20]);
+        peers.connecting(&addr(1), now);
+        peers.disconnected(
+            &addr(1),
+            DisconnectReason::ProtocolViolation,
+            false,
+        );
+        // the banned peer is evicted to make room for a new peer
+        let other = SocketAddr::from(([10, 0, 0, 2], 1));
+        assert!(peers.add(other, PeerSource::Tracker, [0; 20]));
+        assert!(peers.get(&addr(1)).is_none());
+
+        // but its address is remembered
+        assert!(!peers.add(addr(1), PeerSource::Pex, [0; 20]));
+        assert!(!peers.add_incoming(addr(1)));
+        assert!(peers.is_banned(addr(1).ip()));
+    }
+
+    #[test]
+    fn test_reject_incoming_protocol_violator() {
+        let mut peers = peer_list(10);
+        assert!(peers.add_incoming(addr(1)));
+        peers.disconnected(
+            &addr(1),
+            DisconnectReason::ProtocolViolation,
+            false,
+        );
+        // the incoming peer is forgotten, but not its violation, and it's
+        // rejected even if it connects from another port
+        assert!(peers.get(&addr(1)).is_none());
+        assert!(peers.is_banned(addr(2).ip()));
+        assert!(!peers.add_incoming(addr(2)));
+        assert!(!peers.add(addr(3), PeerSource::Tracker, [0; 20]));
+    }
+
+    #[test]
+    fn test_forget_incoming_peers() {
+        let mut peers = peer_list(10);
+        assert!(peers.add_incoming(addr(1)));
+        // we already have a session with the peer
+        assert!(!peers.add_incoming(addr(1)));
+        assert_eq!(peers.get(&addr(1)).unwrap().info_hash, None);
+        assert_eq!(peers.available_count(), 0);
+        peers.disconnected(&addr(1), DisconnectReason::ConnectionLost, false);
+        assert!(peers.get(&addr(1)).is_none());
+
+        // a known peer that connects to us is kept, along with its swarm
+        let now = Instant::now();
+        peers.add(addr(2), PeerSource::Tracker, [1; 20]);
+        assert!(peers.add_incoming(addr(2)));
+        assert!(peers.connect_candidates(now, false).is_empty());
+        peers.disconnected(&addr(2), DisconnectReason::ConnectionLost, false);
+        assert_eq!(peers.get(&addr(2)).unwrap().info_hash, Some([1; 20]));
+        assert_eq!(peers.connect_candidates(now, false), vec![addr(2)]);
+    }
+}
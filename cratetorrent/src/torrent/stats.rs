@@ -1,14 +1,20 @@
 use std::{
+    collections::HashMap,
     net::SocketAddr,
     time::{Duration, Instant},
 };
 
+use reqwest::Url;
+
 use crate::{
     counter::{ChannelCounter, Counter, ThruputCounters},
     PeerId, PieceIndex,
 };
 
-pub use crate::peer::{ConnectionState, SessionState};
+pub use crate::{
+    peer::{ConnectionState, DisconnectReason, SessionState, Transport},
+    tracker::Scrape,
+};
 
 /// Aggregated statistics of a torrent.
 #[derive(Clone, Debug, Default)]
@@ -31,6 +37,19 @@ pub struct TorrentStats {
     /// with aggregate statistics is sent with each tick.
     pub peers: Peers,
 
+    /// The number of peer sessions that ended since the torrent was started,
+    /// by the reason they ended.
+    pub disconnects: HashMap<DisconnectReason, usize>,
+
+    /// The web seeds of the torrent, with aggregate statistics for each.
+    ///
+    /// Like the full list of peers, this is only sent if enabled in the
+    /// torrent's [configuration](crate::conf::TorrentAlertConf::peers).
+    pub web_seeds: Option<Vec<WebSeedStats>>,
+
+    /// The trackers of the torrent, in order of priority.
+    pub trackers: Vec<TrackerStats>,
+
     /// Various thruput statistics of the torrent.
     pub thruput: ThruputStats,
 }
@@ -103,6 +122,11 @@ pub struct PeerSessionStats {
     /// Peer's 20 byte BitTorrent id. Updated when the peer sends us its peer
     /// id, in the handshake.
     pub id: Option<PeerId>,
+    /// The transport over which the peer is connected. Set once the peer is
+    /// connected.
+    pub transport: Option<Transport>,
+    /// Where we learned about the peer.
+    pub source: PeerSource,
     /// The current state of the session.
     pub state: SessionState,
     /// The number of pieces the peer has.
@@ -111,6 +135,91 @@ pub struct PeerSessionStats {
     pub thruput: ThruputStats,
 }
 
+/// How a peer was found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerSource {
+    /// The peer was returned by one of the torrent's trackers.
+    Tracker,
+    /// The peer was found in the DHT, which is not yet supported.
+    Dht,
+    /// Another peer told us about the peer through peer exchange, which is
+    /// not yet supported.
+    Pex,
+    /// The peer announced itself on the local network.
+    Lsd,
+    /// The peer was given by the user.
+    Manual,
+    /// The peer connected to us.
+    Incoming,
+}
+
+/// Statistics of a tracker of the torrent.
+#[derive(Clone, Debug)]
+pub struct TrackerStats {
+    /// The URL of the tracker, as given in the metainfo.
+    pub url: Url,
+    /// The tier of the tracker, where 0 is the tier with the highest priority.
+    pub tier: usize,
+    /// Whether the tracker can be announced to.
+    pub status: TrackerStatus,
+    /// When the tracker was last announced to.
+    pub last_announce_time: Option<Instant>,
+    /// When the tracker is next announced to, unless we need peers sooner.
+    /// A failing tracker is retried before its announce interval, see
+    /// [`TorrentConf::tracker_backoff`](crate::conf::TorrentConf::tracker_backoff).
+    /// This is not set if the tracker hasn't been contacted yet or if it's
+    /// disabled.
+    pub next_announce_time: Option<Instant>,
+    /// The number of seeders in the swarm, according to the last announce
+    /// response.
+    pub seeder_count: Option<usize>,
+    /// The number of leechers in the swarm, according to the last announce
+    /// response.
+    pub leecher_count: Option<usize>,
+    /// The warning the tracker sent in the last announce response, if any.
+    pub warning_message: Option<String>,
+    /// The number of peers the tracker returned in the last announce.
+    pub peer_count: usize,
+    /// The swarm statistics from the last successful scrape of the tracker.
+    ///
+    /// For hybrid torrents, this is the larger of the counts of the v1 and v2
+    /// swarms.
+    pub scrape: Option<Scrape>,
+}
+
+/// The status of a tracker.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TrackerStatus {
+    /// The tracker hasn't been announced to yet, which is the case when
+    /// a tracker in a tier with higher priority, or before it in its tier, is
+    /// working.
+    NotContacted,
+    /// The last announce to the tracker succeeded.
+    Working,
+    /// An announce to the tracker is in progress.
+    Updating,
+    /// The last announce to the tracker failed with the given error.
+    Error(String),
+    /// The tracker failed too many times and is no longer announced to. See
+    /// [`TorrentConf::tracker_error_threshold`](crate::conf::TorrentConf::tracker_error_threshold).
+    Disabled,
+}
+
+/// Aggregate statistics of a web seed.
+#[derive(Clone, Debug)]
+pub struct WebSeedStats {
+    /// The URL of the web seed, as given in the metainfo.
+    pub url: Url,
+    /// Whether pieces are currently being downloaded from the web seed.
+    pub is_active: bool,
+    /// The number of times in a row downloading from the web seed failed.
+    /// A failing web seed is retried less and less often.
+    pub error_count: usize,
+    /// Various thruput statistics of the web seed. There is never any upload
+    /// or protocol chatter.
+    pub thruput: ThruputStats,
+}
+
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct ThruputStats {
     /// Statistics about the protocol transfer rates in both directions.
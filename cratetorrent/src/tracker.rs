@@ -1,6 +1,7 @@
 use std::{
+    collections::HashMap,
     fmt,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     time::Duration,
 };
 
@@ -8,10 +9,14 @@ use bytes::Buf;
 use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
 use reqwest::{Client, Url};
 use serde::de;
+use serde_bytes::{ByteBuf, Bytes};
 
 use crate::{metainfo::BencodeError, PeerId, Sha1Hash};
 
 pub use reqwest::Error as HttpError;
+pub use tokio::io::Error as IoError;
+
+mod udp;
 
 pub(crate) type Result<T, E = TrackerError> = crate::error::Result<T, E>;
 
@@ -23,6 +28,19 @@ pub enum TrackerError {
     Bencode(BencodeError),
     /// HTTP related errors when contacting the tracker.
     Http(HttpError),
+    /// IO related errors when contacting a UDP tracker.
+    Io(IoError),
+    /// The tracker's URL has no host or port.
+    InvalidUrl,
+    /// The tracker sent a response that doesn't conform to the protocol.
+    InvalidResponse,
+    /// The UDP tracker didn't respond, even after retrying the request.
+    Timeout,
+    /// The tracker refused the request, with the given reason.
+    Failure(String),
+    /// The tracker doesn't support scraping, which for HTTP trackers is
+    /// inferred from the announce URL.
+    ScrapeNotSupported,
 }
 
 impl From<BencodeError> for TrackerError {
@@ -37,11 +55,25 @@ impl From<HttpError> for TrackerError {
     }
 }
 
+impl From<IoError> for TrackerError {
+    fn from(e: IoError) -> Self {
+        Self::Io(e)
+    }
+}
+
 impl fmt::Display for TrackerError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::Bencode(e) => e.fmt(f),
             Self::Http(e) => e.fmt(f),
+            Self::Io(e) => e.fmt(f),
+            Self::InvalidUrl => write!(f, "invalid tracker url"),
+            Self::InvalidResponse => write!(f, "invalid tracker response"),
+            Self::Timeout => write!(f, "tracker timed out"),
+            Self::Failure(reason) => write!(f, "tracker failure: {}", reason),
+            Self::ScrapeNotSupported => {
+                write!(f, "tracker doesn't support scraping")
+            }
         }
     }
 }
@@ -59,6 +91,10 @@ pub(crate) struct Announce {
     /// proxy, or when the tracker is on the same NAT'd subnet as peer (in which case it
     /// is necessary that tracker not give out an unroutable address to peer).
     pub ip: Option<IpAddr>,
+    /// Our IPv6 address, if we have one. This lets the tracker give out our
+    /// IPv6 address to other peers even if we announce over IPv4, as
+    /// described in [BEP 7](http://bittorrent.org/beps/bep_0007.html).
+    pub ipv6: Option<Ipv6Addr>,
 
     /// Number up bytes downloaded so far.
     pub downloaded: u64,
@@ -131,23 +167,87 @@ pub(crate) struct Response {
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_peers")]
     pub peers: Vec<SocketAddr>,
+
+    /// The IPv6 peers, which are sent in a separate list in the compact
+    /// representation.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_peers6")]
+    pub peers6: Vec<SocketAddr>,
+}
+
+/// Returns whether we can announce to the tracker at the URL, which is the case
+/// for HTTP and UDP trackers.
+pub(crate) fn is_supported_url(url: &Url) -> bool {
+    matches!(url.scheme(), "http" | "https" | "udp")
+}
+
+/// The swarm statistics of a torrent, as returned by a tracker scrape.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Scrape {
+    /// The number of peers that have the whole torrent.
+    pub seeder_count: usize,
+    /// The number of peers that are still downloading the torrent.
+    pub leecher_count: usize,
+    /// The number of times the torrent was fully downloaded, as registered by
+    /// the tracker.
+    pub download_count: usize,
+}
+
+/// The response to an HTTP scrape request.
+#[derive(Debug, Deserialize)]
+struct ScrapeResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    /// The scrape of each requested torrent known to the tracker, keyed by
+    /// the torrent's info hash.
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrapeFile {
+    #[serde(default)]
+    complete: usize,
+    #[serde(default)]
+    incomplete: usize,
+    #[serde(default)]
+    downloaded: usize,
 }
 
-/// The HTTP tracker for a torrent for which we can request peers as well as to
-/// announce transfer progress.
+/// The tracker of a torrent from which we can request peers as well as to
+/// which we announce transfer progress. The tracker may be contacted over
+/// HTTP(S) or over UDP.
 pub(crate) struct Tracker {
-    /// The HTTP client.
-    client: Client,
     /// The URL of the tracker.
     url: Url,
+    /// How the tracker is contacted, based on the URL's scheme.
+    protocol: Protocol,
+}
+
+enum Protocol {
+    /// The tracker is contacted with the HTTP client.
+    Http(Client),
+    /// The tracker is contacted over UDP, as described in
+    /// [BEP 15](http://bittorrent.org/beps/bep_0015.html). The key identifies
+    /// us to the tracker in case our IP address changes.
+    Udp { key: u32 },
 }
 
 impl Tracker {
     pub fn new(url: Url) -> Self {
-        Self {
-            client: Client::new(),
-            url,
-        }
+        let protocol = if url.scheme() == "udp" {
+            Protocol::Udp {
+                key: rand::random(),
+            }
+        } else {
+            Protocol::Http(Client::new())
+        };
+        Self { url, protocol }
+    }
+
+    /// Returns the URL of the tracker.
+    pub fn url(&self) -> &Url {
+        &self.url
     }
 
     /// Sends an announce request to the tracker with the specified parameters.
@@ -160,6 +260,36 @@ impl Tracker {
     /// The tracker may not be contacted more often than the minimum interval
     /// returned in the first announce response.
     pub async fn announce(&self, params: Announce) -> Result<Response> {
+        match &self.protocol {
+            Protocol::Http(client) => self.announce_http(client, params).await,
+            Protocol::Udp { key } => {
+                udp::announce(&self.url, *key, params).await
+            }
+        }
+    }
+
+    /// Requests the swarm statistics of the torrents with the given info
+    /// hashes from the tracker.
+    ///
+    /// The scrapes are returned in the same order as the info hashes. If the
+    /// tracker doesn't know of a torrent, its scrape is all zeros.
+    pub async fn scrape(
+        &self,
+        info_hashes: &[Sha1Hash],
+    ) -> Result<Vec<Scrape>> {
+        match &self.protocol {
+            Protocol::Http(client) => {
+                self.scrape_http(client, info_hashes).await
+            }
+            Protocol::Udp { .. } => udp::scrape(&self.url, info_hashes).await,
+        }
+    }
+
+    async fn announce_http(
+        &self,
+        client: &Client,
+        params: Announce,
+    ) -> Result<Response> {
         // announce parameters are built up in the query string, see:
         // https://www.bittorrent.org/beps/bep_0003.html trackers section
         let mut query = vec![
@@ -180,6 +310,17 @@ impl Tracker {
         if let Some(ip) = &params.ip {
             query.push(("ip", ip.to_string()));
         }
+        if let Some(ipv6) = &params.ipv6 {
+            query.push(("ipv6", ipv6.to_string()));
+        }
+        if let Some(event) = params.event {
+            let event = match event {
+                Event::Started => "started",
+                Event::Completed => "completed",
+                Event::Stopped => "stopped",
+            };
+            query.push(("event", event.to_string()));
+        }
 
         // hack:
         // reqwest uses serde_urlencoded which doesn't support encoding a raw
@@ -216,8 +357,7 @@ impl Tracker {
         );
 
         // send request
-        let resp = self
-            .client
+        let resp = client
             .get(&url)
             .query(&query)
             .send()
@@ -228,6 +368,76 @@ impl Tracker {
         let resp = serde_bencode::from_bytes(&resp)?;
         Ok(resp)
     }
+
+    async fn scrape_http(
+        &self,
+        client: &Client,
+        info_hashes: &[Sha1Hash],
+    ) -> Result<Vec<Scrape>> {
+        let scrape_url =
+            scrape_url(&self.url).ok_or(TrackerError::ScrapeNotSupported)?;
+
+        // the info hashes are hard-coded into the URL for the same reason as
+        // in the announce request
+        let mut url = scrape_url.to_string();
+        for info_hash in info_hashes.iter() {
+            url.push(if url.contains('?') { '&' } else { '?' });
+            url.push_str("info_hash=");
+            url.extend(percent_encoding::percent_encode(
+                info_hash,
+                URL_ENCODE_RESERVED,
+            ));
+        }
+
+        let resp = client
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let mut resp: ScrapeResponse = serde_bencode::from_bytes(&resp)?;
+        if let Some(failure_reason) = resp.failure_reason {
+            return Err(TrackerError::Failure(failure_reason));
+        }
+
+        Ok(info_hashes
+            .iter()
+            .map(|info_hash| {
+                resp.files
+                    .remove(Bytes::new(info_hash))
+                    .map(|file| Scrape {
+                        seeder_count: file.complete,
+                        leecher_count: file.incomplete,
+                        download_count: file.downloaded,
+                    })
+                    .unwrap_or_default()
+            })
+            .collect())
+    }
+}
+
+/// Returns the scrape URL of an HTTP tracker, or `None` if the tracker doesn't
+/// support scraping.
+///
+/// As described in [BEP 48](http://bittorrent.org/beps/bep_0048.html), the
+/// scrape URL is derived by replacing "announce" with "scrape" in the last path
+/// segment of the announce URL, provided that the segment starts with it.
+fn scrape_url(announce_url: &Url) -> Option<Url> {
+    let path = announce_url.path();
+    let segment_start = path.rfind('/')? + 1;
+    let segment = &path[segment_start..];
+    if !segment.starts_with("announce") {
+        return None;
+    }
+    let path = format!(
+        "{}scrape{}",
+        &path[..segment_start],
+        &segment["announce".len()..]
+    );
+    let mut url = announce_url.clone();
+    url.set_path(&path);
+    Some(url)
 }
 
 impl fmt::Display for Tracker {
@@ -327,6 +537,40 @@ where
     deserializer.deserialize_any(Visitor)
 }
 
+/// Deserializes the compact string of IPv6 peers, as described in
+/// [BEP 7](http://bittorrent.org/beps/bep_0007.html).
+///
+/// Each entry is 18 bytes long, where the first 16 bytes are the IPv6 address
+/// of the peer, and the last 2 bytes are the port of the peer. Both are in
+/// network byte order.
+fn deserialize_peers6<'de, D>(
+    deserializer: D,
+) -> Result<Vec<SocketAddr>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    const ENTRY_LEN: usize = 18;
+
+    let buf: serde_bytes::ByteBuf = de::Deserialize::deserialize(deserializer)?;
+    let entries = buf.chunks_exact(ENTRY_LEN);
+    if !entries.remainder().is_empty() {
+        return Err(TrackerError::Bencode(BencodeError::InvalidValue(
+            "peers6 compact string must be a multiple of 18".into(),
+        )))
+        .map_err(de::Error::custom);
+    }
+
+    let peers = entries
+        .map(|mut entry| {
+            let addr = Ipv6Addr::from(entry.get_u128());
+            let port = entry.get_u16();
+            SocketAddr::new(IpAddr::V6(addr), port)
+        })
+        .collect();
+
+    Ok(peers)
+}
+
 /// Deserializes an integer representing seconds into a `Duration`.
 fn deserialize_seconds<'de, D>(
     deserializer: D,
@@ -358,6 +602,12 @@ mod tests {
         peers: Vec<SocketAddr>,
     }
 
+    #[derive(Deserialize)]
+    struct Peers6Response {
+        #[serde(deserialize_with = "deserialize_peers6")]
+        peers6: Vec<SocketAddr>,
+    }
+
     #[test]
     fn should_parse_compact_peer_list() {
         let ip = Ipv4Addr::new(192, 168, 0, 10);
@@ -375,6 +625,27 @@ mod tests {
         assert_eq!(decoded.peers, vec![addr]);
     }
 
+    #[test]
+    fn should_parse_compact_ipv6_peer_list() {
+        let peers = [
+            SocketAddr::new("2001:db8::1".parse().unwrap(), 49123),
+            SocketAddr::new("fe80::abcd:12".parse().unwrap(), 6881),
+        ];
+
+        let mut encoded = Vec::new();
+        encoded.extend_from_slice(b"d6:peers6");
+        encoded.extend_from_slice(&encode_compact_peers6_list(&peers));
+        encoded.push(b'e');
+
+        let decoded: Peers6Response = serde_bencode::from_bytes(&encoded)
+            .expect("cannot decode bencode string of peers6");
+        assert_eq!(decoded.peers6, peers);
+
+        // an entry is missing a byte
+        let invalid = b"d6:peers617:00000000000000000e";
+        assert!(serde_bencode::from_bytes::<Peers6Response>(invalid).is_err());
+    }
+
     #[test]
     fn should_parse_full_peer_list() {
         #[derive(Debug, Serialize)]
@@ -402,6 +673,10 @@ mod tests {
                     ip: "123.123.123.123".into(),
                     port: 49950,
                 },
+                RawPeer {
+                    ip: "2001:db8::2".into(),
+                    port: 6881,
+                },
             ],
         };
 
@@ -417,6 +692,69 @@ mod tests {
         assert_eq!(decoded.peers, expected);
     }
 
+    #[test]
+    fn should_derive_scrape_url() {
+        let scrape = |url: &str| {
+            scrape_url(&Url::parse(url).unwrap()).map(|url| url.to_string())
+        };
+        assert_eq!(
+            scrape("http://example.com/announce").as_deref(),
+            Some("http://example.com/scrape")
+        );
+        assert_eq!(
+            scrape("http://example.com/x/announce").as_deref(),
+            Some("http://example.com/x/scrape")
+        );
+        assert_eq!(
+            scrape("http://example.com/announce.php").as_deref(),
+            Some("http://example.com/scrape.php")
+        );
+        assert_eq!(
+            scrape("http://example.com/announce?x2%0644").as_deref(),
+            Some("http://example.com/scrape?x2%0644")
+        );
+        assert_eq!(scrape("http://example.com/a"), None);
+        assert_eq!(scrape("http://example.com/announce/x"), None);
+        assert_eq!(scrape("http://example.com/x%064announce"), None);
+    }
+
+    #[tokio::test]
+    async fn should_return_swarm_stats_on_scrape() {
+        let addr = mockito::server_url();
+        let tracker =
+            Tracker::new(format!("{}/announce", addr).parse().unwrap());
+
+        let known = *b"abcdefghij1234567890";
+        let unknown = *b"0987654321jihgfedcba";
+        let _m = mock("GET", "/scrape")
+            .match_query(Matcher::Exact(
+                "info_hash=abcdefghij1234567890&info_hash=0987654321jihgfedcba"
+                    .into(),
+            ))
+            .with_status(200)
+            .with_body(
+                b"d5:filesd\
+                20:abcdefghij1234567890\
+                d8:completei5e10:downloadedi50e10:incompletei10ee\
+                ee"
+                .as_ref(),
+            )
+            .create();
+
+        let scrapes = tracker.scrape(&[known, unknown]).await.unwrap();
+        assert_eq!(
+            scrapes,
+            vec![
+                Scrape {
+                    seeder_count: 5,
+                    leecher_count: 10,
+                    download_count: 50,
+                },
+                Scrape::default(),
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn should_return_peers_on_announce() {
         let addr = mockito::server_url();
@@ -439,11 +777,13 @@ mod tests {
             left: 1234,
             peer_count: Some(2),
             ip: None,
-            event: None,
+            ipv6: Some("2001:db8::5".parse().unwrap()),
+            event: Some(Event::Started),
             tracker_id: None,
         };
         let peer_ip = Ipv4Addr::new(2, 156, 201, 254);
         let peer_port = 49123;
+        let peer6 = SocketAddr::new("2001:db8::7".parse().unwrap(), 6881);
         let expected_resp = Response {
             tracker_id: None,
             failure_reason: None,
@@ -453,6 +793,7 @@ mod tests {
             seeder_count: Some(5),
             leecher_count: Some(3),
             peers: vec![SocketAddr::new(peer_ip.into(), peer_port)],
+            peers6: vec![peer6],
         };
 
         let mut encoded_resp = Vec::new();
@@ -469,6 +810,8 @@ mod tests {
         encoded_resp.extend_from_slice(&encode_compact_peers_list(&[(
             peer_ip, peer_port,
         )]));
+        encoded_resp.extend_from_slice(b"6:peers6");
+        encoded_resp.extend_from_slice(&encode_compact_peers6_list(&[peer6]));
         // terminate dict
         encoded_resp.push(b'e');
 
@@ -491,6 +834,8 @@ mod tests {
                     "numwant".into(),
                     announce.peer_count.unwrap().to_string(),
                 ),
+                Matcher::UrlEncoded("ipv6".into(), "2001:db8::5".into()),
+                Matcher::UrlEncoded("event".into(), "started".into()),
             ]))
             .with_status(200)
             .with_body(encoded_resp)
@@ -520,4 +865,21 @@ mod tests {
 
         encoded
     }
+
+    fn encode_compact_peers6_list(peers: &[SocketAddr]) -> Vec<u8> {
+        let mut encoded_peers = Vec::new();
+        for peer in peers {
+            if let IpAddr::V6(ip) = peer.ip() {
+                encoded_peers.extend_from_slice(&ip.octets());
+                encoded_peers.extend_from_slice(&peer.port().to_be_bytes());
+            }
+        }
+
+        let mut encoded = Vec::new();
+        encoded.extend_from_slice(encoded_peers.len().to_string().as_bytes());
+        encoded.push(b':');
+        encoded.extend_from_slice(&encoded_peers);
+
+        encoded
+    }
 }
@@ -0,0 +1,429 @@
+//! The UDP tracker protocol, as described in
+//! [BEP 15](http://bittorrent.org/beps/bep_0015.html).
+//!
+//! Each request is preceded by a connect request, with which the tracker hands
+//! out a connection id that prevents IP spoofing. Requests are retransmitted
+//! with an exponentially increasing timeout, since UDP doesn't guarantee
+//! delivery.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use reqwest::Url;
+use tokio::{net::UdpSocket, task, time};
+
+use super::{Announce, Event, Response, Result, Scrape, TrackerError};
+use crate::Sha1Hash;
+
+/// The magic constant that identifies the protocol in connect requests.
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// The timeout of the first attempt of a request, which is doubled with each
+/// retransmission.
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The number of times a request is retransmitted before giving up. The
+/// protocol allows for 8, but that would mean waiting for over an hour on an
+/// unreachable tracker.
+const MAX_RETRY_COUNT: u32 = 2;
+
+/// The maximum number of info hashes that may be scraped in a single request.
+const MAX_SCRAPE_COUNT: usize = 74;
+
+/// The largest response we expect, which is an announce response with 200
+/// IPv6 peers.
+const MAX_RESPONSE_LEN: usize = 20 + 200 * 18;
+
+/// Announces to the tracker at the URL.
+pub(super) async fn announce(
+    url: &Url,
+    key: u32,
+    params: Announce,
+) -> Result<Response> {
+    let mut conn = Connection::new(url).await?;
+
+    let mut req = BytesMut::with_capacity(98);
+    req.put_u64(conn.id);
+    req.put_u32(ACTION_ANNOUNCE);
+    let transaction_id = rand::random();
+    req.put_u32(transaction_id);
+    req.put_slice(&params.info_hash);
+    req.put_slice(&params.peer_id);
+    req.put_u64(params.downloaded);
+    req.put_u64(params.left);
+    req.put_u64(params.uploaded);
+    req.put_u32(match params.event {
+        None => 0,
+        Some(Event::Completed) => 1,
+        Some(Event::Started) => 2,
+        Some(Event::Stopped) => 3,
+    });
+    // only an IPv4 address may be sent, and 0 means the source address of the
+    // request is used
+    req.put_u32(match params.ip {
+        Some(IpAddr::V4(ip)) => ip.into(),
+        _ => 0,
+    });
+    req.put_u32(key);
+    // -1 lets the tracker decide how many peers to return
+    req.put_i32(params.peer_count.map(|n| n as i32).unwrap_or(-1));
+    req.put_u16(params.port);
+
+    let resp = conn.request(&req, ACTION_ANNOUNCE, transaction_id).await?;
+    let mut resp = &resp[..];
+    if resp.len() < 12 {
+        return Err(TrackerError::InvalidResponse);
+    }
+    let interval = Duration::from_secs(resp.get_u32() as u64);
+    let leecher_count = resp.get_u32() as usize;
+    let seeder_count = resp.get_u32() as usize;
+
+    // the peers are of the same IP version as the address over which the
+    // tracker is contacted
+    let mut peers = Vec::new();
+    let mut peers6 = Vec::new();
+    if conn.addr.is_ipv4() {
+        for mut entry in resp.chunks_exact(6) {
+            let ip = Ipv4Addr::from(entry.get_u32());
+            peers.push(SocketAddr::new(ip.into(), entry.get_u16()));
+        }
+    } else {
+        for mut entry in resp.chunks_exact(18) {
+            let ip = Ipv6Addr::from(entry.get_u128());
+            peers6.push(SocketAddr::new(ip.into(), entry.get_u16()));
+        }
+    }
+
+    Ok(Response {
+        tracker_id: None,
+        failure_reason: None,
+        warning_message: None,
+        interval: Some(interval),
+        min_interval: None,
+        seeder_count: Some(seeder_count),
+        leecher_count: Some(leecher_count),
+        peers,
+        peers6,
+    })
+}
+
+/// Scrapes the torrents with the given info hashes from the tracker at the
+/// URL.
+pub(super) async fn scrape(
+    url: &Url,
+    info_hashes: &[Sha1Hash],
+) -> Result<Vec<Scrape>> {
+    let mut conn = Connection::new(url).await?;
+
+    let mut scrapes = Vec::with_capacity(info_hashes.len());
+    for info_hashes in info_hashes.chunks(MAX_SCRAPE_COUNT) {
+        let mut req = BytesMut::with_capacity(16 + info_hashes.len() * 20);
+        req.put_u64(conn.id);
+        req.put_u32(ACTION_SCRAPE);
+        let transaction_id = rand::random();
+        req.put_u32(transaction_id);
+        for info_hash in info_hashes.iter() {
+            req.put_slice(info_hash);
+        }
+
+        let resp = conn.request(&req, ACTION_SCRAPE, transaction_id).await?;
+        if resp.len() < info_hashes.len() * 12 {
+            return Err(TrackerError::InvalidResponse);
+        }
+        scrapes.extend(resp.chunks_exact(12).take(info_hashes.len()).map(
+            |mut entry| Scrape {
+                seeder_count: entry.get_u32() as usize,
+                download_count: entry.get_u32() as usize,
+                leecher_count: entry.get_u32() as usize,
+            },
+        ));
+    }
+
+    Ok(scrapes)
+}
+
+/// A socket connected to the tracker, along with the connection id the
+/// tracker gave us.
+struct Connection {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    id: u64,
+}
+
+impl Connection {
+    /// Resolves the tracker's address and sends it a connect request.
+    async fn new(url: &Url) -> Result<Self> {
+        let host_url = url.clone();
+        let addr = task::spawn_blocking(move || host_url.socket_addrs(|| None))
+            .await
+            .expect("task error")?
+            .into_iter()
+            .next()
+            .ok_or(TrackerError::InvalidUrl)?;
+
+        let bind_addr = if addr.is_ipv4() {
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+        } else {
+            SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(addr).await?;
+
+        let mut conn = Self {
+            socket,
+            addr,
+            id: PROTOCOL_ID,
+        };
+        let mut req = BytesMut::with_capacity(16);
+        req.put_u64(PROTOCOL_ID);
+        req.put_u32(ACTION_CONNECT);
+        let transaction_id = rand::random();
+        req.put_u32(transaction_id);
+        let resp = conn.request(&req, ACTION_CONNECT, transaction_id).await?;
+        let mut resp = &resp[..];
+        if resp.len() < 8 {
+            return Err(TrackerError::InvalidResponse);
+        }
+        conn.id = resp.get_u64();
+
+        Ok(conn)
+    }
+
+    /// Sends the request until a response with the same transaction id
+    /// arrives, and returns the response without its header.
+    async fn request(
+        &mut self,
+        req: &[u8],
+        action: u32,
+        transaction_id: u32,
+    ) -> Result<Vec<u8>> {
+        for retry_count in 0..=MAX_RETRY_COUNT {
+            self.socket.send(req).await?;
+            let timeout = BASE_TIMEOUT * 2u32.pow(retry_count);
+            match time::timeout(timeout, self.recv(action, transaction_id))
+                .await
+            {
+                Ok(resp) => return resp,
+                Err(_) => {
+                    log::debug!(
+                        "UDP tracker {} timed out after {} s",
+                        self.addr,
+                        timeout.as_secs()
+                    );
+                }
+            }
+        }
+        Err(TrackerError::Timeout)
+    }
+
+    /// Waits for the response to the request with the transaction id,
+    /// ignoring stale responses to earlier requests and datagrams too short to
+    /// be responses.
+    async fn recv(
+        &mut self,
+        action: u32,
+        transaction_id: u32,
+    ) -> Result<Vec<u8>> {
+        let mut buf = vec![0; MAX_RESPONSE_LEN];
+        loop {
+            let len = self.socket.recv(&mut buf).await?;
+            if len < 8 {
+                log::debug!(
+                    "Ignoring {} byte datagram from {}",
+                    len,
+                    self.addr
+                );
+                continue;
+            }
+            let mut resp = &buf[..len];
+            let resp_action = resp.get_u32();
+            if resp.get_u32() != transaction_id {
+                continue;
+            }
+            if resp_action == ACTION_ERROR {
+                return Err(TrackerError::Failure(
+                    String::from_utf8_lossy(resp).into_owned(),
+                ));
+            }
+            if resp_action != action {
+                return Err(TrackerError::InvalidResponse);
+            }
+            return Ok(resp.to_vec());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Spawns a tracker that answers connect requests, and then responds to the
+    /// next request with the response built from it by the given function.
+    async fn spawn_tracker<F>(respond: F) -> Url
+    where
+        F: FnOnce(&[u8]) -> Vec<u8> + Send + 'static,
+    {
+        let mut socket =
+            UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        task::spawn(async move {
+            let mut buf = vec![0; 1024];
+
+            let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
+            let mut req = &buf[..len];
+            assert_eq!(req.get_u64(), PROTOCOL_ID);
+            assert_eq!(req.get_u32(), ACTION_CONNECT);
+            let mut resp = BytesMut::new();
+            resp.put_u32(ACTION_CONNECT);
+            resp.put_u32(req.get_u32());
+            resp.put_u64(0x1234);
+            socket.send_to(&resp, &addr).await.unwrap();
+
+            let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
+            let mut req = &buf[..len];
+            assert_eq!(req.get_u64(), 0x1234);
+            let action = req.get_u32();
+            let transaction_id = req.get_u32();
+            let mut resp = BytesMut::new();
+            resp.put_u32(action);
+            resp.put_u32(transaction_id);
+            resp.put_slice(&respond(req));
+            socket.send_to(&resp, &addr).await.unwrap();
+        });
+        url.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_announce() {
+        let peer =
+            SocketAddr::new(Ipv4Addr::new(2, 156, 201, 254).into(), 49123);
+        let url = spawn_tracker(move |mut req| {
+            assert_eq!(req.len(), 82);
+            assert_eq!(&req[..20], &[1; 20]);
+            req.advance(40);
+            assert_eq!(req.get_u64(), 10);
+            assert_eq!(req.get_u64(), 20);
+            assert_eq!(req.get_u64(), 30);
+            // started
+            assert_eq!(req.get_u32(), 2);
+            assert_eq!(req.get_u32(), 0);
+            assert_eq!(req.get_u32(), 5);
+            assert_eq!(req.get_i32(), -1);
+            assert_eq!(req.get_u16(), 6881);
+
+            let mut resp = BytesMut::new();
+            resp.put_u32(1800);
+            resp.put_u32(3);
+            resp.put_u32(7);
+            resp.put_u32(0x029cc9fe);
+            resp.put_u16(peer.port());
+            resp.to_vec()
+        })
+        .await;
+
+        let resp = announce(
+            &url,
+            5,
+            Announce {
+                info_hash: [1; 20],
+                peer_id: [2; 20],
+                port: 6881,
+                ip: None,
+                ipv6: None,
+                downloaded: 10,
+                uploaded: 30,
+                left: 20,
+                peer_count: None,
+                tracker_id: None,
+                event: Some(Event::Started),
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.interval, Some(Duration::from_secs(1800)));
+        assert_eq!(resp.leecher_count, Some(3));
+        assert_eq!(resp.seeder_count, Some(7));
+        assert_eq!(resp.peers, vec![peer]);
+    }
+
+    #[tokio::test]
+    async fn test_scrape() {
+        let url = spawn_tracker(|req| {
+            assert_eq!(req, [[1; 20], [2; 20]].concat().as_slice());
+            let mut resp = BytesMut::new();
+            for n in [5, 50, 10, 0, 1, 2].iter() {
+                resp.put_u32(*n);
+            }
+            resp.to_vec()
+        })
+        .await;
+
+        let scrapes = scrape(&url, &[[1; 20], [2; 20]]).await.unwrap();
+        assert_eq!(
+            scrapes,
+            vec![
+                Scrape {
+                    seeder_count: 5,
+                    download_count: 50,
+                    leecher_count: 10,
+                },
+                Scrape {
+                    seeder_count: 0,
+                    download_count: 1,
+                    leecher_count: 2,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ignore_short_datagrams() {
+        let mut socket =
+            UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url: Url = format!("udp://{}", socket.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        task::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
+            let mut req = &buf[8..len];
+            assert_eq!(req.get_u32(), ACTION_CONNECT);
+            // a stray datagram arrives before the response
+            socket.send_to(&[1, 2, 3], &addr).await.unwrap();
+            let mut resp = BytesMut::new();
+            resp.put_u32(ACTION_CONNECT);
+            resp.put_u32(req.get_u32());
+            resp.put_u64(0x1234);
+            socket.send_to(&resp, &addr).await.unwrap();
+        });
+
+        let conn = Connection::new(&url).await.unwrap();
+        assert_eq!(conn.id, 0x1234);
+    }
+
+    #[tokio::test]
+    async fn test_error() {
+        let url = spawn_tracker(|_| b"unregistered torrent".to_vec()).await;
+        // the test tracker echoes the action of the request, so the error
+        // action has to be set here
+        let mut conn = Connection::new(&url).await.unwrap();
+        let mut req = BytesMut::new();
+        req.put_u64(conn.id);
+        req.put_u32(ACTION_ERROR);
+        req.put_u32(7);
+        match conn.request(&req, ACTION_SCRAPE, 7).await {
+            Err(TrackerError::Failure(reason)) => {
+                assert_eq!(reason, "unregistered torrent")
+            }
+            resp => panic!("unexpected response {:?}", resp),
+        }
+    }
+}
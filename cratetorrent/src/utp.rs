@@ -0,0 +1,467 @@
+//! The uTorrent transport protocol (uTP), as described in
+//! [BEP 29](http://bittorrent.org/beps/bep_0029.html).
+//!
+//! uTP provides reliable, ordered delivery over UDP, like TCP, but uses LEDBAT
+//! congestion control which yields to other traffic on the network, so that
+//! torrents don't saturate the user's connection.
+//!
+//! A [`UtpSocket`] is bound to a UDP port and is driven by a task that owns
+//! the underlying UDP socket. The task demultiplexes incoming packets to
+//! connections, accepts new connections, and sends the packets the
+//! connections produce. Each connection is exposed to the rest of the engine
+//! as a [`UtpStream`], which implements `AsyncRead` and `AsyncWrite`, so the
+//! same codecs can be used on it as on TCP streams.
+
+mod conn;
+mod ledbat;
+mod packet;
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::{
+    channel::oneshot,
+    select,
+    sink::SinkExt,
+    stream::{Fuse, FusedStream, SplitSink, StreamExt},
+};
+use rand::Rng;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::UdpSocket,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task, time,
+};
+use tokio_util::udp::UdpFramed;
+
+use crate::net;
+use conn::Conn;
+use packet::{Header, Packet, PacketCodec, PacketType};
+
+/// The largest packet we send, including the header. This is chosen to fit in
+/// the MTU of most links so that packets are not fragmented.
+const MAX_PACKET_SIZE: usize = 1400;
+/// The largest payload we send in a single packet.
+const MAX_PAYLOAD_LEN: usize = MAX_PACKET_SIZE - packet::HEADER_LEN;
+/// How often connections are checked for timeouts.
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The channel on which the connections accepted by the socket are returned.
+pub(crate) type Incoming = UnboundedReceiver<UtpStream>;
+
+/// A connection is identified by the address of the peer and the connection
+/// id with which the peer sends us packets.
+type ConnKey = (SocketAddr, u16);
+
+/// A handle to a uTP socket bound to a local UDP port, through which
+/// connections to peers may be made.
+///
+/// The socket is closed once all handles to it are dropped and all of its
+/// connections are closed.
+#[derive(Clone)]
+pub(crate) struct UtpSocket {
+    cmd_tx: UnboundedSender<Command>,
+    local_addr: SocketAddr,
+}
+
+impl UtpSocket {
+    /// Binds the socket to the address and spawns the task driving it.
+    ///
+    /// Connections initiated by peers are returned on the incoming channel.
+    pub async fn bind(addr: SocketAddr) -> io::Result<(Self, Incoming)> {
+        let socket = net::bind_udp_socket(addr)?;
+        let local_addr = socket.local_addr()?;
+
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let (notify_tx, notify_rx) = mpsc::unbounded_channel();
+        let mut driver = Driver {
+            conns: HashMap::new(),
+            cmd_rx: cmd_rx.fuse(),
+            incoming_tx,
+            notify_tx,
+            notify_rx: notify_rx.fuse(),
+            epoch: Instant::now(),
+            is_ipv6: local_addr.is_ipv6(),
+        };
+        task::spawn(async move { driver.run(socket).await });
+
+        Ok((Self { cmd_tx, local_addr }, incoming_rx))
+    }
+
+    /// Returns the address to which the socket is bound.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Connects to the peer at the given address.
+    ///
+    /// This fails with a timeout error if the peer doesn't respond, most
+    /// likely because it doesn't support uTP.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let closed =
+            || io::Error::new(io::ErrorKind::NotConnected, "uTP socket closed");
+        let (result_tx, result_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::Connect { addr, result_tx })
+            .map_err(|_| closed())?;
+        result_rx.await.map_err(|_| closed())?
+    }
+}
+
+/// The commands the socket handles can send to the driver task.
+enum Command {
+    Connect {
+        addr: SocketAddr,
+        result_tx: oneshot::Sender<io::Result<UtpStream>>,
+    },
+}
+
+type Sink = SplitSink<UdpFramed<PacketCodec>, (Packet, SocketAddr)>;
+
+/// The task that owns the UDP socket and drives all connections on it.
+struct Driver {
+    conns: HashMap<ConnKey, ConnEntry>,
+    cmd_rx: Fuse<UnboundedReceiver<Command>>,
+    /// Streams of the connections initiated by peers are sent here.
+    incoming_tx: UnboundedSender<UtpStream>,
+    /// Streams notify the driver on this channel when they have something to
+    /// send.
+    notify_tx: UnboundedSender<ConnKey>,
+    notify_rx: Fuse<UnboundedReceiver<ConnKey>>,
+    /// The microsecond timestamps in packets are relative to this instant.
+    epoch: Instant,
+    /// Whether the socket is an IPv6 socket, in which case IPv4 peers are
+    /// reached through their IPv4-mapped IPv6 addresses.
+    is_ipv6: bool,
+}
+
+struct ConnEntry {
+    conn: Arc<Mutex<Conn>>,
+    /// If we initiated the connection, the stream is returned on this channel
+    /// once the handshake completes.
+    connect_tx: Option<oneshot::Sender<io::Result<UtpStream>>>,
+}
+
+impl Driver {
+    /// Runs the driver until all socket handles are dropped and all
+    /// connections are closed.
+    async fn run(&mut self, socket: UdpSocket) {
+        let (mut sink, stream) = UdpFramed::new(socket, PacketCodec).split();
+        let mut stream = stream.fuse();
+        let mut tick = time::interval(TICK_INTERVAL).fuse();
+
+        loop {
+            select! {
+                _ = tick.select_next_some() => {
+                    let keys: Vec<_> = self.conns.keys().copied().collect();
+                    for key in keys {
+                        self.flush(&mut sink, key).await;
+                    }
+                }
+                packet = stream.select_next_some() => match packet {
+                    Ok((packet, addr)) => {
+                        let addr = net::to_canonical(addr);
+                        if let Some(key) = self.handle_packet(packet, addr) {
+                            self.flush(&mut sink, key).await;
+                        }
+                    }
+                    Err(e) => {
+                        log::debug!("Invalid uTP packet: {}", e);
+                    }
+                },
+                key = self.notify_rx.select_next_some() => {
+                    self.flush(&mut sink, key).await;
+                }
+                cmd = self.cmd_rx.select_next_some() => match cmd {
+                    Command::Connect { addr, result_tx } => {
+                        let key = self.connect(addr, result_tx);
+                        self.flush(&mut sink, key).await;
+                    }
+                },
+            }
+
+            if self.cmd_rx.is_terminated() && self.conns.is_empty() {
+                break;
+            }
+        }
+
+        log::info!("uTP socket closed");
+    }
+
+    /// Passes the packet to its connection, or creates a new connection if
+    /// it's a SYN packet.
+    ///
+    /// Returns the key of the connection that handled the packet.
+    fn handle_packet(
+        &mut self,
+        packet: Packet,
+        addr: SocketAddr,
+    ) -> Option<ConnKey> {
+        let header = packet.header;
+        // the SYN is sent with the id with which the peer will be receiving,
+        // which is one less than the id with which it will be sending
+        let key = if header.ty == PacketType::Syn {
+            (addr, header.conn_id.wrapping_add(1))
+        } else {
+            (addr, header.conn_id)
+        };
+
+        if let Some(entry) = self.conns.get(&key) {
+            entry.conn.lock().unwrap().on_packet(
+                packet,
+                Instant::now(),
+                self.now_us(),
+            );
+            return Some(key);
+        }
+
+        if header.ty == PacketType::Syn {
+            self.accept(key, &header)
+        } else {
+            log::trace!("uTP packet from {} for unknown connection", addr);
+            None
+        }
+    }
+
+    /// Creates a connection in response to a peer's SYN and returns its
+    /// stream on the incoming channel.
+    fn accept(&mut self, key: ConnKey, syn: &Header) -> Option<ConnKey> {
+        // no new connections are accepted when the socket is being closed
+        if self.cmd_rx.is_terminated() {
+            return None;
+        }
+        log::debug!("Accepting uTP connection from {}", key.0);
+        let seq_nr = rand::thread_rng().gen();
+        let conn = Arc::new(Mutex::new(Conn::inbound(syn, seq_nr)));
+        let stream = UtpStream {
+            conn: Arc::clone(&conn),
+            key,
+            notify_tx: self.notify_tx.clone(),
+        };
+        if self.incoming_tx.send(stream).is_err() {
+            return None;
+        }
+        self.conns.insert(
+            key,
+            ConnEntry {
+                conn,
+                connect_tx: None,
+            },
+        );
+        Some(key)
+    }
+
+    /// Creates a connection to the peer, whose stream is returned on the
+    /// channel once the handshake completes.
+    fn connect(
+        &mut self,
+        addr: SocketAddr,
+        result_tx: oneshot::Sender<io::Result<UtpStream>>,
+    ) -> ConnKey {
+        // the peer uses both our receive id and the one above it to identify
+        // the connection, so neither may be in use
+        let recv_id = loop {
+            let id: u16 = rand::thread_rng().gen();
+            if !self.conns.contains_key(&(addr, id))
+                && !self.conns.contains_key(&(addr, id.wrapping_add(1)))
+            {
+                break id;
+            }
+        };
+        log::debug!("Connecting to {} over uTP", addr);
+        let key = (addr, recv_id);
+        self.conns.insert(
+            key,
+            ConnEntry {
+                conn: Arc::new(Mutex::new(Conn::outbound(recv_id))),
+                connect_tx: Some(result_tx),
+            },
+        );
+        key
+    }
+
+    /// Sends the packets the connection has ready, completes its pending
+    /// connect request if the handshake finished, and removes the connection
+    /// if it's closed.
+    async fn flush(&mut self, sink: &mut Sink, key: ConnKey) {
+        let now_us = self.now_us();
+        let entry = match self.conns.get_mut(&key) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        let (packets, connect_result, is_done) = {
+            let mut conn = entry.conn.lock().unwrap();
+            let packets = conn.poll_transmit(Instant::now(), now_us);
+            let connect_result = if entry.connect_tx.is_none() {
+                None
+            } else if conn.is_connected() {
+                Some(Ok(()))
+            } else {
+                conn.error().map(Err)
+            };
+            (packets, connect_result, conn.is_done())
+        };
+
+        // the stream must be created and possibly dropped outside the lock,
+        // as dropping it locks the connection
+        if let Some(result) = connect_result {
+            let connect_tx = entry.connect_tx.take().unwrap();
+            let notify_tx = &self.notify_tx;
+            let result = result
+                .map(|_| UtpStream {
+                    conn: Arc::clone(&entry.conn),
+                    key,
+                    notify_tx: notify_tx.clone(),
+                })
+                .map_err(io::Error::from);
+            // if the connecting side gave up, the stream is dropped, which
+            // closes the connection
+            let _ = connect_tx.send(result);
+        }
+
+        let addr = if self.is_ipv6 {
+            net::to_ipv6_mapped(key.0)
+        } else {
+            key.0
+        };
+        for packet in packets {
+            if let Err(e) = sink.send((packet, addr)).await {
+                log::debug!("Error sending uTP packet to {}: {}", key.0, e);
+            }
+        }
+
+        if is_done {
+            log::debug!("uTP connection to {} closed", key.0);
+            self.conns.remove(&key);
+        }
+    }
+
+    /// Returns the current timestamp to include in packets.
+    fn now_us(&self) -> u32 {
+        // the timestamp is allowed to wrap around
+        self.epoch.elapsed().as_micros() as u32
+    }
+}
+
+/// A uTP connection to a peer.
+pub(crate) struct UtpStream {
+    conn: Arc<Mutex<Conn>>,
+    key: ConnKey,
+    notify_tx: UnboundedSender<ConnKey>,
+}
+
+impl UtpStream {
+    /// Returns the address of the peer.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.key.0
+    }
+
+    /// Tells the driver that the connection has something to send.
+    fn notify(&self) {
+        // the driver only stops after all connections are closed, which
+        // can't happen while this stream exists
+        let _ = self.notify_tx.send(self.key);
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let (result, is_window_update_needed) =
+            self.conn.lock().unwrap().poll_read(cx, buf);
+        if is_window_update_needed {
+            self.notify();
+        }
+        result
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = self.conn.lock().unwrap().poll_write(cx, buf);
+        if let Poll::Ready(Ok(_)) = result {
+            self.notify();
+        }
+        result
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        // written data is sent by the driver as soon as the send window
+        // allows it
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.conn.lock().unwrap().shutdown();
+        self.notify();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.conn.lock().unwrap().drop_stream();
+        self.notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// Tests that data is transferred in both directions between two sockets
+    /// on the loopback interface.
+    #[tokio::test]
+    async fn test_loopback_transfer() {
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let (a, _) = UtpSocket::bind(addr).await.unwrap();
+        let (b, mut b_incoming) = UtpSocket::bind(addr).await.unwrap();
+
+        let data: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+
+        let mut outbound = a.connect(b.local_addr()).await.unwrap();
+        let mut inbound = b_incoming.recv().await.unwrap();
+        assert_eq!(inbound.peer_addr(), a.local_addr());
+
+        let expected = data.clone();
+        let b_task = task::spawn(async move {
+            let mut received = vec![0; expected.len()];
+            inbound.read_exact(&mut received).await.unwrap();
+            assert!(received == expected);
+            inbound.write_all(&received).await.unwrap();
+            inbound.shutdown().await.unwrap();
+        });
+
+        outbound.write_all(&data).await.unwrap();
+        let mut received = Vec::new();
+        outbound.read_to_end(&mut received).await.unwrap();
+        assert!(received == data);
+        b_task.await.unwrap();
+    }
+}
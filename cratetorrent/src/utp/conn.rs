@@ -0,0 +1,744 @@
+//! The state machine of a single uTP connection.
+//!
+//! This type does no IO itself: the socket driver feeds it the packets
+//! received from the peer and sends the packets it produces, while the stream
+//! handle reads and writes its buffers. This keeps the protocol logic easy to
+//! test in isolation.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use super::{
+    ledbat::Ledbat,
+    packet::{Header, Packet, PacketType},
+    MAX_PAYLOAD_LEN,
+};
+
+/// The most bytes we buffer for the user to read. This is also the most we
+/// advertise to the peer as our receive window.
+const RECV_BUF_LEN: usize = 1024 * 1024;
+/// The most bytes we buffer from the user before they are sent.
+const SEND_BUF_LEN: usize = 256 * 1024;
+/// The retransmission timeout before the first round trip time measurement.
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+/// The retransmission timeout never goes below this value.
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+/// After this many consecutive timeouts the connection is considered dead.
+const MAX_TIMEOUTS: u32 = 6;
+/// After this many consecutive timeouts of the SYN packet, the peer is
+/// considered not to be reachable over uTP. This is lower than
+/// [`MAX_TIMEOUTS`] so that we can quickly fall back to TCP.
+const MAX_SYN_TIMEOUTS: u32 = 2;
+/// The number of duplicate acknowledgements after which we consider the
+/// packet following the acknowledged one lost.
+const DUP_ACK_THRESHOLD: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    /// We sent the SYN and are waiting for the peer's response.
+    SynSent,
+    Connected,
+    /// The connection was reset or timed out.
+    Closed,
+}
+
+/// A packet we sent but which the peer hasn't yet acknowledged.
+struct SentPacket {
+    ty: PacketType,
+    seq_nr: u16,
+    payload: Bytes,
+    /// When the packet was last sent, or none if it needs to be (re)sent.
+    sent_time: Option<Instant>,
+    /// The number of times the packet was sent. Round trip times are only
+    /// measured on packets sent once.
+    transmissions: u32,
+}
+
+pub(super) struct Conn {
+    state: State,
+    /// The connection id that we put in the packets we send.
+    send_id: u16,
+    /// The sequence number of the next packet we send.
+    seq_nr: u16,
+    /// The sequence number of the last packet received in order.
+    ack_nr: u16,
+
+    /// The bytes written by the user that haven't been sent yet.
+    send_buf: BytesMut,
+    /// The packets that have been sent but not acknowledged, in order.
+    in_flight: VecDeque<SentPacket>,
+    /// The sum of the payload lengths in `in_flight`.
+    bytes_in_flight: usize,
+    /// The receive window the peer last advertised.
+    peer_window: usize,
+    congestion: Ledbat,
+    /// The smoothed round trip time and its variance.
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    /// The current retransmission timeout.
+    timeout: Duration,
+    /// The number of consecutive timeouts.
+    timeout_count: u32,
+    /// The last acknowledgement and the number of times we received it.
+    last_ack_nr: u16,
+    dup_ack_count: u32,
+    /// When a loss is detected, this is set to the last packet sent at the
+    /// time. Until it's acknowledged, every acknowledgement that doesn't cover
+    /// all packets in flight means the next packet was lost too.
+    recovery_seq_nr: Option<u16>,
+
+    /// The bytes received in order, waiting to be read by the user.
+    recv_buf: BytesMut,
+    /// Packets received out of order, keyed by their sequence number.
+    out_of_order: HashMap<u16, Bytes>,
+    /// The sequence number of the peer's FIN packet, if received.
+    fin_seq_nr: Option<u16>,
+    /// Whether everything up to and including the peer's FIN was received.
+    is_eof: bool,
+    /// Whether we need to acknowledge received packets.
+    is_ack_needed: bool,
+    /// The timestamp difference we send to the peer, measured on the last
+    /// packet received.
+    reply_timestamp_diff: u32,
+    /// The receive window we last advertised.
+    advertised_window: usize,
+
+    /// Whether the user shut down the write half or dropped the stream. In
+    /// either case a FIN is sent after the pending data.
+    is_write_closed: bool,
+    is_fin_sent: bool,
+    /// Whether the user no longer holds the stream.
+    is_stream_dropped: bool,
+    /// The reason the connection was closed, if it was closed abnormally.
+    error: Option<io::ErrorKind>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Conn {
+    /// Creates a connection that initiates the handshake with a SYN packet.
+    ///
+    /// The peer sends its packets to us with `recv_id`, which is also what we
+    /// send in the SYN packet, and we send with `recv_id + 1`.
+    pub fn outbound(recv_id: u16) -> Self {
+        let mut conn = Self::new(State::SynSent, recv_id.wrapping_add(1), 1, 0);
+        conn.in_flight.push_back(SentPacket {
+            ty: PacketType::Syn,
+            seq_nr: 1,
+            payload: Bytes::new(),
+            sent_time: None,
+            transmissions: 0,
+        });
+        conn.seq_nr = 2;
+        conn
+    }
+
+    /// Creates a connection in response to the peer's SYN packet.
+    pub fn inbound(syn: &Header, seq_nr: u16) -> Self {
+        let mut conn =
+            Self::new(State::Connected, syn.conn_id, seq_nr, syn.seq_nr);
+        conn.peer_window = syn.wnd_size as usize;
+        conn.is_ack_needed = true;
+        conn
+    }
+
+    fn new(state: State, send_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            state,
+            send_id,
+            seq_nr,
+            ack_nr,
+            send_buf: BytesMut::new(),
+            in_flight: VecDeque::new(),
+            bytes_in_flight: 0,
+            peer_window: MAX_PAYLOAD_LEN,
+            congestion: Ledbat::new(),
+            rtt: None,
+            rtt_var: Duration::default(),
+            timeout: INITIAL_TIMEOUT,
+            timeout_count: 0,
+            last_ack_nr: 0,
+            dup_ack_count: 0,
+            recovery_seq_nr: None,
+            recv_buf: BytesMut::new(),
+            out_of_order: HashMap::new(),
+            fin_seq_nr: None,
+            is_eof: false,
+            is_ack_needed: false,
+            reply_timestamp_diff: 0,
+            advertised_window: RECV_BUF_LEN,
+            is_write_closed: false,
+            is_fin_sent: false,
+            is_stream_dropped: false,
+            error: None,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    /// Returns true once the handshake completed.
+    pub fn is_connected(&self) -> bool {
+        self.state == State::Connected
+    }
+
+    /// Returns the reason the connection failed, if it did.
+    pub fn error(&self) -> Option<io::ErrorKind> {
+        self.error
+    }
+
+    /// Returns true if the connection no longer needs to be driven and can be
+    /// removed.
+    pub fn is_done(&self) -> bool {
+        self.state == State::Closed
+            || (self.is_fin_sent
+                && self.in_flight.is_empty()
+                && (self.is_eof || self.is_stream_dropped))
+    }
+
+    /// Processes a packet the peer sent on this connection.
+    pub fn on_packet(&mut self, packet: Packet, now: Instant, now_us: u32) {
+        let Packet { header, payload } = packet;
+        if self.state == State::Closed {
+            return;
+        }
+        if header.ty == PacketType::Reset {
+            self.close(io::ErrorKind::ConnectionReset);
+            return;
+        }
+
+        self.reply_timestamp_diff = now_us.wrapping_sub(header.timestamp);
+        self.peer_window = header.wnd_size as usize;
+
+        if self.state == State::SynSent {
+            if header.ty != PacketType::State {
+                return;
+            }
+            // the peer's first data packet has the sequence number of this
+            // packet
+            self.ack_nr = header.seq_nr.wrapping_sub(1);
+            self.state = State::Connected;
+        }
+
+        self.handle_ack(header.ack_nr, header.timestamp_diff, now);
+
+        match header.ty {
+            PacketType::Data | PacketType::Fin => {
+                self.handle_data(header.ty, header.seq_nr, payload);
+            }
+            PacketType::Syn => {
+                // our response to the SYN must have been lost
+                self.is_ack_needed = true;
+            }
+            PacketType::State | PacketType::Reset => {}
+        }
+        self.wake();
+    }
+
+    /// Removes the packets acknowledged by the peer and updates the
+    /// congestion control state.
+    fn handle_ack(&mut self, ack_nr: u16, delay: u32, now: Instant) {
+        let bytes_in_flight = self.bytes_in_flight;
+        let mut packets_acked = 0;
+        let mut bytes_acked = 0;
+        let mut rtt_sample = None;
+        while let Some(packet) = self.in_flight.front() {
+            if !is_seq_less_eq(packet.seq_nr, ack_nr) {
+                break;
+            }
+            let packet = self.in_flight.pop_front().unwrap();
+            packets_acked += 1;
+            bytes_acked += packet.payload.len();
+            self.bytes_in_flight -= packet.payload.len();
+            if packet.transmissions == 1 {
+                rtt_sample = packet.sent_time.map(|t| now - t);
+            }
+        }
+
+        if let Some(rtt) = rtt_sample {
+            self.update_rtt(rtt);
+        }
+
+        if packets_acked > 0 {
+            self.timeout_count = 0;
+            self.dup_ack_count = 0;
+            self.congestion
+                .on_ack(now, delay, bytes_acked, bytes_in_flight);
+            if let Some(recovery_seq_nr) = self.recovery_seq_nr {
+                if is_seq_less_eq(recovery_seq_nr, ack_nr) {
+                    self.recovery_seq_nr = None;
+                } else if let Some(packet) = self.in_flight.front_mut() {
+                    // a partial acknowledgement: the packet after the one
+                    // retransmitted was lost too
+                    if packet.transmissions == 1 {
+                        packet.sent_time = None;
+                    }
+                }
+            }
+        } else if ack_nr == self.last_ack_nr && !self.in_flight.is_empty() {
+            self.dup_ack_count += 1;
+            if self.dup_ack_count == DUP_ACK_THRESHOLD
+                && self.recovery_seq_nr.is_none()
+            {
+                // fast retransmit the first unacknowledged packet
+                self.in_flight[0].sent_time = None;
+                self.recovery_seq_nr = Some(self.seq_nr.wrapping_sub(1));
+                self.congestion.on_loss();
+            }
+        }
+        self.last_ack_nr = ack_nr;
+    }
+
+    /// Updates the round trip time estimate and the retransmission timeout,
+    /// as described in BEP 29.
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            Some(rtt) => {
+                let delta = rtt.max(sample) - rtt.min(sample);
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+        }
+        self.timeout = (self.rtt.unwrap() + self.rtt_var * 4).max(MIN_TIMEOUT);
+    }
+
+    /// Buffers the payload of the data packet, or marks the end of the stream
+    /// if it's a FIN.
+    fn handle_data(&mut self, ty: PacketType, seq_nr: u16, payload: Bytes) {
+        // every data packet is acknowledged, even duplicates, as the peer may
+        // not have received our previous acknowledgement
+        self.is_ack_needed = true;
+        if self.is_eof {
+            return;
+        }
+        if ty == PacketType::Fin {
+            self.fin_seq_nr = Some(seq_nr);
+        }
+
+        let next_seq_nr = self.ack_nr.wrapping_add(1);
+        if seq_nr == next_seq_nr {
+            if self.recv_buf.len() + payload.len() > RECV_BUF_LEN {
+                // the peer ignored our receive window, so drop the packet and
+                // let it be retransmitted when there is space
+                return;
+            }
+            self.recv_buf.extend_from_slice(&payload);
+            self.ack_nr = seq_nr;
+            // the packet may have filled a gap
+            while let Some(payload) =
+                self.out_of_order.remove(&self.ack_nr.wrapping_add(1))
+            {
+                self.recv_buf.extend_from_slice(&payload);
+                self.ack_nr = self.ack_nr.wrapping_add(1);
+            }
+        } else if is_seq_less_eq(next_seq_nr, seq_nr)
+            && (self.out_of_order.len() + 1) * MAX_PAYLOAD_LEN < RECV_BUF_LEN
+        {
+            self.out_of_order.insert(seq_nr, payload);
+        }
+
+        if self.is_stream_dropped {
+            self.recv_buf.clear();
+        }
+        if self.fin_seq_nr == Some(self.ack_nr) {
+            self.is_eof = true;
+            self.out_of_order.clear();
+        }
+    }
+
+    /// Returns the packets that need to be sent now: retransmissions, new data
+    /// allowed by the send window, the FIN, and acknowledgements.
+    pub fn poll_transmit(&mut self, now: Instant, now_us: u32) -> Vec<Packet> {
+        let mut packets = Vec::new();
+        if self.state == State::Closed {
+            return packets;
+        }
+
+        self.check_timeout(now);
+        if self.state == State::Closed {
+            return packets;
+        }
+
+        // (re)send the packets that are due
+        for i in 0..self.in_flight.len() {
+            if self.in_flight[i].sent_time.is_none() {
+                let packet = &mut self.in_flight[i];
+                packet.sent_time = Some(now);
+                packet.transmissions += 1;
+                let (ty, seq_nr, payload) =
+                    (packet.ty, packet.seq_nr, packet.payload.clone());
+                packets.push(self.make_packet(ty, seq_nr, payload, now_us));
+            }
+        }
+
+        if self.state == State::Connected {
+            // send new data while the window allows it, but always allow one
+            // packet in flight so that we can probe a closed peer window
+            let window = self.congestion.window().min(self.peer_window);
+            while !self.send_buf.is_empty() {
+                let len = self.send_buf.len().min(MAX_PAYLOAD_LEN);
+                if !self.in_flight.is_empty()
+                    && self.bytes_in_flight + len > window
+                {
+                    break;
+                }
+                let payload = self.send_buf.split_to(len).freeze();
+                packets.push(self.send_new(
+                    PacketType::Data,
+                    payload,
+                    now,
+                    now_us,
+                ));
+            }
+
+            if self.is_write_closed
+                && self.send_buf.is_empty()
+                && !self.is_fin_sent
+            {
+                self.is_fin_sent = true;
+                packets.push(self.send_new(
+                    PacketType::Fin,
+                    Bytes::new(),
+                    now,
+                    now_us,
+                ));
+            }
+        }
+
+        // data packets carry acknowledgements too, so a STATE packet is only
+        // needed if nothing else was sent
+        if self.is_ack_needed && packets.is_empty() {
+            packets.push(self.make_packet(
+                PacketType::State,
+                self.seq_nr,
+                Bytes::new(),
+                now_us,
+            ));
+        }
+        self.is_ack_needed = false;
+
+        if !packets.is_empty() {
+            self.wake();
+        }
+        packets
+    }
+
+    /// Marks the packets in flight for retransmission if the oldest one timed
+    /// out, and closes the connection if this happens too many times in a row.
+    fn check_timeout(&mut self, now: Instant) {
+        let sent_time = match self.in_flight.front().and_then(|p| p.sent_time) {
+            Some(sent_time) => sent_time,
+            None => return,
+        };
+        if now < sent_time + self.timeout {
+            return;
+        }
+
+        self.timeout_count += 1;
+        let max_timeouts = if self.state == State::SynSent {
+            MAX_SYN_TIMEOUTS
+        } else {
+            MAX_TIMEOUTS
+        };
+        if self.timeout_count > max_timeouts {
+            self.close(io::ErrorKind::TimedOut);
+            return;
+        }
+        self.timeout *= 2;
+        self.congestion.on_timeout();
+        // without selective acknowledgements we don't know which packets were
+        // lost, so resend all of them
+        for packet in self.in_flight.iter_mut() {
+            packet.sent_time = None;
+        }
+        self.recovery_seq_nr = None;
+    }
+
+    /// Assigns the next sequence number to a new packet and records it as in
+    /// flight.
+    fn send_new(
+        &mut self,
+        ty: PacketType,
+        payload: Bytes,
+        now: Instant,
+        now_us: u32,
+    ) -> Packet {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.bytes_in_flight += payload.len();
+        self.in_flight.push_back(SentPacket {
+            ty,
+            seq_nr,
+            payload: payload.clone(),
+            sent_time: Some(now),
+            transmissions: 1,
+        });
+        self.make_packet(ty, seq_nr, payload, now_us)
+    }
+
+    fn make_packet(
+        &mut self,
+        ty: PacketType,
+        seq_nr: u16,
+        payload: Bytes,
+        now_us: u32,
+    ) -> Packet {
+        self.advertised_window = RECV_BUF_LEN - self.recv_buf.len();
+        // the SYN packet is sent with the id the peer is to send with
+        let conn_id = if ty == PacketType::Syn {
+            self.send_id.wrapping_sub(1)
+        } else {
+            self.send_id
+        };
+        Packet {
+            header: Header {
+                ty,
+                conn_id,
+                timestamp: now_us,
+                timestamp_diff: self.reply_timestamp_diff,
+                wnd_size: self.advertised_window as u32,
+                seq_nr,
+                ack_nr: self.ack_nr,
+            },
+            payload,
+        }
+    }
+
+    /// Reads buffered data into `buf`, or registers the task to be woken up
+    /// when there is data.
+    ///
+    /// Returns true along with the result if the read reopened our receive
+    /// window enough that the peer should be told about it.
+    pub fn poll_read(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> (Poll<io::Result<usize>>, bool) {
+        if !self.recv_buf.is_empty() {
+            let n = buf.len().min(self.recv_buf.len());
+            buf[..n].copy_from_slice(&self.recv_buf[..n]);
+            self.recv_buf.advance(n);
+            let window = RECV_BUF_LEN - self.recv_buf.len();
+            let is_window_update_needed = self.advertised_window
+                < MAX_PAYLOAD_LEN
+                && window >= MAX_PAYLOAD_LEN;
+            if is_window_update_needed {
+                self.is_ack_needed = true;
+            }
+            return (Poll::Ready(Ok(n)), is_window_update_needed);
+        }
+        if self.is_eof {
+            return (Poll::Ready(Ok(0)), false);
+        }
+        if let Some(error) = self.error {
+            return (Poll::Ready(Err(error.into())), false);
+        }
+        self.read_waker = Some(cx.waker().clone());
+        (Poll::Pending, false)
+    }
+
+    /// Buffers as much of `buf` as there is space for, or registers the task
+    /// to be woken up when there is space.
+    pub fn poll_write(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if let Some(error) = self.error {
+            return Poll::Ready(Err(error.into()));
+        }
+        if self.is_write_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let n = buf.len().min(SEND_BUF_LEN - self.send_buf.len());
+        if n == 0 {
+            self.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        self.send_buf.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    /// Closes the write half of the connection: a FIN is sent once all
+    /// buffered data is sent.
+    pub fn shutdown(&mut self) {
+        self.is_write_closed = true;
+    }
+
+    /// Called when the user drops the stream. The connection is closed
+    /// gracefully in the background.
+    pub fn drop_stream(&mut self) {
+        self.is_write_closed = true;
+        self.is_stream_dropped = true;
+        // whatever the peer sends from now on is not read by anyone
+        self.recv_buf.clear();
+    }
+
+    fn close(&mut self, error: io::ErrorKind) {
+        self.state = State::Closed;
+        self.error = Some(error);
+        self.wake();
+    }
+
+    /// Wakes up the reader and writer of the stream, as the state of the
+    /// connection may have changed in a way they are interested in.
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Compares two wrapping sequence numbers.
+fn is_seq_less_eq(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) >= 0
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::task::noop_waker;
+
+    use super::*;
+
+    #[test]
+    fn test_seq_compare() {
+        assert!(is_seq_less_eq(1, 1));
+        assert!(is_seq_less_eq(1, 2));
+        assert!(!is_seq_less_eq(2, 1));
+        assert!(is_seq_less_eq(0xfffe, 1));
+        assert!(!is_seq_less_eq(1, 0xfffe));
+    }
+
+    /// Establishes a connection between two in-memory connections.
+    fn connect(now: Instant) -> (Conn, Conn) {
+        let mut a = Conn::outbound(100);
+        let syn = a.poll_transmit(now, 0).pop().unwrap();
+        assert_eq!(syn.header.ty, PacketType::Syn);
+        assert_eq!(syn.header.conn_id, 100);
+        let mut b = Conn::inbound(&syn.header, 5000);
+        let state = b.poll_transmit(now, 0).pop().unwrap();
+        assert_eq!(state.header.ty, PacketType::State);
+        assert_eq!(state.header.conn_id, 100);
+        a.on_packet(state, now, 0);
+        assert!(a.is_connected());
+        assert!(a.in_flight.is_empty());
+        (a, b)
+    }
+
+    /// Delivers the packets, dropping those for which `drop` returns true.
+    fn deliver(
+        packets: Vec<Packet>,
+        to: &mut Conn,
+        now: Instant,
+        drop: &mut impl FnMut(&Packet) -> bool,
+    ) {
+        for packet in packets {
+            if !drop(&packet) {
+                to.on_packet(packet, now, 0);
+            }
+        }
+    }
+
+    /// Reads everything that is currently buffered.
+    fn read_all(conn: &mut Conn) -> Vec<u8> {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut data = Vec::new();
+        let mut buf = [0; 4096];
+        while let (Poll::Ready(Ok(n)), _) = conn.poll_read(&mut cx, &mut buf) {
+            if n == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..n]);
+        }
+        data
+    }
+
+    /// Transfers data from a to b over a lossy link and checks that it
+    /// arrives intact and in order, followed by the end of stream.
+    #[test]
+    fn test_transfer_with_loss() {
+        let mut now = Instant::now();
+        let (mut a, mut b) = connect(now);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let data = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut written = 0;
+        let mut received = Vec::new();
+        // drop every 7th packet in both directions
+        let mut counter = 0;
+        let mut drop = |_: &Packet| {
+            counter += 1;
+            counter % 7 == 0
+        };
+
+        for _ in 0..10_000 {
+            if written < data.len() {
+                if let Poll::Ready(Ok(n)) =
+                    a.poll_write(&mut cx, &data[written..])
+                {
+                    written += n;
+                }
+            } else {
+                a.shutdown();
+            }
+            let packets = a.poll_transmit(now, 0);
+            deliver(packets, &mut b, now, &mut drop);
+            received.extend(read_all(&mut b));
+            let packets = b.poll_transmit(now, 0);
+            deliver(packets, &mut a, now, &mut drop);
+            if b.is_eof {
+                break;
+            }
+            now += Duration::from_millis(100);
+        }
+
+        assert_eq!(received.len(), data.len());
+        assert!(received == data);
+        assert!(b.is_eof);
+        let (result, _) = b.poll_read(&mut cx, &mut [0; 10]);
+        assert!(matches!(result, Poll::Ready(Ok(0))));
+    }
+
+    #[test]
+    fn test_reset() {
+        let now = Instant::now();
+        let (mut a, mut b) = connect(now);
+        let reset = b.make_packet(PacketType::Reset, 0, Bytes::new(), 0);
+        a.on_packet(reset, now, 0);
+        assert!(a.is_done());
+        assert_eq!(a.error(), Some(io::ErrorKind::ConnectionReset));
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(matches!(
+            a.poll_write(&mut cx, b"data"),
+            Poll::Ready(Err(_))
+        ));
+    }
+
+    #[test]
+    fn test_syn_timeout() {
+        let mut now = Instant::now();
+        let mut a = Conn::outbound(100);
+        // the SYN and its retransmissions
+        for _ in 0..=MAX_SYN_TIMEOUTS {
+            assert_eq!(a.poll_transmit(now, 0).len(), 1);
+            now += Duration::from_secs(60);
+        }
+        assert!(a.poll_transmit(now, 0).is_empty());
+        assert_eq!(a.error(), Some(io::ErrorKind::TimedOut));
+    }
+}
@@ -0,0 +1,183 @@
+//! LEDBAT congestion control, as described in
+//! [BEP 29](http://bittorrent.org/beps/bep_0029.html#congestion-control).
+//!
+//! The congestion window grows while the measured one-way queuing delay is
+//! below the target delay and shrinks when it's above it. This way uTP yields
+//! to other traffic on the link: as soon as other connections start filling
+//! up the queues of the network path, our delay measurements go up and we send
+//! less.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use super::MAX_PACKET_SIZE;
+
+/// The queuing delay we aim for, in microseconds.
+const TARGET_DELAY: f64 = 100_000.0;
+/// The maximum number of bytes the window may grow by in a round trip.
+const MAX_WINDOW_INCREASE: f64 = 3000.0;
+/// The window never shrinks below a single packet so that we can always make
+/// progress.
+const MIN_WINDOW: f64 = MAX_PACKET_SIZE as f64;
+/// The window with which a connection starts.
+const INITIAL_WINDOW: f64 = 2.0 * MAX_PACKET_SIZE as f64;
+/// The base delay is the minimum delay seen in this many past minutes. Older
+/// samples are discarded so that we adapt to route changes and clock drift.
+const BASE_DELAY_HISTORY_LEN: usize = 2;
+
+pub(super) struct Ledbat {
+    /// The congestion window, in bytes.
+    window: f64,
+    /// The minimum delay sample of each recent minute, the first being the
+    /// oldest, with the start time of the minute.
+    base_delays: VecDeque<(Instant, u32)>,
+}
+
+impl Ledbat {
+    pub fn new() -> Self {
+        Self {
+            window: INITIAL_WINDOW,
+            base_delays: VecDeque::with_capacity(BASE_DELAY_HISTORY_LEN),
+        }
+    }
+
+    /// Returns the number of bytes that may be in flight.
+    pub fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    /// Updates the window when some of our data is acknowledged.
+    ///
+    /// The delay is the timestamp difference the peer measured for our packets.
+    /// Since the peer's and our clocks are not synchronized, this is only
+    /// meaningful relative to the base (minimum) delay.
+    pub fn on_ack(
+        &mut self,
+        now: Instant,
+        delay: u32,
+        bytes_acked: usize,
+        bytes_in_flight: usize,
+    ) {
+        // a zero delay means the peer hasn't received anything from us to
+        // measure the delay with
+        if delay == 0 || bytes_acked == 0 {
+            return;
+        }
+        let base_delay = self.update_base_delay(now, delay);
+        // the timestamps wrap, so compute the difference in wrapping
+        // arithmetic
+        let queuing_delay = delay.wrapping_sub(base_delay) as i32 as f64;
+        let off_target = (TARGET_DELAY - queuing_delay.max(0.0)) / TARGET_DELAY;
+        let window_factor =
+            bytes_acked as f64 / self.window.max(bytes_in_flight as f64);
+        let gain = MAX_WINDOW_INCREASE * off_target * window_factor;
+        self.window = (self.window + gain).max(MIN_WINDOW);
+    }
+
+    /// Halves the window when a packet is lost.
+    pub fn on_loss(&mut self) {
+        self.window = (self.window / 2.0).max(MIN_WINDOW);
+    }
+
+    /// Resets the window to its minimum when a packet times out.
+    pub fn on_timeout(&mut self) {
+        self.window = MIN_WINDOW;
+    }
+
+    /// Records the delay sample and returns the current base delay.
+    fn update_base_delay(&mut self, now: Instant, delay: u32) -> u32 {
+        match self.base_delays.back_mut() {
+            Some((minute_start, min_delay))
+                if now.duration_since(*minute_start)
+                    < Duration::from_secs(60) =>
+            {
+                if is_less(delay, *min_delay) {
+                    *min_delay = delay;
+                }
+            }
+            _ => {
+                if self.base_delays.len() == BASE_DELAY_HISTORY_LEN {
+                    self.base_delays.pop_front();
+                }
+                self.base_delays.push_back((now, delay));
+            }
+        }
+        self.base_delays
+            .iter()
+            .map(|(_, d)| *d)
+            .fold(delay, |min, d| if is_less(d, min) { d } else { min })
+    }
+}
+
+/// Compares two wrapping microsecond timestamps.
+fn is_less(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_grows_below_target() {
+        let mut ledbat = Ledbat::new();
+        let now = Instant::now();
+        let initial = ledbat.window();
+        // the delay stays at its base, so there is no queuing delay
+        for _ in 0..10 {
+            let window = ledbat.window();
+            ledbat.on_ack(now, 5000, window, window);
+        }
+        assert!(ledbat.window() > initial);
+    }
+
+    #[test]
+    fn test_window_shrinks_above_target() {
+        let mut ledbat = Ledbat::new();
+        let now = Instant::now();
+        for _ in 0..100 {
+            let window = ledbat.window();
+            ledbat.on_ack(now, 5000, window, window);
+        }
+        let grown = ledbat.window();
+        // the delay is now 200 ms above the base
+        for _ in 0..10 {
+            let window = ledbat.window();
+            ledbat.on_ack(now, 205_000, window, window);
+        }
+        assert!(ledbat.window() < grown);
+    }
+
+    #[test]
+    fn test_loss_and_timeout() {
+        let mut ledbat = Ledbat::new();
+        let now = Instant::now();
+        for _ in 0..100 {
+            let window = ledbat.window();
+            ledbat.on_ack(now, 5000, window, window);
+        }
+        let grown = ledbat.window();
+        ledbat.on_loss();
+        assert_eq!(ledbat.window(), grown / 2);
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window(), MAX_PACKET_SIZE);
+        // never goes below the minimum
+        ledbat.on_loss();
+        assert_eq!(ledbat.window(), MAX_PACKET_SIZE);
+    }
+
+    #[test]
+    fn test_base_delay_expires() {
+        let mut ledbat = Ledbat::new();
+        let now = Instant::now();
+        assert_eq!(ledbat.update_base_delay(now, 100), 100);
+        assert_eq!(ledbat.update_base_delay(now, 200), 100);
+        let now = now + Duration::from_secs(61);
+        assert_eq!(ledbat.update_base_delay(now, 300), 100);
+        // the minute with the 100 us sample is now more than two minutes old
+        let now = now + Duration::from_secs(61);
+        assert_eq!(ledbat.update_base_delay(now, 400), 300);
+    }
+}
@@ -0,0 +1,210 @@
+//! The uTP packet format.
+
+use std::{convert::TryFrom, io};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// The length of the fixed size packet header.
+pub(super) const HEADER_LEN: usize = 20;
+
+/// The only version of the protocol.
+const VERSION: u8 = 1;
+
+/// The type of a uTP packet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum PacketType {
+    /// A regular data packet. Always has a payload.
+    Data = 0,
+    /// Finalizes the connection. The sequence number of this packet is the
+    /// last one the sender of the packet will send.
+    Fin = 1,
+    /// A packet without payload, used to acknowledge data and to respond to
+    /// the SYN packet.
+    State = 2,
+    /// Forcibly terminates the connection.
+    Reset = 3,
+    /// Initiates a connection.
+    Syn = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = io::Error;
+
+    fn try_from(ty: u8) -> io::Result<Self> {
+        use PacketType::*;
+        match ty {
+            0 => Ok(Data),
+            1 => Ok(Fin),
+            2 => Ok(State),
+            3 => Ok(Reset),
+            4 => Ok(Syn),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unknown uTP packet type",
+            )),
+        }
+    }
+}
+
+/// The uTP packet header.
+///
+/// Extensions are skipped when decoding (selective ACKs are not used) and are
+/// never sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) struct Header {
+    pub ty: PacketType,
+    /// The id of the connection the packet belongs to, as seen by the
+    /// receiver of the packet.
+    pub conn_id: u16,
+    /// The time the packet was sent, in microseconds, on the sender's clock.
+    pub timestamp: u32,
+    /// The difference between the time the last packet was received and its
+    /// timestamp, in microseconds. This is the one-way delay (plus the clock
+    /// offset) that the sender of this packet measured, and which the receiver
+    /// uses for congestion control.
+    pub timestamp_diff: u32,
+    /// The number of bytes the sender is still able to receive.
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    /// The sequence number of the last packet received in order.
+    pub ack_nr: u16,
+}
+
+/// A header and its possibly empty payload.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct Packet {
+    pub header: Header,
+    pub payload: Bytes,
+}
+
+/// Encodes and decodes uTP packets. Each datagram is exactly one packet.
+pub(super) struct PacketCodec;
+
+impl Encoder<Packet> for PacketCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: Packet, buf: &mut BytesMut) -> io::Result<()> {
+        let Header {
+            ty,
+            conn_id,
+            timestamp,
+            timestamp_diff,
+            wnd_size,
+            seq_nr,
+            ack_nr,
+        } = packet.header;
+        buf.reserve(HEADER_LEN + packet.payload.len());
+        buf.put_u8((ty as u8) << 4 | VERSION);
+        // no extensions
+        buf.put_u8(0);
+        buf.put_u16(conn_id);
+        buf.put_u32(timestamp);
+        buf.put_u32(timestamp_diff);
+        buf.put_u32(wnd_size);
+        buf.put_u16(seq_nr);
+        buf.put_u16(ack_nr);
+        buf.extend_from_slice(&packet.payload);
+        Ok(())
+    }
+}
+
+impl Decoder for PacketCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Packet>> {
+        let invalid =
+            |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        if buf.len() < HEADER_LEN {
+            return Err(invalid("uTP packet too short"));
+        }
+        let ty_ver = buf.get_u8();
+        if ty_ver & 0x0f != VERSION {
+            return Err(invalid("Unknown uTP version"));
+        }
+        let ty = PacketType::try_from(ty_ver >> 4)?;
+        let mut extension = buf.get_u8();
+        let header = Header {
+            ty,
+            conn_id: buf.get_u16(),
+            timestamp: buf.get_u32(),
+            timestamp_diff: buf.get_u32(),
+            wnd_size: buf.get_u32(),
+            seq_nr: buf.get_u16(),
+            ack_nr: buf.get_u16(),
+        };
+
+        // skip the linked list of extensions
+        while extension != 0 {
+            if buf.len() < 2 {
+                return Err(invalid("uTP extension header too short"));
+            }
+            extension = buf.get_u8();
+            let len = buf.get_u8() as usize;
+            if buf.len() < len {
+                return Err(invalid("uTP extension too short"));
+            }
+            buf.advance(len);
+        }
+
+        let payload = buf.split().freeze();
+        Ok(Some(Packet { header, payload }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let packet = Packet {
+            header: Header {
+                ty: PacketType::Data,
+                conn_id: 0x1234,
+                timestamp: 0xdeadbeef,
+                timestamp_diff: 42,
+                wnd_size: 1 << 20,
+                seq_nr: 0xffff,
+                ack_nr: 7,
+            },
+            payload: Bytes::from_static(b"payload"),
+        };
+        let mut buf = BytesMut::new();
+        PacketCodec.encode(packet.clone(), &mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_LEN + 7);
+        assert_eq!(buf[0], 0x01);
+        assert_eq!(PacketCodec.decode(&mut buf).unwrap().unwrap(), packet);
+    }
+
+    #[test]
+    fn test_decode_skips_extensions() {
+        let mut buf = BytesMut::new();
+        // a STATE packet with a selective ACK extension of 4 bytes
+        buf.extend_from_slice(&[0x21, 1, 0, 1]);
+        buf.extend_from_slice(&[0; 12]);
+        buf.extend_from_slice(&[0, 2, 0, 3]);
+        buf.extend_from_slice(&[0, 4, 0xff, 0xff, 0xff, 0xff]);
+        let packet = PacketCodec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(packet.header.ty, PacketType::State);
+        assert_eq!(packet.header.conn_id, 1);
+        assert_eq!(packet.header.seq_nr, 2);
+        assert_eq!(packet.header.ack_nr, 3);
+        assert!(packet.payload.is_empty());
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        // too short
+        let mut buf = BytesMut::from(&[0x01; 10][..]);
+        assert!(PacketCodec.decode(&mut buf).is_err());
+        // wrong version
+        let mut buf = BytesMut::from(&[0x02; HEADER_LEN][..]);
+        assert!(PacketCodec.decode(&mut buf).is_err());
+        // unknown type
+        let mut buf = BytesMut::from(&[0x51; HEADER_LEN][..]);
+        assert!(PacketCodec.decode(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,517 @@
+//! HTTP web seeds, as described in
+//! [BEP 19](http://bittorrent.org/beps/bep_0019.html).
+//!
+//! A web seed is an HTTP server that has all the files of a torrent, laid out
+//! the same way as in the torrent. We download whole pieces from it with HTTP
+//! range requests, one request per file that the piece overlaps with, and the
+//! downloaded blocks are verified and saved by the disk task just like the
+//! blocks received from peers.
+
+use std::{collections::HashSet, fmt, ops::Range, sync::Arc, time::Duration};
+
+use futures::{
+    select,
+    stream::{Fuse, StreamExt},
+    FutureExt,
+};
+use reqwest::{header::RANGE, Client, StatusCode, Url};
+use tokio::sync::{mpsc, RwLock};
+
+use crate::{
+    disk,
+    download::{BlockStatus, PieceDownload},
+    metainfo::FileAttrs,
+    storage_info::FileInfo,
+    torrent::{self, TorrentContext},
+    BlockInfo,
+};
+
+use tokio::sync::mpsc::error::SendError;
+
+pub(crate) type Result<T, E = WebSeedError> = std::result::Result<T, E>;
+
+/// How long to wait for a web seed to send a piece before giving up.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The channel on which a web seed session receives commands.
+pub(crate) type Sender = mpsc::UnboundedSender<Command>;
+type Receiver = mpsc::UnboundedReceiver<Command>;
+
+/// The commands a web seed session can receive.
+pub(crate) enum Command {
+    /// Stop the session, abandoning the piece being downloaded, if any.
+    Shutdown,
+}
+
+/// Error type returned on failed web seed sessions.
+///
+/// This error is non-fatal: the web seed is retried later.
+#[derive(Debug)]
+pub(crate) enum WebSeedError {
+    /// The channel on which some component in engine was listening or sending
+    /// died.
+    Channel,
+    /// The HTTP request failed.
+    Http(reqwest::Error),
+    /// The server responded with an unexpected status code.
+    InvalidStatus(StatusCode),
+    /// The server sent fewer or more bytes than were requested.
+    InvalidLength,
+}
+
+impl fmt::Display for WebSeedError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use WebSeedError::*;
+        match self {
+            Channel => write!(fmt, "channel error"),
+            Http(e) => write!(fmt, "{}", e),
+            InvalidStatus(status) => {
+                write!(fmt, "unexpected status {}", status)
+            }
+            InvalidLength => write!(fmt, "invalid response length"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for WebSeedError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+impl<T> From<SendError<T>> for WebSeedError {
+    fn from(_: SendError<T>) -> Self {
+        Self::Channel
+    }
+}
+
+/// Returns the URL of each file of the torrent on the web seed.
+///
+/// If the torrent is an archive, the files are in a directory named as the
+/// torrent under the web seed URL. Otherwise the web seed URL is either that
+/// of the file itself, or, if it ends with a slash, that of the directory in
+/// which the file is found under the torrent's name.
+///
+/// Padding files are not on the web seed, so they have no URL.
+pub(crate) fn file_urls(
+    url: &Url,
+    name: &str,
+    files: &[FileInfo],
+    file_attrs: &[FileAttrs],
+    is_archive: bool,
+) -> Vec<Option<Url>> {
+    files
+        .iter()
+        .zip(file_attrs.iter())
+        .map(|(file, attrs)| {
+            if attrs.is_padding() {
+                return None;
+            }
+            let mut file_url = url.clone();
+            if !is_archive && !url.path().ends_with('/') {
+                return Some(file_url);
+            }
+            // HTTP URLs always have a path, but just in case the URL is
+            // returned as is otherwise
+            if let Ok(mut segments) = file_url.path_segments_mut() {
+                segments.pop_if_empty().push(name);
+                if is_archive {
+                    segments.extend(
+                        file.path
+                            .iter()
+                            .map(|component| component.to_string_lossy()),
+                    );
+                }
+            }
+            Some(file_url)
+        })
+        .collect()
+}
+
+/// A download from a single web seed.
+///
+/// The session downloads pieces one at a time until there are no more pieces
+/// to pick, after which it stops. Like peer sessions, it may continue piece
+/// downloads started by other sessions.
+pub(crate) struct WebSeedSession {
+    /// Shared information of the torrent.
+    torrent: Arc<TorrentContext>,
+    /// The web seed's index in the torrent, with which it reports back to
+    /// torrent.
+    id: usize,
+    /// The URL of each of the torrent's files on the web seed, or `None` for
+    /// padding files.
+    file_urls: Vec<Option<Url>>,
+    client: Client,
+    /// The port on which the session receives commands.
+    cmd_rx: Fuse<Receiver>,
+    /// The log target of the session, which includes the torrent id and the
+    /// web seed's URL.
+    log_target: String,
+}
+
+impl WebSeedSession {
+    /// Creates a new session with the web seed at the given index in the
+    /// torrent, whose files are at the given URLs.
+    pub fn new(
+        torrent: Arc<TorrentContext>,
+        id: usize,
+        url: &Url,
+        file_urls: Vec<Option<Url>>,
+    ) -> Result<(Self, Sender)> {
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let client = Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        let log_target =
+            format!("cratetorrent::web_seed [{}][{}]", torrent.id, url);
+        Ok((
+            Self {
+                torrent,
+                id,
+                file_urls,
+                client,
+                cmd_rx: cmd_rx.fuse(),
+                log_target,
+            },
+            cmd_tx,
+        ))
+    }
+
+    /// Runs the session until there are no more pieces to download, or until
+    /// an error occurs or it's shut down.
+    ///
+    /// The session always tells torrent when it stops.
+    pub async fn start(&mut self) -> Result<()> {
+        log::info!(target: &self.log_target, "Starting web seed session");
+        let result = self.run().await;
+        if let Err(e) = &result {
+            log::warn!(target: &self.log_target, "Web seed error: {}", e);
+        }
+        self.torrent.cmd_tx.send(torrent::Command::WebSeedStopped {
+            id: self.id,
+            is_error: result.is_err(),
+        })?;
+        result
+    }
+
+    async fn run(&mut self) -> Result<()> {
+        while let Some(blocks) = self.pick_blocks().await {
+            let ranges = self.file_ranges(&blocks);
+            let fetch = fetch(self.client.clone(), ranges).fuse();
+            futures::pin_mut!(fetch);
+            let result = select! {
+                result = fetch => result,
+                cmd = self.cmd_rx.select_next_some() => match cmd {
+                    Command::Shutdown => {
+                        log::info!(
+                            target: &self.log_target,
+                            "Shutting down web seed session"
+                        );
+                        self.free_blocks(&blocks).await;
+                        return Ok(());
+                    }
+                },
+            };
+            match result {
+                Ok(data) => self.save_blocks(&blocks, data).await?,
+                Err(e) => {
+                    self.free_blocks(&blocks).await;
+                    return Err(e);
+                }
+            }
+        }
+
+        log::info!(target: &self.log_target, "No more pieces to download");
+        Ok(())
+    }
+
+    /// Picks the free blocks of the first piece download that has any, or if
+    /// there are no such downloads, starts downloading a new piece and picks
+    /// all its blocks.
+    ///
+    /// Returns `None` if there are no more blocks to pick.
+    async fn pick_blocks(&mut self) -> Option<Vec<BlockInfo>> {
+        let mut blocks = Vec::new();
+        let no_prev_picked = HashSet::new();
+        for download in self.torrent.downloads.read().await.values() {
+            download.write().await.pick_blocks(
+                usize::MAX,
+                &mut blocks,
+                false,
+                &no_prev_picked,
+            );
+            if !blocks.is_empty() {
+                return Some(blocks);
+            }
+        }
+
+        let index = self.torrent.piece_picker.write().await.pick_piece()?;
+        log::info!(target: &self.log_target, "Picked piece {}", index);
+        let mut download =
+            PieceDownload::new(index, self.torrent.storage.piece_len(index));
+        download.pick_blocks(usize::MAX, &mut blocks, false, &no_prev_picked);
+        self.torrent
+            .downloads
+            .write()
+            .await
+            .insert(index, RwLock::new(download));
+        Some(blocks)
+    }
+
+    /// Returns the ranges of files that need to be fetched to get the blocks,
+    /// which are all in the same piece.
+    ///
+    /// To make as few requests as possible, everything from the first to the
+    /// last block is fetched, even if blocks in between are downloaded from
+    /// someone else.
+    fn file_ranges(
+        &self,
+        blocks: &[BlockInfo],
+    ) -> Vec<(Option<Url>, Range<u64>)> {
+        let storage = &self.torrent.storage;
+        let (first, last) = (blocks[0], blocks[blocks.len() - 1]);
+        let piece_offset = storage.torrent_piece_offset(first.piece_index);
+        let start = piece_offset + first.offset as u64;
+        let end = piece_offset + last.offset as u64 + last.len as u64;
+
+        let mut ranges = Vec::new();
+        for index in storage.files_intersecting_bytes(start..end) {
+            let file = &storage.files[index];
+            let slice_start = start.max(file.torrent_offset);
+            let slice = file.get_slice(slice_start, end - slice_start);
+            ranges.push((
+                self.file_urls[index].clone(),
+                slice.offset..slice.offset + slice.len,
+            ));
+        }
+        ranges
+    }
+
+    /// Sends the downloaded blocks to disk, unless they have been downloaded
+    /// from someone else in the meantime.
+    async fn save_blocks(
+        &mut self,
+        blocks: &[BlockInfo],
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let first_offset = blocks[0].offset;
+        let mut downloaded = 0;
+        let mut wasted = 0;
+        for block in blocks.iter() {
+            let prev_status = match self
+                .torrent
+                .downloads
+                .read()
+                .await
+                .get(&block.piece_index)
+            {
+                Some(download) => download.write().await.received_block(block),
+                // the piece may have been completed in the meantime
+                None => BlockStatus::Received,
+            };
+            if prev_status == BlockStatus::Received {
+                log::debug!(
+                    target: &self.log_target,
+                    "Already downloaded block {}",
+                    block
+                );
+                wasted += block.len as u64;
+                continue;
+            }
+
+            log::debug!(target: &self.log_target, "Got block {}", block);
+            downloaded += block.len as u64;
+            let offset = (block.offset - first_offset) as usize;
+            self.torrent.disk_tx.send(disk::Command::WriteBlock {
+                id: self.torrent.id,
+                block_info: *block,
+                data: data[offset..offset + block.len as usize].to_vec(),
+                sender: None,
+            })?;
+        }
+
+        self.torrent
+            .cmd_tx
+            .send(torrent::Command::WebSeedTransfer {
+                id: self.id,
+                downloaded,
+                wasted,
+            })?;
+        Ok(())
+    }
+
+    /// Marks the blocks free in their download so that they can be picked
+    /// again.
+    async fn free_blocks(&self, blocks: &[BlockInfo]) {
+        let downloads = self.torrent.downloads.read().await;
+        for block in blocks.iter() {
+            // the piece may have been completed in the meantime
+            if let Some(download) = downloads.get(&block.piece_index) {
+                download.write().await.free_block(block);
+            }
+        }
+    }
+}
+
+/// Fetches the ranges of files, in order, and returns their concatenation.
+///
+/// Ranges without a URL are of padding files, which are all zeros.
+///
+/// The server must respond to a range request with the requested range only,
+/// but a server that doesn't support range requests and sends the whole file
+/// is also accepted if the whole file was requested.
+async fn fetch(
+    client: Client,
+    ranges: Vec<(Option<Url>, Range<u64>)>,
+) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for (url, range) in ranges {
+        let url = match url {
+            Some(url) => url,
+            None => {
+                data.resize(data.len() + (range.end - range.start) as usize, 0);
+                continue;
+            }
+        };
+        log::trace!("Fetching bytes {:?} of {}", range, url);
+        let resp = client
+            .get(url)
+            .header(RANGE, format!("bytes={}-{}", range.start, range.end - 1))
+            .send()
+            .await?;
+        let status = resp.status();
+        if status != StatusCode::PARTIAL_CONTENT
+            && !(status == StatusCode::OK && range.start == 0)
+        {
+            return Err(WebSeedError::InvalidStatus(status));
+        }
+        let body = resp.bytes().await?;
+        if body.len() as u64 != range.end - range.start {
+            return Err(WebSeedError::InvalidLength);
+        }
+        data.extend_from_slice(&body);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use mockito::{mock, Matcher};
+
+    use super::*;
+
+    #[test]
+    fn test_file_urls() {
+        let files = vec![
+            FileInfo {
+                path: PathBuf::from("a/b.txt"),
+                len: 10,
+                torrent_offset: 0,
+            },
+            FileInfo {
+                path: PathBuf::from(".pad/6"),
+                len: 6,
+                torrent_offset: 10,
+            },
+            FileInfo {
+                path: PathBuf::from("c d.txt"),
+                len: 5,
+                torrent_offset: 16,
+            },
+        ];
+        let mut file_attrs = vec![FileAttrs::default(); 3];
+        file_attrs[1].attr = Some("p".into());
+
+        let url = Url::parse("http://example.com/seeds/").unwrap();
+        let urls = file_urls(&url, "my torrent", &files, &file_attrs, true);
+        assert_eq!(
+            urls,
+            vec![
+                Some(
+                    Url::parse("http://example.com/seeds/my%20torrent/a/b.txt")
+                        .unwrap()
+                ),
+                None,
+                Some(
+                    Url::parse(
+                        "http://example.com/seeds/my%20torrent/c%20d.txt"
+                    )
+                    .unwrap()
+                ),
+            ]
+        );
+
+        // single file torrents may point to the file itself or to its
+        // directory
+        let url = Url::parse("http://example.com/file.iso").unwrap();
+        let urls =
+            file_urls(&url, "file.iso", &files[..1], &file_attrs[..1], false);
+        assert_eq!(urls, vec![Some(url)]);
+        let url = Url::parse("http://example.com/files/").unwrap();
+        let urls =
+            file_urls(&url, "file.iso", &files[..1], &file_attrs[..1], false);
+        assert_eq!(
+            urls,
+            vec![Some(
+                Url::parse("http://example.com/files/file.iso").unwrap()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_across_files() {
+        let _first = mock("GET", "/seed/first")
+            .match_header("range", "bytes=6-9")
+            .with_status(206)
+            .with_body("6789")
+            .create();
+        let _second = mock("GET", "/seed/second")
+            .match_header("range", "bytes=0-2")
+            .with_status(206)
+            .with_body("abc")
+            .create();
+
+        let base = Url::parse(&mockito::server_url()).unwrap();
+        let ranges = vec![
+            (Some(base.join("/seed/first").unwrap()), 6..10),
+            (None, 0..2),
+            (Some(base.join("/seed/second").unwrap()), 0..3),
+        ];
+        let data = fetch(Client::new(), ranges).await.unwrap();
+        assert_eq!(data, b"6789\0\0abc");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_invalid_responses() {
+        // the server ignored the range and sent the whole file
+        let _full = mock("GET", "/seed/full")
+            .match_header("range", Matcher::Any)
+            .with_status(200)
+            .with_body("0123456789")
+            .create();
+        let base = Url::parse(&mockito::server_url()).unwrap();
+        let url = base.join("/seed/full").unwrap();
+        assert!(matches!(
+            fetch(Client::new(), vec![(Some(url.clone()), 2..4)]).await,
+            Err(WebSeedError::InvalidStatus(StatusCode::OK))
+        ));
+        assert!(matches!(
+            fetch(Client::new(), vec![(Some(url.clone()), 0..4)]).await,
+            Err(WebSeedError::InvalidLength)
+        ));
+        assert_eq!(
+            fetch(Client::new(), vec![(Some(url), 0..10)])
+                .await
+                .unwrap(),
+            b"0123456789"
+        );
+
+        let _missing = mock("GET", "/seed/missing").with_status(404).create();
+        let url = base.join("/seed/missing").unwrap();
+        assert!(matches!(
+            fetch(Client::new(), vec![(Some(url), 0..4)]).await,
+            Err(WebSeedError::InvalidStatus(StatusCode::NOT_FOUND))
+        ));
+    }
+}
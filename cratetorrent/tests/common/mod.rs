@@ -0,0 +1,83 @@
+//! Fixtures shared by the integration tests.
+//!
+//! Each test binary uses only some of these.
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+};
+
+use cratetorrent::{metainfo::MetainfoBuilder, prelude::*};
+
+/// The length of the pieces of the torrents created by [`create_metainfo`].
+pub const PIECE_LEN: u32 = 32 * 1024;
+
+/// Returns the directory of the test with the name, which is unique to the
+/// test run so that runs don't see each other's files.
+pub fn test_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "cratetorrent-{}-{}",
+        name,
+        std::process::id()
+    ))
+}
+
+/// Returns the contents of a test file of the given length, which differ from
+/// piece to piece.
+pub fn test_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// Spawns an engine that downloads into the directory, with local service
+/// discovery turned off so that tests can't find each other's engines.
+///
+/// The engine is further configured by the function.
+pub fn spawn_engine(
+    dir: &Path,
+    configure: impl FnOnce(&mut Conf),
+) -> (EngineHandle, AlertReceiver) {
+    let mut conf = Conf::new(dir);
+    conf.engine.lsd = None;
+    configure(&mut conf);
+    engine::spawn(conf).unwrap()
+}
+
+/// Writes the data to the file at the path, and creates the metainfo of the
+/// single file torrent made up of it, with the given tiers of trackers.
+///
+/// The torrent is named after the file, so a seed of the torrent may use the
+/// file's directory as its download directory.
+pub fn create_metainfo(
+    path: &Path,
+    data: &[u8],
+    trackers: &[&[&str]],
+) -> Metainfo {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).unwrap();
+    }
+    fs::write(path, data).unwrap();
+    let mut builder = MetainfoBuilder::new(path).piece_len(PIECE_LEN);
+    for tier in trackers {
+        builder = builder.tracker_tier(
+            tier.iter().map(|url| url.parse().unwrap()).collect(),
+        );
+    }
+    let (_, metainfo) = builder.build().unwrap();
+    metainfo
+}
+
+/// Returns a local address with a port that is free at the time of the call.
+///
+/// Torrents may have to know their address before they start listening, for
+/// example to give it to other torrents or to announce it.
+pub fn free_addr() -> SocketAddr {
+    free_addr_on(Ipv4Addr::LOCALHOST.into())
+}
+
+/// Returns an address with the IP address and a port that is free at the time
+/// of the call.
+pub fn free_addr_on(ip: IpAddr) -> SocketAddr {
+    TcpListener::bind((ip, 0)).unwrap().local_addr().unwrap()
+}
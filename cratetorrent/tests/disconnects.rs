@@ -0,0 +1,166 @@
+//! Tests that the end of each peer session is reported with its reason and
+//! totals, and counted in the torrent's stats.
+
+mod common;
+
+use std::{fs, net::SocketAddr, path::Path, time::Duration};
+
+use common::{create_metainfo, free_addr, test_data, test_dir, PIECE_LEN};
+use cratetorrent::{
+    alert::{Alert, AlertReceiver},
+    peer::DisconnectReason,
+    prelude::*,
+    torrent::stats::TorrentStats,
+};
+
+#[tokio::test]
+async fn test_connect_failed() {
+    let dir = test_dir("disconnects-connect-failed");
+    let metainfo = create_metainfo(
+        &dir.join("source").join("connect-failed"),
+        &test_data(2 * PIECE_LEN as usize),
+        &[],
+    );
+    // nothing listens at the address
+    let addr = free_addr();
+
+    let (engine, mut alerts) = spawn_engine(&dir.join("download"));
+    engine
+        .create_torrent(TorrentParams {
+            metainfo,
+            conf: None,
+            mode: Mode::Download { seeds: vec![addr] },
+            listen_addr: None,
+            queued: false,
+        })
+        .unwrap();
+
+    let (disconnect, stats) =
+        wait_for_disconnect(&mut alerts, |peer_addr| peer_addr == addr).await;
+    engine.shutdown().await.unwrap();
+    fs::remove_dir_all(&dir).ok();
+
+    assert_eq!(disconnect.reason, DisconnectReason::ConnectFailed);
+    assert_eq!(disconnect.downloaded, 0);
+    assert_eq!(disconnect.uploaded, 0);
+    assert_eq!(disconnect.wasted, 0);
+    assert_eq!(
+        stats.disconnects.get(&DisconnectReason::ConnectFailed),
+        Some(&1)
+    );
+}
+
+#[tokio::test]
+async fn test_disconnect_after_download() {
+    let dir = test_dir("disconnects-download");
+    let seed_dir = dir.join("seed");
+    let download_dir = dir.join("download");
+    let data = test_data(200_000);
+    let metainfo = create_metainfo(&seed_dir.join("download"), &data, &[]);
+
+    let seed_addr = free_addr();
+    let (seed, _seed_alerts) = spawn_engine(&seed_dir);
+    seed.create_torrent(TorrentParams {
+        metainfo: metainfo.clone(),
+        conf: None,
+        mode: Mode::Seed,
+        listen_addr: Some(seed_addr),
+        queued: false,
+    })
+    .unwrap();
+
+    let (downloader, mut alerts) = spawn_engine(&download_dir);
+    downloader
+        .create_torrent(TorrentParams {
+            metainfo,
+            conf: None,
+            mode: Mode::Download {
+                seeds: vec![seed_addr],
+            },
+            listen_addr: Some(free_addr()),
+            queued: false,
+        })
+        .unwrap();
+    let complete = async {
+        while let Some(alert) = alerts.next().await {
+            if let Alert::TorrentComplete(_) = alert {
+                return;
+            }
+        }
+        panic!("engine stopped before completing the download");
+    };
+    tokio::time::timeout(Duration::from_secs(60), complete)
+        .await
+        .expect("download timed out");
+
+    // the seed closes the connection when it stops
+    seed.shutdown().await.unwrap();
+    let (disconnect, stats) =
+        wait_for_disconnect(&mut alerts, |addr| addr == seed_addr).await;
+    downloader.shutdown().await.unwrap();
+    fs::remove_dir_all(&dir).ok();
+
+    assert_eq!(disconnect.reason, DisconnectReason::ConnectionLost);
+    // the session's totals include the whole download
+    assert_eq!(disconnect.downloaded, data.len() as u64);
+    assert_eq!(disconnect.uploaded, 0);
+    assert_eq!(
+        stats.disconnects.get(&DisconnectReason::ConnectionLost),
+        Some(&1)
+    );
+}
+
+/// The fields of a peer disconnected alert.
+struct Disconnect {
+    reason: DisconnectReason,
+    downloaded: u64,
+    uploaded: u64,
+    wasted: u64,
+}
+
+/// Waits for the alert of the first disconnect of a peer whose address
+/// satisfies the predicate, and for the stats that follow it.
+async fn wait_for_disconnect(
+    alerts: &mut AlertReceiver,
+    pred: impl Fn(SocketAddr) -> bool,
+) -> (Disconnect, TorrentStats) {
+    let disconnect = async {
+        let mut disconnect = None;
+        while let Some(alert) = alerts.next().await {
+            match alert {
+                Alert::PeerDisconnected {
+                    addr,
+                    reason,
+                    downloaded,
+                    uploaded,
+                    wasted,
+                    ..
+                } if disconnect.is_none() && pred(addr) => {
+                    disconnect = Some(Disconnect {
+                        reason,
+                        downloaded,
+                        uploaded,
+                        wasted,
+                    });
+                }
+                Alert::TorrentStats { stats, .. } => {
+                    if let Some(disconnect) = disconnect.take() {
+                        return (disconnect, *stats);
+                    }
+                }
+                _ => (),
+            }
+        }
+        panic!("engine stopped before the peer disconnected");
+    };
+    tokio::time::timeout(Duration::from_secs(30), disconnect)
+        .await
+        .expect("timed out waiting for the peer to disconnect")
+}
+
+/// Spawns an engine that alerts of the peers that disconnect.
+fn spawn_engine(dir: &Path) -> (EngineHandle, AlertReceiver) {
+    common::spawn_engine(dir, |conf| {
+        conf.torrent.alerts.peer_disconnects = true
+    })
+}
@@ -0,0 +1,241 @@
+//! Tests that a seed of a hybrid torrent serves the v1 view of the torrent to
+//! v1 peers, in which each file is followed by padding up to the next piece
+//! boundary.
+
+mod common;
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use common::{free_addr, spawn_engine, test_dir};
+use cratetorrent::{prelude::*, Sha1Hash};
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+const BLOCK_LEN: usize = 0x4000;
+const PIECE_LEN: usize = 2 * BLOCK_LEN;
+
+#[tokio::test(threaded_scheduler)]
+async fn test_serve_padding_to_v1_peer() {
+    let dir = test_dir("hybrid");
+    let seed_dir = dir.join("seed");
+    // the first file is less than a block long, so its only piece is followed
+    // by more than a block of padding in the v1 view
+    let a: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let b: Vec<u8> = (0..40_000u32).map(|i| (i % 241) as u8).collect();
+    fs::create_dir_all(seed_dir.join("hybrid")).unwrap();
+    fs::write(seed_dir.join("hybrid").join("a"), &a).unwrap();
+    fs::write(seed_dir.join("hybrid").join("b"), &b).unwrap();
+    let metainfo = create_hybrid_metainfo(&[("a", &a), ("b", &b)]);
+    let info_hash = metainfo.info_hash;
+
+    let (seed, _alerts) = spawn_engine(&seed_dir, |conf| {
+        conf.torrent.unchoke_interval = Duration::from_secs(1);
+    });
+    let seed_addr = free_addr();
+    seed.create_torrent(TorrentParams {
+        metainfo,
+        conf: None,
+        mode: Mode::Seed,
+        listen_addr: Some(seed_addr),
+        queued: false,
+    })
+    .unwrap();
+
+    // the first block of the first piece is partly padding, and the second
+    // one is all padding
+    let blocks = tokio::task::spawn_blocking(move || {
+        request_blocks(seed_addr, info_hash, &[(0, 0), (0, BLOCK_LEN)])
+    })
+    .await
+    .unwrap();
+
+    seed.shutdown().await.unwrap();
+    fs::remove_dir_all(&dir).ok();
+
+    let mut expected = a.clone();
+    expected.resize(PIECE_LEN, 0);
+    assert_eq!(blocks[0], &expected[..BLOCK_LEN]);
+    assert_eq!(blocks[1], &expected[BLOCK_LEN..]);
+}
+
+/// Connects to the seed as a v1 peer and requests the blocks, given as their
+/// piece indices and offsets, returning them in the same order.
+fn request_blocks(
+    addr: SocketAddr,
+    info_hash: Sha1Hash,
+    blocks: &[(u32, usize)],
+) -> Vec<Vec<u8>> {
+    let mut socket = connect(addr);
+    socket
+        .set_read_timeout(Some(Duration::from_secs(30)))
+        .unwrap();
+
+    let mut handshake = Vec::new();
+    handshake.push(19);
+    handshake.extend_from_slice(b"BitTorrent protocol");
+    handshake.extend_from_slice(&[0; 8]);
+    handshake.extend_from_slice(&info_hash);
+    handshake.extend_from_slice(b"-v1-peer-00000000000");
+    socket.write_all(&handshake).unwrap();
+    let mut peer_handshake = [0; 68];
+    socket.read_exact(&mut peer_handshake).unwrap();
+    assert_eq!(&peer_handshake[28..48], &info_hash);
+
+    // interested
+    send(&mut socket, 2, &[]);
+    while read_msg(&mut socket).0 != 1 {}
+
+    for (piece_index, offset) in blocks.iter() {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&piece_index.to_be_bytes());
+        payload.extend_from_slice(&(*offset as u32).to_be_bytes());
+        payload.extend_from_slice(&(BLOCK_LEN as u32).to_be_bytes());
+        send(&mut socket, 6, &payload);
+    }
+
+    let mut received = HashMap::new();
+    while received.len() < blocks.len() {
+        let (id, payload) = read_msg(&mut socket);
+        if id == 7 {
+            let mut piece_index = [0; 4];
+            piece_index.copy_from_slice(&payload[..4]);
+            let mut offset = [0; 4];
+            offset.copy_from_slice(&payload[4..8]);
+            let key = (
+                u32::from_be_bytes(piece_index),
+                u32::from_be_bytes(offset) as usize,
+            );
+            received.insert(key, payload[8..].to_vec());
+        }
+    }
+    blocks
+        .iter()
+        .map(|key| received.remove(key).unwrap())
+        .collect()
+}
+
+/// Connects to the seed, which starts listening some time after the torrent
+/// is created.
+fn connect(addr: SocketAddr) -> TcpStream {
+    for _ in 0..50 {
+        if let Ok(socket) = TcpStream::connect(addr) {
+            return socket;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    panic!("seed is not listening");
+}
+
+fn send(socket: &mut TcpStream, id: u8, payload: &[u8]) {
+    let len = 1 + payload.len() as u32;
+    socket.write_all(&len.to_be_bytes()).unwrap();
+    socket.write_all(&[id]).unwrap();
+    socket.write_all(payload).unwrap();
+}
+
+/// Reads the next message that is not a keep alive, and returns its id and
+/// payload.
+fn read_msg(socket: &mut TcpStream) -> (u8, Vec<u8>) {
+    loop {
+        let mut len = [0; 4];
+        socket.read_exact(&mut len).unwrap();
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 {
+            continue;
+        }
+        let mut msg = vec![0; len];
+        socket.read_exact(&mut msg).unwrap();
+        return (msg[0], msg[1..].to_vec());
+    }
+}
+
+/// Creates a hybrid torrent of the files, in which each file but the last is
+/// followed by a padding file in the v1 view.
+///
+/// This is encoded by hand as `MetainfoBuilder` only creates v1 torrents.
+fn create_hybrid_metainfo(files: &[(&str, &[u8])]) -> Metainfo {
+    let mut file_tree = HashMap::new();
+    let mut piece_layers = HashMap::new();
+    let mut v1_files = Vec::new();
+    let mut v1_data = Vec::new();
+    for (i, (name, data)) in files.iter().enumerate() {
+        let leaves: Vec<_> = data.chunks(BLOCK_LEN).map(sha256).collect();
+        let root = merkle_root(&leaves, leaves.len().next_power_of_two());
+        if data.len() > PIECE_LEN {
+            let piece_hashes: Vec<u8> = leaves
+                .chunks(PIECE_LEN / BLOCK_LEN)
+                .flat_map(|leaves| merkle_root(leaves, PIECE_LEN / BLOCK_LEN))
+                .collect();
+            piece_layers.insert(root.to_vec(), Value::Bytes(piece_hashes));
+        }
+
+        let mut props = HashMap::new();
+        props.insert(b"length".to_vec(), Value::Int(data.len() as i64));
+        props.insert(b"pieces root".to_vec(), Value::Bytes(root.to_vec()));
+        let mut file = HashMap::new();
+        file.insert(Vec::new(), Value::Dict(props));
+        file_tree.insert(name.as_bytes().to_vec(), Value::Dict(file));
+
+        v1_data.extend_from_slice(data);
+        v1_files.push(v1_file(name, data.len(), false));
+        let pad_len = (PIECE_LEN - data.len() % PIECE_LEN) % PIECE_LEN;
+        if i + 1 < files.len() && pad_len > 0 {
+            v1_data.resize(v1_data.len() + pad_len, 0);
+            v1_files.push(v1_file(".pad", pad_len, true));
+        }
+    }
+    let pieces = v1_data
+        .chunks(PIECE_LEN)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect();
+
+    let mut info = HashMap::new();
+    info.insert(b"name".to_vec(), Value::Bytes(b"hybrid".to_vec()));
+    info.insert(b"piece length".to_vec(), Value::Int(PIECE_LEN as i64));
+    info.insert(b"meta version".to_vec(), Value::Int(2));
+    info.insert(b"file tree".to_vec(), Value::Dict(file_tree));
+    info.insert(b"files".to_vec(), Value::List(v1_files));
+    info.insert(b"pieces".to_vec(), Value::Bytes(pieces));
+    let mut metainfo = HashMap::new();
+    metainfo.insert(b"info".to_vec(), Value::Dict(info));
+    metainfo.insert(b"piece layers".to_vec(), Value::Dict(piece_layers));
+    let buf = serde_bencode::to_bytes(&Value::Dict(metainfo)).unwrap();
+    Metainfo::from_bytes(&buf).unwrap()
+}
+
+fn v1_file(path: &str, len: usize, is_padding: bool) -> Value {
+    let mut file = HashMap::new();
+    file.insert(b"length".to_vec(), Value::Int(len as i64));
+    file.insert(
+        b"path".to_vec(),
+        Value::List(vec![Value::Bytes(path.as_bytes().to_vec())]),
+    );
+    if is_padding {
+        file.insert(b"attr".to_vec(), Value::Bytes(b"p".to_vec()));
+    }
+    Value::Dict(file)
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&Sha256::digest(data));
+    hash
+}
+
+/// Returns the root of the merkle tree whose leaf layer starts with the
+/// leaves and is `width` leaves wide, padded with zero leaves.
+fn merkle_root(leaves: &[[u8; 32]], width: usize) -> [u8; 32] {
+    let mut nodes = leaves.to_vec();
+    nodes.resize(width, [0; 32]);
+    while nodes.len() > 1 {
+        nodes = nodes.chunks(2).map(|pair| sha256(&pair.concat())).collect();
+    }
+    nodes[0]
+}
@@ -0,0 +1,135 @@
+//! Tests that two engines on the same host can download a torrent from one
+//! another, with the downloader given the address of the seed.
+
+mod common;
+
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    time::Duration,
+};
+
+use common::{create_metainfo, free_addr_on, test_data, test_dir};
+use cratetorrent::{
+    alert::{Alert, AlertReceiver},
+    conf::{Conf, TransportPolicy},
+    peer::Transport,
+    prelude::*,
+    torrent::stats::{PeerSessionStats, Peers},
+};
+
+#[tokio::test]
+async fn test_download_over_utp() {
+    let peers = download("utp", Ipv4Addr::LOCALHOST.into(), |conf| {
+        conf.torrent.transport = TransportPolicy::PreferUtp;
+    })
+    .await;
+    let transports: Vec<_> = peers.iter().map(|peer| peer.transport).collect();
+    assert_eq!(transports, vec![Some(Transport::Utp)]);
+}
+
+#[tokio::test]
+async fn test_download_over_ipv6() {
+    let peers = download("ipv6", Ipv6Addr::LOCALHOST.into(), |_| ()).await;
+    let addrs: Vec<_> = peers.iter().map(|peer| peer.addr.ip()).collect();
+    assert_eq!(addrs, vec![IpAddr::from(Ipv6Addr::LOCALHOST)]);
+}
+
+/// Seeds a torrent on an engine listening on the IP address, downloads it with
+/// another engine listening on the same address, and returns the peers of the
+/// downloader.
+///
+/// Both engines are configured by the function.
+async fn download(
+    name: &str,
+    ip: IpAddr,
+    configure: impl Fn(&mut Conf),
+) -> Vec<PeerSessionStats> {
+    let dir = test_dir(&format!("loopback-{}", name));
+    let seed_dir = dir.join("seed");
+    let download_dir = dir.join("download");
+    let data = test_data(200_000);
+    let metainfo = create_metainfo(&seed_dir.join(name), &data, &[]);
+
+    // the seed's address is given to the downloader before the seed starts
+    // listening, so it has to be known in advance
+    let seed_addr = free_addr_on(ip);
+    let (seed, _seed_alerts) = spawn_engine(&seed_dir, &configure);
+    seed.create_torrent(TorrentParams {
+        metainfo: metainfo.clone(),
+        conf: None,
+        mode: Mode::Seed,
+        listen_addr: Some(seed_addr),
+        queued: false,
+    })
+    .unwrap();
+
+    let (downloader, alerts) = spawn_engine(&download_dir, &configure);
+    downloader
+        .create_torrent(TorrentParams {
+            metainfo,
+            conf: None,
+            mode: Mode::Download {
+                seeds: vec![seed_addr],
+            },
+            listen_addr: Some(free_addr_on(ip)),
+            queued: false,
+        })
+        .unwrap();
+
+    let peers = tokio::time::timeout(Duration::from_secs(60), complete(alerts))
+        .await
+        .expect("download timed out");
+
+    downloader.shutdown().await.unwrap();
+    seed.shutdown().await.unwrap();
+
+    assert_eq!(fs::read(download_dir.join(name)).unwrap(), data);
+    fs::remove_dir_all(&dir).ok();
+    peers
+}
+
+/// Waits for the download to complete, and returns the peers in the first
+/// stats in which all peers are connected.
+///
+/// The download may complete before the first stats are sent, so the stats
+/// are waited for even after the download completes.
+async fn complete(mut alerts: AlertReceiver) -> Vec<PeerSessionStats> {
+    let mut is_complete = false;
+    let mut connected_peers = None;
+    while let Some(alert) = alerts.next().await {
+        match alert {
+            Alert::TorrentComplete(_) => is_complete = true,
+            Alert::TorrentStats { stats, .. } => {
+                if let Peers::Full(peers) = stats.peers {
+                    if connected_peers.is_none()
+                        && !peers.is_empty()
+                        && peers.iter().all(|peer| peer.transport.is_some())
+                    {
+                        connected_peers = Some(peers);
+                    }
+                }
+            }
+            _ => (),
+        }
+        if is_complete {
+            if let Some(peers) = connected_peers.take() {
+                return peers;
+            }
+        }
+    }
+    panic!("engine stopped before completing the download");
+}
+
+/// Spawns an engine that reports its peers in its stats, configured further
+/// by the function.
+fn spawn_engine(
+    dir: &Path,
+    configure: impl Fn(&mut Conf),
+) -> (EngineHandle, AlertReceiver) {
+    common::spawn_engine(dir, |conf| {
+        conf.torrent.alerts.peers = true;
+        configure(conf);
+    })
+}
@@ -0,0 +1,84 @@
+//! Tests that queued torrents scrape their trackers, without announcing
+//! themselves.
+
+mod common;
+
+use std::{collections::HashMap, time::Duration};
+
+use common::{create_metainfo, spawn_engine, test_data, test_dir, PIECE_LEN};
+use cratetorrent::{
+    alert::{Alert, AlertReceiver},
+    prelude::*,
+    torrent::stats::Scrape,
+};
+use mockito::{mock, Matcher};
+use serde_bencode::value::Value;
+
+#[tokio::test]
+async fn test_scrape_queued_torrent() {
+    let dir = test_dir("queue");
+    let announce_url = format!("{}/announce", mockito::server_url());
+    let metainfo = create_metainfo(
+        &dir.join("source").join("queued"),
+        &test_data(2 * PIECE_LEN as usize),
+        &[&[&announce_url]],
+    );
+
+    let mut files = HashMap::new();
+    let mut file = HashMap::new();
+    file.insert(b"complete".to_vec(), Value::Int(5));
+    file.insert(b"incomplete".to_vec(), Value::Int(10));
+    file.insert(b"downloaded".to_vec(), Value::Int(50));
+    files.insert(metainfo.info_hash.to_vec(), Value::Dict(file));
+    let mut resp = HashMap::new();
+    resp.insert(b"files".to_vec(), Value::Dict(files));
+    let scrape_mock = mock("GET", "/scrape")
+        .match_query(Matcher::Any)
+        .with_body(serde_bencode::to_bytes(&Value::Dict(resp)).unwrap())
+        .create();
+    let announce_mock = mock("GET", "/announce")
+        .match_query(Matcher::Any)
+        .expect(0)
+        .create();
+
+    let (engine, alerts) = spawn_engine(&dir.join("download"), |_| ());
+    engine
+        .create_torrent(TorrentParams {
+            metainfo,
+            conf: None,
+            mode: Mode::Download { seeds: Vec::new() },
+            listen_addr: None,
+            queued: true,
+        })
+        .unwrap();
+
+    let scrape = tokio::time::timeout(Duration::from_secs(10), scraped(alerts))
+        .await
+        .expect("queued torrent wasn't scraped");
+    engine.shutdown().await.unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+
+    assert_eq!(
+        scrape,
+        Scrape {
+            seeder_count: 5,
+            leecher_count: 10,
+            download_count: 50,
+        }
+    );
+    scrape_mock.assert();
+    announce_mock.assert();
+}
+
+/// Waits for the first stats of the torrent that include the result of
+/// a scrape of its tracker.
+async fn scraped(mut alerts: AlertReceiver) -> Scrape {
+    while let Some(alert) = alerts.next().await {
+        if let Alert::TorrentStats { stats, .. } = alert {
+            if let Some(scrape) = stats.trackers[0].scrape {
+                return scrape;
+            }
+        }
+    }
+    panic!("engine stopped before the torrent was scraped");
+}
@@ -0,0 +1,84 @@
+//! Tests that a tracker that is slow to respond doesn't hold up the torrent.
+//!
+//! The mock server responds to one request at a time, so this is kept apart
+//! from the other tracker tests.
+
+mod common;
+
+use std::{collections::HashMap, thread, time::Duration};
+
+use common::{create_metainfo, spawn_engine, test_data, test_dir, PIECE_LEN};
+use cratetorrent::{
+    alert::{Alert, AlertReceiver},
+    prelude::*,
+    torrent::stats::TrackerStatus,
+};
+use mockito::{mock, Matcher};
+use serde_bencode::value::Value;
+
+#[tokio::test]
+async fn test_slow_tracker_does_not_block_torrent() {
+    let dir = test_dir("slow-tracker");
+    let announce_url = format!("{}/announce", mockito::server_url());
+    let metainfo = create_metainfo(
+        &dir.join("source").join("slow-tracker"),
+        &test_data(2 * PIECE_LEN as usize),
+        &[&[&announce_url]],
+    );
+
+    let mut resp = HashMap::new();
+    resp.insert(b"interval".to_vec(), Value::Int(1800));
+    resp.insert(b"peers".to_vec(), Value::Bytes(Vec::new()));
+    let resp = serde_bencode::to_bytes(&Value::Dict(resp)).unwrap();
+    let _mock = mock("GET", "/announce")
+        .match_query(Matcher::Any)
+        .with_body_from_fn(move |w| {
+            thread::sleep(Duration::from_secs(5));
+            w.write_all(&resp)
+        })
+        .create();
+
+    let (engine, alerts) = spawn_engine(&dir.join("download"), |conf| {
+        conf.torrent.scrape_interval = None;
+        conf.torrent.stop_announce_timeout = Duration::from_secs(1);
+    });
+    engine
+        .create_torrent(TorrentParams {
+            metainfo,
+            conf: None,
+            mode: Mode::Download { seeds: Vec::new() },
+            listen_addr: None,
+            queued: false,
+        })
+        .unwrap();
+
+    // the torrent keeps ticking, and so posting its stats every second, while
+    // it waits for the tracker
+    let updating_count = tokio::time::timeout(
+        Duration::from_secs(4),
+        count_updating_stats(alerts, 3),
+    )
+    .await
+    .expect("torrent was blocked by the tracker");
+    engine.shutdown().await.unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+
+    assert_eq!(updating_count, 3);
+}
+
+/// Counts the stats of the torrent in which its tracker is being announced
+/// to, until the given count is reached.
+async fn count_updating_stats(mut alerts: AlertReceiver, max: usize) -> usize {
+    let mut count = 0;
+    while let Some(alert) = alerts.next().await {
+        if let Alert::TorrentStats { stats, .. } = alert {
+            if stats.trackers[0].status == TrackerStatus::Updating {
+                count += 1;
+                if count == max {
+                    break;
+                }
+            }
+        }
+    }
+    count
+}
@@ -0,0 +1,338 @@
+//! Tests the editing of a running torrent's trackers, announcing to them
+//! outside of their schedule, and choosing which trackers of which tiers to
+//! announce to.
+
+mod common;
+
+use std::{collections::HashMap, time::Duration};
+
+use common::{create_metainfo, spawn_engine, test_data, test_dir, PIECE_LEN};
+use cratetorrent::{
+    alert::{Alert, AlertReceiver},
+    conf::Conf,
+    prelude::*,
+    torrent::stats::{TorrentStats, TrackerStatus},
+};
+use mockito::{mock, Matcher, Mock};
+use reqwest::Url;
+use serde_bencode::value::Value;
+
+#[tokio::test]
+async fn test_add_tracker() {
+    let url = tracker_url("/add/announce");
+    let started_mock = announce_mock("/add/announce", Some("started"));
+
+    let (engine, mut alerts, id) = spawn_torrent("add", &[]);
+    engine.add_tracker(id, url.clone(), 0).unwrap();
+    let stats = wait_for_stats(&mut alerts, |stats| {
+        stats.trackers.len() == 1
+            && stats.trackers[0].status == TrackerStatus::Working
+    })
+    .await;
+    engine.shutdown().await.unwrap();
+
+    assert_eq!(stats.trackers[0].url, url);
+    assert_eq!(stats.trackers[0].tier, 0);
+    // the first announce to the new tracker tells it of the torrent's start
+    started_mock.assert();
+}
+
+#[tokio::test]
+async fn test_remove_tracker() {
+    let _mock_a = announce_mock("/remove/a", None);
+    let mock_b = announce_mock("/remove/b", Some("started"));
+    let url_a = tracker_url("/remove/a");
+    let url_b = tracker_url("/remove/b");
+
+    let (engine, mut alerts, id) =
+        spawn_torrent("remove", &[&[&url_a], &[&url_b]]);
+    engine.remove_tracker(id, url_a).unwrap();
+    // the removed tracker's tier is removed with it, so the next tier becomes
+    // the first one and is announced to
+    let stats = wait_for_stats(&mut alerts, |stats| {
+        stats.trackers.len() == 1
+            && stats.trackers[0].status == TrackerStatus::Working
+    })
+    .await;
+    engine.shutdown().await.unwrap();
+
+    assert_eq!(stats.trackers[0].url, url_b);
+    assert_eq!(stats.trackers[0].tier, 0);
+    mock_b.assert();
+}
+
+#[tokio::test]
+async fn test_replace_trackers() {
+    let _mock_a = announce_mock("/replace/a", None);
+    let mock_b = announce_mock("/replace/b", Some("started"));
+    let url_a = tracker_url("/replace/a");
+    let url_b = tracker_url("/replace/b");
+
+    let (engine, mut alerts, id) = spawn_torrent("replace", &[&[&url_a]]);
+    let stats = wait_for_stats(&mut alerts, |stats| {
+        stats.trackers[0].status == TrackerStatus::Working
+    })
+    .await;
+    let last_announce_time = stats.trackers[0].last_announce_time;
+
+    engine
+        .replace_trackers(id, vec![vec![url_b.clone()], vec![url_a.clone()]])
+        .unwrap();
+    let stats = wait_for_stats(&mut alerts, |stats| {
+        stats.trackers.len() == 2
+            && stats.trackers[0].status == TrackerStatus::Working
+    })
+    .await;
+    engine.shutdown().await.unwrap();
+
+    assert_eq!(stats.trackers[0].url, url_b);
+    assert_eq!(stats.trackers[0].tier, 0);
+    mock_b.assert();
+    // the tracker that is kept is moved to its new tier with its state
+    assert_eq!(stats.trackers[1].url, url_a);
+    assert_eq!(stats.trackers[1].tier, 1);
+    assert_eq!(stats.trackers[1].status, TrackerStatus::Working);
+    assert_eq!(stats.trackers[1].last_announce_time, last_announce_time);
+}
+
+#[tokio::test]
+async fn test_reannounce() {
+    // the torrent's start, the forced announce and the torrent's stop
+    let mock = announce_mock("/reannounce/announce", None).expect(3);
+    let url = tracker_url("/reannounce/announce");
+
+    let (engine, mut alerts, id) = spawn_torrent("reannounce", &[&[&url]]);
+    let stats = wait_for_stats(&mut alerts, |stats| {
+        stats.trackers[0].status == TrackerStatus::Working
+    })
+    .await;
+    let last_announce_time = stats.trackers[0].last_announce_time;
+
+    // the tracker's minimum interval hasn't passed, so it's skipped unless
+    // forced
+    engine.announce(id, None, false).unwrap();
+    tokio::time::delay_for(Duration::from_secs(2)).await;
+    let stats = wait_for_stats(&mut alerts, |_| true).await;
+    assert_eq!(stats.trackers[0].last_announce_time, last_announce_time);
+
+    engine.announce(id, Some(url), true).unwrap();
+    let stats = wait_for_stats(&mut alerts, |stats| {
+        stats.trackers[0].status == TrackerStatus::Working
+            && stats.trackers[0].last_announce_time > last_announce_time
+    })
+    .await;
+    engine.shutdown().await.unwrap();
+
+    assert_eq!(stats.trackers[0].status, TrackerStatus::Working);
+    mock.assert();
+}
+
+#[tokio::test]
+async fn test_announce_stop_to_failing_tracker() {
+    let started_mock = announce_mock("/stop/announce", Some("started"));
+    let stopped_mock = announce_mock("/stop/announce", Some("stopped"));
+    let url = tracker_url("/stop/announce");
+
+    let (engine, mut alerts, id) = spawn_torrent("stop", &[&[&url]]);
+    wait_for_stats(&mut alerts, |stats| {
+        stats.trackers[0].status == TrackerStatus::Working
+    })
+    .await;
+    // announces without an event aren't mocked, so the tracker fails
+    engine.announce(id, None, true).unwrap();
+    wait_for_stats(&mut alerts, |stats| {
+        matches!(stats.trackers[0].status, TrackerStatus::Error(_))
+    })
+    .await;
+    engine.shutdown().await.unwrap();
+
+    // the tracker was told of the torrent's start, so it's told of its stop
+    // even though it's no longer working
+    started_mock.assert();
+    stopped_mock.assert();
+}
+
+#[tokio::test]
+async fn test_try_next_tracker_in_tier() {
+    // the first tracker isn't mocked, so it fails
+    let url_a = tracker_url("/next-tracker/a");
+    let mock_b = announce_mock("/next-tracker/b", Some("started"));
+    let url_b = tracker_url("/next-tracker/b");
+
+    // the trackers of the metainfo are shuffled within their tiers, but not
+    // those that replace them, so the failing tracker is tried first
+    let (engine, mut alerts, id) = spawn_torrent("next-tracker", &[]);
+    engine
+        .replace_trackers(id, vec![vec![url_a.clone(), url_b.clone()]])
+        .unwrap();
+    let stats = wait_for_stats(&mut alerts, |stats| {
+        stats.trackers.len() == 2
+            && stats.trackers[0].status == TrackerStatus::Working
+    })
+    .await;
+    engine.shutdown().await.unwrap();
+
+    // the tracker that responded is moved to the front of its tier, so that
+    // it's tried first from then on
+    assert_eq!(stats.trackers[0].url, url_b);
+    assert_eq!(stats.trackers[0].tier, 0);
+    assert_eq!(stats.trackers[1].url, url_a);
+    assert_eq!(stats.trackers[1].tier, 0);
+    assert!(matches!(stats.trackers[1].status, TrackerStatus::Error(_)));
+    // the event of the failed announce is announced to the next tracker
+    mock_b.assert();
+}
+
+#[tokio::test]
+async fn test_try_next_tier() {
+    // the tracker of the first tier isn't mocked, so it fails
+    let url_a = tracker_url("/next-tier/a");
+    let mock_b = announce_mock("/next-tier/b", Some("started"));
+    let url_b = tracker_url("/next-tier/b");
+
+    let (engine, mut alerts, _) =
+        spawn_torrent("next-tier", &[&[&url_a], &[&url_b]]);
+    let stats = wait_for_stats(&mut alerts, |stats| {
+        stats.trackers[1].status == TrackerStatus::Working
+    })
+    .await;
+    engine.shutdown().await.unwrap();
+
+    assert!(matches!(stats.trackers[0].status, TrackerStatus::Error(_)));
+    assert_eq!(stats.trackers[1].url, url_b);
+    assert_eq!(stats.trackers[1].tier, 1);
+    mock_b.assert();
+}
+
+#[tokio::test]
+async fn test_stop_at_first_working_tier() {
+    let mock_a = announce_mock("/first-tier/a", Some("started"));
+    let mock_b = announce_mock("/first-tier/b", None).expect(0);
+    let url_a = tracker_url("/first-tier/a");
+    let url_b = tracker_url("/first-tier/b");
+
+    let (engine, mut alerts, _) =
+        spawn_torrent("first-tier", &[&[&url_a], &[&url_b]]);
+    wait_for_stats(&mut alerts, |stats| {
+        stats.trackers[0].status == TrackerStatus::Working
+    })
+    .await;
+    // the following ticks don't announce to the second tier either
+    tokio::time::delay_for(Duration::from_secs(2)).await;
+    let stats = wait_for_stats(&mut alerts, |_| true).await;
+    engine.shutdown().await.unwrap();
+
+    assert_eq!(stats.trackers[0].status, TrackerStatus::Working);
+    assert_eq!(stats.trackers[1].status, TrackerStatus::NotContacted);
+    mock_a.assert();
+    mock_b.assert();
+}
+
+#[tokio::test]
+async fn test_announce_to_all_tiers() {
+    let mock_a = announce_mock("/all-tiers/a", Some("started"));
+    let mock_b = announce_mock("/all-tiers/b", Some("started"));
+    let url_a = tracker_url("/all-tiers/a");
+    let url_b = tracker_url("/all-tiers/b");
+
+    let (engine, mut alerts, _) =
+        spawn_torrent_with("all-tiers", &[&[&url_a], &[&url_b]], |conf| {
+            conf.torrent.announce_to_all_tiers = true
+        });
+    wait_for_stats(&mut alerts, |stats| {
+        stats
+            .trackers
+            .iter()
+            .all(|tracker| tracker.status == TrackerStatus::Working)
+    })
+    .await;
+    engine.shutdown().await.unwrap();
+
+    mock_a.assert();
+    mock_b.assert();
+}
+
+fn tracker_url(path: &str) -> Url {
+    format!("{}{}", mockito::server_url(), path)
+        .parse()
+        .unwrap()
+}
+
+/// Mocks a tracker at the path that responds to announces with the given
+/// event, or to any announce if no event is given.
+fn announce_mock(path: &str, event: Option<&str>) -> Mock {
+    let query = match event {
+        Some(event) => Matcher::UrlEncoded("event".into(), event.into()),
+        None => Matcher::Any,
+    };
+    let mut resp = HashMap::new();
+    resp.insert(b"interval".to_vec(), Value::Int(1800));
+    resp.insert(b"min interval".to_vec(), Value::Int(1800));
+    resp.insert(b"peers".to_vec(), Value::Bytes(Vec::new()));
+    mock("GET", path)
+        .match_query(query)
+        .with_body(serde_bencode::to_bytes(&Value::Dict(resp)).unwrap())
+        .create()
+}
+
+/// Spawns an engine with a torrent that has the given tiers of trackers.
+fn spawn_torrent(
+    name: &str,
+    trackers: &[&[&Url]],
+) -> (EngineHandle, AlertReceiver, TorrentId) {
+    spawn_torrent_with(name, trackers, |_| ())
+}
+
+/// Like [`spawn_torrent`], but the engine is further configured by the
+/// function.
+fn spawn_torrent_with(
+    name: &str,
+    trackers: &[&[&Url]],
+    configure: impl FnOnce(&mut Conf),
+) -> (EngineHandle, AlertReceiver, TorrentId) {
+    let dir = test_dir(&format!("trackers-{}", name));
+    let tiers: Vec<Vec<&str>> = trackers
+        .iter()
+        .map(|tier| tier.iter().map(|url| url.as_str()).collect())
+        .collect();
+    let tiers: Vec<&[&str]> = tiers.iter().map(Vec::as_slice).collect();
+    let metainfo = create_metainfo(
+        &dir.join("source").join(name),
+        &test_data(2 * PIECE_LEN as usize),
+        &tiers,
+    );
+    let (engine, alerts) = spawn_engine(&dir.join("download"), |conf| {
+        conf.torrent.scrape_interval = None;
+        configure(conf);
+    });
+    let id = engine
+        .create_torrent(TorrentParams {
+            metainfo,
+            conf: None,
+            mode: Mode::Download { seeds: Vec::new() },
+            listen_addr: None,
+            queued: false,
+        })
+        .unwrap();
+    (engine, alerts, id)
+}
+
+/// Waits for the first stats of the torrent that satisfy the predicate.
+async fn wait_for_stats(
+    alerts: &mut AlertReceiver,
+    pred: impl Fn(&TorrentStats) -> bool,
+) -> TorrentStats {
+    let stats = async {
+        while let Some(alert) = alerts.next().await {
+            if let Alert::TorrentStats { stats, .. } = alert {
+                if pred(&stats) {
+                    return *stats;
+                }
+            }
+        }
+        panic!("engine stopped before the expected stats");
+    };
+    tokio::time::timeout(Duration::from_secs(10), stats)
+        .await
+        .expect("timed out waiting for stats")
+}